@@ -0,0 +1,206 @@
+/*
+A persistent job queue with at least once delivery. Jobs are keyed by their id, and the top
+bit of the key says whether a job waits or runs
+---------------------------------------
+| running (1 bit) | job id (63 bits) |
+---------------------------------------
+so waiting jobs come first in id order and the first key of the tree is the next job. Claiming
+a job moves it to the running half in one transaction, acknowledging it deletes it. Key 0
+holds the id the next job gets.
+
+A worker that dies with jobs claimed leaves them in the running half. Opening the queue puts
+every running job back in line, under its old id so it keeps its place, and it runs again.
+
+    cargo run --example job_queue [dir]
+*/
+
+use std::error::Error;
+use std::path::Path;
+
+use e_bin::btree::BTree;
+use e_bin::log::WalStore;
+use e_bin::page::{PageStore, Pager};
+
+const RUNNING: u64 = 1 << 63;
+const NEXT_ID: u64 = 0;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Opens the tree in `dir`, creating it on first use
+fn open(dir: &Path) -> Result<BTree<WalStore<Pager>>> {
+    let mut pager = Pager::open(dir.join("jobs.db").to_str().ok_or("Path isn't UTF-8")?)?;
+    if pager.root() == 0 {
+        let tree = BTree::create(pager)?;
+        let root = tree.root();
+        pager = tree.into_store();
+        pager.set_root(root)?;
+        pager.sync()?;
+    }
+    let root = pager.root();
+    let wal = WalStore::open(
+        pager,
+        dir.join("jobs.wal").to_str().ok_or("Path isn't UTF-8")?,
+    )?;
+    Ok(BTree::open(wal, root)?)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Job {
+    id: u64,
+    payload: Vec<u8>,
+}
+
+struct Queue {
+    jobs: BTree<WalStore<Pager>>,
+}
+
+impl Queue {
+    /// Opens the queue in `dir`, putting jobs that were running back in line. Returns how
+    /// many were.
+    fn open(dir: &Path) -> Result<(Self, usize)> {
+        let mut jobs = open(dir)?;
+        let mut running = Vec::new();
+        let mut cursor = jobs.cursor()?;
+        let mut entry = cursor.seek(RUNNING)?;
+        while let Some((key, payload)) = entry {
+            running.push((key & !RUNNING, payload.to_vec()));
+            entry = cursor.next()?;
+        }
+
+        let mut transaction = jobs.transaction();
+        for (id, payload) in &running {
+            transaction.delete(id | RUNNING)?;
+            transaction.insert(*id, payload)?;
+        }
+        transaction.commit()?;
+        Ok((Self { jobs }, running.len()))
+    }
+
+    fn enqueue(&mut self, payload: &[u8]) -> Result<u64> {
+        let mut transaction = self.jobs.transaction();
+        let id = match transaction.get(NEXT_ID)? {
+            Some(next) => u64::from_le_bytes(next.as_slice().try_into()?),
+            None => 1,
+        };
+        transaction.insert(NEXT_ID, &(id + 1).to_le_bytes())?;
+        transaction.insert(id, payload)?;
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    /// Takes the oldest waiting job, `None` if there is none
+    fn claim(&mut self) -> Result<Option<Job>> {
+        let mut transaction = self.jobs.transaction();
+        let job = match transaction.cursor()?.seek(NEXT_ID + 1)? {
+            Some((id, payload)) if id < RUNNING => Job {
+                id,
+                payload: payload.to_vec(),
+            },
+            _ => return Ok(None),
+        };
+        transaction.delete(job.id)?;
+        transaction.insert(job.id | RUNNING, &job.payload)?;
+        transaction.commit()?;
+        Ok(Some(job))
+    }
+
+    /// Marks a claimed job as done, false if it wasn't running
+    fn ack(&mut self, id: u64) -> Result<bool> {
+        let mut transaction = self.jobs.transaction();
+        let done = transaction.delete(id | RUNNING)?.is_some();
+        transaction.commit()?;
+        Ok(done)
+    }
+
+    fn waiting(&mut self) -> Result<usize> {
+        let mut waiting = 0;
+        let mut cursor = self.jobs.cursor()?;
+        let mut entry = cursor.seek(NEXT_ID + 1)?;
+        while entry.is_some_and(|(key, _)| key < RUNNING) {
+            waiting += 1;
+            entry = cursor.next()?;
+        }
+        Ok(waiting)
+    }
+}
+
+fn run(dir: &Path) -> Result<()> {
+    let (mut queue, _) = Queue::open(dir)?;
+    for n in 0..5 {
+        let id = queue.enqueue(format!("resize image {n}").as_bytes())?;
+        println!("enqueued job {id}");
+    }
+    let first = queue.claim()?.ok_or("Queue is empty")?;
+    queue.ack(first.id)?;
+    println!("done with job {}", first.id);
+    let second = queue.claim()?.ok_or("Queue is empty")?;
+    println!("claimed job {}, then the worker died", second.id);
+    drop(queue);
+
+    let (mut queue, requeued) = Queue::open(dir)?;
+    println!("requeued {requeued} jobs, {} waiting", queue.waiting()?);
+    while let Some(job) = queue.claim()? {
+        println!("job {}: {}", job.id, String::from_utf8_lossy(&job.payload));
+        queue.ack(job.id)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    match std::env::args().nth(1) {
+        Some(dir) => run(Path::new(&dir)),
+        None => run(tempfile::tempdir()?.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn jobs_run_in_order() {
+        let dir = tempdir().unwrap();
+        let (mut queue, requeued) = Queue::open(dir.path()).unwrap();
+        assert_eq!(requeued, 0);
+        for n in 0..200u32 {
+            queue.enqueue(&n.to_le_bytes()).unwrap();
+        }
+        for n in 0..200u32 {
+            let job = queue.claim().unwrap().unwrap();
+            assert_eq!(job.id, n as u64 + 1);
+            assert_eq!(job.payload, n.to_le_bytes());
+            assert!(queue.ack(job.id).unwrap());
+        }
+        assert_eq!(queue.claim().unwrap(), None);
+        assert!(!queue.ack(1).unwrap());
+        assert_eq!(queue.enqueue(b"later").unwrap(), 201);
+    }
+
+    #[test]
+    fn claimed_jobs_run_again_after_a_crash() {
+        let dir = tempdir().unwrap();
+        let (mut queue, _) = Queue::open(dir.path()).unwrap();
+        for payload in [b"a", b"b", b"c", b"d"] {
+            queue.enqueue(payload).unwrap();
+        }
+        let a = queue.claim().unwrap().unwrap();
+        queue.ack(a.id).unwrap();
+        let b = queue.claim().unwrap().unwrap();
+        let c = queue.claim().unwrap().unwrap();
+        queue.ack(c.id).unwrap();
+        // A claim that never commits takes nothing
+        let mut transaction = queue.jobs.transaction();
+        transaction.delete(4).unwrap();
+        drop(transaction);
+        drop(queue);
+
+        let (mut queue, requeued) = Queue::open(dir.path()).unwrap();
+        assert_eq!(requeued, 1);
+        assert_eq!(queue.waiting().unwrap(), 2);
+        assert_eq!(queue.claim().unwrap(), Some(b));
+        assert_eq!(queue.claim().unwrap().unwrap().payload, b"d");
+        assert_eq!(queue.claim().unwrap(), None);
+    }
+}
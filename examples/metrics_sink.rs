@@ -0,0 +1,236 @@
+/*
+A sink for time series samples. Every sample is keyed by its series in the top 16 bits and its
+timestamp in seconds below, so the samples of a series sit next to each other in timestamp
+order and a time range of one series is a single scan
+-----------------------------------------------
+| series (16 bits) | timestamp (48 bits) |
+-----------------------------------------------
+Values are f64, little endian. Samples arrive in batches and a batch is one transaction: after
+a crash either all of it is there or none of it, a dashboard never sums half a batch.
+Retention deletes everything older than a cutoff, series by series.
+
+    cargo run --example metrics_sink [dir]
+*/
+
+use std::error::Error;
+use std::ops::Range;
+use std::path::Path;
+
+use e_bin::btree::BTree;
+use e_bin::log::WalStore;
+use e_bin::page::{PageStore, Pager};
+
+const TIMESTAMP_BITS: u32 = 48;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn key(series: u16, timestamp: u64) -> u64 {
+    assert!(timestamp < 1 << TIMESTAMP_BITS, "Timestamp out of range");
+    (series as u64) << TIMESTAMP_BITS | timestamp
+}
+
+/// Opens the tree in `dir`, creating it on first use
+fn open(dir: &Path) -> Result<BTree<WalStore<Pager>>> {
+    let mut pager = Pager::open(dir.join("metrics.db").to_str().ok_or("Path isn't UTF-8")?)?;
+    if pager.root() == 0 {
+        let tree = BTree::create(pager)?;
+        let root = tree.root();
+        pager = tree.into_store();
+        pager.set_root(root)?;
+        pager.sync()?;
+    }
+    let root = pager.root();
+    let log = dir.join("metrics.wal");
+    let wal = WalStore::open(pager, log.to_str().ok_or("Path isn't UTF-8")?)?;
+    Ok(BTree::open(wal, root)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    series: u16,
+    timestamp: u64,
+    value: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Summary {
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+}
+
+struct Sink {
+    samples: BTree<WalStore<Pager>>,
+}
+
+impl Sink {
+    fn open(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            samples: open(dir)?,
+        })
+    }
+
+    /// Stores `batch` atomically. A sample for a series and timestamp seen before replaces
+    /// the earlier one.
+    fn record(&mut self, batch: &[Sample]) -> Result<()> {
+        let mut transaction = self.samples.transaction();
+        for sample in batch {
+            let key = key(sample.series, sample.timestamp);
+            transaction.insert(key, &sample.value.to_le_bytes())?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Calls `f` with every sample of `series` within `time`, oldest first
+    fn scan(&mut self, series: u16, time: Range<u64>, mut f: impl FnMut(u64, f64)) -> Result<()> {
+        let end = key(series, time.end);
+        let mut cursor = self.samples.cursor()?;
+        let mut entry = cursor.seek(key(series, time.start))?;
+        while let Some((key, value)) = entry.filter(|(key, _)| *key < end) {
+            let timestamp = key & ((1 << TIMESTAMP_BITS) - 1);
+            f(timestamp, f64::from_le_bytes(value.try_into()?));
+            entry = cursor.next()?;
+        }
+        Ok(())
+    }
+
+    fn summary(&mut self, series: u16, time: Range<u64>) -> Result<Option<Summary>> {
+        let mut summary: Option<Summary> = None;
+        let mut sum = 0.0;
+        self.scan(series, time, |_, value| {
+            sum += value;
+            let summary = summary.get_or_insert(Summary {
+                count: 0,
+                min: value,
+                max: value,
+                mean: 0.0,
+            });
+            summary.count += 1;
+            summary.min = summary.min.min(value);
+            summary.max = summary.max.max(value);
+        })?;
+        Ok(summary.map(|summary| Summary {
+            mean: sum / summary.count as f64,
+            ..summary
+        }))
+    }
+
+    /// Deletes the samples of `series` older than `cutoff`, returns how many there were
+    fn expire(&mut self, series: u16, cutoff: u64) -> Result<usize> {
+        let mut expired = Vec::new();
+        self.scan(series, 0..cutoff, |timestamp, _| expired.push(timestamp))?;
+        let mut transaction = self.samples.transaction();
+        for &timestamp in &expired {
+            transaction.delete(key(series, timestamp))?;
+        }
+        transaction.commit()?;
+        Ok(expired.len())
+    }
+}
+
+/// A made up cpu load of `series` at `timestamp`
+fn load(series: u16, timestamp: u64) -> f64 {
+    (series as f64 * 10.0) + (timestamp % 60) as f64 / 6.0
+}
+
+fn run(dir: &Path) -> Result<()> {
+    let mut sink = Sink::open(dir)?;
+    for minute in 0..60 {
+        let batch: Vec<Sample> = (0..4)
+            .flat_map(|series| {
+                (0..60).map(move |second| {
+                    let timestamp = minute * 60 + second;
+                    Sample {
+                        series,
+                        timestamp,
+                        value: load(series, timestamp),
+                    }
+                })
+            })
+            .collect();
+        sink.record(&batch)?;
+    }
+    for series in 0..4 {
+        println!("series {series}: {:?}", sink.summary(series, 0..3600)?);
+    }
+    println!("expired {} samples", sink.expire(0, 1800)?);
+
+    // Dies in the middle of a batch, none of it survives
+    let mut transaction = sink.samples.transaction();
+    transaction.insert(key(0, 3600), &99.0f64.to_le_bytes())?;
+    drop(transaction);
+    drop(sink);
+
+    let mut sink = Sink::open(dir)?;
+    println!("recovery: {:?}", sink.samples.store().recovery());
+    println!("series 0 now: {:?}", sink.summary(0, 0..u64::MAX >> 16)?);
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    match std::env::args().nth(1) {
+        Some(dir) => run(Path::new(&dir)),
+        None => run(tempfile::tempdir()?.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn batch(series: u16, time: Range<u64>) -> Vec<Sample> {
+        time.map(|timestamp| Sample {
+            series,
+            timestamp,
+            value: load(series, timestamp),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn ranges_only_cover_their_series() {
+        let dir = tempdir().unwrap();
+        let mut sink = Sink::open(dir.path()).unwrap();
+        for series in [1, 2, 3] {
+            sink.record(&batch(series, 0..600)).unwrap();
+        }
+
+        let summary = sink.summary(2, 60..120).unwrap().unwrap();
+        assert_eq!(summary.count, 60);
+        assert_eq!(summary.min, load(2, 60));
+        assert_eq!(summary.max, load(2, 119));
+        assert_eq!(summary.mean, (load(2, 60) + load(2, 119)) / 2.0);
+        assert_eq!(sink.summary(4, 0..600).unwrap(), None);
+
+        let mut timestamps = Vec::new();
+        sink.scan(3, 590..1000, |timestamp, _| timestamps.push(timestamp))
+            .unwrap();
+        assert_eq!(timestamps, (590..600).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retention_and_crashes_keep_whole_batches() {
+        let dir = tempdir().unwrap();
+        let mut sink = Sink::open(dir.path()).unwrap();
+        sink.record(&batch(1, 0..1000)).unwrap();
+        assert_eq!(sink.expire(1, 400).unwrap(), 400);
+
+        let mut transaction = sink.samples.transaction();
+        for sample in batch(1, 1000..1500) {
+            transaction
+                .insert(key(1, sample.timestamp), &sample.value.to_le_bytes())
+                .unwrap();
+        }
+        drop(transaction);
+        drop(sink);
+
+        let mut sink = Sink::open(dir.path()).unwrap();
+        let summary = sink.summary(1, 0..2000).unwrap().unwrap();
+        assert_eq!(summary.count, 600);
+        assert_eq!(summary.min, load(1, 420));
+    }
+}
@@ -0,0 +1,216 @@
+/*
+A URL shortener on a single tree. Every link gets the next id, handed out as its base62 code,
+and the tree maps ids to URLs. The next id is the last key plus one, read in the same
+transaction that inserts the link, so a link that never committed never uses up an id.
+
+The tree lives in a pager file behind a write-ahead log. A shortener that dies in the middle
+of a transaction leaves the file as it was at the last commit, the next one to open it
+replays the log and carries on from there.
+
+    cargo run --example url_shortener [dir]
+*/
+
+use std::error::Error;
+use std::path::Path;
+
+use e_bin::btree::BTree;
+use e_bin::log::WalStore;
+use e_bin::page::{PageStore, Pager};
+
+const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn encode(mut id: u64) -> String {
+    let mut code = Vec::new();
+    loop {
+        code.push(ALPHABET[(id % 62) as usize]);
+        id /= 62;
+        if id == 0 {
+            break;
+        }
+    }
+    code.reverse();
+    String::from_utf8(code).expect("The alphabet is ASCII")
+}
+
+fn decode(code: &str) -> Option<u64> {
+    code.bytes().try_fold(0u64, |id, byte| {
+        let digit = ALPHABET.iter().position(|&c| c == byte)?;
+        id.checked_mul(62)?.checked_add(digit as u64)
+    })
+}
+
+/// Opens the tree in `dir`, creating it on first use
+fn open(dir: &Path) -> Result<BTree<WalStore<Pager>>> {
+    let mut pager = Pager::open(dir.join("links.db").to_str().ok_or("Path isn't UTF-8")?)?;
+    if pager.root() == 0 {
+        let tree = BTree::create(pager)?;
+        let root = tree.root();
+        pager = tree.into_store();
+        pager.set_root(root)?;
+        pager.sync()?;
+    }
+    let root = pager.root();
+    let wal = WalStore::open(
+        pager,
+        dir.join("links.wal").to_str().ok_or("Path isn't UTF-8")?,
+    )?;
+    Ok(BTree::open(wal, root)?)
+}
+
+struct Shortener {
+    links: BTree<WalStore<Pager>>,
+}
+
+impl Shortener {
+    fn open(dir: &Path) -> Result<Self> {
+        Ok(Self { links: open(dir)? })
+    }
+
+    /// Stores `url` and returns its code
+    fn shorten(&mut self, url: &str) -> Result<String> {
+        let mut transaction = self.links.transaction();
+        let id = match transaction.cursor()?.seek_last()? {
+            Some((last, _)) => last + 1,
+            None => 1,
+        };
+        transaction.insert(id, url.as_bytes())?;
+        transaction.commit()?;
+        Ok(encode(id))
+    }
+
+    fn resolve(&mut self, code: &str) -> Result<Option<String>> {
+        let Some(id) = decode(code) else {
+            return Ok(None);
+        };
+        match self.links.get(id)? {
+            Some(url) => Ok(Some(String::from_utf8(url)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The last `n` links, newest first
+    fn recent(&mut self, n: usize) -> Result<Vec<(String, String)>> {
+        let mut links = Vec::new();
+        for entry in self.links.iter().rev().take(n) {
+            let (id, url) = entry?;
+            links.push((encode(id), String::from_utf8(url)?));
+        }
+        Ok(links)
+    }
+
+    fn delete(&mut self, code: &str) -> Result<bool> {
+        let Some(id) = decode(code) else {
+            return Ok(false);
+        };
+        let mut transaction = self.links.transaction();
+        let deleted = transaction.delete(id)?.is_some();
+        transaction.commit()?;
+        Ok(deleted)
+    }
+}
+
+fn run(dir: &Path) -> Result<()> {
+    let mut shortener = Shortener::open(dir)?;
+    for url in [
+        "https://www.rust-lang.org",
+        "https://doc.rust-lang.org/std/collections/struct.BTreeMap.html",
+        "https://en.wikipedia.org/wiki/B-tree",
+    ] {
+        println!("{} -> {url}", shortener.shorten(url)?);
+    }
+    let code = shortener.shorten("https://expired.example")?;
+    println!("{code} resolves to {:?}", shortener.resolve(&code)?);
+    shortener.delete(&code)?;
+
+    // Dies before committing, the link is gone once the log is replayed
+    let mut transaction = shortener.links.transaction();
+    transaction.insert(1000, b"https://lost.example")?;
+    drop(transaction);
+    drop(shortener);
+
+    let mut shortener = Shortener::open(dir)?;
+    println!("recovery: {:?}", shortener.links.store().recovery());
+    for (code, url) in shortener.recent(10)? {
+        println!("{code} -> {url}");
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    match std::env::args().nth(1) {
+        Some(dir) => run(Path::new(&dir)),
+        None => run(tempfile::tempdir()?.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn codes_round_trip() {
+        for id in [0, 1, 61, 62, 3843, u64::MAX] {
+            assert_eq!(decode(&encode(id)), Some(id));
+        }
+        assert_eq!(encode(62), "10");
+        assert_eq!(decode("not-base62"), None);
+    }
+
+    #[test]
+    fn links_survive_reopening() {
+        let dir = tempdir().unwrap();
+        let mut shortener = Shortener::open(dir.path()).unwrap();
+        let codes: Vec<String> = (0..300)
+            .map(|i| shortener.shorten(&format!("https://example.com/{i}")))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(shortener.delete(&codes[7]).unwrap());
+        drop(shortener);
+
+        let mut shortener = Shortener::open(dir.path()).unwrap();
+        assert_eq!(
+            shortener.resolve(&codes[42]).unwrap().as_deref(),
+            Some("https://example.com/42")
+        );
+        assert_eq!(shortener.resolve(&codes[7]).unwrap(), None);
+        let recent = shortener.recent(2).unwrap();
+        assert_eq!(
+            recent,
+            [
+                (codes[299].clone(), "https://example.com/299".to_string()),
+                (codes[298].clone(), "https://example.com/298".to_string()),
+            ]
+        );
+        // Ids carry on after the last one
+        assert_eq!(
+            decode(&shortener.shorten("https://new.example").unwrap()),
+            Some(301)
+        );
+    }
+
+    #[test]
+    fn uncommitted_links_are_lost_in_a_crash() {
+        let dir = tempdir().unwrap();
+        let mut shortener = Shortener::open(dir.path()).unwrap();
+        let code = shortener.shorten("https://kept.example").unwrap();
+        let mut transaction = shortener.links.transaction();
+        transaction.insert(2, b"https://lost.example").unwrap();
+        drop(transaction);
+        drop(shortener);
+
+        let mut shortener = Shortener::open(dir.path()).unwrap();
+        assert_eq!(
+            shortener.resolve(&code).unwrap().as_deref(),
+            Some("https://kept.example")
+        );
+        assert_eq!(shortener.resolve(&encode(2)).unwrap(), None);
+        assert_eq!(
+            shortener.shorten("https://next.example").unwrap(),
+            encode(2)
+        );
+    }
+}
@@ -0,0 +1,44 @@
+/*
+Random operations on a node against a BTreeMap, see e_bin::testing. Run with
+`cargo fuzz run node_ops` from the repository root. Inputs pick the node's page size and
+layout before the operations, so every layout gets its share of the freeblock and
+fragmentation edge cases.
+*/
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use e_bin::btree::NodeConfig;
+use e_bin::testing::{NodeModel, NodeOp};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    /// Power of two exponent of the page size, 512 to 4096 bytes
+    page_shift: u8,
+    inline_values: bool,
+    adaptive_layout: bool,
+    align_values: bool,
+    checksums: bool,
+    ops: Vec<NodeOp>,
+}
+
+fuzz_target!(|input: Input| {
+    let config = NodeConfig {
+        inline_values: input.inline_values,
+        adaptive_layout: input.adaptive_layout,
+        prefix_compression: input.adaptive_layout,
+        align_values: input.align_values,
+        checksums: input.checksums,
+        verify_checksums: input.checksums,
+        strict: true,
+        ..Default::default()
+    };
+    let page_size = 512 << (input.page_shift % 4);
+    let mut model = NodeModel::with_config(page_size, config).unwrap();
+    for op in &input.ops {
+        if let Err(mismatch) = model.apply(op) {
+            panic!("{mismatch}");
+        }
+    }
+});
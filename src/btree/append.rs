@@ -0,0 +1,344 @@
+/*
+Append-only trees for logs. Keys have to grow with every append and nothing is ever deleted,
+so entries only ever go to the rightmost leaf. There they are written behind the last key
+record without a search, freeblocks or fragment accounting, and a full leaf is left full
+instead of being split in half: the next entry starts a new leaf. Only the rightmost path
+changes, the leaf at its end is kept in memory until it is full or flushed.
+
+The pages have the node format of every other tree, into_tree hands the pages to a BTree for
+range scans, cursors or even deletes. The root keeps its page like it does in a BTree.
+*/
+
+use std::marker::PhantomData;
+
+use super::codec::KeyCodec;
+use super::compression::{decode_value, encode_value};
+use super::errors::{BTreeError, LimitError};
+use super::header::NodeType;
+use super::key::KEY_SIZE;
+use super::tree::config_for;
+use super::{BTree, Node, NodeConfig, MAX_DEPTH};
+use crate::limits::ResourceLimits;
+use crate::page::{Page, PageStore};
+
+pub struct AppendTree<S: PageStore, K: KeyCodec = u64> {
+    store: S,
+    config: NodeConfig,
+    root: u32,
+    /// Pages of the rightmost path, root first. The last one is the leaf being filled.
+    spine: Vec<u32>,
+    leaf: Page,
+    last_key: Option<u64>,
+    key: PhantomData<K>,
+}
+
+impl Node<'_> {
+    /// Adds `key` behind the last key record if the gap between key records and values
+    /// has room for it. Returns false if it doesn't, unless the node is empty.
+    fn append(&mut self, key: u64, value: &[u8]) -> Result<bool, BTreeError> {
+        let value_len = value.len() as u16;
+        if value.len() > self.max_value_size().into() {
+            return Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                limit: self.max_value_size().into(),
+                actual: value.len(),
+            }));
+        }
+        let available = self.unallocated_space()?;
+        let idx = self.read_header()?.num_keys.get();
+        if available < KEY_SIZE + value_len + self.value_padding(value_len)? {
+            if idx == 0 {
+                return Err(BTreeError::NotEnoughSpace {
+                    required: (KEY_SIZE + value_len).into(),
+                    actual: available.into(),
+                });
+            }
+            return Ok(false);
+        }
+        let offset = self.prepend_value(value)?;
+        self.insert_key_at(idx, key, 0, offset, value_len)?;
+        Ok(true)
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> AppendTree<S, K> {
+    pub fn create(store: S) -> Result<Self, BTreeError> {
+        Self::create_with_config(store, NodeConfig::default())
+    }
+
+    /// Creates an empty tree whose root is appended to `store`
+    pub fn create_with_config(mut store: S, config: NodeConfig) -> Result<Self, BTreeError> {
+        let config = config_for(&store, config);
+        let leaf = empty_page(&store, config, NodeType::Leaf, 0)?;
+        let root = append_page(&mut store, &leaf)?;
+        Ok(Self {
+            store,
+            config,
+            root,
+            spine: vec![root],
+            leaf,
+            last_key: None,
+            key: PhantomData,
+        })
+    }
+
+    /// Continues appending to the tree rooted at page `root` of `store`
+    pub fn open(store: S, root: u32) -> Result<Self, BTreeError> {
+        Self::open_with_config(store, root, NodeConfig::default())
+    }
+
+    pub fn open_with_config(
+        mut store: S,
+        root: u32,
+        config: NodeConfig,
+    ) -> Result<Self, BTreeError> {
+        let config = config_for(&store, config);
+        let max_depth = ResourceLimits::default().max_depth;
+        let mut spine = vec![root];
+        let mut last_key = None;
+        loop {
+            let mut page = store.read_page(*spine.last().expect("Starts with the root") as usize)?;
+            let node = Node::load_with_config(page.mutate(), config)?;
+            let num_keys = node.read_header()?.num_keys.get();
+            if num_keys > 0 {
+                last_key = Some(node.key_at(num_keys - 1)?);
+            }
+            if node.is_leaf()? {
+                drop(node);
+                return Ok(Self {
+                    store,
+                    config,
+                    root,
+                    spine,
+                    leaf: page,
+                    last_key,
+                    key: PhantomData,
+                });
+            }
+            if spine.len() >= max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: max_depth,
+                }));
+            }
+            spine.push(node.child_at(num_keys)?);
+        }
+    }
+
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    /// Number of levels, 1 for a tree that only has its root leaf
+    pub fn depth(&self) -> usize {
+        self.spine.len()
+    }
+
+    /// Adds an entry whose key sorts after every key appended so far
+    pub fn append(&mut self, key: K, value: &[u8]) -> Result<(), BTreeError> {
+        let key = key.encode();
+        if let Some(previous) = self.last_key.filter(|&previous| previous >= key) {
+            return Err(BTreeError::UnsortedInput { previous, key });
+        }
+        let value = encode_value(self.config.compression, value)?;
+        let value = value.as_ref();
+        let mut node = Node::load_with_config(self.leaf.mutate(), self.config)?;
+        if !node.append(key, value)? {
+            drop(node);
+            self.start_leaf()?;
+            let mut node = Node::load_with_config(self.leaf.mutate(), self.config)?;
+            node.append(key, value)?;
+        }
+        self.last_key = Some(key);
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        let key = key.encode();
+        let mut page_id = self.root;
+        for _ in 1..self.spine.len() {
+            let mut page = self.store.read_page(page_id as usize)?;
+            page_id = Node::load_with_config(page.mutate(), self.config)?.find_child_page(key)?;
+        }
+        let stored = if page_id == *self.spine.last().expect("Ends in the leaf") {
+            let node = Node::load_with_config(self.leaf.mutate(), self.config)?;
+            node.get(key)?.map(<[u8]>::to_vec)
+        } else {
+            let mut page = self.store.read_page(page_id as usize)?;
+            let node = Node::load_with_config(page.mutate(), self.config)?;
+            node.get(key)?.map(<[u8]>::to_vec)
+        };
+        let max_len = ResourceLimits::default().max_memory;
+        stored
+            .map(|stored| decode_value(self.config.compression, key, stored, max_len))
+            .transpose()
+    }
+
+    /// Writes the leaf being filled to the store
+    pub fn flush(&mut self) -> Result<(), BTreeError> {
+        let leaf = *self.spine.last().expect("Ends in the leaf");
+        self.store.write_page(leaf as usize, &self.leaf)?;
+        Ok(())
+    }
+
+    /// Flushes the tree and opens it as a BTree
+    pub fn into_tree(mut self) -> Result<BTree<S, K>, BTreeError> {
+        self.flush()?;
+        Ok(BTree::open_with_config(self.store, self.root, self.config)?.keyed::<K>())
+    }
+
+    /// Writes out the full leaf and continues with an empty one to its right
+    fn start_leaf(&mut self) -> Result<(), BTreeError> {
+        let separator = self.last_key.expect("Full leaves have keys");
+        self.flush()?;
+        let full = self.spine.pop().expect("Ends in the leaf");
+        self.leaf = empty_page(&self.store, self.config, NodeType::Leaf, 0)?;
+        let leaf = append_page(&mut self.store, &self.leaf)?;
+        self.push_right(separator, full, leaf)?;
+        self.spine.push(leaf);
+        Ok(())
+    }
+
+    /// Makes `right` the rightmost child of the node above `left`, which was the rightmost
+    /// child so far and holds keys up to `separator`. `spine` holds the nodes above `left`
+    /// and ends with the parent of `right` afterwards. A full parent gets a new right
+    /// sibling, which becomes the rightmost child of the node above, and so on up the spine.
+    fn push_right(
+        &mut self,
+        separator: u64,
+        mut left: u32,
+        mut right: u32,
+    ) -> Result<(), BTreeError> {
+        // New siblings, bottom up, they end the spine once a node above had room
+        let mut siblings = Vec::new();
+        loop {
+            let Some(parent) = self.spine.pop() else {
+                // The root keeps its page, its content moves to a new one below it
+                if siblings.len() + 2 > MAX_DEPTH {
+                    return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                        limit: MAX_DEPTH,
+                    }));
+                }
+                let page = self.store.read_page(left as usize)?;
+                let moved = append_page(&mut self.store, &page)?;
+                let mut root = empty_page(&self.store, self.config, NodeType::Internal, right)?;
+                Node::load_with_config(root.mutate(), self.config)?
+                    .insert_separator(separator, moved)?;
+                self.store.write_page(self.root as usize, &root)?;
+                self.spine.push(self.root);
+                break;
+            };
+
+            let mut page = self.store.read_page(parent as usize)?;
+            let mut node = Node::load_with_config(page.mutate(), self.config)?;
+            match node.insert_separator(separator, left) {
+                Ok(()) => {
+                    node.mutate_header()?.rightmost_child_page.set(right);
+                    drop(node);
+                    self.store.write_page(parent as usize, &page)?;
+                    self.spine.push(parent);
+                    break;
+                }
+                Err(BTreeError::NotEnoughSpace { .. }) => {
+                    drop(node);
+                    let sibling = empty_page(&self.store, self.config, NodeType::Internal, right)?;
+                    let sibling = append_page(&mut self.store, &sibling)?;
+                    siblings.push(sibling);
+                    (left, right) = (parent, sibling);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.spine.extend(siblings.into_iter().rev());
+        Ok(())
+    }
+}
+
+fn empty_page<S: PageStore>(
+    store: &S,
+    config: NodeConfig,
+    node_type: NodeType,
+    rightmost: u32,
+) -> Result<Page, BTreeError> {
+    let mut page = Page::new(store.page_size());
+    let mut node = Node::new_with_config(page.mutate(), config)?;
+    let header = node.mutate_header()?;
+    header.node_type = node_type;
+    header.rightmost_child_page.set(rightmost);
+    drop(node);
+    Ok(page)
+}
+
+fn append_page<S: PageStore>(store: &mut S, page: &Page) -> Result<u32, BTreeError> {
+    let page_id = store.append_page(page)?;
+    page_id.try_into().map_err(|_| BTreeError::NotEnoughSpace {
+        required: page_id,
+        actual: u32::MAX as usize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::header::HEADER_SIZE;
+    use super::super::verify::check_page;
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn value(key: u64) -> Vec<u8> {
+        vec![key as u8; 200 + (key % 60) as usize]
+    }
+
+    #[test]
+    fn appends_fill_pages_completely() {
+        let mut tree = AppendTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..6000 {
+            tree.append(key * 2, &value(key)).unwrap();
+        }
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.get(2000).unwrap(), Some(value(1000)));
+        assert_eq!(tree.get(11_998).unwrap(), Some(value(5999)));
+        assert_eq!(tree.get(11_999).unwrap(), None);
+        assert!(matches!(
+            tree.append(11_998, b"again"),
+            Err(BTreeError::UnsortedInput {
+                previous: 11_998,
+                key: 11_998
+            })
+        ));
+        assert!(tree.append(20_000, &[0; PAGE_SIZE as usize]).is_err());
+
+        // Reopening continues where the last append left off
+        tree.flush().unwrap();
+        let root = tree.root();
+        let mut tree = AppendTree::open(tree.store, root).unwrap();
+        assert_eq!(tree.depth(), 3);
+        assert!(tree.append(11_998, b"again").is_err());
+        for key in 6000..6500 {
+            tree.append(key * 2, &value(key)).unwrap();
+        }
+
+        tree.flush().unwrap();
+        for page_id in 0..tree.store.n_pages().unwrap() {
+            let page = tree.store.read_page(page_id).unwrap();
+            check_page(page.read(), |issue| {
+                panic!("Page {page_id} is invalid: {issue:?}")
+            });
+        }
+
+        let mut tree = tree.into_tree().unwrap();
+        let mut cursor = tree.cursor().unwrap();
+        for key in 0..6500 {
+            assert_eq!(cursor.current(), Some((key * 2, value(key).as_slice())));
+            cursor.next().unwrap();
+        }
+        assert_eq!(cursor.current(), None);
+        drop(cursor);
+
+        // Leaves are only left with less room than the next value needs
+        let payload: usize = (0..6500)
+            .map(|key| KEY_SIZE as usize + value(key).len())
+            .sum();
+        let pages = tree.store().n_pages().unwrap();
+        assert!(pages <= payload / (PAGE_SIZE - HEADER_SIZE) as usize * 108 / 100 + 4);
+    }
+}
@@ -0,0 +1,314 @@
+/*
+Overlap audit of the content area. Every live value (or byte key cell) and every freeblock
+claims a byte range between free_end and the end of the page, and no two of them may share a
+byte. Allocator bugs, like handing out a freeblock that is still linked or reusing a value's
+bytes, break exactly this. With NodeConfig::strict the audit runs after every mutation, so
+tests fail at the operation that corrupted the node instead of at some later read.
+validate() checks the rest of the node's invariants on top of that: the free space bounds, the
+slot count, the key order and the fragmented byte counter.
+*/
+
+use alloc::vec::Vec;
+
+use super::bytekeys::CELL_HEADER_SIZE;
+use super::errors::{BTreeError, CorruptionError};
+use super::freeblock::FREEBLOCK_SIZE;
+use super::header::HEADER_SIZE;
+use super::Node;
+
+impl<'a> Node<'a> {
+    /// Byte ranges claimed in the content area as (start, end), values and freeblocks alike
+    fn claimed_ranges(&self) -> Result<Vec<(usize, usize)>, BTreeError> {
+        let header = self.read_header()?;
+        if header.is_packed() {
+            return Ok(Vec::new());
+        }
+        let num_keys = header.num_keys.get();
+        let mut ranges = Vec::with_capacity(num_keys.into());
+
+        for idx in 0..num_keys {
+            if header.has_byte_keys() {
+                let (offset, key_len, value_len) = self.cell_at(idx)?;
+                ranges.push((
+                    offset,
+                    offset + CELL_HEADER_SIZE as usize + key_len + value_len,
+                ));
+                continue;
+            }
+            let key = self.read_key_at(idx)?;
+            if key.is_inline() || key.value_len.get() == 0 {
+                continue;
+            }
+            // value_at checks the value's bounds
+            self.value_at(idx)?;
+            let start = key.value_offset.get() as usize;
+            ranges.push((start, start + key.value_len.get() as usize));
+        }
+
+        let free_end = header.free_end.get() as usize;
+        let content_end = header.content_end(self.page_size()) as usize;
+        let mut offset = header.first_freeblock.get() as usize;
+        while offset != 0 {
+            if offset < free_end || offset + FREEBLOCK_SIZE as usize > content_end {
+                return Err(BTreeError::Corrupt(CorruptionError::FreeblockOutOfBounds {
+                    offset: offset as u16,
+                }));
+            }
+            let freeblock = self.read_freeblock(offset)?;
+            let end = offset + freeblock.size.get() as usize;
+            let next = freeblock.next_freeblock.get() as usize;
+            // The chain is sorted by offset, anything else could loop forever
+            if freeblock.size.get() < FREEBLOCK_SIZE
+                || end > content_end
+                || (next != 0 && next <= offset)
+            {
+                return Err(BTreeError::Corrupt(CorruptionError::FreeblockOutOfBounds {
+                    offset: offset as u16,
+                }));
+            }
+            ranges.push((offset, end));
+            offset = next;
+        }
+        Ok(ranges)
+    }
+
+    /// Makes sure no two values, cells or freeblocks share a byte of the content area
+    pub fn check_overlaps(&self) -> Result<(), BTreeError> {
+        check_disjoint(&mut self.claimed_ranges()?)
+    }
+
+    /// Checks every structural invariant of the node, failing with the first one violated
+    pub fn validate(&self) -> Result<(), BTreeError> {
+        let header = self.read_header()?;
+        let num_keys = header.num_keys.get();
+        let free_start = header.free_start.get();
+        let free_end = header.free_end.get();
+        let content_end = header.content_end(self.page_size());
+
+        if free_start < HEADER_SIZE || free_start > free_end || free_end > content_end {
+            return Err(BTreeError::Corrupt(CorruptionError::FreeSpaceOutOfRange {
+                free_start,
+                free_end,
+            }));
+        }
+        let slots_len = free_start.checked_sub(header.slots_start());
+        if slots_len.map(usize::from) != Some(num_keys as usize * header.slot_size() as usize) {
+            return Err(BTreeError::Corrupt(CorruptionError::KeyCountMismatch {
+                num_keys,
+                free_start,
+            }));
+        }
+
+        for index in 1..num_keys {
+            let sorted = if header.has_byte_keys() {
+                let (prev, key) = (self.byte_key_at(index - 1)?, self.byte_key_at(index)?);
+                self.compare_byte_keys(prev, key).is_lt()
+            } else {
+                self.key_at(index - 1)? < self.key_at(index)?
+            };
+            if !sorted {
+                return Err(BTreeError::Corrupt(CorruptionError::KeysNotSorted {
+                    index,
+                }));
+            }
+        }
+
+        let mut ranges = self.claimed_ranges()?;
+        check_disjoint(&mut ranges)?;
+
+        // Fragments are the gaps between claimed ranges that are too small for a freeblock
+        let claimed: usize = ranges.iter().map(|(start, end)| end - start).sum();
+        let unclaimed = (content_end - free_end) as usize - claimed;
+        if header.fragmented_bytes as usize > unclaimed {
+            return Err(BTreeError::Corrupt(
+                CorruptionError::FragmentedBytesOutOfRange {
+                    fragmented: header.fragmented_bytes,
+                    unclaimed: unclaimed as u16,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs the overlap audit after a mutation if the node is in strict mode
+    pub(crate) fn audit_if_strict(&self) -> Result<(), BTreeError> {
+        if self.config.strict {
+            self.check_overlaps()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sorts `ranges` and fails on the first two that share a byte
+fn check_disjoint(ranges: &mut [(usize, usize)]) -> Result<(), BTreeError> {
+    ranges.sort_unstable();
+    for pair in ranges.windows(2) {
+        let ((first, first_end), (second, _)) = (pair[0], pair[1]);
+        if second < first_end {
+            return Err(BTreeError::Corrupt(CorruptionError::OverlappingCells {
+                first: first as u16,
+                second: second as u16,
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{NodeConfig, PAGE_SIZE};
+    use super::*;
+
+    fn strict() -> NodeConfig {
+        NodeConfig {
+            strict: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn strict_mutations_pass_the_audit() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, strict()).unwrap();
+        for key in 0..60u64 {
+            node.insert(key, &vec![key as u8; (key % 7 * 9) as usize])
+                .unwrap();
+        }
+        for key in (0..60).step_by(3) {
+            node.delete(key).unwrap();
+        }
+        for key in (0..60).step_by(3) {
+            node.insert(key, &[1; 5]).unwrap();
+        }
+        node.defrag().unwrap();
+        node.check_overlaps().unwrap();
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, strict()).unwrap();
+        for key in 0..40u8 {
+            node.insert_bytes(&[key; 3], &[key; 20]).unwrap();
+        }
+        node.delete_bytes(&[7; 3]).unwrap();
+        node.check_overlaps().unwrap();
+    }
+
+    #[test]
+    fn overlapping_freeblock_is_caught() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, strict()).unwrap();
+        node.insert(1, &[1; 40]).unwrap();
+        node.insert(2, &[2; 40]).unwrap();
+        node.insert(3, &[3; 40]).unwrap();
+
+        // Simulate an allocator that leaves a freeblock linked over a live value
+        let value_offset = node.read_key_at(1).unwrap().value_offset.get();
+        node.write_freeblock(value_offset.into(), 0, 40);
+        node.mutate_header()
+            .unwrap()
+            .first_freeblock
+            .set(value_offset);
+
+        assert!(matches!(
+            node.insert(4, &[4; 40]),
+            Err(BTreeError::Corrupt(
+                CorruptionError::OverlappingCells { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn overlapping_values_are_caught() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.insert(1, &[1; 40]).unwrap();
+        node.insert(2, &[2; 40]).unwrap();
+        node.check_overlaps().unwrap();
+
+        let offset = node.read_key_at(1).unwrap().value_offset.get();
+        node.mut_key_at(0).unwrap().value_offset.set(offset + 10);
+        assert!(matches!(
+            node.check_overlaps(),
+            Err(BTreeError::Corrupt(
+                CorruptionError::OverlappingCells { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn validate_accepts_every_layout() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in 0..50u64 {
+            node.insert(key, &vec![key as u8; (key % 5 * 7) as usize])
+                .unwrap();
+        }
+        for key in (0..50).step_by(4) {
+            node.delete(key).unwrap();
+        }
+        node.validate().unwrap();
+        node.defrag().unwrap();
+        node.validate().unwrap();
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in 0..20u64 {
+            node.insert(key, &key.to_le_bytes()).unwrap();
+        }
+        assert!(node.pack().unwrap());
+        node.validate().unwrap();
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, NodeConfig::default()).unwrap();
+        for key in [b"b".as_slice(), b"a", b"ab", b"c"] {
+            node.insert_bytes(key, b"abekat").unwrap();
+        }
+        node.validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_first_violation() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.validate().unwrap();
+        node.mutate_header().unwrap().fragmented_bytes = 1;
+        assert!(matches!(
+            node.validate(),
+            Err(BTreeError::Corrupt(
+                CorruptionError::FragmentedBytesOutOfRange {
+                    fragmented: 1,
+                    unclaimed: 0
+                }
+            ))
+        ));
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in 1..=3 {
+            node.insert(key, &[key as u8; 10]).unwrap();
+        }
+        node.mut_key_at(2).unwrap().key.set(2);
+        assert!(matches!(
+            node.validate(),
+            Err(BTreeError::Corrupt(CorruptionError::KeysNotSorted {
+                index: 2
+            }))
+        ));
+
+        node.mutate_header().unwrap().num_keys.set(2);
+        assert!(matches!(
+            node.validate(),
+            Err(BTreeError::Corrupt(CorruptionError::KeyCountMismatch {
+                num_keys: 2,
+                ..
+            }))
+        ));
+
+        node.mutate_header().unwrap().free_end.set(PAGE_SIZE + 1);
+        assert!(matches!(
+            node.validate(),
+            Err(BTreeError::Corrupt(
+                CorruptionError::FreeSpaceOutOfRange { .. }
+            ))
+        ));
+    }
+}
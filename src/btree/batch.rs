@@ -0,0 +1,223 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::errors::BTreeError;
+use super::fallible::try_to_vec;
+#[cfg(feature = "pager")]
+use super::BTree;
+use super::Node;
+#[cfg(feature = "pager")]
+use crate::page::PageStore;
+
+pub enum BatchOp {
+    Insert { key: u64, value: Vec<u8> },
+    Delete { key: u64 },
+}
+
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ApplyOutcome {
+    Applied,
+    /// The operation id was already recorded, nothing was written
+    Skipped,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: u64, value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Insert {
+            key,
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: u64) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// What the batch leaves behind for every key it touches, in key order: the value of
+    /// the last insert, `None` where it deletes the key last. A source for merge_sorted.
+    pub fn sorted(&self) -> Vec<(u64, Option<Vec<u8>>)> {
+        let mut last = BTreeMap::new();
+        for op in &self.ops {
+            match op {
+                BatchOp::Insert { key, value } => last.insert(*key, Some(value.clone())),
+                BatchOp::Delete { key } => last.insert(*key, None),
+            };
+        }
+        last.into_iter().collect()
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Applies every operation of the batch, or none of them if one fails
+    pub fn apply_batch(&mut self, batch: &WriteBatch) -> Result<(), BTreeError> {
+        let backup = try_to_vec(self.page)?;
+
+        let result = batch.ops.iter().try_for_each(|op| match op {
+            BatchOp::Insert { key, value } => self.insert(*key, value).map(|_| ()),
+            BatchOp::Delete { key } => self.delete(*key).map(|_| ()),
+        });
+
+        if result.is_err() {
+            self.page.copy_from_slice(&backup);
+        }
+        result
+    }
+
+    /// Applies the batch unless `op_id` is already recorded in `op_log`. The id is
+    /// recorded together with the batch, so replaying the same batch is a no-op.
+    pub fn apply_batch_once(
+        &mut self,
+        batch: &WriteBatch,
+        op_id: u64,
+        op_log: &mut Node,
+    ) -> Result<ApplyOutcome, BTreeError> {
+        if op_log.get(op_id)?.is_some() {
+            return Ok(ApplyOutcome::Skipped);
+        }
+
+        let backup = try_to_vec(self.page)?;
+        self.apply_batch(batch)?;
+
+        if let Err(err) = op_log.insert(op_id, &[]) {
+            self.page.copy_from_slice(&backup);
+            return Err(err);
+        }
+        Ok(ApplyOutcome::Applied)
+    }
+}
+
+#[cfg(feature = "pager")]
+impl<S: PageStore> BTree<S> {
+    /// Creates the tree that records applied operation ids for apply_batch_once, returning
+    /// its root. It lives in this tree's store and grows like any other tree. Keep the root
+    /// next to this tree's, in the catalog on a Pager so gc_unreachable doesn't take it.
+    pub fn create_op_log(&mut self) -> Result<u32, BTreeError> {
+        self.create_subtree()
+    }
+
+    /// Applies the batch unless `op_id` is already recorded in the op log rooted at
+    /// `op_log`, then records it. A failed batch may be applied in part, run it in a
+    /// transaction over a WalStore so the batch and its id are committed or rolled back
+    /// together.
+    pub fn apply_batch_once(
+        &mut self,
+        batch: &WriteBatch,
+        op_id: u64,
+        op_log: u32,
+    ) -> Result<ApplyOutcome, BTreeError> {
+        if self.in_subtree(op_log, |log| log.get_raw(op_id))?.is_some() {
+            return Ok(ApplyOutcome::Skipped);
+        }
+
+        for op in &batch.ops {
+            match op {
+                BatchOp::Insert { key, value } => self.insert(*key, value).map(|_| ())?,
+                BatchOp::Delete { key } => self.delete(*key).map(|_| ())?,
+            }
+        }
+        self.in_subtree(op_log, |log| log.insert(op_id, &[]))?;
+        Ok(ApplyOutcome::Applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Limits, NodeConfig, PAGE_SIZE};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn batch_is_atomic() {
+        let config = NodeConfig {
+            limits: Limits {
+                max_keys: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        node.insert(1, b"one").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete(1).insert(2, b"two").insert(3, b"three");
+        assert!(node.apply_batch(&batch).is_ok());
+
+        let mut batch = WriteBatch::new();
+        batch.insert(4, b"four").delete(2);
+        assert!(node.apply_batch(&batch).is_err());
+
+        assert_eq!(node.get(2).unwrap(), Some(b"two".as_slice()));
+        assert_eq!(node.get(3).unwrap(), Some(b"three".as_slice()));
+        assert_eq!(node.get(4).unwrap(), None);
+    }
+
+    #[test]
+    fn replayed_op_id_is_skipped() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut log_page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let mut op_log = Node::new(&mut log_page).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.insert(1, b"one").insert(2, b"two");
+
+        assert_eq!(
+            node.apply_batch_once(&batch, 42, &mut op_log).unwrap(),
+            ApplyOutcome::Applied
+        );
+        node.delete(1).unwrap();
+        assert_eq!(
+            node.apply_batch_once(&batch, 42, &mut op_log).unwrap(),
+            ApplyOutcome::Skipped
+        );
+        assert_eq!(node.get(1).unwrap(), None);
+        assert_eq!(op_log.get(42).unwrap(), Some([].as_slice()));
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn op_log_of_a_tree_keeps_growing() {
+        use crate::page::MemoryStore;
+
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        let op_log = tree.create_op_log().unwrap();
+
+        for op_id in 0..2000 {
+            let mut batch = WriteBatch::new();
+            batch.insert(op_id % 10, &op_id.to_le_bytes());
+            assert_eq!(
+                tree.apply_batch_once(&batch, op_id, op_log).unwrap(),
+                ApplyOutcome::Applied
+            );
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.insert(1, b"replayed");
+        assert_eq!(
+            tree.apply_batch_once(&batch, 1, op_log).unwrap(),
+            ApplyOutcome::Skipped
+        );
+        assert_eq!(tree.get(1).unwrap(), Some(1991u64.to_le_bytes().to_vec()));
+        assert_eq!(tree.get(1000).unwrap(), None);
+    }
+}
@@ -0,0 +1,352 @@
+/*
+Layout for leaves keyed by arbitrary byte strings. The slot array only holds the offsets of
+the cells, sorted by key, and every cell carries its key next to its value
+-------------------------------------------------------------------------------------
+| header | offset (2 bytes) | ... | free space | key len (2) | value len (2) | key | value |
+-------------------------------------------------------------------------------------
+Keys compare bytewise, like slices do, unless the node was created with a comparator. Cells deleted from the middle of the content area are
+not tracked in freeblocks, their space is reclaimed by compacting the cells once an insert
+runs out of unallocated space.
+*/
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use zerocopy::little_endian::U16;
+use zerocopy::FromBytes;
+
+use super::errors::{BTreeError, CorruptionError};
+use super::fallible::{try_to_vec, try_with_capacity};
+use super::header::{FLAG_BYTE_KEYS, FLAG_CUSTOM_ORDER, HEADER_SIZE};
+use super::{Node, NodeConfig};
+
+pub const BYTE_KEY_SLOT_SIZE: u16 = size_of::<U16>() as u16;
+pub const CELL_HEADER_SIZE: u16 = 2 * size_of::<U16>() as u16;
+
+impl<'a> Node<'a> {
+    /// Creates an empty leaf keyed by byte strings instead of u64s
+    pub fn new_with_byte_keys(page: &'a mut [u8], config: NodeConfig) -> Result<Self, BTreeError> {
+        let mut node = Self::new_with_config(page, config)?;
+        node.mutate_header()?.flags |= FLAG_BYTE_KEYS;
+        if config.comparator.is_some() {
+            node.mutate_header()?.flags |= FLAG_CUSTOM_ORDER;
+        }
+        Ok(node)
+    }
+
+    /// Orders byte keys by the configured comparator, bytewise without one
+    pub(crate) fn compare_byte_keys(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match self.config.comparator {
+            Some(comparator) => comparator.compare(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    pub fn has_byte_keys(&self) -> Result<bool, BTreeError> {
+        Ok(self.read_header()?.has_byte_keys())
+    }
+
+    /// Fails unless the node is keyed the way the caller expects
+    pub(crate) fn check_key_type(&self, byte_keys: bool) -> Result<(), BTreeError> {
+        if self.has_byte_keys()? != byte_keys {
+            return Err(BTreeError::KeyTypeMismatch);
+        }
+        Ok(())
+    }
+
+    /// Offset and size of the cell at `index`, checked to lie within the content area
+    pub(crate) fn cell_at(&self, index: u16) -> Result<(usize, usize, usize), BTreeError> {
+        self.check_key_index(index)?;
+        let slot_pos = (HEADER_SIZE + BYTE_KEY_SLOT_SIZE * index) as usize;
+        let offset = U16::read_from_bytes(self.get_page_slice(slot_pos, BYTE_KEY_SLOT_SIZE.into()))
+            .expect("Shouldn't fail, hardcoded")
+            .get() as usize;
+
+        let header = self.read_header()?;
+        let (free_end, content_end) = (
+            header.free_end.get() as usize,
+            header.content_end(self.page_size()) as usize,
+        );
+        let out_of_bounds = |key_len: usize, value_len: usize| {
+            let len = CELL_HEADER_SIZE as usize + key_len + value_len;
+            BTreeError::Corrupt(CorruptionError::ValueOutOfBounds {
+                index,
+                offset: offset as u16,
+                len: len.min(u16::MAX.into()) as u16,
+            })
+        };
+        if offset < free_end || offset + CELL_HEADER_SIZE as usize > content_end {
+            return Err(out_of_bounds(0, 0));
+        }
+        let lens = self.get_page_slice(offset, CELL_HEADER_SIZE.into());
+        let key_len = u16::from_le_bytes([lens[0], lens[1]]) as usize;
+        let value_len = u16::from_le_bytes([lens[2], lens[3]]) as usize;
+        if offset + CELL_HEADER_SIZE as usize + key_len + value_len > content_end {
+            return Err(out_of_bounds(key_len, value_len));
+        }
+        Ok((offset, key_len, value_len))
+    }
+
+    pub fn byte_key_at(&self, index: u16) -> Result<&[u8], BTreeError> {
+        self.check_key_type(true)?;
+        let (offset, key_len, _) = self.cell_at(index)?;
+        Ok(self.get_page_slice(offset + CELL_HEADER_SIZE as usize, key_len))
+    }
+
+    pub fn byte_value_at(&self, index: u16) -> Result<&[u8], BTreeError> {
+        self.check_key_type(true)?;
+        let (offset, key_len, value_len) = self.cell_at(index)?;
+        Ok(self.get_page_slice(offset + CELL_HEADER_SIZE as usize + key_len, value_len))
+    }
+
+    /// Byte key counterpart of find_le_key_idx
+    pub fn find_le_byte_key_idx(&self, key: &[u8]) -> Result<(usize, bool), BTreeError> {
+        self.check_key_type(true)?;
+        let mut low = 0;
+        let mut high = self.read_header()?.num_keys.get();
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.compare_byte_keys(self.byte_key_at(mid)?, key) {
+                Ordering::Equal => return Ok((mid.into(), true)),
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        Ok((low.into(), false))
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<&[u8]>, BTreeError> {
+        let (idx, exists) = self.find_le_byte_key_idx(key)?;
+        if !exists {
+            return Ok(None);
+        }
+        self.byte_value_at(idx as u16).map(Some)
+    }
+
+    /// Inserts or replaces `key`, returning the old value. Fails without touching the node
+    /// if the cell doesn't fit.
+    pub fn insert_bytes(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, BTreeError> {
+        let (idx, exists) = self.find_le_byte_key_idx(key)?;
+        self.check_limits(value.len(), !exists)?;
+
+        let cell_len = CELL_HEADER_SIZE as usize + key.len() + value.len();
+        let slot_len = if exists {
+            0
+        } else {
+            BYTE_KEY_SLOT_SIZE as usize
+        };
+        let mut available = self.content_end()? as usize
+            - self.read_header()?.free_start.get() as usize
+            - self.live_cell_bytes()?;
+        if exists {
+            let (_, key_len, value_len) = self.cell_at(idx as u16)?;
+            available += CELL_HEADER_SIZE as usize + key_len + value_len;
+        }
+        if cell_len + slot_len > available {
+            return Err(BTreeError::NotEnoughSpace {
+                required: cell_len + slot_len,
+                actual: available,
+            });
+        }
+
+        let old = if exists {
+            let old = try_to_vec(self.byte_value_at(idx as u16)?)?;
+            self.remove_cell(idx as u16)?;
+            Some(old)
+        } else {
+            None
+        };
+        if (self.unallocated_space()? as usize) < cell_len + BYTE_KEY_SLOT_SIZE as usize {
+            self.compact_cells()?;
+        }
+
+        let header = self.read_header()?;
+        let offset = header.free_end.get() as usize - cell_len;
+        let free_start = header.free_start.get() as usize;
+        let cell = self.get_mut_page_slice(offset, cell_len);
+        cell[0..2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+        cell[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        cell[4..4 + key.len()].copy_from_slice(key);
+        cell[4 + key.len()..].copy_from_slice(value);
+
+        let slot_pos = (HEADER_SIZE + BYTE_KEY_SLOT_SIZE * idx as u16) as usize;
+        self.page
+            .copy_within(slot_pos..free_start, slot_pos + BYTE_KEY_SLOT_SIZE as usize);
+        self.get_mut_page_slice(slot_pos, BYTE_KEY_SLOT_SIZE.into())
+            .copy_from_slice(&(offset as u16).to_le_bytes());
+
+        let header = self.mutate_header()?;
+        header.free_end.set(offset as u16);
+        header.free_start += BYTE_KEY_SLOT_SIZE;
+        header.num_keys += 1;
+        self.audit_if_strict()?;
+        Ok(old)
+    }
+
+    pub fn delete_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, BTreeError> {
+        let (idx, exists) = self.find_le_byte_key_idx(key)?;
+        if !exists {
+            return Ok(None);
+        }
+        let value = try_to_vec(self.byte_value_at(idx as u16)?)?;
+        self.remove_cell(idx as u16)?;
+        self.audit_if_strict()?;
+        Ok(Some(value))
+    }
+
+    /// Drops the slot at `index`. Its cell's space is only reclaimed right away if the cell
+    /// borders the free space.
+    fn remove_cell(&mut self, index: u16) -> Result<(), BTreeError> {
+        let (offset, key_len, value_len) = self.cell_at(index)?;
+        let cell_len = CELL_HEADER_SIZE as usize + key_len + value_len;
+        self.scrub(offset, cell_len);
+
+        let header = self.read_header()?;
+        let free_start = header.free_start.get() as usize;
+        let slot_pos = (HEADER_SIZE + BYTE_KEY_SLOT_SIZE * index) as usize;
+        self.page
+            .copy_within(slot_pos + BYTE_KEY_SLOT_SIZE as usize..free_start, slot_pos);
+        self.scrub(
+            free_start - BYTE_KEY_SLOT_SIZE as usize,
+            BYTE_KEY_SLOT_SIZE.into(),
+        );
+
+        let header = self.mutate_header()?;
+        if offset == header.free_end.get() as usize {
+            header.free_end += cell_len as u16;
+        }
+        header.free_start -= BYTE_KEY_SLOT_SIZE;
+        header.num_keys -= 1;
+        Ok(())
+    }
+
+    fn live_cell_bytes(&self) -> Result<usize, BTreeError> {
+        (0..self.read_header()?.num_keys.get())
+            .map(|idx| {
+                let (_, key_len, value_len) = self.cell_at(idx)?;
+                Ok(CELL_HEADER_SIZE as usize + key_len + value_len)
+            })
+            .sum()
+    }
+
+    /// Moves all cells to the end of the page, reclaiming the space of deleted ones
+    pub(crate) fn compact_cells(&mut self) -> Result<(), BTreeError> {
+        let num_keys = self.read_header()?.num_keys.get();
+        // All cells are copied into one buffer, in slot order
+        let mut cells = try_with_capacity(self.live_cell_bytes()?)?;
+        let mut lens = try_with_capacity(num_keys.into())?;
+        for idx in 0..num_keys {
+            let (offset, key_len, value_len) = self.cell_at(idx)?;
+            let len = CELL_HEADER_SIZE as usize + key_len + value_len;
+            cells.extend_from_slice(self.get_page_slice(offset, len));
+            lens.push(len);
+        }
+
+        let free_start = self.read_header()?.free_start.get() as usize;
+        let content_end = self.content_end()? as usize;
+        self.scrub(free_start, content_end - free_start);
+        let mut free_end = content_end;
+        let mut cells = cells.as_slice();
+        for (idx, len) in lens.into_iter().enumerate() {
+            let (cell, rest) = cells.split_at(len);
+            cells = rest;
+            free_end -= cell.len();
+            self.get_mut_page_slice(free_end, cell.len())
+                .copy_from_slice(cell);
+            let slot_pos = HEADER_SIZE as usize + BYTE_KEY_SLOT_SIZE as usize * idx;
+            self.get_mut_page_slice(slot_pos, BYTE_KEY_SLOT_SIZE.into())
+                .copy_from_slice(&(free_end as u16).to_le_bytes());
+        }
+        self.mutate_header()?.free_end.set(free_end as u16);
+        self.audit_if_strict()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::verify::check_page;
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn string_keys_sort_bytewise() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, NodeConfig::default()).unwrap();
+        for key in ["kat", "abe", "", "abekat", "zebra"] {
+            assert_eq!(
+                node.insert_bytes(key.as_bytes(), key.as_bytes()).unwrap(),
+                None
+            );
+        }
+        let keys: Vec<_> = (0..5).map(|idx| node.byte_key_at(idx).unwrap()).collect();
+        assert_eq!(keys, vec![&b""[..], b"abe", b"abekat", b"kat", b"zebra"]);
+        assert_eq!(node.get_bytes(b"abekat").unwrap(), Some(&b"abekat"[..]));
+        assert_eq!(node.get_bytes(b"ab").unwrap(), None);
+        assert_eq!(node.find_le_byte_key_idx(b"b").unwrap(), (3, false));
+
+        assert_eq!(
+            node.insert_bytes(b"abe", b"a much longer value").unwrap(),
+            Some(b"abe".to_vec())
+        );
+        assert_eq!(node.delete_bytes(b"kat").unwrap(), Some(b"kat".to_vec()));
+        assert_eq!(node.delete_bytes(b"kat").unwrap(), None);
+        assert_eq!(
+            node.get_bytes(b"abe").unwrap(),
+            Some(&b"a much longer value"[..])
+        );
+        drop(node);
+        check_page(&page, |issue| panic!("Invalid page: {issue:?}"));
+    }
+
+    #[test]
+    fn deleted_cells_are_reclaimed() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, NodeConfig::default()).unwrap();
+        let key = |i: u32| format!("key-{i:05}").into_bytes();
+
+        let mut count = 0;
+        while node.insert_bytes(&key(count), &[count as u8; 40]).is_ok() {
+            count += 1;
+        }
+        assert!(matches!(
+            node.insert_bytes(&key(count), &[0; 40]),
+            Err(BTreeError::NotEnoughSpace { .. })
+        ));
+
+        // Deleting from the middle leaves holes only compaction can reuse
+        for i in (0..count).step_by(2) {
+            node.delete_bytes(&key(i)).unwrap();
+        }
+        for i in (0..count).step_by(2) {
+            node.insert_bytes(&key(i), &[i as u8; 40]).unwrap();
+        }
+        for i in 0..count {
+            assert_eq!(node.get_bytes(&key(i)).unwrap(), Some(&[i as u8; 40][..]));
+        }
+        drop(node);
+        check_page(&page, |issue| panic!("Invalid page: {issue:?}"));
+    }
+
+    #[test]
+    fn key_types_dont_mix() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, NodeConfig::default()).unwrap();
+        assert!(matches!(
+            node.insert(1, b"u64"),
+            Err(BTreeError::KeyTypeMismatch)
+        ));
+        assert!(matches!(node.get(1), Err(BTreeError::KeyTypeMismatch)));
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let node = Node::new(&mut page).unwrap();
+        assert!(matches!(
+            node.get_bytes(b"bytes"),
+            Err(BTreeError::KeyTypeMismatch)
+        ));
+    }
+}
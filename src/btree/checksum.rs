@@ -0,0 +1,187 @@
+/*
+Page checksums. Nodes created with NodeConfig::checksums set FLAG_CHECKSUM and reserve the
+last bytes of the page for a crc32c of everything before them
+----------------------------------------------------------
+| header | slots | free space | content | crc32c (4 bytes) |
+----------------------------------------------------------
+A reserved tail (FLAG_RESERVED_TAIL) follows the checksum and isn't covered by it, the store
+fills it in after the node is done.
+The checksum is refreshed when a node that changed its page is dropped, so it is valid
+whenever no Node borrows the page, which is when pages get written. Load only verifies it
+with NodeConfig::verify_checksums, the check costs a pass over the whole page.
+The crc is computed in software to keep the core free of dependencies.
+*/
+
+use super::errors::BTreeError;
+use super::Node;
+
+pub const CHECKSUM_SIZE: u16 = size_of::<u32>() as u16;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl<'a> Node<'a> {
+    /// End of the content area, the page end unless a checksum or reserved tail follows it
+    pub(crate) fn content_end(&self) -> Result<u16, BTreeError> {
+        Ok(self.read_header()?.content_end(self.page_size()))
+    }
+
+    /// Offset of the checksum, which the content area ends at
+    fn checksum_offset(&self) -> Result<usize, BTreeError> {
+        Ok(self.content_end()?.into())
+    }
+
+    /// Checksum stored in the page, `None` if the page doesn't carry one
+    pub fn stored_checksum(&self) -> Result<Option<u32>, BTreeError> {
+        if !self.read_header()?.has_checksum() {
+            return Ok(None);
+        }
+        let bytes = self.get_page_slice(self.checksum_offset()?, CHECKSUM_SIZE.into());
+        Ok(Some(u32::from_le_bytes(
+            bytes.try_into().expect("Shouldn't fail, hardcoded"),
+        )))
+    }
+
+    /// Fails with ChecksumMismatch if the page carries a checksum that doesn't match its
+    /// content. Pages without a checksum always pass.
+    pub fn verify_checksum(&self) -> Result<(), BTreeError> {
+        let Some(stored) = self.stored_checksum()? else {
+            return Ok(());
+        };
+        let computed = crc32c(self.get_page_slice(0, self.checksum_offset()?));
+        if stored != computed {
+            return Err(BTreeError::ChecksumMismatch { stored, computed });
+        }
+        Ok(())
+    }
+
+    /// Recomputes the checksum, done automatically when a modified node is dropped
+    pub fn update_checksum(&mut self) -> Result<(), BTreeError> {
+        if !self.read_header()?.has_checksum() {
+            return Ok(());
+        }
+        let offset = self.checksum_offset()?;
+        let crc = crc32c(self.get_page_slice(0, offset));
+        self.page[offset..offset + CHECKSUM_SIZE as usize].copy_from_slice(&crc.to_le_bytes());
+        Ok(())
+    }
+}
+
+impl Drop for Node<'_> {
+    fn drop(&mut self) {
+        if self.dirty {
+            // A header that can't be read has no checksum flag we could honour
+            let _ = self.update_checksum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::verify::check_page;
+    use super::super::{IssueKind, NodeConfig, PAGE_SIZE, RESERVED_TAIL};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn checksummed() -> NodeConfig {
+        NodeConfig {
+            checksums: true,
+            verify_checksums: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn load_detects_bit_rot() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        {
+            let mut node = Node::new_with_config(&mut page, checksummed()).unwrap();
+            for key in 0..150 {
+                node.insert(key, &[key as u8; 8]).unwrap();
+            }
+            assert_eq!(node.content_end().unwrap(), PAGE_SIZE - CHECKSUM_SIZE);
+        }
+        let node = Node::load_with_config(&mut page, checksummed()).unwrap();
+        node.verify_checksum().unwrap();
+        assert_eq!(node.get(149).unwrap(), Some([149u8; 8].as_slice()));
+        drop(node);
+
+        page[3000] ^= 1;
+        assert!(matches!(
+            Node::load_with_config(&mut page, checksummed()),
+            Err(BTreeError::ChecksumMismatch { .. })
+        ));
+        // Reads without verification don't paper over the damage
+        let node = Node::load(&mut page).unwrap();
+        assert!(node.verify_checksum().is_err());
+        assert!(node.stored_checksum().unwrap().is_some());
+        drop(node);
+
+        let mut issues = Vec::new();
+        check_page(&page, |issue| issues.push(issue));
+        assert_eq!(issues, vec![IssueKind::ChecksumMismatch]);
+    }
+
+    #[test]
+    fn plain_pages_have_no_checksum() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.insert(1, b"abekat").unwrap();
+        assert_eq!(node.stored_checksum().unwrap(), None);
+        node.verify_checksum().unwrap();
+        assert_eq!(node.content_end().unwrap(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn checksum_ends_before_the_reserved_tail() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let config = NodeConfig {
+            reserve_tail: true,
+            ..checksummed()
+        };
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        for key in 0..150 {
+            node.insert(key, &[key as u8; 8]).unwrap();
+        }
+        let end = PAGE_SIZE - RESERVED_TAIL - CHECKSUM_SIZE;
+        assert_eq!(node.content_end().unwrap(), end);
+        drop(node);
+
+        // The store owns the tail, changing it doesn't touch the checksum
+        page[PAGE_SIZE as usize - 1] = 0xff;
+        let node = Node::load_with_config(&mut page, config).unwrap();
+        assert_eq!(node.get(149).unwrap(), Some([149u8; 8].as_slice()));
+        drop(node);
+        let mut issues = Vec::new();
+        check_page(&page, |issue| issues.push(issue));
+        assert_eq!(issues, vec![]);
+    }
+}
@@ -0,0 +1,156 @@
+/*
+Typed keys. Nodes only store u64 keys and compare them as integers, so any key type that maps
+onto u64 without breaking its order can be used: a < b must hold exactly when
+a.encode() < b.encode(). Unsigned integers widen, signed integers flip their sign bit so
+negative numbers sort first, and byte arrays of up to 8 bytes are read big endian with the
+missing bytes zeroed, which sorts them lexicographically.
+Trees in the catalog can record a fingerprint of their key and value types, a hash of both
+schema names, so opening one with the wrong types fails instead of decoding garbage.
+*/
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Order preserving conversion between a key type and the u64 keys stored in nodes
+pub trait KeyCodec: Sized {
+    fn encode(&self) -> u64;
+    fn decode(raw: u64) -> Self;
+}
+
+impl KeyCodec for u64 {
+    fn encode(&self) -> u64 {
+        *self
+    }
+
+    fn decode(raw: u64) -> Self {
+        raw
+    }
+}
+
+macro_rules! unsigned_codec {
+    ($($ty:ty),*) => {$(
+        impl KeyCodec for $ty {
+            fn encode(&self) -> u64 {
+                (*self).into()
+            }
+
+            fn decode(raw: u64) -> Self {
+                raw as $ty
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_codec {
+    ($($ty:ty),*) => {$(
+        impl KeyCodec for $ty {
+            fn encode(&self) -> u64 {
+                (*self as i64 as u64) ^ (1 << 63)
+            }
+
+            fn decode(raw: u64) -> Self {
+                (raw ^ (1 << 63)) as i64 as $ty
+            }
+        }
+    )*};
+}
+
+macro_rules! array_codec {
+    ($($len:literal),*) => {$(
+        impl KeyCodec for [u8; $len] {
+            fn encode(&self) -> u64 {
+                let mut bytes = [0; 8];
+                bytes[..$len].copy_from_slice(self);
+                u64::from_be_bytes(bytes)
+            }
+
+            fn decode(raw: u64) -> Self {
+                raw.to_be_bytes()[..$len]
+                    .try_into()
+                    .expect("Shouldn't fail, hardcoded")
+            }
+        }
+    )*};
+}
+
+unsigned_codec!(u8, u16, u32);
+signed_codec!(i8, i16, i32, i64);
+array_codec!(1, 2, 3, 4, 5, 6, 7, 8);
+
+/// Name of a key or value type as far as the schema fingerprint is concerned. It has to stay
+/// the same for as long as the type's encoding does.
+pub trait SchemaName {
+    fn schema_name() -> String;
+}
+
+macro_rules! schema_name {
+    ($($ty:ty),*) => {$(
+        impl SchemaName for $ty {
+            fn schema_name() -> String {
+                stringify!($ty).to_string()
+            }
+        }
+    )*};
+}
+
+schema_name!(u8, u16, u32, u64, i8, i16, i32, i64, String);
+
+impl SchemaName for Vec<u8> {
+    fn schema_name() -> String {
+        "bytes".to_string()
+    }
+}
+
+impl<const N: usize> SchemaName for [u8; N] {
+    fn schema_name() -> String {
+        format!("[u8; {N}]")
+    }
+}
+
+/// Fingerprint of a tree keyed by `K` holding `V` values, FNV-1a of both schema names. Never 0,
+/// which the catalog uses for trees without a fingerprint.
+pub fn schema_fingerprint<K: SchemaName, V: SchemaName>() -> u64 {
+    let names = format!("{}\0{}", K::schema_name(), V::schema_name());
+    let hash = names.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn assert_order_preserved<K: KeyCodec + Ord + Copy + std::fmt::Debug>(keys: &[K]) {
+        for a in keys {
+            assert_eq!(K::decode(a.encode()), *a);
+            for b in keys {
+                assert_eq!(a.cmp(b), a.encode().cmp(&b.encode()), "{a:?} vs {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn codecs_preserve_order() {
+        assert_order_preserved(&[0u8, 1, 127, 128, 255]);
+        assert_order_preserved(&[0u32, 1, u32::MAX]);
+        assert_order_preserved(&[i8::MIN, -1, 0, 1, i8::MAX]);
+        assert_order_preserved(&[i64::MIN, -5, -1, 0, 3, i64::MAX]);
+        assert_order_preserved(&[*b"abc", *b"abd", *b"b\0\0", *b"\xff\0\0"]);
+        assert_order_preserved(&[[0u8; 8], [1; 8], [0xff; 8]]);
+    }
+
+    #[test]
+    fn fingerprints_tell_types_apart() {
+        let fingerprint = schema_fingerprint::<u64, Vec<u8>>();
+        assert_eq!(fingerprint, schema_fingerprint::<u64, Vec<u8>>());
+        assert_ne!(fingerprint, schema_fingerprint::<i64, Vec<u8>>());
+        assert_ne!(fingerprint, schema_fingerprint::<u64, String>());
+        assert_ne!(
+            schema_fingerprint::<u32, [u8; 4]>(),
+            schema_fingerprint::<[u8; 4], u32>()
+        );
+    }
+}
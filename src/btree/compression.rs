@@ -0,0 +1,217 @@
+/*
+Compression of values. A tree with a codec stores every value behind a flag byte, so get can
+tell which values to decompress.
+Raw value
+--------------------------
+| 0 (1 byte) | value |
+--------------------------
+Compressed value
+------------------------------------------------------------
+| 1 (1 byte) | value length (4 bytes) | compressed value |
+------------------------------------------------------------
+The length is little endian. Values shorter than the threshold and values that don't shrink
+are stored raw. The flag lives in the value and not in its key record: packed slots and
+inline values have no bits to spare, and splits and merges move values between pages as
+plain bytes.
+Trees in the catalog record the name of their codec, opening one with another codec or none
+fails with PluginMismatch instead of handing out flagged bytes. Readers below the tree, nodes,
+views and mmap snapshots, see the stored bytes.
+*/
+
+#[cfg(feature = "pager")]
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::errors::{BTreeError, CorruptionError};
+use super::fallible::{try_to_vec, try_with_capacity};
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+const HEADER_LEN: usize = 5;
+
+/// Compresses values, see Compression
+pub trait ValueCodec: Sync {
+    /// Identifies the codec's format, recorded in the catalog
+    fn name(&self) -> &'static str;
+
+    fn compress(&self, value: &[u8]) -> Vec<u8>;
+
+    /// Restores a value of `len` bytes from `compressed`, `None` if it's invalid
+    fn decompress(&self, compressed: &[u8], len: usize) -> Option<Vec<u8>>;
+}
+
+/// A codec applied to values of at least `threshold` bytes
+#[derive(Clone, Copy)]
+pub struct Compression {
+    pub codec: &'static dyn ValueCodec,
+    pub threshold: usize,
+}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compression")
+            .field("codec", &self.codec.name())
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+impl Compression {
+    /// The bytes stored for `value`
+    pub fn encode(&self, value: &[u8]) -> Result<Vec<u8>, BTreeError> {
+        if value.len() >= self.threshold {
+            let compressed = self.codec.compress(value);
+            if HEADER_LEN + compressed.len() < 1 + value.len() {
+                if let Ok(len) = u32::try_from(value.len()) {
+                    let mut stored = try_with_capacity(HEADER_LEN + compressed.len())?;
+                    stored.push(COMPRESSED);
+                    stored.extend_from_slice(&len.to_le_bytes());
+                    stored.extend_from_slice(&compressed);
+                    return Ok(stored);
+                }
+            }
+        }
+        let mut stored = try_with_capacity(1 + value.len())?;
+        stored.push(RAW);
+        stored.extend_from_slice(value);
+        Ok(stored)
+    }
+
+    /// Whether `stored` holds its value as it is, right after the flag, so it can be read in
+    /// place
+    pub fn is_raw(stored: &[u8]) -> bool {
+        stored.first() == Some(&RAW)
+    }
+
+    /// The value of `key` stored as `stored`, for reading stored bytes from below the tree.
+    /// Fails if it's invalid or would be longer than `max_len`.
+    pub fn decode(&self, key: u64, stored: &[u8], max_len: usize) -> Result<Vec<u8>, BTreeError> {
+        let invalid = || BTreeError::Corrupt(CorruptionError::CompressedValue { key });
+        match stored.split_first() {
+            Some((&RAW, value)) => try_to_vec(value),
+            Some((&COMPRESSED, rest)) => {
+                let (len, compressed) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+                let len = u32::from_le_bytes(*len) as usize;
+                if len > max_len {
+                    return Err(invalid());
+                }
+                self.codec
+                    .decompress(compressed, len)
+                    .filter(|value| value.len() == len)
+                    .ok_or_else(invalid)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The bytes stored for `value` in a tree compressing with `compression`
+#[cfg(feature = "pager")]
+pub(crate) fn encode_value(
+    compression: Option<Compression>,
+    value: &[u8],
+) -> Result<Cow<'_, [u8]>, BTreeError> {
+    match compression {
+        Some(compression) => compression.encode(value).map(Cow::Owned),
+        None => Ok(Cow::Borrowed(value)),
+    }
+}
+
+/// The value of `key` stored as `stored` in a tree compressing with `compression`
+#[cfg(feature = "pager")]
+pub(crate) fn decode_value(
+    compression: Option<Compression>,
+    key: u64,
+    stored: Vec<u8>,
+    max_len: usize,
+) -> Result<Vec<u8>, BTreeError> {
+    match compression {
+        Some(compression) => compression.decode(key, &stored, max_len),
+        None => Ok(stored),
+    }
+}
+
+/// LZ4 block compression
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl ValueCodec for Lz4 {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, value: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress(value)
+    }
+
+    fn decompress(&self, compressed: &[u8], len: usize) -> Option<Vec<u8>> {
+        lz4_flex::block::decompress(compressed, len).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Stores runs of a byte as the byte and the run's length
+    struct Runs;
+
+    impl ValueCodec for Runs {
+        fn name(&self) -> &'static str {
+            "runs"
+        }
+
+        fn compress(&self, value: &[u8]) -> Vec<u8> {
+            value
+                .chunk_by(|a, b| a == b)
+                .flat_map(|run| [run[0], run.len() as u8])
+                .collect()
+        }
+
+        fn decompress(&self, compressed: &[u8], _len: usize) -> Option<Vec<u8>> {
+            let runs = compressed.chunks_exact(2);
+            runs.remainder()
+                .is_empty()
+                .then(|| runs.flat_map(|run| vec![run[0]; run[1].into()]).collect())
+        }
+    }
+
+    const RUNS: Compression = Compression {
+        codec: &Runs,
+        threshold: 8,
+    };
+
+    #[test]
+    fn values_round_trip() {
+        let values: [&[u8]; 4] = [b"", b"aaaa", &[7; 200], b"abcdefghij"];
+        for value in values {
+            let stored = RUNS.encode(value).unwrap();
+            assert_eq!(RUNS.decode(1, &stored, 1000).unwrap(), value);
+        }
+
+        // Short and incompressible values are stored raw
+        assert_eq!(RUNS.encode(b"aaaa").unwrap(), b"\0aaaa");
+        assert_eq!(RUNS.encode(b"abcdefghij").unwrap()[0], RAW);
+        assert_eq!(RUNS.encode(&[7; 200]).unwrap(), [1, 200, 0, 0, 0, 7, 200]);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let stored = RUNS.encode(&[7; 200]).unwrap();
+        let invalid: [&[u8]; 4] = [&stored[..6], &[2, 1, 2], &[], &[1, 200, 0, 0, 0, 7, 100]];
+        for stored in invalid {
+            assert!(matches!(
+                RUNS.decode(1, stored, 1000),
+                Err(BTreeError::Corrupt(CorruptionError::CompressedValue {
+                    key: 1
+                }))
+            ));
+        }
+        // Longer than allowed
+        assert!(RUNS.decode(1, &stored, 199).is_err());
+    }
+}
@@ -0,0 +1,649 @@
+/*
+Many readers and writers on one tree. The tree sits behind a RwLock that operations take
+shared, and each leaf an operation touches is latched on its own: readers share a leaf's latch,
+a writer holds it alone. Internal nodes are read without latches, they only change while the
+tree is locked exclusively. Writers on different leaves don't block each other, nor readers of
+other leaves.
+
+Writers change leaves in a buffer pool of latched pages instead of the store, which they only
+reach through a shared reference. A write that fits into its leaf is done in place. One that
+would split the leaf splits it under its latch alone, B-link style: the right half goes to a
+spare page set aside earlier and into the pool, and the left half links to it. The parent
+still points at the left half only, so readers and writers that reach a leaf check whether
+the key lies at or above the first key of the page it links to and move right if so. Writers
+latch the right page before letting go of the left one, readers copy one page at a time.
+Keys only ever move right, and a right half never loses its first key to a page further left,
+so a reader that stops at a page finds the key there if it's anywhere.
+
+A write that would leave a leaf underflowing, or split it without a spare page left, gives up
+its latch and starts over under the exclusive lock. That first flushes the pool into the
+store, clears the links and posts the separators of the linked pages to their parents. So does
+a write once the pool holds POOL_PAGES pages. Readers latch a leaf only if it's in the pool
+and read it from the store otherwise, pages reach the store only while nobody reads.
+
+Pages in the pool are written to the store by flush, by write, when the tree is taken apart or
+dropped. A tree over a WAL store still has to be committed after that. commit does both, and
+waits for the log without holding the tree, so the commits of threads that wait at the same
+time share one sync of the log.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::Duration;
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::{BTree, KeyValuePair};
+#[cfg(feature = "wal")]
+use crate::log::{SyncMode, WalStore};
+use crate::page::{Page, SharedRead};
+
+/// Most leaves the pool holds before a write flushes it
+pub const POOL_PAGES: usize = 1024;
+/// Pages set aside for splits under a latch, taken from the store under the exclusive lock
+const SPARE_PAGES: usize = 16;
+
+const POISONED: &str = "ConcurrentTree lock poisoned";
+const LINKED: &str = "Linked pages stay in the pool until the links are cleared";
+
+/// A leaf in the pool, the RwLock around it is its latch
+struct Frame {
+    page: Page,
+    /// Whether the page changed since it was read from the store
+    dirty: bool,
+}
+
+type Pool = HashMap<u32, Arc<RwLock<Frame>>>;
+
+/// A tree threads share by reference, see the module docs
+pub struct ConcurrentTree<S: SharedRead, K: KeyCodec = u64> {
+    tree: RwLock<BTree<S, K>>,
+    pool: Mutex<Pool>,
+    /// Allocated pages no leaf uses yet
+    spares: Mutex<Vec<u32>>,
+    /// Leaves split under a latch since the last flush, left and right half
+    splits: Mutex<Vec<(u32, u32)>>,
+}
+
+impl<S: SharedRead, K: KeyCodec> ConcurrentTree<S, K> {
+    pub fn new(tree: BTree<S, K>) -> Self {
+        Self {
+            tree: RwLock::new(tree),
+            pool: Mutex::new(HashMap::new()),
+            spares: Mutex::new(Vec::new()),
+            splits: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Looks up `key`, alongside other readers and writers of other leaves
+    pub fn get(&self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        let key = key.encode();
+        let tree = self.tree.read().expect(POISONED);
+        let (mut leaf, mut page) = tree.leaf_shared(key)?;
+        if let Some(frame) = self.pooled(leaf) {
+            page = frame.read().expect(POISONED).page.clone();
+        }
+        loop {
+            let link = tree.leaf_link(leaf, &mut page)?;
+            if link == 0 {
+                break;
+            }
+            let frame = self.pooled(link).expect(LINKED);
+            let mut right = frame.read().expect(POISONED).page.clone();
+            if tree
+                .first_key_in_leaf(link, &mut right)?
+                .is_none_or(|first| key < first)
+            {
+                break;
+            }
+            (leaf, page) = (link, right);
+        }
+        tree.get_in_leaf(leaf, &mut page, key)
+    }
+
+    /// Inserts or replaces `key`, in place if it fits into its leaf or there is a spare page
+    /// to split it into. Trees with a max_keys or max_depth quota take every write under
+    /// the exclusive lock.
+    pub fn insert(&self, key: K, value: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
+        let encoded = key.encode();
+        let done = self.in_leaf(encoded, |tree, leaf, page| {
+            if tree.has_quota() {
+                return Ok(None);
+            }
+            tree.invalidate_absent(encoded);
+            let done = match tree.insert_in_leaf(leaf, page, encoded, value)? {
+                Some(old) => Some(old),
+                None => self.split(tree, leaf, page, encoded, value)?,
+            };
+            if done.is_some() {
+                tree.notify_watcher(encoded);
+            }
+            Ok(done)
+        })?;
+        if let Some(old) = done {
+            return Ok(old);
+        }
+        let mut tree = self.exclusive()?;
+        let old = tree.insert(key, value)?;
+        let mut spares = self.spares.lock().expect(POISONED);
+        while spares.len() < SPARE_PAGES {
+            spares.push(tree.allocate()?);
+        }
+        Ok(old)
+    }
+
+    /// Deletes `key`, in place unless its leaf would underflow
+    pub fn delete(&self, key: K) -> Result<Option<KeyValuePair>, BTreeError> {
+        let encoded = key.encode();
+        let done = self.in_leaf(encoded, |tree, leaf, page| {
+            if tree.has_quota() {
+                return Ok(None);
+            }
+            let done = tree.delete_in_leaf(leaf, page, encoded)?;
+            if let Some(Some(_)) = done {
+                tree.notify_watcher(encoded);
+            }
+            Ok(done)
+        })?;
+        match done {
+            Some(old) => Ok(old),
+            None => self.exclusive()?.delete(key),
+        }
+    }
+
+    /// Blocks until `key` is written after this call, see BTree::wait_for. Doesn't hold the
+    /// tree while waiting, so writers go on.
+    pub fn wait_for(&self, key: K, timeout: Duration) -> bool {
+        let key = key.encode();
+        let watcher = self.tree.read().expect(POISONED).watcher().cloned();
+        watcher.is_some_and(|watcher| watcher.wait_for(key, timeout))
+    }
+
+    /// Exclusive access to the tree, for cursors, transactions and everything else that
+    /// needs `&mut BTree`. Flushes the pool first and returns the spare pages to the store.
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, BTree<S, K>>, BTreeError> {
+        let mut tree = self.exclusive()?;
+        release_spares(&mut tree, &mut self.spares.lock().expect(POISONED))?;
+        Ok(tree)
+    }
+
+    /// Writes the leaves changed in the pool to the store
+    pub fn flush(&self) -> Result<(), BTreeError> {
+        self.write().map(drop)
+    }
+
+    /// Leaves in the pool
+    pub fn pooled_pages(&self) -> usize {
+        self.pool.lock().expect(POISONED).len()
+    }
+
+    pub fn into_inner(self) -> Result<BTree<S, K>, BTreeError> {
+        self.flush()?;
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is never dropped, so each field is moved out exactly once
+        let (tree, pool, spares, splits) = unsafe {
+            (
+                ptr::read(&this.tree),
+                ptr::read(&this.pool),
+                ptr::read(&this.spares),
+                ptr::read(&this.splits),
+            )
+        };
+        drop((pool, spares, splits));
+        Ok(tree.into_inner().expect(POISONED))
+    }
+
+    /// The tree locked exclusively, with the pool flushed
+    fn exclusive(&self) -> Result<RwLockWriteGuard<'_, BTree<S, K>>, BTreeError> {
+        let mut tree = self.tree.write().expect(POISONED);
+        flush_pool(
+            &mut tree,
+            &mut self.pool.lock().expect(POISONED),
+            &mut self.splits.lock().expect(POISONED),
+        )?;
+        Ok(tree)
+    }
+
+    fn pooled(&self, page_id: u32) -> Option<Arc<RwLock<Frame>>> {
+        self.pool.lock().expect(POISONED).get(&page_id).cloned()
+    }
+
+    /// Runs `f` on the leaf that holds `key` under its exclusive latch. `None` if `f`
+    /// gives up or the pool is full, the caller starts over under the exclusive lock then.
+    fn in_leaf<T>(
+        &self,
+        key: u64,
+        f: impl FnOnce(&BTree<S, K>, u32, &mut Page) -> Result<Option<T>, BTreeError>,
+    ) -> Result<Option<T>, BTreeError> {
+        let tree = self.tree.read().expect(POISONED);
+        let (leaf, page) = tree.leaf_shared(key)?;
+        let frame = {
+            let mut pool = self.pool.lock().expect(POISONED);
+            if !pool.contains_key(&leaf) && pool.len() >= POOL_PAGES {
+                return Ok(None);
+            }
+            let frame = pool
+                .entry(leaf)
+                .or_insert_with(|| Arc::new(RwLock::new(Frame { page, dirty: false })));
+            Arc::clone(frame)
+        };
+        let latch = frame.write().expect(POISONED);
+        self.move_right(&tree, leaf, latch, key, f)
+    }
+
+    /// Runs `f` on the latched `leaf`, or on the page it links to if that holds `key`. The
+    /// right page is latched before the latch on `leaf` is let go.
+    fn move_right<T>(
+        &self,
+        tree: &BTree<S, K>,
+        leaf: u32,
+        mut latch: RwLockWriteGuard<'_, Frame>,
+        key: u64,
+        f: impl FnOnce(&BTree<S, K>, u32, &mut Page) -> Result<Option<T>, BTreeError>,
+    ) -> Result<Option<T>, BTreeError> {
+        let link = tree.leaf_link(leaf, &mut latch.page)?;
+        if link != 0 {
+            let frame = self.pooled(link).expect(LINKED);
+            let mut right = frame.write().expect(POISONED);
+            if tree
+                .first_key_in_leaf(link, &mut right.page)?
+                .is_some_and(|first| key >= first)
+            {
+                drop(latch);
+                return self.move_right(tree, link, right, key, f);
+            }
+        }
+        let done = f(tree, leaf, &mut latch.page)?;
+        latch.dirty |= done.is_some();
+        Ok(done)
+    }
+
+    /// Splits the full `leaf`, latched by the caller, with `key` inserted into a spare page.
+    /// `None` if there is no spare page left.
+    fn split(
+        &self,
+        tree: &BTree<S, K>,
+        leaf: u32,
+        page: &mut Page,
+        key: u64,
+        value: &[u8],
+    ) -> Result<Option<Option<KeyValuePair>>, BTreeError> {
+        let Some(right) = self.spares.lock().expect(POISONED).pop() else {
+            return Ok(None);
+        };
+        let split = tree.split_in_leaf(leaf, page, key, value, right);
+        let Ok(Some((right_page, old))) = split else {
+            self.spares.lock().expect(POISONED).push(right);
+            return split.map(|_| None);
+        };
+        // In the pool before the latch on `leaf` is let go and anyone can follow the link
+        let frame = Frame {
+            page: right_page,
+            dirty: true,
+        };
+        let mut pool = self.pool.lock().expect(POISONED);
+        pool.insert(right, Arc::new(RwLock::new(frame)));
+        self.splits.lock().expect(POISONED).push((leaf, right));
+        Ok(Some(old))
+    }
+}
+
+/// Empties `pool` into the store of `tree` and posts the `splits` made under latches. With
+/// the tree locked exclusively no latch is held.
+fn flush_pool<S: SharedRead, K: KeyCodec>(
+    tree: &mut BTree<S, K>,
+    pool: &mut Pool,
+    splits: &mut Vec<(u32, u32)>,
+) -> Result<(), BTreeError> {
+    // Every chain of linked leaves starts at a left half that isn't a right half itself
+    let rights: HashSet<u32> = splits.iter().map(|&(_, right)| right).collect();
+    let mut heads: Vec<u32> = splits
+        .iter()
+        .map(|&(left, _)| left)
+        .filter(|left| !rights.contains(left))
+        .collect();
+    heads.sort_unstable();
+    heads.dedup();
+    let mut chains = Vec::new();
+    for head in heads {
+        let mut chain = Vec::new();
+        let mut leaf = head;
+        loop {
+            let mut frame = pool[&leaf].write().expect(POISONED);
+            let link = tree.leaf_link(leaf, &mut frame.page)?;
+            if link == 0 {
+                break;
+            }
+            tree.set_leaf_link(leaf, &mut frame.page, 0)?;
+            drop(frame);
+            let mut right = pool.get(&link).expect(LINKED).write().expect(POISONED);
+            let first = tree.first_key_in_leaf(link, &mut right.page)?;
+            chain.push((link, first.expect("Leaves split off others aren't empty")));
+            leaf = link;
+        }
+        chains.push((head, chain));
+    }
+    splits.clear();
+
+    let leaves: Vec<u32> = pool.keys().copied().collect();
+    for leaf in leaves {
+        let frame = pool[&leaf].read().expect(POISONED);
+        if frame.dirty {
+            tree.write_page(leaf, &frame.page)?;
+        }
+        drop(frame);
+        pool.remove(&leaf);
+    }
+    for (head, chain) in chains {
+        tree.post_links(head, &chain)?;
+    }
+    Ok(())
+}
+
+fn release_spares<S: SharedRead, K: KeyCodec>(
+    tree: &mut BTree<S, K>,
+    spares: &mut Vec<u32>,
+) -> Result<(), BTreeError> {
+    while let Some(page_id) = spares.pop() {
+        tree.release(page_id)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "wal")]
+impl<S: SharedRead, K: KeyCodec> ConcurrentTree<WalStore<S>, K> {
+    /// Commits every change made to the tree so far, by any thread. In SyncMode::Full it
+    /// waits for the log to reach the disk once the tree is free again, along with the
+    /// commits of other threads, see WalStore::commit_pipelined.
+    pub fn commit(&self) -> Result<(), BTreeError> {
+        let (token, sync_mode) = {
+            let mut tree = self.write()?;
+            (tree.commit_pipelined()?, tree.store().sync_mode())
+        };
+        if sync_mode == SyncMode::Full {
+            token.wait_durable()?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: SharedRead, K: KeyCodec> Drop for ConcurrentTree<S, K> {
+    /// Flushes the pool, errors are lost like a BufWriter's
+    fn drop(&mut self) {
+        if let (Ok(tree), Ok(pool), Ok(splits), Ok(spares)) = (
+            self.tree.get_mut(),
+            self.pool.get_mut(),
+            self.splits.get_mut(),
+            self.spares.get_mut(),
+        ) {
+            let _ = flush_pool(tree, pool, splits).and_then(|()| release_spares(tree, spares));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::{MemoryStore, Pager};
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn readers_run_alongside_a_writer() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let mut tree = BTree::create(Pager::open(file_path.to_str().unwrap()).unwrap()).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &[key as u8; 64]).unwrap();
+        }
+        let tree = ConcurrentTree::new(tree);
+        let writing = AtomicBool::new(true);
+
+        thread::scope(|scope| {
+            for reader in 0..4u64 {
+                let (tree, writing) = (&tree, &writing);
+                scope.spawn(move || {
+                    let mut lookups = 0;
+                    while writing.load(Ordering::Relaxed) || lookups < 1000 {
+                        let key = (reader * 7919 + lookups) % 1000;
+                        // Keys below 1000 are never touched by the writer
+                        assert_eq!(tree.get(key).unwrap(), Some(vec![key as u8; 64]));
+                        lookups += 1;
+                    }
+                });
+            }
+            // Splits leaves and internal nodes while the readers look up keys
+            for key in 1000..4000 {
+                tree.insert(key, &[key as u8; 64]).unwrap();
+                if key % 3 == 0 {
+                    tree.delete(key - 1).unwrap();
+                }
+            }
+            writing.store(false, Ordering::Relaxed);
+        });
+
+        assert_eq!(tree.get(3999).unwrap(), Some(vec![3999u16 as u8; 64]));
+        assert_eq!(tree.get(2000).unwrap(), None);
+        let mut tree = tree.into_inner().unwrap();
+        assert_eq!(tree.get(1000).unwrap(), Some(vec![1000u16 as u8; 64]));
+    }
+
+    #[test]
+    fn writers_on_different_leaves_run_at_once() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..4800 {
+            tree.insert(key, &[0; 64]).unwrap();
+        }
+        let tree = ConcurrentTree::new(tree);
+
+        // A writer holding the latch of key 0's leaf doesn't hold up one on another leaf
+        tree.insert(0, &[1; 64]).unwrap();
+        let leaf = tree.tree.read().unwrap().leaf_shared(0).unwrap().0;
+        let frame = Arc::clone(&tree.pool.lock().unwrap()[&leaf]);
+        let latch = frame.write().unwrap();
+        let (done, finished) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                tree.insert(3999, &[1; 64]).unwrap();
+                done.send(()).unwrap();
+            });
+            finished.recv_timeout(Duration::from_secs(10)).unwrap();
+            drop(latch);
+        });
+        assert_eq!(tree.pooled_pages(), 2);
+
+        // Updates that fit run in place on all threads, inserts that split flush the pool
+        thread::scope(|scope| {
+            for writer in 0..4u64 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for key in (writer * 1200..(writer + 1) * 1200).step_by(3) {
+                        tree.insert(key, &[writer as u8 + 2; 64]).unwrap();
+                        tree.delete(key + 1).unwrap();
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for key in 5000..6000 {
+                    tree.insert(key, &[9; 64]).unwrap();
+                }
+            });
+        });
+
+        let mut tree = tree.into_inner().unwrap();
+        for key in 0..6000u64 {
+            let expected = match key {
+                5000.. => Some(vec![9; 64]),
+                4800.. => None,
+                _ if key % 3 == 0 => Some(vec![(key / 1200) as u8 + 2; 64]),
+                _ if key % 3 == 1 => None,
+                _ => Some(vec![0; 64]),
+            };
+            assert_eq!(tree.get(key).unwrap(), expected, "key {key}");
+        }
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn commits_of_many_threads_are_durable() {
+        use crate::log::WalStore;
+
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+        let wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        let mut tree = BTree::create(wal).unwrap();
+        tree.commit().unwrap();
+        let root = tree.root();
+
+        let tree = ConcurrentTree::new(tree);
+        thread::scope(|scope| {
+            for writer in 0..8u64 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for key in writer * 50..(writer + 1) * 50 {
+                        tree.insert(key, &[writer as u8; 64]).unwrap();
+                        tree.commit().unwrap();
+                    }
+                });
+            }
+        });
+        let tree = tree.into_inner().unwrap();
+        let stats = tree.store().group_commit_stats();
+        assert_eq!(stats.commits, 400);
+        assert!(stats.fsyncs <= stats.commits, "{stats:?}");
+
+        // The store only has what was synced before, the rest is replayed from the log
+        let store = tree.into_store().into_inner();
+        let wal = WalStore::open(store, log_path).unwrap();
+        let mut tree = BTree::open(wal, root).unwrap();
+        for key in 0..400u64 {
+            assert_eq!(tree.get(key).unwrap(), Some(vec![(key / 50) as u8; 64]));
+        }
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn a_commit_window_batches_tree_commits() {
+        use crate::log::WalStore;
+
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut wal = WalStore::open(store, log_path.to_str().unwrap()).unwrap();
+        wal.set_commit_window(Duration::from_millis(20));
+        let tree = ConcurrentTree::new(BTree::create(wal).unwrap());
+
+        thread::scope(|scope| {
+            for writer in 0..8u64 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for key in writer * 5..(writer + 1) * 5 {
+                        tree.insert(key, &[writer as u8; 64]).unwrap();
+                        tree.commit().unwrap();
+                    }
+                });
+            }
+        });
+        let stats = tree.into_inner().unwrap().store().group_commit_stats();
+        assert_eq!(stats.commits, 40);
+        assert!(stats.fsyncs < 40, "{stats:?}");
+    }
+
+    #[test]
+    fn writes_wake_waiters() {
+        use super::super::KeyWatcher;
+
+        let watcher = Arc::new(KeyWatcher::new());
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into()))
+            .unwrap()
+            .with_watcher(Arc::clone(&watcher));
+        tree.insert(1, b"one").unwrap();
+        tree.delete(1).unwrap();
+        tree.delete(2).unwrap();
+        assert_eq!(watcher.version(1), 2);
+        assert_eq!(watcher.version(2), 0);
+        assert!(!tree.wait_for(1, Duration::from_millis(10)));
+
+        let tree = ConcurrentTree::new(tree);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| tree.wait_for(7, Duration::from_secs(10)));
+            while !waiter.is_finished() {
+                tree.insert(7, b"seven").unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn leaves_split_under_their_latch() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..2000 {
+            tree.insert(key * 4, &[1; 64]).unwrap();
+        }
+        let tree = ConcurrentTree::new(tree);
+
+        // The first split sets spare pages aside under the exclusive lock, the next ones take
+        // them and link the halves instead of flushing
+        for key in 0..200 {
+            tree.insert(key * 4 + 1, &[2; 64]).unwrap();
+        }
+        assert!(!tree.splits.lock().unwrap().is_empty());
+        for key in 0..200 {
+            assert_eq!(tree.get(key * 4 + 1).unwrap(), Some(vec![2; 64]));
+            assert_eq!(tree.get(key * 4).unwrap(), Some(vec![1; 64]));
+        }
+
+        // Writers split the leaves under the readers and find their keys past the links
+        let writing = AtomicBool::new(true);
+        thread::scope(|scope| {
+            for reader in 0..2u64 {
+                let (tree, writing) = (&tree, &writing);
+                scope.spawn(move || {
+                    let mut lookups = 0;
+                    while writing.load(Ordering::Relaxed) || lookups < 1000 {
+                        let key = (reader * 7919 + lookups) % 2000 * 4;
+                        assert_eq!(tree.get(key).unwrap(), Some(vec![1; 64]));
+                        lookups += 1;
+                    }
+                });
+            }
+            let writers: Vec<_> = (0..4u64)
+                .map(|writer| {
+                    let tree = &tree;
+                    scope.spawn(move || {
+                        for key in writer * 500..(writer + 1) * 500 {
+                            tree.insert(key * 4 + 2, &[writer as u8; 64]).unwrap();
+                            assert_eq!(
+                                tree.get(key * 4 + 2).unwrap(),
+                                Some(vec![writer as u8; 64])
+                            );
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            writing.store(false, Ordering::Relaxed);
+        });
+
+        let mut tree = tree.into_inner().unwrap();
+        assert_eq!(tree.iter().count(), 4200);
+        for key in 0..2000u64 {
+            assert_eq!(tree.get(key * 4).unwrap(), Some(vec![1; 64]));
+            assert_eq!(
+                tree.get(key * 4 + 2).unwrap(),
+                Some(vec![(key / 500) as u8; 64])
+            );
+        }
+    }
+}
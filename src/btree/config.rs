@@ -0,0 +1,62 @@
+use super::compression::Compression;
+use super::plugin::{Comparator, MergeOperator};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeConfig {
+    /// Zero every byte that is released or left unused, so the page image only
+    /// depends on the sequence of operations and never on stale buffer contents.
+    pub deterministic: bool,
+    pub limits: Limits,
+    pub defrag_policy: DefragPolicy,
+    /// Store tiny, equally sized values in the packed layout while possible
+    pub adaptive_layout: bool,
+    /// Store the high bytes all keys of a packed node share once instead of in every slot,
+    /// see adaptive_layout
+    pub prefix_compression: bool,
+    /// Store leaf values of up to MAX_INLINE_VALUE bytes inside their key record
+    pub inline_values: bool,
+    /// Reject key records that would leave the key array unsorted or duplicated, and
+    /// audit the content area for overlaps after every mutation, catching layout bugs
+    /// where they happen instead of at query time
+    pub strict: bool,
+    /// Reserve the end of new pages for a checksum, refreshed whenever a node changes
+    pub checksums: bool,
+    /// Leave the last RESERVED_TAIL bytes of new pages to the store. Trees set it on their
+    /// own over stores that reserve them, see PageStore::reserves_tail.
+    pub reserve_tail: bool,
+    /// Make load fail with ChecksumMismatch on pages whose checksum doesn't match
+    pub verify_checksums: bool,
+    /// Place values at offsets that are a multiple of VALUE_ALIGNMENT, so structs with u64
+    /// fields can be read in place from pages that are aligned themselves. The padding
+    /// counts as fragmented bytes. Turns off adaptive_layout, packed slots aren't aligned.
+    pub align_values: bool,
+    /// Order of the keys in byte keyed nodes, bytewise if unset
+    pub comparator: Option<Comparator>,
+    /// Combines values in `merge`
+    pub merge_operator: Option<MergeOperator>,
+    /// Compresses the values trees store, see Compression. Nodes store whatever they are
+    /// given, only trees apply it.
+    pub compression: Option<Compression>,
+}
+
+/// Order in which `defrag` packs values into the content area
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DefragPolicy {
+    /// Values end up sorted by key
+    #[default]
+    KeyOrder,
+    /// Values keep their relative placement, so values written together stay together
+    OriginalOffset,
+}
+
+/// Hard limits enforced on insert with LimitExceeded errors. A node applies them to
+/// itself, a tree to the whole tree instead of to each of its nodes. `None` means only the
+/// page format itself limits the node or tree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Keys in the node, or in every leaf of the tree together
+    pub max_keys: Option<u32>,
+    pub max_value_size: Option<u16>,
+    /// Levels a tree may grow to, its root being the first. Nodes ignore it.
+    pub max_depth: Option<usize>,
+}
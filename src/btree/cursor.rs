@@ -0,0 +1,146 @@
+use core::ops::{Bound, RangeBounds};
+
+use super::errors::BTreeError;
+use super::Node;
+
+/// Entries of a node within a key range, in key order. Values are borrowed from the page.
+pub struct RangeIter<'n, 'a> {
+    node: &'n Node<'a>,
+    idx: u16,
+    end: u16,
+}
+
+impl<'a> Node<'a> {
+    /// Index of the first key that is at least `key`, and whether it is `key`
+    fn lower_bound(&self, key: u64) -> Result<(u16, bool), BTreeError> {
+        let (idx, exists) = self.find_le_key_idx(key)?;
+        Ok((idx as u16, exists))
+    }
+
+    pub fn iter_range(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> Result<RangeIter<'_, 'a>, BTreeError> {
+        let num_keys = self.read_header()?.num_keys.get();
+        let idx = match range.start_bound() {
+            Bound::Included(&start) => self.lower_bound(start)?.0,
+            Bound::Excluded(&start) => match self.lower_bound(start)? {
+                (idx, true) => idx + 1,
+                (idx, false) => idx,
+            },
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => match self.lower_bound(end)? {
+                (idx, true) => idx + 1,
+                (idx, false) => idx,
+            },
+            Bound::Excluded(&end) => self.lower_bound(end)?.0,
+            Bound::Unbounded => num_keys,
+        };
+
+        Ok(RangeIter {
+            node: self,
+            idx,
+            end: end.max(idx),
+        })
+    }
+
+    pub fn iter(&self) -> Result<RangeIter<'_, 'a>, BTreeError> {
+        self.iter_range(..)
+    }
+}
+
+impl<'n> Iterator for RangeIter<'n, '_> {
+    type Item = (u64, &'n [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.end {
+            return None;
+        }
+        let key = self
+            .node
+            .key_at(self.idx)
+            .expect("The header was valid when the iterator was created");
+        let value = self
+            .node
+            .value_at(self.idx)
+            .expect("The header was valid when the iterator was created");
+        self.idx += 1;
+        Some((key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.end - self.idx) as usize;
+        (len, Some(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{NodeConfig, PAGE_SIZE};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn keys(iter: RangeIter) -> Vec<u64> {
+        iter.map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn ranges_over_keys() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in [10, 20, 30, 40] {
+            node.insert(key, format!("value {key}").as_bytes()).unwrap();
+        }
+
+        assert_eq!(keys(node.iter().unwrap()), vec![10, 20, 30, 40]);
+        assert_eq!(keys(node.iter_range(20..40).unwrap()), vec![20, 30]);
+        assert_eq!(keys(node.iter_range(20..=40).unwrap()), vec![20, 30, 40]);
+        assert_eq!(keys(node.iter_range(15..35).unwrap()), vec![20, 30]);
+        assert_eq!(
+            keys(
+                node.iter_range((Bound::Excluded(10), Bound::Unbounded))
+                    .unwrap()
+            ),
+            vec![20, 30, 40]
+        );
+        assert_eq!(keys(node.iter_range(41..).unwrap()), Vec::<u64>::new());
+        let (start, end) = (30, 20);
+        assert_eq!(
+            keys(node.iter_range(start..end).unwrap()),
+            Vec::<u64>::new()
+        );
+
+        let mut iter = node.iter_range(25..).unwrap();
+        assert_eq!(iter.size_hint(), (2, Some(2)));
+        assert_eq!(iter.next(), Some((30, b"value 30".as_slice())));
+    }
+
+    #[test]
+    fn values_in_every_layout() {
+        let config = NodeConfig {
+            adaptive_layout: true,
+            ..NodeConfig::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        for key in 0..5 {
+            node.insert(key, &[key as u8; 2]).unwrap();
+        }
+        assert!(node.is_packed().unwrap());
+        let packed: Vec<_> = node.iter_range(1..3).unwrap().collect();
+        assert_eq!(
+            packed,
+            vec![(1, [1u8; 2].as_slice()), (2, [2u8; 2].as_slice())]
+        );
+
+        // Inline and offset values side by side
+        node.insert(5, b"a longer value").unwrap();
+        let mixed: Vec<_> = node.iter_range(4..).unwrap().collect();
+        assert_eq!(
+            mixed,
+            vec![(4, [4u8; 2].as_slice()), (5, b"a longer value".as_slice())]
+        );
+    }
+}
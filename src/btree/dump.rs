@@ -0,0 +1,397 @@
+/*
+Annotated dump of a node's page, for diagnosing corruption by eye. The accessors give up at
+the first inconsistency, the dump reads the raw bytes instead and carries on past damage:
+every offset, length or chain that doesn't add up gets a line starting with `!`. It lists the
+header fields, the slot array, the free space, the freeblock chain and the cells in page
+order, with the first DUMP_BYTES bytes of every cell hex-dumped.
+
+    leaf, 4096 byte page
+    header
+      node_type             1 leaf
+      num_keys              2
+      ...
+    slots 16..48, 2 of 16 bytes
+      #0 @16    key 10  value @4089+7
+      #1 @32    key 30  inline 01 02
+    free space 48..3989, 3941 bytes
+    freeblocks
+      @3989  100 bytes, next 0
+    cells
+      @4089..4096  key 10
+        0ff9  76 61 6c 75 65 31 30                             |value10|
+*/
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use memoffset::offset_of;
+use zerocopy::FromBytes;
+
+use super::bytekeys::{BYTE_KEY_SLOT_SIZE, CELL_HEADER_SIZE};
+use super::checksum::CHECKSUM_SIZE;
+use super::freeblock::{Freeblock, FREEBLOCK_SIZE};
+use super::header::{
+    Header, FLAG_BYTE_KEYS, FLAG_CHECKSUM, FLAG_CUSTOM_ORDER, FLAG_PACKED, FLAG_RESERVED_TAIL,
+    HEADER_SIZE, RESERVED_TAIL,
+};
+use super::key::{Key, KEY_SIZE, MAX_INLINE_VALUE};
+use super::packed::PACKED_KEY_SIZE;
+use super::Node;
+
+/// Bytes of every cell shown in the dump, the rest is only counted
+const DUMP_BYTES: usize = 64;
+
+/// A cell found through a slot: where it starts, its length and what the slot says it holds
+type Cell = (usize, usize, String);
+
+impl Node<'_> {
+    /// The page laid out field by field, see the top of dump.rs
+    pub fn debug_dump(&self) -> String {
+        let mut out = String::new();
+        self.dump(&mut out)
+            .expect("Shouldn't fail, writing to a String can't");
+        out
+    }
+
+    fn dump(&self, out: &mut impl Write) -> fmt::Result {
+        let page: &[u8] = self.page;
+        let byte = |offset: usize| page[offset];
+        let u16_at = |offset: usize| u16::from_le_bytes([page[offset], page[offset + 1]]);
+        let node_type = byte(offset_of!(Header, node_type));
+        let num_keys = u16_at(offset_of!(Header, num_keys));
+        let free_start = u16_at(offset_of!(Header, free_start)) as usize;
+        let free_end = u16_at(offset_of!(Header, free_end)) as usize;
+        let first_freeblock = u16_at(offset_of!(Header, first_freeblock)) as usize;
+        let rightmost = offset_of!(Header, rightmost_child_page);
+        let rightmost = u32::from_le_bytes(page[rightmost..rightmost + 4].try_into().unwrap());
+        let flags = byte(offset_of!(Header, flags));
+        let packed_width = byte(offset_of!(Header, packed_width)) as usize;
+
+        let kind = match node_type {
+            0 => "internal",
+            1 => "leaf",
+            _ => "! unknown node type",
+        };
+        writeln!(out, "{kind}, {} byte page", page.len())?;
+        writeln!(out, "header")?;
+        writeln!(out, "  node_type             {node_type} {kind}")?;
+        writeln!(out, "  num_keys              {num_keys}")?;
+        writeln!(out, "  free_start            {free_start}")?;
+        writeln!(out, "  free_end              {free_end}")?;
+        writeln!(out, "  first_freeblock       {first_freeblock}")?;
+        writeln!(
+            out,
+            "  fragmented_bytes      {}",
+            byte(offset_of!(Header, fragmented_bytes))
+        )?;
+        writeln!(out, "  rightmost_child_page  {rightmost}")?;
+        write!(out, "  flags                 {flags:#04x}")?;
+        for (flag, name) in [
+            (FLAG_PACKED, "packed"),
+            (FLAG_BYTE_KEYS, "byte_keys"),
+            (FLAG_CHECKSUM, "checksum"),
+            (FLAG_CUSTOM_ORDER, "custom_order"),
+            (FLAG_RESERVED_TAIL, "reserved_tail"),
+        ] {
+            if flags & flag != 0 {
+                write!(out, " {name}")?;
+            }
+        }
+        let prefix_len = if flags & FLAG_PACKED != 0 {
+            (flags >> 5) as usize
+        } else {
+            0
+        };
+        if prefix_len > 0 {
+            write!(out, " prefix={prefix_len}")?;
+        }
+        writeln!(out)?;
+        writeln!(out, "  packed_width          {packed_width}")?;
+
+        let mut content_end = page.len();
+        let mut tail = Vec::new();
+        if flags & FLAG_RESERVED_TAIL != 0 {
+            content_end -= RESERVED_TAIL as usize;
+            tail.push(("reserved tail", content_end, RESERVED_TAIL as usize));
+        }
+        if flags & FLAG_CHECKSUM != 0 {
+            content_end -= CHECKSUM_SIZE as usize;
+            tail.push(("checksum", content_end, CHECKSUM_SIZE as usize));
+        }
+
+        let slots_start = HEADER_SIZE as usize + prefix_len;
+        let slot_size = if flags & FLAG_PACKED != 0 {
+            PACKED_KEY_SIZE as usize - prefix_len + packed_width
+        } else if flags & FLAG_BYTE_KEYS != 0 {
+            BYTE_KEY_SLOT_SIZE as usize
+        } else {
+            KEY_SIZE as usize
+        };
+        let slots_end = slots_start + slot_size * num_keys as usize;
+        writeln!(
+            out,
+            "slots {slots_start}..{slots_end}, {num_keys} of {slot_size} bytes"
+        )?;
+        if slots_end != free_start {
+            writeln!(
+                out,
+                "  ! slots end at {slots_end}, free_start is {free_start}"
+            )?;
+        }
+        let mut cells: Vec<Cell> = Vec::new();
+        for idx in 0..num_keys as usize {
+            let pos = slots_start + idx * slot_size;
+            let Some(slot) = page.get(pos..pos + slot_size) else {
+                writeln!(out, "  ! #{idx} @{pos} runs past the end of the page")?;
+                break;
+            };
+            write!(out, "  #{idx} @{pos:<5}")?;
+            if flags & FLAG_PACKED != 0 {
+                let mut key = [0; 8];
+                key[..8 - prefix_len].copy_from_slice(&slot[..8 - prefix_len]);
+                key[8 - prefix_len..].copy_from_slice(&page[HEADER_SIZE as usize..slots_start]);
+                write!(out, " key {}  value ", u64::from_le_bytes(key))?;
+                write_hex(out, &slot[8 - prefix_len..])?;
+                writeln!(out)?;
+            } else if flags & FLAG_BYTE_KEYS != 0 {
+                let cell = u16::from_le_bytes([slot[0], slot[1]]) as usize;
+                let Some(lens) = page.get(cell..cell + CELL_HEADER_SIZE as usize) else {
+                    writeln!(out, " cell @{cell}")?;
+                    writeln!(out, "  ! cell @{cell} starts past the end of the page")?;
+                    continue;
+                };
+                let key_len = u16::from_le_bytes([lens[0], lens[1]]) as usize;
+                let value_len = u16::from_le_bytes([lens[2], lens[3]]) as usize;
+                let len = CELL_HEADER_SIZE as usize + key_len + value_len;
+                writeln!(
+                    out,
+                    " cell @{cell}+{len}  key {key_len} bytes  value {value_len} bytes"
+                )?;
+                let key_start = cell + CELL_HEADER_SIZE as usize;
+                let mut label = String::from("key ");
+                match page.get(key_start..key_start + key_len) {
+                    Some(key) => write_hex(&mut label, &key[..key.len().min(16)])?,
+                    None => label.push('?'),
+                }
+                cells.push((cell, len, label));
+            } else {
+                let key = Key::read_from_bytes(slot).expect("Slots are as large as a Key");
+                let offset = key.value_offset.get() as usize;
+                let len = key.value_len.get() as usize;
+                write!(out, " key {}", key.key.get())?;
+                if node_type == 0 {
+                    writeln!(out, "  child {}", key.left_child_page.get())?;
+                } else if key.is_inline() {
+                    write!(out, "  inline ")?;
+                    if len > MAX_INLINE_VALUE as usize {
+                        writeln!(out)?;
+                        writeln!(out, "  ! inline value of {len} bytes")?;
+                    } else {
+                        write_hex(out, key.inline_value())?;
+                        writeln!(out)?;
+                    }
+                } else {
+                    writeln!(out, "  value @{offset}+{len}")?;
+                    cells.push((offset, len, format!("key {}", key.key.get())));
+                }
+            }
+        }
+
+        if free_start <= free_end && free_end <= content_end {
+            writeln!(
+                out,
+                "free space {free_start}..{free_end}, {} bytes",
+                free_end - free_start
+            )?;
+        } else {
+            writeln!(
+                out,
+                "! free space {free_start}..{free_end} outside the content area ..{content_end}"
+            )?;
+        }
+
+        writeln!(out, "freeblocks")?;
+        let mut offset = first_freeblock;
+        let mut left = page.len() / FREEBLOCK_SIZE as usize;
+        while offset != 0 {
+            let Some(bytes) = page.get(offset..offset + FREEBLOCK_SIZE as usize) else {
+                writeln!(out, "  ! @{offset} runs past the end of the page")?;
+                break;
+            };
+            if left == 0 {
+                writeln!(out, "  ! the chain loops")?;
+                break;
+            }
+            left -= 1;
+            let freeblock =
+                Freeblock::read_from_bytes(bytes).expect("Freeblocks are as large as the slice");
+            let (next, size) = (
+                freeblock.next_freeblock.get() as usize,
+                freeblock.size.get() as usize,
+            );
+            writeln!(out, "  @{offset:<5} {size} bytes, next {next}")?;
+            if offset < free_end || offset + size > content_end {
+                writeln!(out, "  ! outside the cell area {free_end}..{content_end}")?;
+            }
+            offset = next;
+        }
+
+        writeln!(out, "cells")?;
+        cells.sort_by_key(|(start, _, _)| *start);
+        let mut previous_end = 0;
+        for (start, len, label) in cells {
+            let end = start + len;
+            writeln!(out, "  @{start}..{end}  {label}")?;
+            if start < previous_end {
+                writeln!(
+                    out,
+                    "  ! overlaps the cell before, which ends at {previous_end}"
+                )?;
+            }
+            if start < free_end || end > content_end {
+                writeln!(out, "  ! outside the cell area {free_end}..{content_end}")?;
+            }
+            previous_end = previous_end.max(end);
+            let shown = start.min(page.len())..end.min(start + DUMP_BYTES).min(page.len());
+            hexdump(out, shown.start, &page[shown.clone()])?;
+            if shown.len() < len {
+                writeln!(out, "    ... {} more bytes", len - shown.len())?;
+            }
+        }
+
+        for (name, start, len) in tail.into_iter().rev() {
+            write!(out, "{name} {start}..{}  ", start + len)?;
+            write_hex(out, &page[start..start + len])?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Node<'_> {
+    /// The same as debug_dump
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.dump(f)
+    }
+}
+
+fn write_hex(out: &mut impl Write, bytes: &[u8]) -> fmt::Result {
+    for (idx, byte) in bytes.iter().enumerate() {
+        if idx > 0 {
+            out.write_char(' ')?;
+        }
+        write!(out, "{byte:02x}")?;
+    }
+    Ok(())
+}
+
+/// Lines of 16 bytes starting at page offset `offset`, in hex and as ASCII
+fn hexdump(out: &mut impl Write, offset: usize, bytes: &[u8]) -> fmt::Result {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        write!(out, "    {:04x}  ", offset + line * 16)?;
+        let mut hex = String::new();
+        write_hex(&mut hex, chunk)?;
+        write!(out, "{hex:<47}  |")?;
+        for &byte in chunk {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            out.write_char(if printable { byte as char } else { '.' })?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::header::NodeType;
+    use super::super::{NodeConfig, PAGE_SIZE};
+    use super::*;
+    use alloc::vec;
+    use pretty_assertions::assert_eq;
+
+    fn lines(dump: &str) -> Vec<&str> {
+        dump.lines().collect()
+    }
+
+    #[test]
+    fn leaves_dump_slots_freeblocks_and_cells() {
+        let config = NodeConfig {
+            inline_values: true,
+            ..Default::default()
+        };
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        node.insert(10, b"value10").unwrap();
+        node.insert(20, &[0xab; 100]).unwrap();
+        node.insert(30, b"value30").unwrap();
+        node.insert(40, &[1, 2]).unwrap();
+        node.delete(20).unwrap();
+
+        let dump = node.debug_dump();
+        assert_eq!(format!("{node:?}"), dump);
+        let lines = lines(&dump);
+        assert_eq!(lines[0], "leaf, 4096 byte page");
+        assert!(lines.contains(&"  num_keys              3"));
+        assert!(lines.contains(&"slots 16..64, 3 of 16 bytes"));
+        assert!(lines.contains(&"  #0 @16    key 10  value @4089+7"));
+        assert!(lines.contains(&"  #2 @48    key 40  inline 01 02"));
+        assert!(lines.contains(&"  @3989  100 bytes, next 0"));
+        assert!(lines.contains(&"  @4089..4096  key 10"));
+        let hex = format!("{:<47}", "76 61 6c 75 65 31 30");
+        assert!(lines.contains(&format!("    0ff9  {hex}  |value10|").as_str()));
+        assert!(!dump.contains('!'), "{dump}");
+    }
+
+    #[test]
+    fn damage_is_marked() {
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.insert(10, &[7; 200]).unwrap();
+        node.insert(20, &[8; 200]).unwrap();
+        node.delete(10).unwrap();
+        drop(node);
+        // The value of 20 overlaps a value that isn't there, and the freeblock points to itself
+        let value_offset = HEADER_SIZE as usize + offset_of!(Key, value_offset);
+        page[value_offset..value_offset + 2].copy_from_slice(&4000u16.to_le_bytes());
+        let freeblock = u16::from_le_bytes([page[7], page[8]]) as usize;
+        page[freeblock..freeblock + 2].copy_from_slice(&(freeblock as u16).to_le_bytes());
+
+        let dump = Node::load(&mut page).unwrap().debug_dump();
+        let lines = lines(&dump);
+        assert!(
+            lines.contains(&"  ! outside the cell area 3696..4096"),
+            "{dump}"
+        );
+        assert!(lines.contains(&"  ! the chain loops"), "{dump}");
+        assert!(lines.contains(&"    ... 136 more bytes"), "{dump}");
+    }
+
+    #[test]
+    fn internal_and_packed_nodes() {
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.mutate_header().unwrap().node_type = NodeType::Internal;
+        node.insert_separator(100, 3).unwrap();
+        node.mutate_header().unwrap().rightmost_child_page = 4.into();
+        let dump = node.debug_dump();
+        assert!(dump.starts_with("internal, 4096 byte page\n"));
+        assert!(lines(&dump).contains(&"  #0 @16    key 100  child 3"));
+        assert!(lines(&dump).contains(&"  rightmost_child_page  4"));
+
+        let config = NodeConfig {
+            adaptive_layout: true,
+            checksums: true,
+            ..Default::default()
+        };
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        node.insert(7, &[1, 2, 3, 4, 5, 6]).unwrap();
+        node.pack().unwrap();
+        let dump = node.debug_dump();
+        assert!(dump.contains(" packed"), "{dump}");
+        assert!(dump.contains("key 7  value 01 02 03 04 05 06"), "{dump}");
+        assert!(dump.contains("\nchecksum 4092..4096  "), "{dump}");
+    }
+}
@@ -0,0 +1,253 @@
+/*
+Entries of a tree, like those of std's BTreeMap. Looking a key up once gives an entry that
+knows whether the key exists, holds its value if it does, and keeps the path to the key's leaf,
+so inserting or removing through the entry reads that leaf again without descending from the
+root. Values live in pages rather than in the entry, so changes are written right away instead
+of through a mutable reference. A write may split or merge the leaf, after one the entry finds
+the leaf again from the root.
+*/
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::tree::Path;
+use super::BTree;
+use crate::page::PageStore;
+
+/// A key of a tree, either stored or not, see BTree::entry
+pub enum Entry<'t, S: PageStore, K: KeyCodec> {
+    Occupied(OccupiedEntry<'t, S, K>),
+    Vacant(VacantEntry<'t, S, K>),
+}
+
+/// A key stored in the tree, with its value
+pub struct OccupiedEntry<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    key: u64,
+    value: Vec<u8>,
+    /// Path to the key's leaf, `None` once a write may have moved the key
+    path: Option<Path>,
+}
+
+/// A key not in the tree
+pub struct VacantEntry<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    key: u64,
+    path: Path,
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// The entry of `key`, to read and change it with a single lookup
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, S, K>, BTreeError> {
+        let key = key.encode();
+        let (path, stored) = self.lookup(key)?;
+        Ok(match stored {
+            Some(stored) => Entry::Occupied(OccupiedEntry {
+                value: self.decode(key, stored)?,
+                tree: self,
+                key,
+                path: Some(path),
+            }),
+            None => Entry::Vacant(VacantEntry {
+                tree: self,
+                key,
+                path,
+            }),
+        })
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Entry<'_, S, K> {
+    pub fn key(&self) -> K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// The value of the key, `default` once inserted if the key is vacant
+    pub fn or_insert(self, default: &[u8]) -> Result<Vec<u8>, BTreeError> {
+        self.or_insert_with(|| default.to_vec())
+    }
+
+    /// The value of the key, the result of `default` once inserted if the key is vacant
+    pub fn or_insert_with(self, default: impl FnOnce() -> Vec<u8>) -> Result<Vec<u8>, BTreeError> {
+        self.or_insert_with_key(|_| default())
+    }
+
+    /// Like or_insert_with, `default` gets the key
+    pub fn or_insert_with_key(
+        self,
+        default: impl FnOnce(&K) -> Vec<u8>,
+    ) -> Result<Vec<u8>, BTreeError> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_value()),
+            Entry::Vacant(entry) => {
+                let value = default(&entry.key());
+                entry.insert(&value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    /// Changes the value of an occupied key through `f` and writes it back, does nothing to
+    /// a vacant one
+    pub fn and_modify(self, f: impl FnOnce(&mut Vec<u8>)) -> Result<Self, BTreeError> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.value.clone();
+                f(&mut value);
+                entry.insert(&value)?;
+                Ok(Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> OccupiedEntry<'_, S, K> {
+    pub fn key(&self) -> K {
+        K::decode(self.key)
+    }
+
+    pub fn get(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn into_value(self) -> Vec<u8> {
+        self.value
+    }
+
+    /// Replaces the value, returning the old one
+    pub fn insert(&mut self, value: &[u8]) -> Result<Vec<u8>, BTreeError> {
+        let stored = self.tree.encode(value)?;
+        let path = self.take_path()?;
+        self.tree.insert_at(&path, self.key, &stored)?;
+        Ok(std::mem::replace(&mut self.value, value.to_vec()))
+    }
+
+    /// Deletes the key, returning its value
+    pub fn remove(mut self) -> Result<Vec<u8>, BTreeError> {
+        let path = self.take_path()?;
+        self.tree.delete_at(&path, self.key)?;
+        Ok(self.value)
+    }
+
+    /// The path to the key's leaf, which the caller is about to write
+    fn take_path(&mut self) -> Result<Path, BTreeError> {
+        match self.path.take() {
+            Some(path) => Ok(path),
+            None => self.tree.find_path(self.key),
+        }
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> VacantEntry<'_, S, K> {
+    pub fn key(&self) -> K {
+        K::decode(self.key)
+    }
+
+    pub fn insert(self, value: &[u8]) -> Result<(), BTreeError> {
+        let stored = self.tree.encode(value)?;
+        self.tree.insert_at(&self.path, self.key, &stored)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn count(tree: &mut BTree<MemoryStore>, key: u64) -> u64 {
+        let value = tree
+            .entry(key)
+            .unwrap()
+            .and_modify(|count| {
+                *count = (u64::from_le_bytes(count[..].try_into().unwrap()) + 1)
+                    .to_le_bytes()
+                    .to_vec()
+            })
+            .unwrap()
+            .or_insert(&1u64.to_le_bytes())
+            .unwrap();
+        u64::from_le_bytes(value.try_into().unwrap())
+    }
+
+    #[test]
+    fn entries_count_keys() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for i in 0..3000 {
+            count(&mut tree, i % 700);
+        }
+        assert_eq!(count(&mut tree, 3), 6);
+        assert_eq!(count(&mut tree, 699), 5);
+        assert_eq!(count(&mut tree, 5000), 1);
+
+        let Entry::Occupied(mut entry) = tree.entry(42).unwrap() else {
+            panic!("42 was counted");
+        };
+        assert_eq!(entry.key(), 42);
+        assert_eq!(entry.insert(b"x").unwrap(), 5u64.to_le_bytes());
+        assert_eq!(entry.insert(b"y").unwrap(), b"x");
+        assert_eq!(entry.remove().unwrap(), b"y");
+        assert_eq!(tree.get(42).unwrap(), None);
+
+        let entry = tree.entry(42).unwrap();
+        assert!(matches!(entry, Entry::Vacant(_)));
+        assert_eq!(
+            entry.or_insert_with_key(|key| vec![*key as u8]).unwrap(),
+            [42]
+        );
+        assert_eq!(tree.get(42).unwrap(), Some(vec![42]));
+    }
+
+    #[test]
+    fn entries_look_up_once() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..5000 {
+            tree.insert(key * 2, &[1; 100]).unwrap();
+        }
+        let depth = tree.depth().unwrap() as u64;
+        assert!(depth > 2);
+
+        // Like get, the lookup reads the leaf twice, writing it reads the leaf once more but
+        // none of the internal nodes
+        tree.reset_stats();
+        tree.entry(778)
+            .unwrap()
+            .and_modify(|value| value[0] = 2)
+            .unwrap();
+        assert_eq!(tree.stats().unwrap().page_reads, depth + 2);
+        assert_eq!(tree.get(778).unwrap().unwrap()[..2], [2, 1]);
+
+        tree.entry(777).unwrap().or_insert(&[2]).unwrap();
+        let Entry::Occupied(entry) = tree.entry(778).unwrap() else {
+            panic!("Even keys are stored");
+        };
+        entry.remove().unwrap();
+        assert_eq!(tree.get(777).unwrap(), Some(vec![2]));
+        assert_eq!(tree.get(778).unwrap(), None);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn entries_go_through_compression() {
+        use super::super::{Compression, Lz4, NodeConfig};
+
+        let config = NodeConfig {
+            compression: Some(Compression {
+                codec: &Lz4,
+                threshold: 64,
+            }),
+            ..Default::default()
+        };
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut tree = BTree::create_with_config(store, config).unwrap();
+        let value = vec![7; 1000];
+        tree.entry(1).unwrap().or_insert(&value).unwrap();
+        assert_eq!(tree.entry(1).unwrap().or_insert(b"").unwrap(), value);
+        assert_eq!(tree.get(1).unwrap(), Some(value));
+    }
+}
@@ -1,13 +1,200 @@
-#[derive(Debug)]
+use alloc::boxed::Box;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::io;
+
+use thiserror::Error;
+
+use super::IssueKind;
+#[cfg(feature = "std")]
+use crate::cancel::Interrupted;
+
+#[derive(Debug, Error)]
 pub enum BTreeError {
+    #[error("invalid node header: {0}")]
     InvalidHeader(InvalidHeaderError),
+    #[error("corrupt node: {0}")]
+    Corrupt(CorruptionError),
+    #[error("checksum mismatch: stored {stored:#010x}, computed {computed:#010x}")]
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// An error about a node's contents, attributed to the page the node was read from
+    #[error("page {page} at offset {offset} is corrupt")]
+    PageCorrupted {
+        page: u32,
+        /// Byte offset of the page in the store
+        offset: u64,
+        #[source]
+        reason: Box<BTreeError>,
+    },
+    /// A position read from a page points outside of it
+    #[error("{len} bytes at offset {offset} lie outside the {page_size} byte page")]
+    InvalidOffset {
+        offset: usize,
+        len: usize,
+        page_size: usize,
+    },
+    #[error("{0}")]
     SerializationError(String),
+    #[error("expected {expected} bytes of data, got {actual}")]
     UnexpectedData { expected: usize, actual: usize },
+    #[error("{required} bytes needed, {actual} available")]
     NotEnoughSpace { required: usize, actual: usize },
+    #[error("limit exceeded: {0}")]
+    LimitExceeded(LimitError),
+    #[error("key index {index} out of range for {num_keys} keys")]
+    KeyIndexOutOfRange { index: u16, num_keys: u16 },
+    #[error("key {key} at index {index} is out of order")]
+    UnsortedKey { index: u16, key: u64 },
+    /// Bulk load or merge input whose keys aren't strictly ascending
+    #[error("key {key} doesn't sort after {previous}")]
+    UnsortedInput { previous: u64, key: u64 },
+    /// An operation that needs the key to exist
+    #[error("key {key} not found")]
+    KeyNotFound { key: u64 },
+    /// The merge operator rejected the operand for `key`
+    #[error("merging into key {key} failed")]
+    MergeFailed { key: u64 },
+    #[error("no merge operator configured")]
+    NoMergeOperator,
+    /// A node or tree was written with another comparator or merge operator, or none
+    #[error("{plugin} mismatch: expected {expected:?}, found {found:?}")]
+    PluginMismatch {
+        plugin: &'static str,
+        expected: Option<String>,
+        found: Option<String>,
+    },
+    /// Child navigation on a leaf
+    #[error("node is a leaf")]
+    NotInternal,
+    /// Leaf maintenance on an internal node
+    #[error("node is internal")]
+    NotLeaf,
+    /// A u64 key used on a node keyed by byte strings or the other way around
+    #[error("key type doesn't match the node's")]
+    KeyTypeMismatch,
+    /// No tree of this name in the catalog
+    #[error("no tree named {0:?}")]
+    UnknownTree(String),
+    /// No savepoint of this name in the transaction
+    #[error("no savepoint named {0:?}")]
+    UnknownSavepoint(String),
+    /// A tree of this name is in the catalog already
+    #[error("a tree named {0:?} exists already")]
+    TreeExists(String),
+    /// The tree was created with other key or value types than it was opened with
+    #[error("schema mismatch: expected {expected:#x}, found {found:#x}")]
+    SchemaMismatch { expected: u64, found: u64 },
+    /// A page of the file is neither free nor part of the tree, like pages of other trees
+    #[error("page {0} belongs to another tree")]
+    ForeignPage(u32),
+    /// Pages have to be a power of two between MIN_PAGE_SIZE and MAX_PAGE_SIZE bytes
+    #[error("invalid page size {0}")]
+    InvalidPageSize(usize),
+    /// An allocation of `requested` bytes failed, the node is left as it was
+    #[error("out of memory allocating {requested} bytes")]
+    OutOfMemory { requested: usize },
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A key an optimistic transaction read was changed by a commit before its own
+    #[error("key {key} changed since it was read")]
+    Conflict { key: u64 },
+    #[error("timed out")]
+    TimedOut,
+    #[error("cancelled")]
+    Cancelled,
 }
 
-#[derive(Debug)]
+#[cfg(feature = "pager")]
+impl BTreeError {
+    /// Attributes errors about a node's contents to page `page`, which starts at `offset`
+    pub(crate) fn in_page(self, page: u32, offset: u64) -> Self {
+        match self {
+            BTreeError::InvalidHeader(_)
+            | BTreeError::Corrupt(_)
+            | BTreeError::ChecksumMismatch { .. }
+            | BTreeError::InvalidOffset { .. }
+            | BTreeError::KeyIndexOutOfRange { .. }
+            | BTreeError::UnsortedKey { .. } => BTreeError::PageCorrupted {
+                page,
+                offset,
+                reason: Box::new(self),
+            },
+            other => other,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Interrupted> for BTreeError {
+    fn from(interrupted: Interrupted) -> Self {
+        match interrupted {
+            Interrupted::TimedOut => BTreeError::TimedOut,
+            Interrupted::Cancelled => BTreeError::Cancelled,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
 pub enum InvalidHeaderError {
+    #[error("unknown node type {0}")]
     InvalidNodeType(u8),
+    #[error("expected {expected} bytes, got {actual}")]
     UnexpectedData { expected: usize, actual: usize },
 }
+
+/// Structural damage found while reading a node, as opposed to a key that is just absent
+#[derive(Debug, PartialEq, Error)]
+pub enum CorruptionError {
+    /// free_start and free_end don't delimit a gap between the header and the content end
+    #[error("free space {free_start}..{free_end} is out of range")]
+    FreeSpaceOutOfRange { free_start: u16, free_end: u16 },
+    /// The slot array doesn't hold exactly num_keys slots
+    #[error("{num_keys} keys don't fill the slot array ending at {free_start}")]
+    KeyCountMismatch { num_keys: u16, free_start: u16 },
+    /// The key at `index` doesn't sort after the one before it
+    #[error("key {index} is out of order")]
+    KeysNotSorted { index: u16 },
+    /// The slot of a key lies beyond the slot array
+    #[error("slot {index} lies beyond the slot array ending at {free_start}")]
+    SlotOutOfBounds { index: u16, free_start: u16 },
+    /// A value doesn't lie within the content area between free_end and the page end
+    #[error("value {index} of {len} bytes at offset {offset} lies outside the content area")]
+    ValueOutOfBounds { index: u16, offset: u16, len: u16 },
+    /// A freeblock lies outside the content area or breaks the sorted chain
+    #[error("freeblock at offset {offset} is out of bounds")]
+    FreeblockOutOfBounds { offset: u16 },
+    /// Two values, cells or freeblocks starting at these offsets share bytes
+    #[error("cells at offsets {first} and {second} overlap")]
+    OverlappingCells { first: u16, second: u16 },
+    /// More bytes are counted as fragmented than the content area leaves unclaimed
+    #[error("{fragmented} fragmented bytes but only {unclaimed} unclaimed")]
+    FragmentedBytesOutOfRange { fragmented: u8, unclaimed: u16 },
+    /// A value of a tree with a codec isn't a valid stored value
+    #[error("value of key {key} can't be decompressed")]
+    CompressedValue { key: u64 },
+    /// The page failed the same check validate_file runs
+    #[error("page check failed: {0:?}")]
+    Page(IssueKind),
+    /// A key lies outside the range the separators above its node route to the node
+    #[error("key {key} lies outside the separators above its node")]
+    KeyOutsideSeparators { key: u64 },
+    /// A leaf isn't as deep as the first one, counting the root as 1
+    #[error("leaf at depth {depth}, the first one is at {expected}")]
+    UnevenLeaves { depth: usize, expected: usize },
+    /// The page is a child of more than one node, or of a node below it
+    #[error("page is linked from more than one node")]
+    SharedPage,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum LimitError {
+    #[error("more than {limit} keys")]
+    MaxKeys { limit: u32 },
+    #[error("value of {actual} bytes exceeds {limit}")]
+    MaxValueSize { limit: usize, actual: usize },
+    #[error("{actual} pages exceed {limit}")]
+    MaxPages { limit: usize, actual: usize },
+    #[error("deeper than {limit} levels")]
+    MaxDepth { limit: usize },
+}
@@ -0,0 +1,175 @@
+/*
+Export of key ranges to the Arrow ecosystem. A Projection turns the entries of a range into
+Arrow columns, KeyValue being the one that keeps them as they are stored: the encoded key as
+a UInt64 column and the value as a Binary column. The whole range ends up in a single record
+batch, which export_parquet stores as a Parquet file.
+*/
+
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use arrow_array::builder::{BinaryBuilder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::BTree;
+use crate::page::PageStore;
+
+/// Turns entries into the columns of a record batch
+pub trait Projection {
+    /// Fields of the columns `finish` returns
+    fn fields(&self) -> Vec<Field>;
+
+    /// Adds a row for an entry, whose key is in its encoded form
+    fn append(&mut self, key: u64, value: &[u8]) -> Result<(), BTreeError>;
+
+    /// Columns of every row appended so far, in the order of `fields`
+    fn finish(&mut self) -> Vec<ArrayRef>;
+}
+
+/// Projects entries to a `key` and a `value` column
+#[derive(Debug, Default)]
+pub struct KeyValue {
+    keys: UInt64Builder,
+    values: BinaryBuilder,
+}
+
+impl Projection for KeyValue {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("key", DataType::UInt64, false),
+            Field::new("value", DataType::Binary, false),
+        ]
+    }
+
+    fn append(&mut self, key: u64, value: &[u8]) -> Result<(), BTreeError> {
+        self.keys.append_value(key);
+        self.values.append_value(value);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<ArrayRef> {
+        vec![Arc::new(self.keys.finish()), Arc::new(self.values.finish())]
+    }
+}
+
+fn export_error(err: impl ToString) -> BTreeError {
+    BTreeError::SerializationError(err.to_string())
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// Collects the entries within `range` into a record batch with the columns of
+    /// `projection`
+    pub fn export_batch<P: Projection>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        mut projection: P,
+    ) -> Result<RecordBatch, BTreeError> {
+        self.for_each_in_range(range, |key, value| projection.append(key, value))?;
+        let schema = Arc::new(Schema::new(projection.fields()));
+        RecordBatch::try_new(schema, projection.finish()).map_err(export_error)
+    }
+
+    /// Writes the entries within `range` to `writer` as a Parquet file. Returns the number
+    /// of rows written.
+    pub fn export_parquet<P: Projection, W: Write + Send>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        projection: P,
+        writer: W,
+    ) -> Result<usize, BTreeError> {
+        let batch = self.export_batch(range, projection)?;
+        let mut writer =
+            ArrowWriter::try_new(writer, batch.schema(), None).map_err(export_error)?;
+        writer.write(&batch).map_err(export_error)?;
+        writer.close().map_err(export_error)?;
+        Ok(batch.num_rows())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use arrow_array::builder::UInt32Builder;
+    use arrow_array::{Array, BinaryArray, UInt32Array, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use pretty_assertions::assert_eq;
+
+    /// Keeps the length of each value instead of the value
+    #[derive(Default)]
+    struct ValueLen(UInt32Builder);
+
+    impl Projection for ValueLen {
+        fn fields(&self) -> Vec<Field> {
+            vec![Field::new("len", DataType::UInt32, false)]
+        }
+
+        fn append(&mut self, _key: u64, value: &[u8]) -> Result<(), BTreeError> {
+            self.0.append_value(value.len() as u32);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Vec<ArrayRef> {
+            vec![Arc::new(self.0.finish())]
+        }
+    }
+
+    #[test]
+    fn ranges_export_to_batches_and_parquet() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, &vec![key as u8; (key % 30) as usize])
+                .unwrap();
+        }
+
+        let batch = tree.export_batch(100..1500, KeyValue::default()).unwrap();
+        assert_eq!(batch.num_rows(), 1400);
+        let keys = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        let values = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .unwrap();
+        assert_eq!(keys.value(0), 100);
+        assert_eq!(keys.value(1399), 1499);
+        assert_eq!(values.value(17), vec![117u8; 27].as_slice());
+
+        let batch = tree.export_batch(..=9, ValueLen::default()).unwrap();
+        let lens = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+        assert_eq!(lens.values().to_vec(), (0..10).collect::<Vec<u32>>());
+
+        let mut file = tempfile::tempfile().unwrap();
+        let rows = tree
+            .export_parquet(1990.., KeyValue::default(), &mut file)
+            .unwrap();
+        assert_eq!(rows, 10);
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 10);
+        let keys = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(keys.value(9), 1999);
+        assert!(keys.nulls().is_none());
+    }
+}
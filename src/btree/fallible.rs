@@ -0,0 +1,94 @@
+/*
+Fallible allocations. Buffers whose size depends on the page or on stored values are
+reserved with try_reserve, so running out of memory fails the operation with OutOfMemory
+instead of aborting the process. Operations allocate before they change anything, so a
+failed allocation leaves the node as it was.
+*/
+
+use alloc::vec::Vec;
+
+use super::errors::BTreeError;
+
+/// An empty vector with room for `capacity` elements
+pub(crate) fn try_with_capacity<T>(capacity: usize) -> Result<Vec<T>, BTreeError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)
+        .map_err(|_| BTreeError::OutOfMemory {
+            requested: capacity.saturating_mul(size_of::<T>()),
+        })?;
+    Ok(vec)
+}
+
+pub(crate) fn try_zeroed(len: usize) -> Result<Vec<u8>, BTreeError> {
+    let mut vec = try_with_capacity(len)?;
+    vec.resize(len, 0);
+    Ok(vec)
+}
+
+pub(crate) fn try_to_vec(bytes: &[u8]) -> Result<Vec<u8>, BTreeError> {
+    let mut vec = try_with_capacity(bytes.len())?;
+    vec.extend_from_slice(bytes);
+    Ok(vec)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Fails allocations larger than the limit set by the current thread
+    struct FailingAllocator;
+
+    thread_local! {
+        static LIMIT: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    unsafe impl GlobalAlloc for FailingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if layout.size() > LIMIT.try_with(Cell::get).unwrap_or(usize::MAX) {
+                return std::ptr::null_mut();
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: FailingAllocator = FailingAllocator;
+
+    /// Fails allocations of more than `limit` bytes on this thread until dropped
+    pub(crate) struct AllocationLimit;
+
+    impl AllocationLimit {
+        pub(crate) fn new(limit: usize) -> Self {
+            LIMIT.set(limit);
+            Self
+        }
+    }
+
+    impl Drop for AllocationLimit {
+        fn drop(&mut self) {
+            LIMIT.set(usize::MAX);
+        }
+    }
+
+    #[test]
+    fn failed_reservations_are_errors() {
+        let limit = AllocationLimit::new(100);
+        assert_eq!(try_to_vec(&[7; 100]).unwrap(), [7; 100]);
+        assert!(matches!(
+            try_zeroed(101),
+            Err(BTreeError::OutOfMemory { requested: 101 })
+        ));
+        assert!(matches!(
+            try_with_capacity::<u64>(usize::MAX / 4),
+            Err(BTreeError::OutOfMemory { .. })
+        ));
+        drop(limit);
+        assert_eq!(try_zeroed(101).unwrap().len(), 101);
+    }
+}
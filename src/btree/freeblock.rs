@@ -1,3 +1,5 @@
+use alloc::string::ToString;
+
 use super::errors::BTreeError;
 use super::Node;
 
@@ -34,6 +36,7 @@ impl Freeblock {
 
 impl<'a> Node<'a> {
     pub fn read_freeblock(&self, offset: usize) -> Result<&Freeblock, BTreeError> {
+        self.check_freeblock_offset(offset)?;
         let freeblock_bytes: &[u8; FREEBLOCK_SIZE as usize] = self
             .get_page_slice(offset, FREEBLOCK_SIZE.into())
             .try_into()
@@ -42,6 +45,7 @@ impl<'a> Node<'a> {
     }
 
     pub fn mut_freeblock(&mut self, offset: usize) -> Result<&mut Freeblock, BTreeError> {
+        self.check_freeblock_offset(offset)?;
         let freeblock_bytes: &mut [u8; FREEBLOCK_SIZE as usize] = self
             .get_mut_page_slice(offset, FREEBLOCK_SIZE.into())
             .try_into()
@@ -49,6 +53,18 @@ impl<'a> Node<'a> {
         Freeblock::intepret_mut_from_bytes(freeblock_bytes)
     }
 
+    /// Freeblock offsets come from the page, a damaged one mustn't reach past its end
+    fn check_freeblock_offset(&self, offset: usize) -> Result<(), BTreeError> {
+        if offset + FREEBLOCK_SIZE as usize > self.page.len() {
+            return Err(BTreeError::InvalidOffset {
+                offset,
+                len: FREEBLOCK_SIZE.into(),
+                page_size: self.page.len(),
+            });
+        }
+        Ok(())
+    }
+
     pub fn write_freeblock(&mut self, offset: usize, next_freeblock: u16, size: u16) {
         debug_assert!(
             offset >= self.read_header().unwrap().free_start.get().into(),
@@ -1,4 +1,10 @@
+use alloc::string::ToString;
+
+use super::bytekeys::BYTE_KEY_SLOT_SIZE;
+use super::checksum::CHECKSUM_SIZE;
 use super::errors::BTreeError;
+use super::key::KEY_SIZE;
+use super::packed::{MAX_KEY_PREFIX, PACKED_KEY_SIZE};
 use super::Node;
 use zerocopy::little_endian::{U16, U32};
 use zerocopy::{
@@ -21,9 +27,32 @@ pub struct Header {
     pub free_end: U16,
     pub first_freeblock: U16,
     pub fragmented_bytes: u8,
+    /// Child holding the keys above the last separator of internal nodes. In leaves the
+    /// right sibling of a split not posted to the parent yet, 0 otherwise.
     pub rightmost_child_page: U32,
+    pub flags: u8,
+    /// Width of every value when the node uses the packed layout
+    pub packed_width: u8,
 }
 
+/// Keys and fixed width values are stored inline in the slot array, without offsets
+pub const FLAG_PACKED: u8 = 1 << 0;
+/// Keys are byte strings stored in the cells, the slot array only holds cell offsets
+pub const FLAG_BYTE_KEYS: u8 = 1 << 1;
+/// The page ends in a checksum of everything before it
+pub const FLAG_CHECKSUM: u8 = 1 << 2;
+/// Byte keys are ordered by a comparator instead of bytewise
+pub const FLAG_CUSTOM_ORDER: u8 = 1 << 3;
+/// The last RESERVED_TAIL bytes of the page belong to the store, see
+/// PageStore::reserves_tail
+pub const FLAG_RESERVED_TAIL: u8 = 1 << 4;
+/// The top three bits of flags hold the length of the key prefix of packed nodes
+const KEY_PREFIX_SHIFT: u8 = 5;
+const KEY_PREFIX_MASK: u8 = 0b111 << KEY_PREFIX_SHIFT;
+
+/// Bytes at the end of a page left to the store, enough for a nonce and an AEAD tag
+pub const RESERVED_TAIL: u16 = 24;
+
 pub const HEADER_SIZE: u16 = {
     if size_of::<Header>() > u16::MAX as usize {
         panic!("Header size does not fit into u16");
@@ -49,6 +78,76 @@ impl Header {
             first_freeblock: first_freeblock.into(),
             fragmented_bytes,
             rightmost_child_page: rightmost_child_page.into(),
+            flags: 0,
+            packed_width: 0,
+        }
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.flags & FLAG_PACKED != 0
+    }
+
+    pub fn has_byte_keys(&self) -> bool {
+        self.flags & FLAG_BYTE_KEYS != 0
+    }
+
+    pub fn has_custom_order(&self) -> bool {
+        self.flags & FLAG_CUSTOM_ORDER != 0
+    }
+
+    pub fn has_checksum(&self) -> bool {
+        self.flags & FLAG_CHECKSUM != 0
+    }
+
+    pub fn has_reserved_tail(&self) -> bool {
+        self.flags & FLAG_RESERVED_TAIL != 0
+    }
+
+    /// End of the content area in a page of `page_size` bytes, the page end unless a
+    /// checksum or a reserved tail follows it. The checksum comes first.
+    pub fn content_end(&self, page_size: u16) -> u16 {
+        let mut end = page_size;
+        if self.has_reserved_tail() {
+            end -= RESERVED_TAIL;
+        }
+        if self.has_checksum() {
+            end -= CHECKSUM_SIZE;
+        }
+        end
+    }
+
+    /// Bytes every key of a packed node shares. They are stored once, right behind the
+    /// header, and left out of the slots.
+    pub fn key_prefix_len(&self) -> u16 {
+        if !self.is_packed() {
+            return 0;
+        }
+        ((self.flags & KEY_PREFIX_MASK) >> KEY_PREFIX_SHIFT).into()
+    }
+
+    pub fn set_key_prefix_len(&mut self, len: u8) {
+        debug_assert!(len <= MAX_KEY_PREFIX);
+        self.flags = (self.flags & !KEY_PREFIX_MASK) | (len << KEY_PREFIX_SHIFT);
+    }
+
+    /// Whether flags hold a key prefix length, which only packed nodes may have
+    pub fn has_key_prefix_bits(&self) -> bool {
+        self.flags & KEY_PREFIX_MASK != 0
+    }
+
+    /// Start of the slot array, behind the key prefix if there is one
+    pub fn slots_start(&self) -> u16 {
+        HEADER_SIZE + self.key_prefix_len()
+    }
+
+    /// Size of one entry in the slot array
+    pub fn slot_size(&self) -> u16 {
+        if self.is_packed() {
+            PACKED_KEY_SIZE - self.key_prefix_len() + self.packed_width as u16
+        } else if self.has_byte_keys() {
+            BYTE_KEY_SLOT_SIZE
+        } else {
+            KEY_SIZE
         }
     }
     pub fn intepret_from_bytes(bytes: &[u8; HEADER_SIZE as usize]) -> Result<&Self, BTreeError> {
@@ -82,8 +181,6 @@ impl<'a> Node<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::btree::freeblock::FREEBLOCK_SIZE;
-
     use super::super::header::HEADER_SIZE;
     use super::super::{Node, PAGE_SIZE};
     use super::*;
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+
+use super::Node;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafHeat {
+    pub page_id: u32,
+    /// Keys the leaf held when it was last accessed
+    pub keys: Option<RangeInclusive<u64>>,
+    pub accesses: u64,
+}
+
+/// Approximate access counts per leaf. Counters only ever get halved by `decay`, so
+/// calling it periodically makes the heatmap favour recent accesses.
+#[derive(Default)]
+pub struct AccessTracker {
+    leaves: Mutex<BTreeMap<u32, LeafHeat>>,
+}
+
+impl AccessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, page_id: u32, keys: Option<RangeInclusive<u64>>) {
+        let mut leaves = self.leaves.lock().expect("AccessTracker lock poisoned");
+        let leaf = leaves.entry(page_id).or_insert(LeafHeat {
+            page_id,
+            keys: None,
+            accesses: 0,
+        });
+        leaf.keys = keys;
+        leaf.accesses += 1;
+    }
+
+    pub fn accesses(&self, page_id: u32) -> u64 {
+        let leaves = self.leaves.lock().expect("AccessTracker lock poisoned");
+        leaves.get(&page_id).map_or(0, |leaf| leaf.accesses)
+    }
+
+    pub fn decay(&self) {
+        let mut leaves = self.leaves.lock().expect("AccessTracker lock poisoned");
+        leaves.retain(|_, leaf| {
+            leaf.accesses /= 2;
+            leaf.accesses > 0
+        });
+    }
+
+    /// Accessed leaves ordered by their key range, empty leaves last
+    pub fn heatmap(&self) -> Vec<LeafHeat> {
+        let leaves = self.leaves.lock().expect("AccessTracker lock poisoned");
+        let mut heatmap: Vec<_> = leaves.values().cloned().collect();
+        heatmap.sort_by_key(|leaf| leaf.keys.as_ref().map_or((1, 0), |keys| (0, *keys.start())));
+        heatmap
+    }
+
+    /// Writes the heatmap as csv with the columns page_id, first_key, last_key, accesses
+    pub fn export_csv<W: Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        writeln!(writer, "page_id,first_key,last_key,accesses")?;
+        for leaf in self.heatmap() {
+            let (first, last) = leaf.keys.map_or((String::new(), String::new()), |keys| {
+                (keys.start().to_string(), keys.end().to_string())
+            });
+            writeln!(writer, "{},{first},{last},{}", leaf.page_id, leaf.accesses)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Counts every lookup and write on this node as an access to leaf `page_id`
+    pub fn with_access_tracker(mut self, tracker: &'a AccessTracker, page_id: u32) -> Self {
+        self.access_tracker = Some((tracker, page_id));
+        self
+    }
+
+    pub(crate) fn record_access(&self) {
+        let Some((tracker, page_id)) = self.access_tracker else {
+            return;
+        };
+        let num_keys = self.read_header().map_or(0, |header| header.num_keys.get());
+        let keys = match num_keys {
+            0 => None,
+            _ => match (self.key_at(0), self.key_at(num_keys - 1)) {
+                (Ok(first), Ok(last)) => Some(first..=last),
+                _ => None,
+            },
+        };
+        tracker.record(page_id, keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn heatmap_orders_leaves_by_keys() {
+        let tracker = AccessTracker::new();
+        let mut hot_page = [0u8; PAGE_SIZE as usize];
+        let mut cold_page = [0u8; PAGE_SIZE as usize];
+        let mut hot = Node::new(&mut hot_page)
+            .unwrap()
+            .with_access_tracker(&tracker, 7);
+        let mut cold = Node::new(&mut cold_page)
+            .unwrap()
+            .with_access_tracker(&tracker, 3);
+
+        cold.insert(100, b"cold").unwrap();
+        hot.insert(1, b"one").unwrap();
+        hot.insert(5, b"five").unwrap();
+        for _ in 0..10 {
+            hot.get(5).unwrap();
+        }
+
+        assert_eq!(
+            tracker.heatmap(),
+            vec![
+                LeafHeat {
+                    page_id: 7,
+                    keys: Some(1..=5),
+                    accesses: 12
+                },
+                LeafHeat {
+                    page_id: 3,
+                    keys: Some(100..=100),
+                    accesses: 1
+                },
+            ]
+        );
+
+        let mut csv = Vec::new();
+        tracker.export_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "page_id,first_key,last_key,accesses\n7,1,5,12\n3,100,100,1\n"
+        );
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn tree_reads_show_in_the_heatmap() {
+        use super::super::{BTree, ConcurrentTree};
+        use crate::page::MemoryStore;
+        use std::sync::Arc;
+
+        let tracker = Arc::new(AccessTracker::new());
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into()))
+            .unwrap()
+            .with_access_tracker(Arc::clone(&tracker));
+        for key in 0..1000 {
+            tree.insert(key, b"value").unwrap();
+        }
+        let inserts: u64 = tracker.heatmap().iter().map(|leaf| leaf.accesses).sum();
+        assert_eq!(inserts, 1000);
+
+        for _ in 0..1000 {
+            tree.get(999).unwrap();
+        }
+        let heatmap = tracker.heatmap();
+        assert!(heatmap.len() > 1);
+        assert!(heatmap
+            .windows(2)
+            .all(|pair| pair[0].keys.as_ref().unwrap().end()
+                < pair[1].keys.as_ref().unwrap().start()));
+        let hottest = heatmap.iter().max_by_key(|leaf| leaf.accesses).unwrap();
+        assert_eq!(hottest.keys.as_ref().map(|keys| *keys.end()), Some(999));
+
+        let tree = ConcurrentTree::new(tree);
+        let accesses = tracker.accesses(hottest.page_id);
+        tree.get(999).unwrap();
+        tree.delete(999).unwrap();
+        assert_eq!(tracker.accesses(hottest.page_id), accesses + 2);
+    }
+
+    #[test]
+    fn decay_drops_cold_leaves() {
+        let tracker = AccessTracker::new();
+        tracker.record(1, None);
+        for _ in 0..4 {
+            tracker.record(2, Some(0..=0));
+        }
+
+        tracker.decay();
+        assert_eq!(tracker.accesses(1), 0);
+        assert_eq!(tracker.accesses(2), 2);
+        assert_eq!(tracker.heatmap().len(), 1);
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "pager")]
+use std::mem::size_of;
+
+#[cfg(feature = "pager")]
+use super::codec::KeyCodec;
+#[cfg(feature = "pager")]
+use super::errors::BTreeError;
+use super::snapshot::NodeSnapshot;
+#[cfg(feature = "pager")]
+use super::snapshot::TreeSnapshot;
+#[cfg(feature = "pager")]
+use super::BTree;
+use super::Node;
+use crate::cancel::{Budget, Interrupted};
+#[cfg(feature = "pager")]
+use crate::page::SharedRead;
+
+/// Limits how much work a single GC run does, so collecting a long backlog of
+/// versions doesn't stall the commit that triggered it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcPacing {
+    /// Most versions reclaimed per run, `None` reclaims everything collectable
+    pub max_versions_per_run: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub runs: u64,
+    pub reclaimed_pages: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// A version a History retains, sized for the GC stats
+pub trait Version {
+    /// Pages the version was copied from
+    fn pages(&self) -> u64;
+    fn bytes(&self) -> u64;
+}
+
+impl Version for NodeSnapshot {
+    fn pages(&self) -> u64 {
+        1
+    }
+
+    fn bytes(&self) -> u64 {
+        self.page_size() as u64
+    }
+}
+
+#[cfg(feature = "pager")]
+impl<K: KeyCodec> Version for TreeSnapshot<K> {
+    fn pages(&self) -> u64 {
+        self.leaves() as u64
+    }
+
+    fn bytes(&self) -> u64 {
+        self.iter()
+            .map(|(_, value)| (size_of::<u64>() + value.len()) as u64)
+            .sum()
+    }
+}
+
+/// Committed versions of a node
+pub type NodeHistory = History<NodeSnapshot>;
+/// Committed versions of a whole tree
+#[cfg(feature = "pager")]
+pub type TreeHistory<K = u64> = History<TreeSnapshot<K>>;
+
+/// Committed versions, addressed by generation. Versions older than the newest `retention`
+/// generations are garbage collected unless a reader still pins them.
+pub struct History<V> {
+    retention: usize,
+    next_generation: u64,
+    versions: VecDeque<(u64, V)>,
+    /// Pinned generations and how many readers pin them
+    readers: BTreeMap<u64, usize>,
+    pacing: GcPacing,
+    stats: GcStats,
+}
+
+impl<V: Version> History<V> {
+    pub fn new(retention: usize) -> Self {
+        Self::with_pacing(retention, GcPacing::default())
+    }
+
+    pub fn with_pacing(retention: usize, pacing: GcPacing) -> Self {
+        Self {
+            retention,
+            next_generation: 0,
+            versions: VecDeque::new(),
+            readers: BTreeMap::new(),
+            pacing,
+            stats: GcStats::default(),
+        }
+    }
+
+    /// Retains `version` as a new generation and returns it
+    fn retain(&mut self, version: V) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+
+        self.versions.push_back((generation, version));
+        self.gc();
+        generation
+    }
+
+    /// Keeps `generation` from being collected until it is unpinned again. Returns
+    /// false if the generation isn't retained (anymore).
+    pub fn pin(&mut self, generation: u64) -> bool {
+        if self.open_at(generation).is_none() {
+            return false;
+        }
+        *self.readers.entry(generation).or_default() += 1;
+        true
+    }
+
+    pub fn unpin(&mut self, generation: u64) {
+        if let Some(count) = self.readers.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                self.readers.remove(&generation);
+            }
+        }
+    }
+
+    pub fn oldest_reader(&self) -> Option<u64> {
+        self.readers.keys().next().copied()
+    }
+
+    /// Number of versions that are outside the retention window and not pinned by a reader
+    pub fn collectable(&self) -> usize {
+        let outside_window = self.versions.len().saturating_sub(self.retention);
+        let Some(oldest_reader) = self.oldest_reader() else {
+            return outside_window;
+        };
+        self.versions
+            .iter()
+            .take(outside_window)
+            .take_while(|(generation, _)| *generation < oldest_reader)
+            .count()
+    }
+
+    /// Reclaims collectable versions, oldest first and at most as many as the pacing
+    /// allows. Returns the number of reclaimed versions.
+    pub fn gc(&mut self) -> usize {
+        self.gc_within(&Budget::unlimited())
+            .expect("Unlimited budgets never interrupt")
+    }
+
+    /// Like `gc`, but stops once `budget` runs out. Versions reclaimed up to that point
+    /// stay reclaimed.
+    pub fn gc_within(&mut self, budget: &Budget) -> Result<usize, Interrupted> {
+        let max_versions = self.pacing.max_versions_per_run.unwrap_or(usize::MAX);
+        let reclaim = self.collectable().min(max_versions);
+
+        self.stats.runs += 1;
+        for _ in 0..reclaim {
+            budget.check()?;
+            let (_, version) = self.versions.pop_front().expect("Version is collectable");
+            self.stats.reclaimed_pages += version.pages();
+            self.stats.reclaimed_bytes += version.bytes();
+        }
+        Ok(reclaim)
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// The version committed in `generation`, if it is still retained
+    pub fn open_at(&self, generation: u64) -> Option<&V> {
+        let oldest = self.versions.front()?.0;
+        let idx = generation.checked_sub(oldest)?;
+        self.versions.get(idx as usize).map(|(_, version)| version)
+    }
+
+    /// Generations that can currently be opened, oldest first
+    pub fn generations(&self) -> impl Iterator<Item = u64> + '_ {
+        self.versions.iter().map(|(generation, _)| *generation)
+    }
+
+    pub fn latest_generation(&self) -> Option<u64> {
+        self.versions.back().map(|(generation, _)| *generation)
+    }
+}
+
+impl NodeHistory {
+    /// Retains the current state of `node` as a new generation and returns it
+    pub fn commit(&mut self, node: &Node) -> u64 {
+        self.retain(node.iter_snapshot())
+    }
+}
+
+#[cfg(feature = "pager")]
+impl<K: KeyCodec> TreeHistory<K> {
+    /// Retains a copy of `tree` as a new generation and returns it, see
+    /// BTree::iter_snapshot. The copy holds the changes a WalStore hasn't committed yet too,
+    /// commit the tree first.
+    pub fn commit<S: SharedRead>(&mut self, tree: &BTree<S, K>) -> Result<u64, BTreeError> {
+        Ok(self.retain(tree.iter_snapshot()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::cancel::CancelToken;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn open_historical_generations() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let mut history = NodeHistory::new(3);
+
+        node.insert(1, b"one").unwrap();
+        let first = history.commit(&node);
+        node.insert(2, b"two").unwrap();
+        let second = history.commit(&node);
+        node.delete(1).unwrap();
+        let third = history.commit(&node);
+
+        assert_eq!(history.generations().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(history.latest_generation(), Some(third));
+
+        let at_first = history.open_at(first).unwrap();
+        assert_eq!(at_first.get(1), Some(b"one".as_slice()));
+        assert_eq!(at_first.get(2), None);
+
+        let at_second = history.open_at(second).unwrap();
+        assert_eq!(at_second.len(), 2);
+
+        let at_third = history.open_at(third).unwrap();
+        assert_eq!(at_third.get(1), None);
+        assert_eq!(at_third.get(2), Some(b"two".as_slice()));
+        assert!(history.open_at(third + 1).is_none());
+    }
+
+    #[test]
+    fn generations_outside_retention_are_dropped() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let mut history = NodeHistory::new(2);
+
+        for key in 0..5 {
+            node.insert(key, b"value").unwrap();
+            history.commit(&node);
+        }
+
+        assert_eq!(history.generations().collect::<Vec<_>>(), vec![3, 4]);
+        assert!(history.open_at(2).is_none());
+        assert_eq!(history.open_at(3).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn readers_hold_back_gc() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let mut history = NodeHistory::new(1);
+
+        let first = history.commit(&node);
+        assert!(history.pin(first));
+        for key in 0..3 {
+            node.insert(key, b"value").unwrap();
+            history.commit(&node);
+        }
+
+        assert_eq!(history.oldest_reader(), Some(first));
+        assert_eq!(history.collectable(), 0);
+        assert!(history.open_at(first).unwrap().is_empty());
+
+        history.unpin(first);
+        assert_eq!(history.collectable(), 3);
+        assert_eq!(history.gc(), 3);
+        assert_eq!(history.generations().collect::<Vec<_>>(), vec![3]);
+        assert!(!history.pin(first));
+
+        let stats = history.gc_stats();
+        assert_eq!(stats.reclaimed_pages, 3);
+        assert_eq!(stats.reclaimed_bytes, 3 * PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn gc_is_paced() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let pacing = GcPacing {
+            max_versions_per_run: Some(1),
+        };
+        let mut history = NodeHistory::with_pacing(1, pacing);
+
+        let first = history.commit(&node);
+        history.pin(first);
+        for key in 0..4 {
+            node.insert(key, b"value").unwrap();
+            history.commit(&node);
+        }
+        history.unpin(first);
+
+        assert_eq!(history.collectable(), 4);
+        assert_eq!(history.gc(), 1);
+        assert_eq!(history.gc(), 1);
+        assert_eq!(history.collectable(), 2);
+        assert_eq!(history.gc_stats().reclaimed_pages, 2);
+    }
+
+    #[test]
+    fn cancelled_gc_keeps_remaining_versions() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let node = Node::new(&mut page).unwrap();
+        let mut history = NodeHistory::new(1);
+
+        let first = history.commit(&node);
+        history.pin(first);
+        for _ in 0..3 {
+            history.commit(&node);
+        }
+        history.unpin(first);
+        assert_eq!(history.collectable(), 3);
+
+        let token = CancelToken::new();
+        token.cancel();
+        let budget = Budget::unlimited().cancel_token(token);
+        assert_eq!(history.gc_within(&budget), Err(Interrupted::Cancelled));
+        assert_eq!(history.collectable(), 3);
+        assert_eq!(history.gc(), 3);
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn open_historical_trees() {
+        use crate::page::MemoryStore;
+
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        let mut history = TreeHistory::new(2);
+        for key in 0..1000 {
+            tree.insert(key, b"first").unwrap();
+        }
+        let first = history.commit(&tree).unwrap();
+        for key in 500..1500 {
+            tree.insert(key, b"second").unwrap();
+        }
+        let second = history.commit(&tree).unwrap();
+        tree.delete(0).unwrap();
+        history.commit(&tree).unwrap();
+
+        assert!(history.open_at(first).is_none());
+        let at_second = history.open_at(second).unwrap();
+        assert_eq!(at_second.len(), 1500);
+        assert_eq!(at_second.get(0), Some(b"first".as_slice()));
+        assert_eq!(at_second.get(1000), Some(b"second".as_slice()));
+
+        let stats = history.gc_stats();
+        assert!(stats.reclaimed_pages > 1);
+        assert_eq!(stats.reclaimed_bytes, 1000 * (8 + 5));
+    }
+}
@@ -0,0 +1,204 @@
+/*
+Import of SQLite tables. Every row becomes an entry keyed by its rowid, rows are read in
+rowid order so they can be bulk loaded. The value is the row's columns in
+table order, each a type tag followed by its data. Numbers are big endian.
+--------------------------------------------------------------------------
+| null: 0 | integer: 1, i64 | real: 2, f64 | text: 3, u32 len, utf-8 bytes | blob: 4, u32 len, bytes |
+--------------------------------------------------------------------------
+*/
+
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Rows};
+
+use super::errors::BTreeError;
+use super::BTree;
+use crate::page::PageStore;
+
+const NULL: u8 = 0;
+const INTEGER: u8 = 1;
+const REAL: u8 = 2;
+const TEXT: u8 = 3;
+const BLOB: u8 = 4;
+
+/// A column of a row read back from its serialized form
+#[derive(Debug, Clone, PartialEq)]
+pub enum Column {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+fn sqlite_error(err: rusqlite::Error) -> BTreeError {
+    BTreeError::SerializationError(err.to_string())
+}
+
+fn encode_column(value: ValueRef, row: &mut Vec<u8>) -> Result<(), BTreeError> {
+    let mut with_len = |tag: u8, bytes: &[u8]| {
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| BTreeError::SerializationError("Column too large".to_owned()))?;
+        row.push(tag);
+        row.extend_from_slice(&len.to_be_bytes());
+        row.extend_from_slice(bytes);
+        Ok(())
+    };
+    match value {
+        ValueRef::Text(text) => with_len(TEXT, text),
+        ValueRef::Blob(blob) => with_len(BLOB, blob),
+        ValueRef::Null => {
+            row.push(NULL);
+            Ok(())
+        }
+        ValueRef::Integer(int) => {
+            row.push(INTEGER);
+            row.extend_from_slice(&int.to_be_bytes());
+            Ok(())
+        }
+        ValueRef::Real(real) => {
+            row.push(REAL);
+            row.extend_from_slice(&real.to_be_bytes());
+            Ok(())
+        }
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], BTreeError> {
+    let (head, rest) = data
+        .split_at_checked(len)
+        .ok_or_else(|| BTreeError::SerializationError("Truncated row".to_owned()))?;
+    *data = rest;
+    Ok(head)
+}
+
+/// Reads back the columns of a row stored by `import_sqlite`
+pub fn decode_row(mut data: &[u8]) -> Result<Vec<Column>, BTreeError> {
+    let mut columns = Vec::new();
+    while let Some((&tag, rest)) = data.split_first() {
+        data = rest;
+        let column = match tag {
+            NULL => Column::Null,
+            INTEGER => Column::Integer(i64::from_be_bytes(
+                take(&mut data, 8)?.try_into().expect("Slice is 8 bytes"),
+            )),
+            REAL => Column::Real(f64::from_be_bytes(
+                take(&mut data, 8)?.try_into().expect("Slice is 8 bytes"),
+            )),
+            TEXT | BLOB => {
+                let len =
+                    u32::from_be_bytes(take(&mut data, 4)?.try_into().expect("Slice is 4 bytes"));
+                let bytes = take(&mut data, len as usize)?.to_vec();
+                if tag == BLOB {
+                    Column::Blob(bytes)
+                } else {
+                    Column::Text(String::from_utf8(bytes).map_err(|_| {
+                        BTreeError::SerializationError("Text column isn't utf-8".to_owned())
+                    })?)
+                }
+            }
+            _ => {
+                return Err(BTreeError::SerializationError(format!(
+                    "Unknown column type {tag}"
+                )))
+            }
+        };
+        columns.push(column);
+    }
+    Ok(columns)
+}
+
+fn read_row(rows: &mut Rows, columns: usize) -> Result<Option<(i64, Vec<u8>)>, BTreeError> {
+    let Some(row) = rows.next().map_err(sqlite_error)? else {
+        return Ok(None);
+    };
+    let rowid = row.get(0).map_err(sqlite_error)?;
+    let mut value = Vec::new();
+    for idx in 1..columns {
+        encode_column(row.get_ref(idx).map_err(sqlite_error)?, &mut value)?;
+    }
+    Ok(Some((rowid, value)))
+}
+
+impl<S: PageStore> BTree<S, i64> {
+    /// Builds a tree in `store` from every row of `table` in `conn`, keyed by rowid. Rows are
+    /// read in rowid order and bulk loaded. Returns the tree and the number of rows imported.
+    pub fn import_sqlite(
+        store: S,
+        conn: &Connection,
+        table: &str,
+    ) -> Result<(Self, usize), BTreeError> {
+        let table = table.replace('"', "\"\"");
+        let mut statement = conn
+            .prepare(&format!("SELECT rowid, * FROM \"{table}\" ORDER BY rowid"))
+            .map_err(sqlite_error)?;
+        let columns = statement.column_count();
+        let mut rows = statement.query([]).map_err(sqlite_error)?;
+
+        let mut imported = 0;
+        let mut error = None;
+        let entries = std::iter::from_fn(|| match read_row(&mut rows, columns) {
+            Ok(row) => {
+                imported += row.is_some() as usize;
+                row
+            }
+            Err(err) => {
+                error = Some(err);
+                None
+            }
+        });
+        let tree = Self::bulk_load(store, entries)?;
+        match error {
+            Some(err) => Err(err),
+            None => Ok((tree, imported)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn rows_are_imported_by_rowid() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE \"my \"\"users\"\"\" (name TEXT, age INTEGER, score REAL, avatar BLOB);
+             INSERT INTO \"my \"\"users\"\"\" (rowid, name, age, score, avatar)
+                 VALUES (-4, 'ada', 36, 1.5, x'00ff'), (10, NULL, 7, NULL, NULL);",
+        )
+        .unwrap();
+        for i in 0..500 {
+            conn.execute(
+                "INSERT INTO \"my \"\"users\"\"\" (name, age) VALUES (?1, ?2)",
+                (format!("user{i}"), i),
+            )
+            .unwrap();
+        }
+
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let (mut tree, imported) = BTree::import_sqlite(store, &conn, "my \"users\"").unwrap();
+        assert_eq!(imported, 502);
+
+        let row = decode_row(&tree.get(-4).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            row,
+            vec![
+                Column::Text("ada".to_owned()),
+                Column::Integer(36),
+                Column::Real(1.5),
+                Column::Blob(vec![0, 255]),
+            ]
+        );
+        let row = decode_row(&tree.get(10).unwrap().unwrap()).unwrap();
+        assert_eq!(row[0], Column::Null);
+        let row = decode_row(&tree.get(11 + 250).unwrap().unwrap()).unwrap();
+        assert_eq!(row[0], Column::Text("user250".to_owned()));
+
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        assert!(BTree::import_sqlite(store, &conn, "missing").is_err());
+        assert!(decode_row(&[TEXT, 0, 0, 0, 9, b'a']).is_err());
+    }
+}
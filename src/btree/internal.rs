@@ -0,0 +1,134 @@
+/*
+Internal nodes reuse the key records of leaves. A separator's left_child_page points to the
+subtree with all keys <= the separator, keys greater than the last separator live under
+rightmost_child_page in the header. Separators have no value, value_offset and value_len
+are 0.
+*/
+
+use super::errors::BTreeError;
+use super::header::NodeType;
+use super::key::KEY_SIZE;
+use super::Node;
+
+impl<'a> Node<'a> {
+    pub fn is_leaf(&self) -> Result<bool, BTreeError> {
+        Ok(self.read_header()?.node_type == NodeType::Leaf)
+    }
+
+    fn check_internal(&self) -> Result<(), BTreeError> {
+        if self.is_leaf()? {
+            return Err(BTreeError::NotInternal);
+        }
+        Ok(())
+    }
+
+    /// Child pointer at `idx`, where `num_keys` refers to the rightmost child
+    pub(crate) fn child_at(&self, idx: u16) -> Result<u32, BTreeError> {
+        let header = self.read_header()?;
+        if idx == header.num_keys.get() {
+            return Ok(header.rightmost_child_page.get());
+        }
+        Ok(self.read_key_at(idx)?.left_child_page.get())
+    }
+
+    /// Index of the child whose subtree may contain `key`
+    pub(crate) fn child_index(&self, key: u64) -> Result<u16, BTreeError> {
+        Ok(self.find_le_key_idx(key)?.0 as u16)
+    }
+
+    /// Page to descend into when looking for `key`
+    pub fn find_child_page(&self, key: u64) -> Result<u32, BTreeError> {
+        self.check_internal()?;
+        self.child_at(self.child_index(key)?)
+    }
+
+    /// Adds a separator whose subtree `left_child_page` holds the keys <= `key` that
+    /// were routed to the next child so far
+    pub fn insert_separator(&mut self, key: u64, left_child_page: u32) -> Result<(), BTreeError> {
+        self.check_internal()?;
+        let (idx, exists) = self.find_le_key_idx(key)?;
+        if exists {
+            return Err(BTreeError::UnsortedKey {
+                index: idx as u16,
+                key,
+            });
+        }
+        let available = self.unallocated_space()?;
+        if available < KEY_SIZE {
+            return Err(BTreeError::NotEnoughSpace {
+                required: KEY_SIZE.into(),
+                actual: available.into(),
+            });
+        }
+        self.insert_key_at(idx as u16, key, left_child_page, 0, 0)
+    }
+
+    /// Removes the separator `key` and returns its left child page. The keys of that
+    /// subtree are routed to the next child afterwards, so it has to be merged into it.
+    pub fn remove_separator(&mut self, key: u64) -> Result<Option<u32>, BTreeError> {
+        self.check_internal()?;
+        let (idx, exists) = self.find_le_key_idx(key)?;
+        if !exists {
+            return Ok(None);
+        }
+        let separator = self.pop_key_at(idx as u16)?;
+        Ok(Some(separator.left_child_page.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::header::HEADER_SIZE;
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_find_child_page() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        assert!(matches!(
+            node.find_child_page(1),
+            Err(BTreeError::NotInternal)
+        ));
+
+        let header = node.mutate_header().unwrap();
+        header.node_type = NodeType::Internal;
+        header.rightmost_child_page.set(9);
+        node.insert_separator(20, 2).unwrap();
+        node.insert_separator(10, 1).unwrap();
+        assert!(matches!(
+            node.insert_separator(10, 3),
+            Err(BTreeError::UnsortedKey { index: 0, key: 10 })
+        ));
+
+        assert_eq!(node.find_child_page(0).unwrap(), 1);
+        assert_eq!(node.find_child_page(10).unwrap(), 1);
+        assert_eq!(node.find_child_page(11).unwrap(), 2);
+        assert_eq!(node.find_child_page(20).unwrap(), 2);
+        assert_eq!(node.find_child_page(21).unwrap(), 9);
+
+        assert_eq!(node.remove_separator(10).unwrap(), Some(1));
+        assert_eq!(node.remove_separator(10).unwrap(), None);
+        assert_eq!(node.find_child_page(5).unwrap(), 2);
+        assert_eq!(node.read_header().unwrap().num_keys.get(), 1);
+    }
+
+    #[test]
+    fn test_full_internal_node() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.mutate_header().unwrap().node_type = NodeType::Internal;
+
+        let mut key = 0;
+        while node.insert_separator(key, key as u32).is_ok() {
+            key += 1;
+        }
+        assert_eq!(key, ((PAGE_SIZE - HEADER_SIZE) / KEY_SIZE) as u64);
+        assert!(matches!(
+            node.insert_separator(key, 0),
+            Err(BTreeError::NotEnoughSpace { .. })
+        ));
+        assert_eq!(node.find_child_page(key / 2).unwrap(), (key / 2) as u32);
+    }
+}
@@ -0,0 +1,161 @@
+/*
+Intersection of two trees by key, for lookups that combine indexes. Instead of reading both
+sides in full, the cursors leapfrog: whichever is behind seeks to the key the other one is on,
+until both land on the same key, which is yielded before both move past it. A seek skips every
+leaf between the two keys, so a side with few keys bounds the leaves read on the other.
+
+The cursors start from the entries they are on, seek them first to intersect part of the
+trees. Like the other iterators, an error ends the intersection and it is fused afterwards.
+*/
+
+use std::iter::FusedIterator;
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::treecursor::TreeCursor;
+use crate::page::PageStore;
+
+/// An encoded key with its value in either tree
+type Match = (u64, Vec<u8>, Vec<u8>);
+
+/// Keys found in both cursors' trees with the value of either, see intersect
+pub struct Intersection<'a, 'b, S: PageStore, T: PageStore, K: KeyCodec> {
+    a: TreeCursor<'a, S, K>,
+    b: TreeCursor<'b, T, K>,
+    /// Both cursors are on the key yielded last and move past it on the next call
+    matched: bool,
+    done: bool,
+}
+
+/// Keys on or after the entries `a` and `b` are on that both trees hold, in key order, with
+/// the value from `a` and from `b`
+pub fn intersect<'a, 'b, S: PageStore, T: PageStore, K: KeyCodec>(
+    a: TreeCursor<'a, S, K>,
+    b: TreeCursor<'b, T, K>,
+) -> Intersection<'a, 'b, S, T, K> {
+    Intersection {
+        a,
+        b,
+        matched: false,
+        done: false,
+    }
+}
+
+impl<S: PageStore, T: PageStore, K: KeyCodec> Intersection<'_, '_, S, T, K> {
+    fn next_match(&mut self) -> Result<Option<Match>, BTreeError> {
+        if self.matched {
+            self.matched = false;
+            self.a.next()?;
+            self.b.next()?;
+        }
+        loop {
+            let (Some((a, _)), Some((b, _))) = (self.a.current_encoded(), self.b.current_encoded())
+            else {
+                return Ok(None);
+            };
+            if a < b {
+                self.a.seek_encoded(b)?;
+            } else if b < a {
+                self.b.seek_encoded(a)?;
+            } else {
+                break;
+            }
+        }
+        let (key, a) = self.a.current_encoded().expect("Both cursors are on a key");
+        let (_, b) = self.b.current_encoded().expect("Both cursors are on a key");
+        self.matched = true;
+        Ok(Some((key, a.to_vec(), b.to_vec())))
+    }
+}
+
+impl<S: PageStore, T: PageStore, K: KeyCodec> Iterator for Intersection<'_, '_, S, T, K> {
+    type Item = Result<(K, Vec<u8>, Vec<u8>), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_match() {
+            Ok(Some((key, a, b))) => Some(Ok((K::decode(key), a, b))),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<S: PageStore, T: PageStore, K: KeyCodec> FusedIterator for Intersection<'_, '_, S, T, K> {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BTree, PAGE_SIZE};
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn tree(keys: impl Iterator<Item = u64>) -> BTree<MemoryStore> {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in keys {
+            tree.insert(key, &key.to_le_bytes()).unwrap();
+        }
+        tree
+    }
+
+    fn keys(iter: Intersection<'_, '_, MemoryStore, MemoryStore, u64>) -> Vec<u64> {
+        iter.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn cursors_leapfrog_to_common_keys() {
+        let mut threes = tree((0..6000).step_by(3));
+        let mut fives = tree((0..6000).step_by(5));
+        let expected: Vec<u64> = (0..6000).step_by(15).collect();
+        assert_eq!(
+            keys(intersect(threes.cursor().unwrap(), fives.cursor().unwrap())),
+            expected
+        );
+
+        // Values come from either side
+        let (key, a, b) = intersect(threes.cursor().unwrap(), fives.cursor().unwrap())
+            .nth(2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (key, a, b),
+            (30, 30u64.to_le_bytes().into(), 30u64.to_le_bytes().into())
+        );
+
+        // Seeking a cursor first starts the intersection there
+        let mut from = threes.cursor().unwrap();
+        from.seek(5980).unwrap();
+        assert_eq!(keys(intersect(from, fives.cursor().unwrap())), [5985]);
+        let mut empty = tree(std::iter::empty());
+        assert_eq!(
+            keys(intersect(threes.cursor().unwrap(), empty.cursor().unwrap())),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn sparse_sides_skip_leaves() {
+        let mut dense = tree(0..20_000);
+        let mut sparse = tree([7, 5000, 5001, 12_345, 19_999, 30_000].into_iter());
+        let leaves = dense.fragmentation().unwrap().len() as u64;
+        let depth = dense.depth().unwrap() as u64;
+        dense.reset_stats();
+
+        assert_eq!(
+            keys(intersect(dense.cursor().unwrap(), sparse.cursor().unwrap())),
+            [7, 5000, 5001, 12_345, 19_999]
+        );
+        // Every match costs at most a descent or two, a full scan reads every leaf
+        let reads = dense.stats().unwrap().page_reads;
+        assert!(reads <= 8 * depth, "{reads} reads");
+        assert!(reads < leaves / 4, "{reads} reads for {leaves} leaves");
+    }
+}
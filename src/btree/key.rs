@@ -1,4 +1,6 @@
-use super::errors::BTreeError;
+use alloc::string::ToString;
+
+use super::errors::{BTreeError, CorruptionError};
 use super::header::HEADER_SIZE;
 use super::Node;
 
@@ -22,6 +24,9 @@ pub const KEY_SIZE: u16 = {
     size_of::<Key>() as u16
 };
 
+/// Largest value that can be stored inline in a leaf's key record
+pub const MAX_INLINE_VALUE: u16 = size_of::<U32>() as u16;
+
 impl Key {
     pub fn new(key: u64, left_child_page: u32, value_offset: u16, value_len: u16) -> Self {
         Self {
@@ -32,6 +37,16 @@ impl Key {
         }
     }
 
+    /// Inline values live in the left_child_page field, which leaves don't use. An offset
+    /// of 0 can never point into the content area, so it marks the value as inline.
+    pub fn is_inline(&self) -> bool {
+        self.value_offset.get() == 0
+    }
+
+    pub fn inline_value(&self) -> &[u8] {
+        &self.left_child_page.as_bytes()[..self.value_len.get() as usize]
+    }
+
     pub fn intepret_from_bytes(bytes: &[u8; KEY_SIZE as usize]) -> Result<&Self, BTreeError> {
         try_transmute_ref!(bytes).map_err(|err| BTreeError::SerializationError(err.to_string()))
     }
@@ -53,11 +68,13 @@ impl<'a> Node<'a> {
         value_len: u16,
     ) -> Result<(), BTreeError> {
         debug_assert!(self.unallocated_space().unwrap() >= KEY_SIZE);
+        let pos = self.get_key_pos(idx)?;
+        if self.config.strict {
+            self.check_key_order(idx, key)?;
+        }
         let key = Key::new(key, left_child_page, value_offset, value_len);
 
-        let header = self.read_header()?;
-        let keys_end = header.free_start.get() as usize;
-        let pos = self.get_key_pos(idx);
+        let keys_end = self.read_header()?.free_start.get() as usize;
 
         self.page
             .copy_within(pos as usize..keys_end, (pos + KEY_SIZE).into());
@@ -73,16 +90,13 @@ impl<'a> Node<'a> {
     }
 
     pub fn pop_key_at(&mut self, idx: u16) -> Result<Key, BTreeError> {
-        let key_pos = self.get_key_pos(idx);
-        let old_header = self.read_header()?;
-        debug_assert!(key_pos < old_header.free_start.get());
-
-        let key_ref = self.read_key_at(idx)?;
-        let key = key_ref.clone();
-        let keys_end = old_header.free_start.get() as usize;
+        let key = self.read_key_at(idx)?.clone();
+        let key_pos = self.get_key_pos(idx)?;
+        let keys_end = self.read_header()?.free_start.get() as usize;
 
         self.page
             .copy_within((key_pos + KEY_SIZE) as usize..keys_end, key_pos as usize);
+        self.scrub(keys_end - KEY_SIZE as usize, KEY_SIZE.into());
 
         let header = self.mutate_header()?;
         header.free_start -= KEY_SIZE;
@@ -92,6 +106,7 @@ impl<'a> Node<'a> {
     }
 
     pub fn find_le_key_idx(&self, key: u64) -> Result<(usize, bool), BTreeError> {
+        self.check_key_type(false)?;
         let header = self.read_header()?;
         let num_keys = header.num_keys.get();
 
@@ -104,8 +119,7 @@ impl<'a> Node<'a> {
 
         while low < high {
             let mid = (low + high) / 2;
-            let key_ptr = self.read_key_at(mid)?;
-            let current_key = key_ptr.key.get();
+            let current_key = self.key_at(mid)?;
 
             // https://github.com/rust-lang/rust-clippy/issues/5354
             #[allow(clippy::comparison_chain)]
@@ -121,32 +135,115 @@ impl<'a> Node<'a> {
         Ok((low.into(), false))
     }
 
-    pub fn get_key_pos(&self, index: u16) -> u16 {
-        HEADER_SIZE + KEY_SIZE * index
+    /// Position of the key record at `index`. `num_keys` is a valid index as well,
+    /// pointing right behind the last key record where a new one can be inserted.
+    pub fn get_key_pos(&self, index: u16) -> Result<u16, BTreeError> {
+        let header = self.read_header()?;
+        debug_assert!(!header.is_packed(), "Packed nodes don't have key records");
+
+        let num_keys = header.num_keys.get();
+        if index > num_keys {
+            return Err(BTreeError::KeyIndexOutOfRange { index, num_keys });
+        }
+        Ok(HEADER_SIZE + KEY_SIZE * index)
+    }
+
+    /// Makes sure `key` sorts strictly between the neighbours of insertion point `idx`
+    fn check_key_order(&self, idx: u16, key: u64) -> Result<(), BTreeError> {
+        let num_keys = self.read_header()?.num_keys.get();
+        let after_prev = idx == 0 || self.read_key_at(idx - 1)?.key.get() < key;
+        let before_next = idx == num_keys || key < self.read_key_at(idx)?.key.get();
+        if !(after_prev && before_next) {
+            return Err(BTreeError::UnsortedKey { index: idx, key });
+        }
+        Ok(())
+    }
+
+    /// Makes sure `index` refers to an existing key whose slot lies within the slot array
+    pub(crate) fn check_key_index(&self, index: u16) -> Result<(), BTreeError> {
+        let header = self.read_header()?;
+        let num_keys = header.num_keys.get();
+        if index >= num_keys {
+            return Err(BTreeError::KeyIndexOutOfRange { index, num_keys });
+        }
+        // A corrupt key count must not send reads past the slot array or the page
+        let slot_end =
+            header.slots_start() as usize + header.slot_size() as usize * (index as usize + 1);
+        let free_start = header.free_start.get();
+        if slot_end > free_start.into() || free_start > header.free_end.get() {
+            return Err(BTreeError::Corrupt(CorruptionError::SlotOutOfBounds {
+                index,
+                free_start,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Key stored at `index`, regardless of the node layout
+    pub fn key_at(&self, index: u16) -> Result<u64, BTreeError> {
+        if self.read_header()?.is_packed() {
+            return self.packed_key_at(index);
+        }
+        Ok(self.read_key_at(index)?.key.get())
+    }
+
+    /// Value stored at `index`, regardless of where the node keeps it
+    pub fn value_at(&self, index: u16) -> Result<&[u8], BTreeError> {
+        if self.is_packed()? {
+            return self.packed_value_at(index);
+        }
+        let key = self.read_key_at(index)?;
+        if key.is_inline() {
+            if key.value_len.get() > MAX_INLINE_VALUE {
+                return Err(self.value_out_of_bounds(index, key));
+            }
+            return Ok(key.inline_value());
+        }
+        let offset = key.value_offset.get();
+        let len = key.value_len.get();
+        let header = self.read_header()?;
+        if offset < header.free_end.get()
+            || offset as usize + len as usize > header.content_end(self.page_size()) as usize
+        {
+            return Err(self.value_out_of_bounds(index, key));
+        }
+        Ok(self.get_page_slice(offset.into(), len.into()))
+    }
+
+    fn value_out_of_bounds(&self, index: u16, key: &Key) -> BTreeError {
+        BTreeError::Corrupt(CorruptionError::ValueOutOfBounds {
+            index,
+            offset: key.value_offset.get(),
+            len: key.value_len.get(),
+        })
     }
 
     pub fn read_key_at(&self, index: u16) -> Result<&Key, BTreeError> {
-        let key_pos = self.get_key_pos(index) as usize;
+        self.check_key_type(false)?;
+        self.check_key_index(index)?;
+        let key_pos = self.get_key_pos(index)? as usize;
         let key_bytes: &[u8; KEY_SIZE as usize] = self
             .get_page_slice(key_pos, KEY_SIZE as usize)
             .try_into()
             .expect("Shouldn't fail, hardcoded");
-        Ok(Key::intepret_from_bytes(key_bytes)?)
+        Key::intepret_from_bytes(key_bytes)
     }
 
     pub fn mut_key_at(&mut self, index: u16) -> Result<&mut Key, BTreeError> {
-        let key_pos = self.get_key_pos(index) as usize;
+        self.check_key_type(false)?;
+        self.check_key_index(index)?;
+        let key_pos = self.get_key_pos(index)? as usize;
         let key_bytes: &mut [u8; KEY_SIZE as usize] = self
             .get_mut_page_slice(key_pos, KEY_SIZE as usize)
             .try_into()
             .expect("Shouldn't fail, hardcoded");
-        Ok(Key::intepret_mut_from_bytes(key_bytes)?)
+        Key::intepret_mut_from_bytes(key_bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::PAGE_SIZE;
+    use super::super::{NodeConfig, PAGE_SIZE};
     use super::*;
 
     #[test]
@@ -226,4 +323,74 @@ mod tests {
         let header = node.read_header().unwrap();
         assert_eq!(header.num_keys.get(), 3);
     }
+
+    #[test]
+    fn test_key_index_out_of_range() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+
+        assert!(matches!(
+            node.read_key_at(0),
+            Err(BTreeError::KeyIndexOutOfRange {
+                index: 0,
+                num_keys: 0
+            })
+        ));
+
+        node.insert(10, b"val10").unwrap();
+        node.insert(20, b"val20").unwrap();
+
+        assert_eq!(node.read_key_at(1).unwrap().key.get(), 20);
+        assert!(matches!(
+            node.read_key_at(2),
+            Err(BTreeError::KeyIndexOutOfRange {
+                index: 2,
+                num_keys: 2
+            })
+        ));
+        assert!(node.mut_key_at(2).is_err());
+        assert!(node.pop_key_at(2).is_err());
+        assert!(node.value_at(2).is_err());
+
+        assert_eq!(node.get_key_pos(2).unwrap(), HEADER_SIZE + 2 * KEY_SIZE);
+        assert!(node.get_key_pos(3).is_err());
+        assert!(matches!(
+            node.insert_key_at(3, 30, 0, 100, 3),
+            Err(BTreeError::KeyIndexOutOfRange {
+                index: 3,
+                num_keys: 2
+            })
+        ));
+        node.insert_key_at(2, 30, 0, 100, 3).unwrap();
+        assert_eq!(node.read_key_at(2).unwrap().key.get(), 30);
+    }
+
+    #[test]
+    fn test_strict_key_order() {
+        let config = NodeConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+
+        // Inline records, strict mode audits where values live as well
+        node.insert_key_at(0, 10, 0, 0, 3).unwrap();
+        node.insert_key_at(1, 30, 0, 0, 3).unwrap();
+        node.insert_key_at(1, 20, 0, 0, 3).unwrap();
+
+        for (idx, key) in [(0, 10), (0, 15), (1, 20), (2, 10), (3, 25)] {
+            assert!(matches!(
+                node.insert_key_at(idx, key, 0, 0, 3),
+                Err(BTreeError::UnsortedKey { index, key: k }) if index == idx && k == key
+            ));
+        }
+        assert_eq!(node.read_header().unwrap().num_keys.get(), 3);
+
+        node.insert_key_at(0, 5, 0, 0, 3).unwrap();
+        node.insert_key_at(4, 35, 0, 0, 3).unwrap();
+        for key in [1, 22, 100] {
+            node.insert(key, b"new").unwrap();
+        }
+    }
 }
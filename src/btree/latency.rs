@@ -0,0 +1,61 @@
+/*
+Latency distributions of tree operations. Every get, insert, commit and checkpoint is timed
+and recorded in nanoseconds in an HdrHistogram with 3 significant digits, which resizes itself
+to the slowest call seen and answers percentiles without keeping the samples.
+
+Calls that fail are recorded as well, an error after a slow read is as slow for the caller as
+a success.
+*/
+
+use std::time::Instant;
+
+use hdrhistogram::Histogram;
+
+/// Latencies in nanoseconds per operation
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub get: Histogram<u64>,
+    pub insert: Histogram<u64>,
+    pub commit: Histogram<u64>,
+    pub checkpoint: Histogram<u64>,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        let histogram = || Histogram::new(3).expect("3 significant digits are supported");
+        Self {
+            get: histogram(),
+            insert: histogram(),
+            commit: histogram(),
+            checkpoint: histogram(),
+        }
+    }
+}
+
+/// Records the time since `start` in `histogram`
+pub(crate) fn record(histogram: &mut Histogram<u64>, start: Instant) {
+    let nanos = start.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+    // Grows the histogram, saturating would clamp to its current range instead
+    if histogram.record(nanos).is_err() {
+        histogram.saturating_record(nanos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn records_elapsed_time() {
+        let mut stats = LatencyStats::default();
+        let start = Instant::now();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        record(&mut stats.get, start);
+        record(&mut stats.get, Instant::now());
+
+        assert_eq!(stats.get.len(), 2);
+        assert!(stats.get.max() >= 2_000_000);
+        assert!(stats.insert.is_empty());
+    }
+}
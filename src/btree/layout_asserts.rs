@@ -0,0 +1,62 @@
+/*
+The on-disk node format every file written so far relies on. All integers are little endian.
+Header (16 bytes)
+---------------------------------------------------------------------------------------
+| offset | 0         | 1        | 3          | 5        | 7               | 9            |
+| field  | node_type | num_keys | free_start | free_end | first_freeblock | fragmented   |
+---------------------------------------------------------------------------------------
+| offset | 10                   | 14    | 15           |
+| field  | rightmost_child_page | flags | packed_width |
+-------------------------------------------------------
+Key record (16 bytes)
+-----------------------------------------------------------
+| offset | 0   | 8               | 12           | 14        |
+| field  | key | left_child_page | value_offset | value_len |
+-----------------------------------------------------------
+Freeblock (4 bytes)
+----------------------------------
+| offset | 0              | 2    |
+| field  | next_freeblock | size |
+----------------------------------
+Packed slots are an 8 byte key followed by packed_width bytes of value. The top three bits of
+flags can hold a key prefix length n, then n prefix bytes follow the header and slots only keep
+the first 8 - n bytes of their key.
+
+The asserts below pin the structs to these numbers, so a change to the layout fails to compile
+instead of silently misreading existing files.
+*/
+
+use memoffset::offset_of;
+
+use super::freeblock::{Freeblock, FREEBLOCK_SIZE};
+use super::header::{Header, HEADER_SIZE};
+use super::key::{Key, KEY_SIZE, MAX_INLINE_VALUE};
+use super::packed::PACKED_KEY_SIZE;
+use super::PAGE_SIZE;
+
+const _: () = assert!(PAGE_SIZE == 4096);
+
+const _: () = assert!(HEADER_SIZE == 16);
+const _: () = assert!(offset_of!(Header, node_type) == 0);
+const _: () = assert!(offset_of!(Header, num_keys) == 1);
+const _: () = assert!(offset_of!(Header, free_start) == 3);
+const _: () = assert!(offset_of!(Header, free_end) == 5);
+const _: () = assert!(offset_of!(Header, first_freeblock) == 7);
+const _: () = assert!(offset_of!(Header, fragmented_bytes) == 9);
+const _: () = assert!(offset_of!(Header, rightmost_child_page) == 10);
+const _: () = assert!(offset_of!(Header, flags) == 14);
+const _: () = assert!(offset_of!(Header, packed_width) == 15);
+
+const _: () = assert!(KEY_SIZE == 16);
+const _: () = assert!(offset_of!(Key, key) == 0);
+const _: () = assert!(offset_of!(Key, left_child_page) == 8);
+const _: () = assert!(offset_of!(Key, value_offset) == 12);
+const _: () = assert!(offset_of!(Key, value_len) == 14);
+// Inline values live in left_child_page
+const _: () = assert!(MAX_INLINE_VALUE == 4);
+
+const _: () = assert!(FREEBLOCK_SIZE == 4);
+const _: () = assert!(offset_of!(Freeblock, next_freeblock) == 0);
+const _: () = assert!(offset_of!(Freeblock, size) == 2);
+
+const _: () = assert!(PACKED_KEY_SIZE == 8);
@@ -0,0 +1,253 @@
+/*
+K-way merge of sorted entry streams: the iterators of several trees, or a tree and the sorted
+contents of a WriteBatch. Every source has to yield strictly ascending keys, like TreeIter
+does. The merge reads one entry ahead from each source and keeps the sources in a min-heap by
+their next key, ties broken by source index, so every step costs O(log k) for k sources.
+
+Every key goes through the resolver together with its versions, one per source holding it, in
+source order. It decides what the merge yields for the key: one of the values, a combination,
+or nothing at all, say for a tombstone in a memtable. An error from a source, or a source out
+of order, ends the merge, which is fused afterwards like TreeIter.
+*/
+
+use alloc::collections::BinaryHeap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::iter::FusedIterator;
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+
+/// A key and its value in every source holding it, by source index
+type Versions<K, V> = (K, Vec<(usize, V)>);
+
+/// Entries of several sorted sources in key order, see merge_sorted
+pub struct MergeIter<K, V, I, F> {
+    sources: Vec<I>,
+    /// The entry read ahead from every source, by source index
+    heads: Vec<Option<(K, V)>>,
+    /// Encoded key and index of every source with an entry in `heads`
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    resolve: F,
+    /// Sources are read ahead on the first call to next
+    started: bool,
+    done: bool,
+}
+
+/// Merges `sources` into one stream in key order. `resolve` gets every key with its
+/// versions as (source index, value) and returns the value to yield, `None` to skip the key.
+/// `|_, versions| versions.into_iter().next().map(|(_, value)| value)` lets the first source
+/// holding a key win.
+pub fn merge_sorted<K, V, T, S, F>(
+    sources: impl IntoIterator<Item = S>,
+    resolve: F,
+) -> MergeIter<K, V, S::IntoIter, F>
+where
+    K: KeyCodec,
+    S: IntoIterator<Item = Result<(K, V), BTreeError>>,
+    F: FnMut(&K, Vec<(usize, V)>) -> Option<T>,
+{
+    let sources: Vec<_> = sources.into_iter().map(IntoIterator::into_iter).collect();
+    MergeIter {
+        heads: sources.iter().map(|_| None).collect(),
+        heap: BinaryHeap::with_capacity(sources.len()),
+        sources,
+        resolve,
+        started: false,
+        done: false,
+    }
+}
+
+impl<K, V, I, F> MergeIter<K, V, I, F>
+where
+    K: KeyCodec,
+    I: Iterator<Item = Result<(K, V), BTreeError>>,
+{
+    /// Reads the next entry of source `idx`, whose last key was `previous`
+    fn advance(&mut self, idx: usize, previous: Option<u64>) -> Result<(), BTreeError> {
+        let Some((key, value)) = self.sources[idx].next().transpose()? else {
+            return Ok(());
+        };
+        let encoded = key.encode();
+        if let Some(previous) = previous.filter(|&previous| previous >= encoded) {
+            return Err(BTreeError::UnsortedInput {
+                previous,
+                key: encoded,
+            });
+        }
+        self.heads[idx] = Some((key, value));
+        self.heap.push(Reverse((encoded, idx)));
+        Ok(())
+    }
+
+    /// The next key and its versions
+    fn next_versions(&mut self) -> Result<Option<Versions<K, V>>, BTreeError> {
+        if !self.started {
+            self.started = true;
+            for idx in 0..self.sources.len() {
+                self.advance(idx, None)?;
+            }
+        }
+        let Some(Reverse((encoded, idx))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        let (key, value) = self.heads[idx]
+            .take()
+            .expect("Sources in the heap have a head");
+        let mut versions = vec![(idx, value)];
+        self.advance(idx, Some(encoded))?;
+        while let Some(&Reverse((next, idx))) = self.heap.peek() {
+            if next != encoded {
+                break;
+            }
+            self.heap.pop();
+            let (_, value) = self.heads[idx]
+                .take()
+                .expect("Sources in the heap have a head");
+            versions.push((idx, value));
+            self.advance(idx, Some(encoded))?;
+        }
+        Ok(Some((key, versions)))
+    }
+}
+
+impl<K, V, T, I, F> Iterator for MergeIter<K, V, I, F>
+where
+    K: KeyCodec,
+    I: Iterator<Item = Result<(K, V), BTreeError>>,
+    F: FnMut(&K, Vec<(usize, V)>) -> Option<T>,
+{
+    type Item = Result<(K, T), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.next_versions() {
+                Ok(Some((key, versions))) => {
+                    if let Some(value) = (self.resolve)(&key, versions) {
+                        return Some(Ok((key, value)));
+                    }
+                }
+                Ok(None) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<K, V, T, I, F> FusedIterator for MergeIter<K, V, I, F>
+where
+    K: KeyCodec,
+    I: Iterator<Item = Result<(K, V), BTreeError>>,
+    F: FnMut(&K, Vec<(usize, V)>) -> Option<T>,
+{
+}
+
+#[cfg(all(test, feature = "pager"))]
+mod tests {
+    use super::super::{BTree, WriteBatch, PAGE_SIZE};
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn tree(keys: impl Iterator<Item = u64>, value: u8) -> BTree<MemoryStore> {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in keys {
+            tree.insert(key, &[value; 100]).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn trees_merge_in_key_order() {
+        let mut a = tree((0..600).step_by(2), 1);
+        let mut b = tree((0..600).step_by(3), 2);
+        let mut c = tree(500..700, 3);
+
+        // Values of the same key are summed up
+        let merged: Vec<(u64, u32)> =
+            merge_sorted([a.iter(), b.iter(), c.iter()], |_, versions| {
+                Some(versions.iter().map(|(_, value)| value[0] as u32).sum())
+            })
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<(u64, u32)> = (0..700)
+            .filter_map(|key| {
+                let sum = (key < 600 && key % 2 == 0) as u32
+                    + 2 * (key < 600 && key % 3 == 0) as u32
+                    + 3 * (key >= 500) as u32;
+                (sum > 0).then_some((key, sum))
+            })
+            .collect();
+        assert_eq!(merged, expected);
+        assert_eq!(
+            merge_sorted(
+                Vec::<Vec<Result<(u64, Vec<u8>), _>>>::new(),
+                |_, _| Some(())
+            )
+            .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn batches_overlay_a_tree() {
+        let mut base = tree(0..300, 1);
+        let mut batch = WriteBatch::new();
+        batch
+            .insert(10, &[2])
+            .delete(20)
+            .insert(1000, &[3])
+            .delete(2000);
+        batch.delete(10).insert(10, &[4]);
+
+        // The batch comes first and wins, its deletes hide the tree's entries
+        let pending = batch.sorted().into_iter().map(Ok);
+        let stored = base
+            .iter()
+            .map(|entry| entry.map(|(key, value)| (key, Some(value))));
+        let sources: [Box<dyn Iterator<Item = _>>; 2] = [Box::new(pending), Box::new(stored)];
+        let merged: Vec<(u64, Vec<u8>)> = merge_sorted(sources, |_, versions| {
+            versions.into_iter().next().and_then(|(_, value)| value)
+        })
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+        assert_eq!(merged.len(), 300);
+        assert_eq!(merged[10], (10, vec![4]));
+        assert_eq!(merged[20], (21, vec![1; 100]));
+        assert_eq!(merged[299], (1000, vec![3]));
+    }
+
+    #[test]
+    fn sources_out_of_order_end_the_merge() {
+        let sources = [
+            vec![Ok((1u64, ())), Ok((5, ())), Ok((3, ()))],
+            vec![Ok((2, ())), Err(BTreeError::NoMergeOperator)],
+        ];
+        let mut merged = merge_sorted(sources, |_, _| Some(()));
+        assert_eq!(merged.next().unwrap().unwrap().0, 1);
+        assert!(matches!(
+            merged.next(),
+            Some(Err(BTreeError::NoMergeOperator))
+        ));
+        assert!(merged.next().is_none());
+
+        let mut merged = merge_sorted([vec![Ok((1u64, ())), Ok((5, ())), Ok((3, ()))]], |_, _| {
+            Some(())
+        });
+        assert_eq!(merged.next().unwrap().unwrap().0, 1);
+        assert!(matches!(
+            merged.next(),
+            Some(Err(BTreeError::UnsortedInput {
+                previous: 5,
+                key: 3
+            }))
+        ));
+        assert!(merged.next().is_none());
+    }
+}
@@ -0,0 +1,228 @@
+/*
+Read-only snapshots of a database file through a memory map. Values are handed out as slices
+of the map, so scanning a whole tree copies nothing. A snapshot borrows the pager it was taken
+from, which keeps this process from writing to the file while the map is alive. Every page is
+checked with NodeView::load before it is read, and descents are bounded like those of an
+untrusted tree.
+*/
+
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+
+use memmap2::Mmap;
+
+use super::errors::{BTreeError, LimitError};
+use super::view::NodeView;
+use crate::limits::ResourceLimits;
+use crate::page::Pager;
+
+pub struct MmapSnapshot<'p> {
+    map: Mmap,
+    page_size: usize,
+    root: u32,
+    max_depth: usize,
+    pager: PhantomData<&'p Pager>,
+}
+
+/// Entries of a snapshot in key order
+pub struct MmapIter<'s> {
+    map: &'s [u8],
+    page_size: usize,
+    max_depth: usize,
+    /// Internal nodes on the way to the current leaf and the next child to visit in each
+    stack: Vec<(NodeView<'s>, u16)>,
+    leaf: Option<(NodeView<'s>, u16)>,
+    error: Option<BTreeError>,
+}
+
+fn node_at(map: &[u8], page_size: usize, page_no: u32) -> Result<NodeView<'_>, BTreeError> {
+    let start = page_no as usize * page_size;
+    // Page 0 holds the file header
+    match map.get(start..start + page_size) {
+        Some(page) if page_no != 0 => {
+            NodeView::load(page).map_err(|err| err.in_page(page_no, start as u64))
+        }
+        _ => Err(BTreeError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Page {page_no} isn't a data page"),
+        ))),
+    }
+}
+
+impl<'p> MmapSnapshot<'p> {
+    /// Maps `file`, which `pager` has to be the only writer of
+    pub(crate) fn new(
+        _pager: &'p Pager,
+        file: &File,
+        page_size: usize,
+        root: u32,
+    ) -> Result<Self, io::Error> {
+        // Safety: the map is only ever read, and the borrow of the pager keeps this process
+        // from changing the file. Other processes writing it at the same time would be
+        // undefined behaviour, like they would be for any reader of the file.
+        let map = unsafe { Mmap::map(file)? };
+        Ok(Self {
+            map,
+            page_size,
+            root,
+            max_depth: ResourceLimits::default().max_depth,
+            pager: PhantomData,
+        })
+    }
+
+    /// Reads the tree rooted at `root` instead of the one recorded in the file header
+    pub fn with_root(mut self, root: u32) -> Self {
+        self.root = root;
+        self
+    }
+
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    /// Raw page `page_no`, `None` if the file has no such page
+    pub fn page(&self, page_no: u32) -> Option<&[u8]> {
+        let start = page_no as usize * self.page_size;
+        self.map.get(start..start + self.page_size)
+    }
+
+    pub fn get(&self, key: u64) -> Result<Option<&[u8]>, BTreeError> {
+        get_in(&self.map, self.page_size, self.root, self.max_depth, key)
+    }
+
+    pub fn iter(&self) -> MmapIter<'_> {
+        MmapIter::new(&self.map, self.page_size, self.root, self.max_depth)
+    }
+}
+
+/// Looks `key` up in the tree rooted at `root` within the mapped file `map`
+pub(crate) fn get_in(
+    map: &[u8],
+    page_size: usize,
+    root: u32,
+    max_depth: usize,
+    key: u64,
+) -> Result<Option<&[u8]>, BTreeError> {
+    let mut node = node_at(map, page_size, root)?;
+    for _ in 0..max_depth {
+        if node.is_leaf() {
+            return Ok(node.get(key));
+        }
+        node = node_at(map, page_size, node.find_child_page(key)?)?;
+    }
+    Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+        limit: max_depth,
+    }))
+}
+
+impl<'s> MmapIter<'s> {
+    /// Entries of the tree rooted at `root` within the mapped file `map`
+    pub(crate) fn new(map: &'s [u8], page_size: usize, root: u32, max_depth: usize) -> Self {
+        let mut iter = MmapIter {
+            map,
+            page_size,
+            max_depth,
+            stack: Vec::new(),
+            leaf: None,
+            error: None,
+        };
+        if let Err(err) = iter.descend(root) {
+            iter.error = Some(err);
+        }
+        iter
+    }
+
+    fn descend(&mut self, page_no: u32) -> Result<(), BTreeError> {
+        let node = node_at(self.map, self.page_size, page_no)?;
+        if node.is_leaf() {
+            self.leaf = Some((node, 0));
+            return Ok(());
+        }
+        if self.stack.len() + 1 >= self.max_depth {
+            return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                limit: self.max_depth,
+            }));
+        }
+        self.stack.push((node, 0));
+        Ok(())
+    }
+}
+
+impl<'s> Iterator for MmapIter<'s> {
+    type Item = Result<(u64, &'s [u8]), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            self.stack.clear();
+            return Some(Err(err));
+        }
+        loop {
+            if let Some((leaf, idx)) = &mut self.leaf {
+                if *idx < leaf.len() {
+                    let entry = leaf.entry_at(*idx);
+                    *idx += 1;
+                    return Some(Ok(entry));
+                }
+                self.leaf = None;
+            }
+
+            let (node, idx) = self.stack.last_mut()?;
+            if *idx > node.len() {
+                self.stack.pop();
+                continue;
+            }
+            let child = node.child_at(*idx);
+            *idx += 1;
+            if let Err(err) = child.and_then(|child| self.descend(child)) {
+                self.stack.clear();
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BTree;
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn snapshot_serves_values_from_the_map() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+        for key in 0..3000 {
+            tree.insert(key * 3, &[key as u8; 50]).unwrap();
+        }
+        for key in (0..3000).step_by(7) {
+            tree.delete(key * 3).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+
+        let snapshot = pager.mmap_snapshot().unwrap();
+        assert_eq!(snapshot.root(), root);
+        assert_eq!(snapshot.get(30).unwrap(), Some([10u8; 50].as_slice()));
+        assert_eq!(snapshot.get(31).unwrap(), None);
+        assert_eq!(snapshot.get(21).unwrap(), None);
+
+        let entries: Vec<_> = snapshot.iter().map(Result::unwrap).collect();
+        let expected: Vec<_> = (0..3000).filter(|key| key % 7 != 0).collect();
+        assert_eq!(entries.len(), expected.len());
+        for ((key, value), expected) in entries.iter().zip(expected) {
+            assert_eq!(*key, expected * 3);
+            assert_eq!(*value, [expected as u8; 50].as_slice());
+        }
+
+        // The header page is no tree
+        let snapshot = snapshot.with_root(0);
+        assert!(snapshot.get(1).is_err());
+        assert!(snapshot.iter().next().unwrap().is_err());
+    }
+}
@@ -1,14 +1,169 @@
-use errors::BTreeError;
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+#[cfg(feature = "pager")]
+use core::ops::Range;
+
+#[cfg(feature = "pager")]
+pub use append::AppendTree;
+pub use batch::{ApplyOutcome, BatchOp, WriteBatch};
+pub use codec::{schema_fingerprint, KeyCodec, SchemaName};
+#[cfg(feature = "lz4")]
+pub use compression::Lz4;
+pub use compression::{Compression, ValueCodec};
+#[cfg(feature = "pager")]
+pub use concurrent::{ConcurrentTree, POOL_PAGES};
+pub use config::{DefragPolicy, Limits, NodeConfig};
+pub use cursor::RangeIter;
+#[cfg(feature = "pager")]
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use errors::{BTreeError, CorruptionError, LimitError};
+#[cfg(feature = "arrow")]
+pub use export::{KeyValue, Projection};
+use fallible::{try_to_vec, try_with_capacity, try_zeroed};
 use freeblock::FREEBLOCK_SIZE;
-use header::{NodeType, HEADER_SIZE};
+pub use header::RESERVED_TAIL;
+use header::{NodeType, FLAG_CHECKSUM, FLAG_RESERVED_TAIL, HEADER_SIZE};
+#[cfg(feature = "std")]
+pub use heat::{AccessTracker, LeafHeat};
+#[cfg(feature = "pager")]
+pub use history::TreeHistory;
+#[cfg(feature = "std")]
+pub use history::{GcPacing, GcStats, History, NodeHistory, Version};
+#[cfg(feature = "sqlite")]
+pub use import::{decode_row, Column};
+#[cfg(feature = "pager")]
+pub use intersect::{intersect, Intersection};
 use key::KEY_SIZE;
-
+pub use key::MAX_INLINE_VALUE;
+#[cfg(feature = "histogram")]
+pub use latency::LatencyStats;
+pub use merge::{merge_sorted, MergeIter};
+#[cfg(feature = "mmap")]
+pub(crate) use mmap::get_in;
+#[cfg(feature = "mmap")]
+pub use mmap::{MmapIter, MmapSnapshot};
+#[cfg(feature = "std")]
+pub use negcache::NegativeCache;
+#[cfg(feature = "pager")]
+pub use overlay::Overlay;
+pub use packed::MAX_PACKED_WIDTH;
+use packed::{PackedInsert, MAX_KEY_PREFIX, PACKED_KEY_SIZE};
+pub use plugin::{Comparator, CompareFn, MergeFn, MergeOperator};
+#[cfg(feature = "pager")]
+pub use snapshot::TreeSnapshot;
+pub use snapshot::{NodeSnapshot, SnapshotIter};
+#[cfg(feature = "trace")]
+pub use trace::{
+    read_trace, replay, replay_within, Divergence, MutationTrace, ReplayReport, TraceError,
+    TraceRecord,
+};
+#[cfg(feature = "wal")]
+pub use transaction::{ReadSet, Transaction};
+#[cfg(feature = "pager")]
+pub use tree::{
+    copy_range, create_tree, open_tree, BTree, HuskReport, LeafFragmentation, LeakReport,
+    OverwritePolicy, Stats,
+};
+#[cfg(feature = "pager")]
+pub use treecursor::TreeCursor;
+#[cfg(feature = "pager")]
+pub use treeiter::{TreeIter, TreeRange};
+#[cfg(feature = "pager")]
+pub use typed::{TypedTree, ValueRef};
+#[cfg(feature = "std")]
+pub use verify::{validate_file, validate_file_with_limits, validate_file_within};
+#[cfg(feature = "pager")]
+pub use verify::{verify_pages, PageReport, PageVerifier};
+pub use verify::{Issue, IssueKind, Report, ValidationLimits};
+pub use view::NodeView;
+#[cfg(feature = "std")]
+pub use watch::{KeyWatcher, WATCHED_KEYS};
+
+#[cfg(feature = "pager")]
+mod append;
+mod audit;
+mod batch;
+mod bytekeys;
+mod checksum;
+mod codec;
+mod compression;
+#[cfg(feature = "pager")]
+mod concurrent;
+mod config;
+mod cursor;
+mod dump;
+#[cfg(feature = "pager")]
+mod entry;
 mod errors;
+#[cfg(feature = "arrow")]
+mod export;
+mod fallible;
 mod freeblock;
 mod header;
+#[cfg(feature = "std")]
+mod heat;
+#[cfg(feature = "std")]
+mod history;
+#[cfg(feature = "sqlite")]
+mod import;
+mod internal;
+#[cfg(feature = "pager")]
+mod intersect;
 mod key;
-
+#[cfg(feature = "histogram")]
+mod latency;
+mod layout_asserts;
+mod merge;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "std")]
+mod negcache;
+#[cfg(feature = "pager")]
+mod overlay;
+mod packed;
+mod plugin;
+mod snapshot;
+#[cfg(all(test, feature = "wal"))]
+mod stress;
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "wal")]
+mod transaction;
+#[cfg(feature = "pager")]
+mod tree;
+#[cfg(feature = "pager")]
+mod treecursor;
+#[cfg(feature = "pager")]
+mod treeiter;
+#[cfg(feature = "pager")]
+mod typed;
+mod verify;
+mod view;
+#[cfg(feature = "std")]
+mod watch;
+
+/// Page size used unless a store is set up with a different one
 pub const PAGE_SIZE: u16 = 4096;
+/// Page sizes are powers of two in this range. Offsets within a page are u16, which rules
+/// out anything larger.
+pub const MIN_PAGE_SIZE: u16 = 512;
+pub const MAX_PAGE_SIZE: u16 = 32768;
+/// Largest value that fits into an otherwise empty page of the default size
+pub const MAX_VALUE_SIZE: u16 = PAGE_SIZE - HEADER_SIZE - KEY_SIZE;
+/// Alignment of value offsets with NodeConfig::align_values
+pub const VALUE_ALIGNMENT: u16 = 8;
+/// Most levels a tree can have. Pages are numbered by u32 and internal nodes split into halves
+/// of two children or more, so 2^32 pages fill 32 levels at most, twice that leaves room for
+/// nodes merges left thin. Trees never descend further, whatever their ResourceLimits say.
+pub const MAX_DEPTH: usize = 64;
+
+/// Fails with InvalidPageSize unless `size` is a supported page size
+pub fn check_page_size(size: usize) -> Result<u16, BTreeError> {
+    if !size.is_power_of_two() || size < MIN_PAGE_SIZE.into() || size > MAX_PAGE_SIZE.into() {
+        return Err(BTreeError::InvalidPageSize(size));
+    }
+    Ok(size as u16)
+}
 
 pub struct KeyValuePair {
     pub key: u64,
@@ -17,30 +172,123 @@ pub struct KeyValuePair {
 
 pub struct Node<'a> {
     page: &'a mut [u8],
+    config: NodeConfig,
+    #[cfg(feature = "std")]
+    watcher: Option<&'a KeyWatcher>,
+    #[cfg(feature = "std")]
+    negative_cache: Option<&'a NegativeCache>,
+    #[cfg(feature = "std")]
+    access_tracker: Option<(&'a AccessTracker, u32)>,
+    /// The page was changed, so its checksum has to be refreshed
+    dirty: bool,
+    /// Times the node was defragmented since it was loaded, for BTree::stats
+    defrags: u32,
+}
+
+/// Watchers, negative caches and access trackers need locks, without std there are none to
+/// keep up to date
+#[cfg(not(feature = "std"))]
+impl Node<'_> {
+    fn known_absent(&self, _key: u64) -> bool {
+        false
+    }
+
+    fn remember_absent(&self, _idx: u16) -> Result<(), BTreeError> {
+        Ok(())
+    }
+
+    fn invalidate_absent(&self, _key: u64) {}
+
+    fn notify_watcher(&self, _key: u64) {}
+
+    fn record_access(&self) {}
 }
 
 impl<'a> Node<'a> {
     pub fn new(page: &'a mut [u8]) -> Result<Self, BTreeError> {
-        debug_assert_eq!(page.len(), PAGE_SIZE.into());
+        Self::new_with_config(page, NodeConfig::default())
+    }
 
-        let mut node = Self { page };
+    pub fn new_with_config(page: &'a mut [u8], config: NodeConfig) -> Result<Self, BTreeError> {
+        let page_size = check_page_size(page.len())?;
+
+        let mut node = Self {
+            page,
+            config,
+            #[cfg(feature = "std")]
+            watcher: None,
+            #[cfg(feature = "std")]
+            negative_cache: None,
+            #[cfg(feature = "std")]
+            access_tracker: None,
+            dirty: true,
+            defrags: 0,
+        };
+        node.scrub(0, page_size.into());
 
         let header = node.mutate_header()?;
         header.node_type = NodeType::Leaf;
         header.num_keys = 0.into();
         header.free_start = HEADER_SIZE.into();
-        header.free_end = PAGE_SIZE.into();
         header.first_freeblock = 0.into();
         header.fragmented_bytes = 0;
         header.rightmost_child_page = 0.into();
+        header.flags = 0;
+        if config.checksums {
+            header.flags |= FLAG_CHECKSUM;
+        }
+        if config.reserve_tail {
+            header.flags |= FLAG_RESERVED_TAIL;
+        }
+        header.packed_width = 0;
+        header.free_end = header.content_end(page_size).into();
 
         Ok(node)
     }
 
     pub fn load(page: &'a mut [u8]) -> Result<Self, BTreeError> {
-        debug_assert_eq!(page.len(), PAGE_SIZE.into());
+        Self::load_with_config(page, NodeConfig::default())
+    }
 
-        Ok(Self { page })
+    /// Fails if `page` has an unsupported size or its header claims space beyond its end,
+    /// like the pages of a file with larger pages would
+    pub fn load_with_config(page: &'a mut [u8], config: NodeConfig) -> Result<Self, BTreeError> {
+        let page_size = check_page_size(page.len())?;
+
+        let node = Self {
+            page,
+            config,
+            #[cfg(feature = "std")]
+            watcher: None,
+            #[cfg(feature = "std")]
+            negative_cache: None,
+            #[cfg(feature = "std")]
+            access_tracker: None,
+            dirty: false,
+            defrags: 0,
+        };
+        if let Ok(header) = node.read_header() {
+            let (free_start, free_end) = (header.free_start.get(), header.free_end.get());
+            if free_end > page_size {
+                return Err(BTreeError::Corrupt(CorruptionError::FreeSpaceOutOfRange {
+                    free_start,
+                    free_end,
+                }));
+            }
+        }
+        if let Ok(header) = node.read_header() {
+            if header.has_byte_keys() && header.has_custom_order() != config.comparator.is_some() {
+                return Err(BTreeError::PluginMismatch {
+                    plugin: "comparator",
+                    expected: config.comparator.map(|c| c.name().to_owned()),
+                    found: header.has_custom_order().then(|| "a comparator".to_owned()),
+                });
+            }
+        }
+        if config.verify_checksums {
+            node.verify_checksum()?;
+        }
+        Ok(node)
     }
 
     fn get_page_slice(&self, offset: usize, len: usize) -> &[u8] {
@@ -55,6 +303,7 @@ impl<'a> Node<'a> {
     }
 
     fn get_mut_page_slice(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        self.dirty = true;
         debug_assert!(
             offset.checked_add(len).unwrap() <= self.page.len(),
             "Invalid page slice: offset {} + len {} exceeds page length {}",
@@ -65,6 +314,59 @@ impl<'a> Node<'a> {
         &mut self.page[offset..(offset + len)]
     }
 
+    /// Size of the page the node lives in
+    pub fn page_size(&self) -> u16 {
+        self.page.len() as u16
+    }
+
+    /// Largest value that fits into an otherwise empty page of this node's size
+    pub fn max_value_size(&self) -> u16 {
+        self.page_size() - HEADER_SIZE - KEY_SIZE
+    }
+
+    /// Most entries the node can ever hold, reached with empty values. Packed nodes fit more
+    /// as their slots leave out the value offset, and even more if they leave out a key prefix.
+    pub fn max_entries(&self) -> Result<u16, BTreeError> {
+        let packs = self.is_packed()? || (self.config.adaptive_layout && !self.config.align_values);
+        let slots = self.content_end()? - HEADER_SIZE;
+        if !packs {
+            return Ok(slots / KEY_SIZE);
+        }
+        if self.config.prefix_compression || self.read_header()?.key_prefix_len() > 0 {
+            // One byte suffixes tell at most 256 keys apart, two bytes do for any page size
+            let prefix_len = MAX_KEY_PREFIX as u16;
+            let one_byte = (slots - prefix_len).min(1 << u8::BITS);
+            let two_bytes = (slots - (prefix_len - 1)) / 2;
+            return Ok(one_byte.max(two_bytes));
+        }
+        Ok(slots / PACKED_KEY_SIZE)
+    }
+
+    /// Alignment of value offsets, 1 unless the config asks for aligned values
+    fn value_alignment(&self) -> u16 {
+        if self.config.align_values {
+            VALUE_ALIGNMENT
+        } else {
+            1
+        }
+    }
+
+    /// Bytes prepend_value leaves unused behind a value of `len` bytes to align it
+    fn value_padding(&self, len: u16) -> Result<u16, BTreeError> {
+        if len == 0 {
+            return Ok(0);
+        }
+        let free_end = self.read_header()?.free_end.get();
+        Ok(free_end.saturating_sub(len) % self.value_alignment())
+    }
+
+    /// Zeroes a released region when running in deterministic mode
+    fn scrub(&mut self, offset: usize, len: usize) {
+        if self.config.deterministic {
+            self.get_mut_page_slice(offset, len).fill(0);
+        }
+    }
+
     fn unallocated_space(&self) -> Result<u16, BTreeError> {
         let header = self.read_header()?;
         Ok(header.free_end.get() - header.free_start.get())
@@ -73,82 +375,205 @@ impl<'a> Node<'a> {
     fn free_space(&self) -> Result<u16, BTreeError> {
         let mut total_space = self.unallocated_space()?;
         total_space += self.read_header()?.fragmented_bytes as u16;
+        total_space += self.freeblock_bytes()?;
+        Ok(total_space)
+    }
 
+    /// Bytes in the freeblock chain, free but only usable by values that fit a freeblock
+    /// until the node is defragmented
+    pub fn freeblock_bytes(&self) -> Result<u16, BTreeError> {
+        let mut total = 0;
         let mut freeblock_offset = self.read_header()?.first_freeblock.get();
         while freeblock_offset != 0 {
             let freeblock = self.read_freeblock(freeblock_offset.into())?;
-            total_space += freeblock.size.get();
+            total += freeblock.size.get();
             freeblock_offset = freeblock.next_freeblock.get();
         }
-
-        Ok(total_space)
+        Ok(total)
     }
 
     pub fn get(&self, key: u64) -> Result<Option<&[u8]>, BTreeError> {
+        self.record_access();
+        if self.known_absent(key) {
+            return Ok(None);
+        }
         let (key_idx, exists) = self.find_le_key_idx(key)?;
         if !exists {
+            self.remember_absent(key_idx as u16)?;
             return Ok(None);
         }
 
-        let key = self.read_key_at(key_idx.try_into().unwrap())?;
-        Ok(Some(self.get_page_slice(
-            key.value_offset.get().into(),
-            key.value_len.get().into(),
-        )))
+        self.value_at(key_idx.try_into().unwrap()).map(Some)
+    }
+
+    /// Where in the page the value of `key` lies, for readers that borrow it from the page
+    #[cfg(feature = "pager")]
+    pub(crate) fn value_range(&self, key: u64) -> Result<Option<Range<usize>>, BTreeError> {
+        Ok(self.get(key)?.map(|value| {
+            let start = value.as_ptr() as usize - self.page.as_ptr() as usize;
+            start..start + value.len()
+        }))
+    }
+
+    /// Times the node was defragmented since it was loaded, on its own or through defrag
+    #[cfg(feature = "pager")]
+    pub(crate) fn defrags(&self) -> u32 {
+        self.defrags
     }
 
     pub fn defrag(&mut self) -> Result<(), BTreeError> {
+        self.defrag_with_policy(self.config.defrag_policy)
+    }
+
+    pub fn defrag_with_policy(&mut self, policy: DefragPolicy) -> Result<(), BTreeError> {
+        if self.is_packed()? {
+            return Ok(());
+        }
+        self.defrags += 1;
+        if self.has_byte_keys()? {
+            return self.compact_cells();
+        }
         let num_keys = { self.read_header()?.num_keys.get() };
 
         let mut total_used = 0;
-        let mut key_infos = Vec::with_capacity(num_keys.into());
+        // Index, old offset, length and new offset of every value
+        let mut key_infos = try_with_capacity(num_keys.into())?;
         for i in 0..num_keys {
             let key_record = self.read_key_at(i)?;
+            if key_record.is_inline() {
+                continue;
+            }
             let val_len = key_record.value_len.get() as usize;
             let old_offset = key_record.value_offset.get() as usize;
-            key_infos.push((i, old_offset, val_len));
+            key_infos.push((i, old_offset, val_len, 0));
             total_used += val_len;
         }
 
-        let mut buffer = vec![0u8; total_used];
-        let mut pos = 0;
-        for &(_idx, old_offset, val_len) in &key_infos {
-            let src_slice = self.get_page_slice(old_offset, val_len);
-            buffer[pos..pos + val_len].copy_from_slice(src_slice);
-            pos += val_len;
+        if policy == DefragPolicy::OriginalOffset {
+            key_infos.sort_unstable_by_key(|&(_idx, old_offset, _val_len, _offset)| old_offset);
         }
 
-        let new_free_end = PAGE_SIZE as usize - total_used;
+        // Values are laid out downwards from the end of the content area, the first one
+        // ending up lowest
+        let align = self.value_alignment() as usize;
+        let content_end = self.content_end()? as usize;
+        let free_start = self.read_header()?.free_start.get() as usize;
+        let mut new_free_end = content_end;
+        for (_idx, _old_offset, val_len, offset) in key_infos.iter_mut().rev() {
+            let val_len = *val_len;
+            if val_len == 0 {
+                // Same as in prepend_value
+                *offset = content_end;
+                continue;
+            }
+            new_free_end = (new_free_end - val_len) / align * align;
+            *offset = new_free_end;
+        }
+        if new_free_end < free_start {
+            // Only possible with padding, which the free space doesn't account for exactly
+            return Err(BTreeError::NotEnoughSpace {
+                required: content_end - new_free_end,
+                actual: content_end - free_start,
+            });
+        }
 
-        self.get_mut_page_slice(new_free_end, total_used)
+        let mut buffer = try_zeroed(content_end - new_free_end)?;
+        for &(_idx, old_offset, val_len, offset) in &key_infos {
+            let src_slice = self.get_page_slice(old_offset, val_len);
+            buffer[offset - new_free_end..][..val_len].copy_from_slice(src_slice);
+        }
+
+        self.scrub(free_start, new_free_end - free_start);
+        self.get_mut_page_slice(new_free_end, buffer.len())
             .copy_from_slice(&buffer);
 
-        pos = 0;
-        for &(idx, _old_offset, val_len) in &key_infos {
+        for &(idx, _old_offset, _val_len, offset) in &key_infos {
             let key_record = self.mut_key_at(idx)?;
-            key_record.value_offset.set((new_free_end + pos) as u16);
-            pos += val_len;
+            key_record.value_offset.set(offset as u16);
         }
 
+        let padding = buffer.len() - total_used;
         let header = self.mutate_header()?;
         header.free_end.set(new_free_end.try_into().unwrap());
         header.first_freeblock.set(0);
-        header.fragmented_bytes = 0;
+        header.fragmented_bytes = padding.min(u8::MAX.into()) as u8;
+
+        if self.config.adaptive_layout && !self.config.align_values {
+            self.pack()?;
+        }
+        self.audit_if_strict()
+    }
+
+    fn check_limits(&self, value_len: usize, new_key: bool) -> Result<(), BTreeError> {
+        let limits = self.config.limits;
+
+        let max_value_size = limits
+            .max_value_size
+            .map_or(self.max_value_size(), |limit| {
+                limit.min(self.max_value_size())
+            });
+        if value_len > max_value_size.into() {
+            return Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                limit: max_value_size.into(),
+                actual: value_len,
+            }));
+        }
+
+        if let Some(max_keys) = limits.max_keys {
+            if new_key && u32::from(self.read_header()?.num_keys.get()) >= max_keys {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxKeys {
+                    limit: max_keys,
+                }));
+            }
+        }
 
         Ok(())
     }
 
     pub fn insert(&mut self, key: u64, value: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
-        debug_assert!(value.len() < u16::MAX.into());
-        let value_len = value.len() as u16;
+        let old = self.insert_value(key, value)?;
+        self.audit_if_strict()?;
+        self.invalidate_absent(key);
+        self.notify_watcher(key);
+        self.record_access();
+        Ok(old)
+    }
 
+    fn insert_value(&mut self, key: u64, value: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
         let (key_idx, exists) = self.find_le_key_idx(key)?;
+        self.check_limits(value.len(), !exists)?;
+        // Space accounting alone should never let more in, fragmented bytes saturate though
+        if !exists && self.read_header()?.num_keys.get() >= self.max_entries()? {
+            return Err(BTreeError::NotEnoughSpace {
+                required: KEY_SIZE as usize + value.len(),
+                actual: self.free_space()?.into(),
+            });
+        }
+
+        match self.insert_packed(key_idx as u16, exists, key, value)? {
+            PackedInsert::Done(old) => return Ok(old),
+            PackedInsert::Unsupported => self.unpack()?,
+        }
+        let value_len = value.len() as u16;
 
         if exists {
-            todo!("If exists, replace. Remember to check if there is enough space, if old val was removed")
+            return self.replace_value(key_idx, key, value).map(Some);
         }
 
-        if self.unallocated_space()? > KEY_SIZE + value_len {
+        let is_leaf = self.read_header()?.node_type == NodeType::Leaf;
+        if self.config.inline_values
+            && is_leaf
+            && value_len <= MAX_INLINE_VALUE
+            && self.unallocated_space()? >= KEY_SIZE
+        {
+            let mut inline = [0u8; MAX_INLINE_VALUE as usize];
+            inline[..value.len()].copy_from_slice(value);
+            let idx = key_idx.try_into().unwrap();
+            self.insert_key_at(idx, key, u32::from_le_bytes(inline), 0, value_len)?;
+            return Ok(None);
+        }
+
+        if self.unallocated_space()? >= KEY_SIZE + value_len + self.value_padding(value_len)? {
             let offset = self.prepend_value(value)?;
             self.insert_key_at(key_idx.try_into().unwrap(), key, 0, offset, value_len)?;
             return Ok(None);
@@ -161,10 +586,13 @@ impl<'a> Node<'a> {
             });
         }
 
+        // A freeblock only takes the value, the key record still needs unallocated space
+        let key_fits = self.unallocated_space()? >= KEY_SIZE;
         let mut prev_freeblock_offset: Option<u16> = None;
         let mut current_freeblock_offset = self.read_header()?.first_freeblock.get();
 
-        while current_freeblock_offset != 0 {
+        // Freeblocks are rarely aligned, aligned values only go to the unallocated space
+        while key_fits && !self.config.align_values && current_freeblock_offset != 0 {
             let (freeblock_size, freeblock_next) = {
                 let freeblock = self.read_freeblock(current_freeblock_offset.into())?;
                 (freeblock.size.get(), freeblock.next_freeblock.get())
@@ -188,6 +616,10 @@ impl<'a> Node<'a> {
             } else {
                 let remaining_size = freeblock_size - value_len;
                 if remaining_size < FREEBLOCK_SIZE {
+                    self.scrub(
+                        (current_freeblock_offset + value_len).into(),
+                        remaining_size.into(),
+                    );
                     {
                         let header = self.mutate_header()?;
                         header.fragmented_bytes =
@@ -232,31 +664,102 @@ impl<'a> Node<'a> {
 
         self.defrag()?;
 
-        if self.unallocated_space()? > KEY_SIZE + value_len {
+        let padding = self.value_padding(value_len)?;
+        if self.unallocated_space()? >= KEY_SIZE + value_len + padding {
             let offset = self.prepend_value(value)?;
             self.insert_key_at(key_idx.try_into().unwrap(), key, 0, offset, value_len)?;
             Ok(None)
+        } else if self.config.align_values {
+            Err(BTreeError::NotEnoughSpace {
+                required: (KEY_SIZE + value_len + padding).into(),
+                actual: self.unallocated_space()?.into(),
+            })
         } else {
             panic!("Defragging didn't give back the required space. This should have been the case, as there was enough free space just before")
         }
     }
 
+    fn replace_value(
+        &mut self,
+        idx: usize,
+        key: u64,
+        value: &[u8],
+    ) -> Result<KeyValuePair, BTreeError> {
+        let old_key = self.read_key_at(idx as u16)?.clone();
+
+        // Values of the same size are overwritten where they are
+        if old_key.value_len.get() as usize == value.len() {
+            if old_key.is_inline() {
+                let mut inline = [0u8; MAX_INLINE_VALUE as usize];
+                inline[..value.len()].copy_from_slice(value);
+                self.mut_key_at(idx as u16)?
+                    .left_child_page
+                    .set(u32::from_le_bytes(inline));
+                return Ok(KeyValuePair {
+                    key,
+                    value: old_key.inline_value().to_vec(),
+                });
+            }
+            let offset = old_key.value_offset.get().into();
+            let old_value = try_to_vec(self.get_page_slice(offset, value.len()))?;
+            self.get_mut_page_slice(offset, value.len())
+                .copy_from_slice(value);
+            return Ok(KeyValuePair {
+                key,
+                value: old_value,
+            });
+        }
+
+        // Otherwise the old value is released and the new one placed like a fresh insert.
+        // If it doesn't fit after all, the node is put back the way it was.
+        let backup = try_to_vec(self.page)?;
+        let old = self.delete_at_idx(idx)?;
+        if let Err(err) = self.insert_value(key, value) {
+            self.page.copy_from_slice(&backup);
+            return Err(err);
+        }
+        Ok(old)
+    }
+
+    /// Stores the merge of the value of `key`, if any, and `operand` through the configured
+    /// merge operator
+    pub fn merge(&mut self, key: u64, operand: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
+        let operator = self
+            .config
+            .merge_operator
+            .ok_or(BTreeError::NoMergeOperator)?;
+        let merged = operator.merge(key, self.get(key)?, operand, self.max_value_size().into())?;
+        self.insert(key, &merged)
+    }
+
     pub fn delete(&mut self, key: u64) -> Result<Option<KeyValuePair>, BTreeError> {
+        self.record_access();
         let (key_idx, found) = self.find_le_key_idx(key)?;
         if !found {
             return Ok(None);
         }
-        Ok(Some(self.delete_at_idx(key_idx)?))
+        if self.is_packed()? {
+            return Ok(Some(self.delete_packed(key_idx as u16)?));
+        }
+        let deleted = self.delete_at_idx(key_idx)?;
+        self.audit_if_strict()?;
+        Ok(Some(deleted))
     }
 
     fn delete_at_idx(&mut self, idx: usize) -> Result<KeyValuePair, BTreeError> {
+        // The value is copied out before anything changes
+        let deleted_val = try_to_vec(self.value_at(idx as u16)?)?;
         let deleted_key = self.pop_key_at(idx as u16)?;
-        let deleted_val = self
-            .get_page_slice(
-                deleted_key.value_offset.get() as usize,
-                deleted_key.value_len.get() as usize,
-            )
-            .to_owned();
+        if deleted_key.is_inline() {
+            return Ok(KeyValuePair {
+                key: deleted_key.key.get(),
+                value: deleted_val,
+            });
+        }
+        self.scrub(
+            deleted_key.value_offset.get().into(),
+            deleted_key.value_len.get().into(),
+        );
 
         // Value is at border. We dont have to care about freeblocks and just reclaim space
         if deleted_key.value_offset == self.read_header()?.free_end {
@@ -308,18 +811,26 @@ impl<'a> Node<'a> {
     }
 
     fn prepend_value(&mut self, value: &[u8]) -> Result<u16, BTreeError> {
-        debug_assert!(self.unallocated_space()? as usize >= value.len());
         debug_assert!(value.len() < u16::MAX as usize);
+        // Empty values point at the end of the content area, at free_end they would end up
+        // out of bounds once the value above them is deleted and free_end moves past them
+        if value.is_empty() {
+            return self.content_end();
+        }
+        let padding = self.value_padding(value.len() as u16)? as usize;
+        debug_assert!(self.unallocated_space()? as usize >= value.len() + padding);
 
         let header = self.read_header()?;
         let free_end = header.free_end.get() as usize;
-        let new_free_end = free_end - value.len();
+        let new_free_end = free_end - value.len() - padding;
 
         self.get_mut_page_slice(new_free_end, value.len())
             .copy_from_slice(value);
+        self.scrub(new_free_end + value.len(), padding);
 
         let mut_header = self.mutate_header()?;
         mut_header.free_end.set(new_free_end.try_into().unwrap());
+        mut_header.fragmented_bytes = mut_header.fragmented_bytes.saturating_add(padding as u8);
         Ok(new_free_end as u16)
     }
 }
@@ -471,7 +982,7 @@ mod tests {
             expected_free_space += KEY_SIZE + value_len;
             assert_eq!(node.free_space().unwrap(), expected_free_space);
         }
-        assert_eq!(node.unallocated_space().unwrap(), 4037);
+        assert_eq!(node.unallocated_space().unwrap(), initial_free - 45);
         assert_eq!(node.free_space().unwrap(), initial_free);
     }
 
@@ -519,6 +1030,50 @@ mod tests {
         assert_eq!(node.get(2).unwrap(), None);
     }
 
+    #[test]
+    fn test_get_reports_corruption() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        node.insert(1, b"abekat").unwrap();
+        node.insert(2, b"giraf").unwrap();
+
+        // A value pointing into the unallocated space
+        node.mut_key_at(0)
+            .unwrap()
+            .value_offset
+            .set(HEADER_SIZE + 100);
+        assert!(matches!(
+            node.get(1),
+            Err(BTreeError::Corrupt(CorruptionError::ValueOutOfBounds {
+                index: 0,
+                ..
+            }))
+        ));
+        // A value running past the page end
+        node.mut_key_at(0).unwrap().value_offset.set(PAGE_SIZE - 2);
+        assert!(node.get(1).is_err());
+        assert_eq!(node.get(2).unwrap().unwrap(), b"giraf");
+        assert_eq!(node.get(3).unwrap(), None);
+
+        // A freeblock chain leading past the page end
+        node.mutate_header()
+            .unwrap()
+            .first_freeblock
+            .set(PAGE_SIZE - 2);
+        assert!(matches!(
+            node.free_space(),
+            Err(BTreeError::InvalidOffset { offset, .. }) if offset == PAGE_SIZE as usize - 2
+        ));
+        node.mutate_header().unwrap().first_freeblock.set(0);
+
+        // A key count larger than the slot array
+        node.mutate_header().unwrap().num_keys.set(u16::MAX);
+        assert!(matches!(
+            node.get(3),
+            Err(BTreeError::Corrupt(CorruptionError::SlotOutOfBounds { .. }))
+        ));
+    }
+
     #[test]
     fn test_defrag_with_multiple_freeblocks() {
         let mut page = [0u8; PAGE_SIZE as usize];
@@ -623,4 +1178,396 @@ mod tests {
         let stored_value = node.get(101).unwrap().unwrap();
         assert_eq!(stored_value, value.as_slice());
     }
+
+    #[test]
+    fn test_deterministic_mode_ignores_stale_bytes() {
+        let config = NodeConfig {
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut first = [0xAAu8; PAGE_SIZE as usize];
+        let mut second = [0x55u8; PAGE_SIZE as usize];
+
+        for page in [&mut first, &mut second] {
+            let mut node = Node::new_with_config(page, config).unwrap();
+            for key in 1..=20 {
+                let value = vec![key as u8; (key * 3) as usize];
+                node.insert(key, &value).unwrap();
+            }
+            for key in (1..=20).filter(|k| k % 4 == 0) {
+                node.delete(key).unwrap();
+            }
+            node.insert(100, b"xyz").unwrap();
+            node.defrag().unwrap();
+            node.delete(1).unwrap();
+        }
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = NodeConfig {
+            limits: Limits {
+                max_keys: Some(2),
+                max_value_size: Some(8),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+
+        node.insert(1, b"12345678").unwrap();
+        assert!(matches!(
+            node.insert(2, b"123456789"),
+            Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                limit: 8,
+                actual: 9
+            }))
+        ));
+
+        node.insert(2, b"two").unwrap();
+        assert!(matches!(
+            node.insert(3, b"three"),
+            Err(BTreeError::LimitExceeded(LimitError::MaxKeys { limit: 2 }))
+        ));
+
+        node.delete(1).unwrap();
+        node.insert(3, b"three").unwrap();
+    }
+
+    #[test]
+    fn test_value_larger_than_page() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+
+        let value = vec![0u8; PAGE_SIZE as usize];
+        assert!(matches!(
+            node.insert(1, &value),
+            Err(BTreeError::LimitExceeded(LimitError::MaxValueSize { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_defrag_policies() {
+        let value_offsets = |node: &Node, keys: &[u64]| -> Vec<u16> {
+            keys.iter()
+                .map(|&key| {
+                    let (idx, _) = node.find_le_key_idx(key).unwrap();
+                    node.read_key_at(idx as u16).unwrap().value_offset.get()
+                })
+                .collect()
+        };
+
+        for policy in [DefragPolicy::KeyOrder, DefragPolicy::OriginalOffset] {
+            let mut page = [0u8; PAGE_SIZE as usize];
+            let mut node = Node::new(&mut page).unwrap();
+
+            // Values are prepended, so insertion order is descending offset order
+            for key in [30, 10, 40, 20] {
+                node.insert(key, &[key as u8; 20]).unwrap();
+            }
+            node.delete(40).unwrap();
+            node.defrag_with_policy(policy).unwrap();
+
+            let offsets = value_offsets(&node, &[30, 10, 20]);
+            match policy {
+                DefragPolicy::KeyOrder => {
+                    let by_key = value_offsets(&node, &[10, 20, 30]);
+                    assert!(by_key.windows(2).all(|w| w[0] < w[1]));
+                }
+                DefragPolicy::OriginalOffset => {
+                    assert!(offsets.windows(2).all(|w| w[0] > w[1]));
+                }
+            }
+            for key in [10, 20, 30] {
+                assert_eq!(node.get(key).unwrap().unwrap(), [key as u8; 20]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_small_values() {
+        let config = NodeConfig {
+            inline_values: true,
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+
+        node.insert(1, b"a").unwrap();
+        node.insert(2, b"abcd").unwrap();
+        node.insert(3, b"abcde").unwrap();
+        node.insert(4, b"").unwrap();
+
+        // Only the 5 byte value ends up in the content area
+        assert_eq!(node.read_header().unwrap().free_end.get(), PAGE_SIZE - 5);
+        assert!(node.read_key_at(1).unwrap().is_inline());
+        assert!(!node.read_key_at(2).unwrap().is_inline());
+
+        assert_eq!(node.get(1).unwrap().unwrap(), b"a");
+        assert_eq!(node.get(2).unwrap().unwrap(), b"abcd");
+        assert_eq!(node.get(3).unwrap().unwrap(), b"abcde");
+        assert_eq!(node.get(4).unwrap().unwrap(), b"");
+
+        assert_eq!(node.delete(2).unwrap().unwrap().value, b"abcd");
+        assert_eq!(node.read_header().unwrap().fragmented_bytes, 0);
+
+        node.defrag().unwrap();
+        let entries: Vec<_> = node
+            .iter_snapshot()
+            .iter()
+            .map(|(k, v)| (k, v.to_vec()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(1, b"a".to_vec()), (3, b"abcde".to_vec()), (4, vec![])]
+        );
+        drop(node);
+        #[cfg(feature = "std")]
+        assert!(validate_file(&page[..]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_replace_existing_key() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+
+        node.insert(1, b"first").unwrap();
+        node.insert(2, b"second").unwrap();
+        node.insert(3, b"third").unwrap();
+        let free_space = node.free_space().unwrap();
+
+        let old = node.insert(2, b"SECOND").unwrap().unwrap();
+        assert_eq!((old.key, old.value), (2, b"second".to_vec()));
+        assert_eq!(node.free_space().unwrap(), free_space);
+
+        let old = node
+            .insert(2, b"a much longer second value")
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.value, b"SECOND".to_vec());
+        assert_eq!(node.free_space().unwrap(), free_space - 20);
+
+        let old = node.insert(2, b"2").unwrap().unwrap();
+        assert_eq!(old.value, b"a much longer second value".to_vec());
+        assert_eq!(node.free_space().unwrap(), free_space + 5);
+
+        assert_eq!(node.read_header().unwrap().num_keys.get(), 3);
+        assert_eq!(node.get(1).unwrap(), Some(b"first".as_slice()));
+        assert_eq!(node.get(2).unwrap(), Some(b"2".as_slice()));
+        assert_eq!(node.get(3).unwrap(), Some(b"third".as_slice()));
+        drop(node);
+        #[cfg(feature = "std")]
+        assert!(validate_file(&page[..]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_replace_inline_value() {
+        let config = NodeConfig {
+            inline_values: true,
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+
+        node.insert(1, b"ab").unwrap();
+        assert_eq!(node.insert(1, b"cd").unwrap().unwrap().value, b"ab");
+        assert_eq!(node.insert(1, b"longer").unwrap().unwrap().value, b"cd");
+        assert!(!node.read_key_at(0).unwrap().is_inline());
+        assert_eq!(node.insert(1, b"x").unwrap().unwrap().value, b"longer");
+        assert!(node.read_key_at(0).unwrap().is_inline());
+        assert_eq!(node.get(1).unwrap(), Some(b"x".as_slice()));
+    }
+
+    #[test]
+    fn test_replace_without_space_keeps_old_value() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+
+        let mut key = 0;
+        while node.insert(key, &[7; 100]).is_ok() {
+            key += 1;
+        }
+        let free_space = node.free_space().unwrap();
+
+        assert!(matches!(
+            node.insert(0, &[8; 100 + PAGE_SIZE as usize / 2]),
+            Err(BTreeError::NotEnoughSpace { .. })
+        ));
+        assert_eq!(node.get(0).unwrap(), Some([7; 100].as_slice()));
+        assert_eq!(node.free_space().unwrap(), free_space);
+
+        let grown = vec![9; 100 + free_space as usize];
+        assert!(node.insert(0, &grown).unwrap().is_some());
+        assert_eq!(node.get(0).unwrap(), Some(grown.as_slice()));
+        assert_eq!(node.get(key - 1).unwrap(), Some([7; 100].as_slice()));
+    }
+
+    #[test]
+    fn page_size_is_checked_on_load() {
+        let mut small = [0u8; 1000];
+        assert!(matches!(
+            Node::new(&mut small),
+            Err(BTreeError::InvalidPageSize(1000))
+        ));
+
+        let mut large = vec![0u8; 8192];
+        let mut node = Node::new(&mut large).unwrap();
+        assert_eq!(node.max_value_size(), 8192 - HEADER_SIZE - KEY_SIZE);
+        node.insert(1, &[1; 6000]).unwrap();
+        assert_eq!(node.get(1).unwrap(), Some([1; 6000].as_slice()));
+        drop(node);
+
+        Node::new(&mut large).unwrap();
+        // The first half of a large page claims space beyond a default sized buffer
+        let mut truncated = [0u8; PAGE_SIZE as usize];
+        truncated.copy_from_slice(&large[..PAGE_SIZE as usize]);
+        assert!(matches!(
+            Node::load(&mut truncated),
+            Err(BTreeError::Corrupt(CorruptionError::FreeSpaceOutOfRange {
+                free_end: 8192,
+                ..
+            }))
+        ));
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn test_aligned_values() {
+        #[derive(zerocopy::FromBytes, zerocopy::Immutable, zerocopy::KnownLayout)]
+        #[repr(C)]
+        struct Sample {
+            id: u64,
+            total: u64,
+        }
+
+        let config = NodeConfig {
+            align_values: true,
+            adaptive_layout: true,
+            ..Default::default()
+        };
+        // A u64 buffer, so aligned offsets are aligned addresses too
+        let mut words = [0u64; PAGE_SIZE as usize / 8];
+        let page = zerocopy::IntoBytes::as_mut_bytes(&mut words[..]);
+        let mut node = Node::new_with_config(page, config).unwrap();
+
+        let sample = |key: u64| {
+            let mut value = Vec::new();
+            value.extend_from_slice(&key.to_ne_bytes());
+            value.extend_from_slice(&(key * 3).to_ne_bytes());
+            value.resize(16 + key as usize % 7, 0);
+            value
+        };
+        for key in 0..60 {
+            node.insert(key, &sample(key)).unwrap();
+        }
+        for key in (0..60).step_by(3) {
+            node.delete(key).unwrap();
+        }
+        let free_space = node.free_space().unwrap();
+        node.defrag().unwrap();
+        // Fragmented bytes saturate, so padding may have been undercounted before
+        assert!(node.free_space().unwrap() >= free_space);
+        assert!(node.read_header().unwrap().fragmented_bytes > 0);
+        for key in 60..100 {
+            node.insert(key, &sample(key)).unwrap();
+        }
+
+        for key in (0..100).filter(|key| key % 3 != 0 || *key >= 60) {
+            let range = node.value_range(key).unwrap().unwrap();
+            assert_eq!(range.start % VALUE_ALIGNMENT as usize, 0);
+            let value = node.get(key).unwrap().unwrap();
+            let (sample, _) = zerocopy::FromBytes::ref_from_prefix(value).unwrap();
+            let Sample { id, total } = sample;
+            assert_eq!((*id, *total), (key, key * 3));
+        }
+        drop(node);
+        assert!(validate_file(zerocopy::IntoBytes::as_bytes(&words[..]))
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_density_limit_with_tiny_values() {
+        let content = (PAGE_SIZE - HEADER_SIZE) as usize;
+        for (config, len, expected) in [
+            (NodeConfig::default(), 0, content / KEY_SIZE as usize),
+            (NodeConfig::default(), 1, content / (KEY_SIZE as usize + 1)),
+            (
+                NodeConfig {
+                    inline_values: true,
+                    ..Default::default()
+                },
+                1,
+                content / KEY_SIZE as usize,
+            ),
+            (
+                NodeConfig {
+                    adaptive_layout: true,
+                    ..Default::default()
+                },
+                1,
+                content / (PACKED_KEY_SIZE as usize + 1),
+            ),
+        ] {
+            let mut page = [0u8; PAGE_SIZE as usize];
+            let mut node = Node::new_with_config(&mut page, config).unwrap();
+            let max_entries = node.max_entries().unwrap() as usize;
+            let mut key = 0;
+            while node.insert(key * 2, &vec![1; len]).is_ok() {
+                key += 1;
+            }
+            assert_eq!(key as usize, expected);
+            assert!(expected <= max_entries);
+            assert!(matches!(
+                node.insert(1, &vec![2; len]),
+                Err(BTreeError::NotEnoughSpace { .. })
+            ));
+            // A different width doesn't fit the offset layout of a full packed node either
+            assert!(node.insert(1, &[2; 2]).is_err());
+
+            // The freed slot takes exactly one entry again
+            node.delete(10).unwrap();
+            node.insert(11, &vec![3; len]).unwrap();
+            assert!(node.insert(13, &vec![3; len]).is_err());
+            assert_eq!(node.get(11).unwrap(), Some(vec![3; len].as_slice()));
+            assert_eq!(
+                node.get(key * 2 - 2).unwrap(),
+                Some(vec![1; len].as_slice())
+            );
+            drop(node);
+            #[cfg(feature = "std")]
+            assert!(validate_file(&page[..]).unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_failed_allocations_leave_node_unchanged() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in 0..15 {
+            node.insert(key, &[key as u8; 150]).unwrap();
+        }
+        node.insert(20, &[20; 1200]).unwrap();
+        node.delete(5).unwrap();
+        let before = node.page.to_vec();
+
+        let limit = fallible::tests::AllocationLimit::new(1000);
+        for result in [
+            node.defrag().map(|_| ()),
+            node.insert(3, &[0; 10]).map(|_| ()),
+            node.delete(20).map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(BTreeError::OutOfMemory { .. })));
+            assert_eq!(node.page, before.as_slice());
+        }
+        // Small copies still work
+        assert_eq!(node.delete(6).unwrap().unwrap().value, [6; 150]);
+        drop(limit);
+
+        node.defrag().unwrap();
+        assert_eq!(node.get(20).unwrap(), Some([20; 1200].as_slice()));
+    }
 }
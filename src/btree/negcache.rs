@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::errors::BTreeError;
+use super::Node;
+
+/// Remembers key ranges recently proven absent, so repeated probes for missing keys skip
+/// the search. A miss proves the whole gap between its neighbouring keys absent, which is
+/// what gets cached. Writes invalidate every range containing the written key.
+pub struct NegativeCache {
+    capacity: usize,
+    ranges: Mutex<VecDeque<RangeInclusive<u64>>>,
+    hits: AtomicU64,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ranges: Mutex::new(VecDeque::with_capacity(capacity)),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        let ranges = self.ranges.lock().expect("NegativeCache lock poisoned");
+        let hit = ranges.iter().any(|range| range.contains(&key));
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    /// Caches `range` as absent, evicting the oldest range when full
+    pub fn insert(&self, range: RangeInclusive<u64>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut ranges = self.ranges.lock().expect("NegativeCache lock poisoned");
+        if ranges.len() == self.capacity {
+            ranges.pop_front();
+        }
+        ranges.push_back(range);
+    }
+
+    pub fn invalidate(&self, key: u64) {
+        let mut ranges = self.ranges.lock().expect("NegativeCache lock poisoned");
+        ranges.retain(|range| !range.contains(&key));
+    }
+
+    /// Forgets every range, for writes that bring back keys wholesale like rollbacks
+    pub fn clear(&self) {
+        self.ranges
+            .lock()
+            .expect("NegativeCache lock poisoned")
+            .clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges
+            .lock()
+            .expect("NegativeCache lock poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lookups answered by the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Answers lookups of keys proven absent from `cache` and keeps it up to date
+    pub fn with_negative_cache(mut self, cache: &'a NegativeCache) -> Self {
+        self.negative_cache = Some(cache);
+        self
+    }
+
+    pub(crate) fn known_absent(&self, key: u64) -> bool {
+        self.negative_cache.is_some_and(|cache| cache.contains(key))
+    }
+
+    /// Caches the gap around insertion point `idx` of a missing key as absent
+    pub(crate) fn remember_absent(&self, idx: u16) -> Result<(), BTreeError> {
+        if let Some(cache) = self.negative_cache {
+            cache.insert(self.absent_gap(idx)?);
+        }
+        Ok(())
+    }
+
+    /// The keys between the neighbours of insertion point `idx`, none of them is in the node
+    pub(crate) fn absent_gap(&self, idx: u16) -> Result<RangeInclusive<u64>, BTreeError> {
+        let num_keys = self.read_header()?.num_keys.get();
+        let low = match idx {
+            0 => 0,
+            _ => self.key_at(idx - 1)? + 1,
+        };
+        let high = if idx == num_keys {
+            u64::MAX
+        } else {
+            self.key_at(idx)? - 1
+        };
+        Ok(low..=high)
+    }
+
+    pub(crate) fn invalidate_absent(&self, key: u64) {
+        if let Some(cache) = self.negative_cache {
+            cache.invalidate(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn misses_are_cached_per_gap() {
+        let cache = NegativeCache::new(8);
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap().with_negative_cache(&cache);
+
+        node.insert(10, b"ten").unwrap();
+        node.insert(20, b"twenty").unwrap();
+
+        assert_eq!(node.get(15).unwrap(), None);
+        assert!(cache.contains(11));
+        assert!(cache.contains(19));
+        assert!(!cache.contains(10));
+        assert!(!cache.contains(20));
+
+        let hits = cache.hits();
+        assert_eq!(node.get(12).unwrap(), None);
+        assert_eq!(cache.hits(), hits + 1);
+
+        node.insert(12, b"twelve").unwrap();
+        assert!(!cache.contains(15));
+        assert_eq!(node.get(12).unwrap(), Some(b"twelve".as_slice()));
+
+        assert_eq!(node.get(5).unwrap(), None);
+        assert_eq!(node.get(u64::MAX).unwrap(), None);
+        assert!(cache.contains(0) && cache.contains(21));
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn tree_gets_go_through_the_cache() {
+        use super::super::BTree;
+        use crate::log::WalStore;
+        use crate::page::MemoryStore;
+        use std::sync::Arc;
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let wal = WalStore::open(
+            MemoryStore::new(PAGE_SIZE.into()),
+            log_path.to_str().unwrap(),
+        );
+        let cache = Arc::new(NegativeCache::new(2000));
+        let mut tree = BTree::create(wal.unwrap())
+            .unwrap()
+            .with_negative_cache(Arc::clone(&cache));
+        for key in 0..1000 {
+            tree.insert(key * 10, b"value").unwrap();
+        }
+        tree.commit().unwrap();
+
+        assert_eq!(tree.get(15).unwrap(), None);
+        assert!(cache.contains(11) && cache.contains(19));
+        let hits = cache.hits();
+        assert_eq!(tree.get(12).unwrap(), None);
+        assert_eq!(cache.hits(), hits + 1);
+        tree.insert(12, b"twelve").unwrap();
+        assert_eq!(tree.get(12).unwrap(), Some(b"twelve".to_vec()));
+
+        // Gaps at the edges of leaves end where the leaf does
+        for key in (5..10_000).step_by(10) {
+            assert_eq!(tree.get(key).unwrap(), None);
+        }
+        for key in (0..10_000).step_by(10) {
+            assert!(!cache.contains(key), "key {key}");
+        }
+
+        // A rename writes its new key like an insert
+        assert!(cache.contains(13));
+        assert!(tree.rename(10, 13).unwrap());
+        assert_eq!(tree.get(13).unwrap(), Some(b"value".to_vec()));
+
+        // Rolled back deletes are found again
+        tree.delete(20).unwrap();
+        assert_eq!(tree.get(20).unwrap(), None);
+        tree.rollback();
+        assert_eq!(tree.get(20).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let cache = NegativeCache::new(2);
+        for key in 0..5 {
+            cache.insert(key * 10..=key * 10 + 5);
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(0));
+        assert!(cache.contains(45));
+    }
+}
@@ -0,0 +1,181 @@
+/*
+Speculative writes over a tree. An overlay buffers inserts and deletes in memory, keyed like
+the tree, and reads through it look at the buffered writes first and at the tree after, so
+code can try changes out and look at the result without touching a single page. apply writes
+the buffered changes to the tree in key order, discard (or dropping the overlay) forgets them.
+
+Unlike a transaction the overlay doesn't need a WalStore, the writes never reach the store
+before apply. apply itself isn't atomic: an error stops it with the writes before it done,
+over a WalStore apply in a transaction to get all or nothing.
+*/
+
+use std::collections::BTreeMap;
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::merge::merge_sorted;
+use super::BTree;
+use crate::page::PageStore;
+
+/// Writes buffered over a tree, see BTree::overlay
+pub struct Overlay<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    /// The last write of every key by encoded key, `None` for a delete
+    writes: BTreeMap<u64, Option<Vec<u8>>>,
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// An empty overlay over the tree
+    pub fn overlay(&mut self) -> Overlay<'_, S, K> {
+        Overlay {
+            tree: self,
+            writes: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Overlay<'_, S, K> {
+    pub fn insert(&mut self, key: K, value: &[u8]) -> &mut Self {
+        self.writes.insert(key.encode(), Some(value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: K) -> &mut Self {
+        self.writes.insert(key.encode(), None);
+        self
+    }
+
+    /// The value of `key` as if the overlay was applied
+    pub fn get(&mut self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        match self.writes.get(&key.encode()) {
+            Some(write) => Ok(write.clone()),
+            None => self.tree.get(key),
+        }
+    }
+
+    /// Every entry of the tree as if the overlay was applied, in key order
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<(K, Vec<u8>), BTreeError>> + '_ {
+        let writes = self
+            .writes
+            .iter()
+            .map(|(key, value)| Ok((K::decode(*key), value.clone())));
+        let stored = self
+            .tree
+            .iter()
+            .map(|entry| entry.map(|(key, value)| (key, Some(value))));
+        let sources: [Box<dyn Iterator<Item = _>>; 2] = [Box::new(writes), Box::new(stored)];
+        // The overlay comes first and wins, its deletes hide the tree's entries
+        merge_sorted(sources, |_, versions| {
+            versions.into_iter().next().and_then(|(_, value)| value)
+        })
+    }
+
+    /// The buffered writes in key order, `None` for deletes
+    pub fn writes(&self) -> impl Iterator<Item = (K, Option<&[u8]>)> {
+        self.writes
+            .iter()
+            .map(|(key, value)| (K::decode(*key), value.as_deref()))
+    }
+
+    /// Number of keys written
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Writes the buffered changes to the tree
+    pub fn apply(self) -> Result<(), BTreeError> {
+        for (key, value) in self.writes {
+            let key = K::decode(key);
+            match value {
+                Some(value) => self.tree.insert(key, &value).map(|_| ())?,
+                None => self.tree.delete(key).map(|_| ())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the buffered changes, like dropping the overlay does
+    pub fn discard(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn tree() -> BTree<MemoryStore> {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &[1; 100]).unwrap();
+        }
+        tree.reset_stats();
+        tree
+    }
+
+    fn keys(entries: impl Iterator<Item = Result<(u64, Vec<u8>), BTreeError>>) -> Vec<u64> {
+        entries.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn reads_see_the_overlay_first() {
+        let mut tree = tree();
+        let mut overlay = tree.overlay();
+        overlay
+            .insert(5, &[2])
+            .delete(6)
+            .insert(2000, &[3])
+            .delete(3000);
+        overlay.delete(7).insert(7, &[4]);
+
+        assert_eq!(overlay.get(5).unwrap(), Some(vec![2]));
+        assert_eq!(overlay.get(6).unwrap(), None);
+        assert_eq!(overlay.get(7).unwrap(), Some(vec![4]));
+        assert_eq!(overlay.get(8).unwrap(), Some(vec![1; 100]));
+        assert_eq!(overlay.get(2000).unwrap(), Some(vec![3]));
+        assert_eq!(overlay.len(), 5);
+        assert_eq!(
+            overlay.writes().collect::<Vec<_>>(),
+            [
+                (5, Some(&[2][..])),
+                (6, None),
+                (7, Some(&[4][..])),
+                (2000, Some(&[3][..])),
+                (3000, None)
+            ]
+        );
+
+        let mut expected: Vec<u64> = (0..1000).filter(|&key| key != 6).collect();
+        expected.push(2000);
+        assert_eq!(keys(overlay.iter()), expected);
+        assert_eq!(overlay.iter().nth(5).unwrap().unwrap(), (5, vec![2]));
+
+        // Nothing reached the tree
+        overlay.discard();
+        assert_eq!(tree.get(5).unwrap(), Some(vec![1; 100]));
+        assert_eq!(tree.get(2000).unwrap(), None);
+        assert_eq!(tree.stats().unwrap().page_writes, 0);
+    }
+
+    #[test]
+    fn applied_writes_reach_the_tree() {
+        let mut tree = tree();
+        let mut overlay = tree.overlay();
+        for key in (0..1000).step_by(2) {
+            overlay.delete(key);
+        }
+        overlay.insert(1, &[5; 10]).insert(1001, &[6]);
+        let expected = keys(overlay.iter());
+        overlay.apply().unwrap();
+
+        assert_eq!(keys(tree.iter()), expected);
+        assert_eq!(tree.get(1).unwrap(), Some(vec![5; 10]));
+        assert_eq!(tree.get(2).unwrap(), None);
+        assert!(tree.overlay().is_empty());
+    }
+}
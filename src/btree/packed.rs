@@ -0,0 +1,541 @@
+/*
+Packed layout for leaves full of tiny values of the same width. Instead of a key record pointing
+into the content area, every slot holds the key directly followed by its value
+-------------------------------------------------------------------------
+| header | key (8 bytes) | value (packed_width bytes) | ... | free space |
+-------------------------------------------------------------------------
+All slots have the same size, so lookups are still a binary search. With adaptive_layout enabled
+a node starts out packed and switches to the offset layout as soon as a value of a different
+width shows up. Defragmenting switches it back once all values share a width again.
+
+With prefix_compression the high bytes every key shares are stored once, between the header
+and the slots, and slots only keep the rest of the key
+-------------------------------------------------------------------------------------
+| header | prefix (n bytes) | key suffix (8 - n bytes) | value | ... | free space |
+-------------------------------------------------------------------------------------
+Keys are little endian, so the suffix is the start of the key and the prefix its end. A key
+that doesn't share the prefix rewrites the slots with a shorter one, defrag doesn't touch
+packed nodes, so the prefix only grows when a node is packed again or starts over empty.
+*/
+
+use alloc::vec::Vec;
+
+use super::errors::BTreeError;
+use super::fallible::{try_to_vec, try_with_capacity};
+use super::header::{Header, FLAG_PACKED, HEADER_SIZE};
+use super::key::KEY_SIZE;
+use super::{KeyValuePair, Node};
+
+pub const PACKED_KEY_SIZE: u16 = size_of::<u64>() as u16;
+pub const MAX_PACKED_WIDTH: u8 = 16;
+/// Slots keep at least one byte of their key
+pub const MAX_KEY_PREFIX: u8 = 7;
+
+pub(crate) enum PackedInsert {
+    Done(Option<KeyValuePair>),
+    /// The value can't be stored in the packed layout, the node has to be unpacked
+    Unsupported,
+}
+
+/// Number of high bytes `a` and `b` share, at most MAX_KEY_PREFIX
+pub(crate) fn shared_prefix_len(a: u64, b: u64) -> u8 {
+    ((a ^ b).leading_zeros() / u8::BITS).min(MAX_KEY_PREFIX.into()) as u8
+}
+
+/// Key and value of packed slot `index` of `page`, the key put back together from its suffix
+/// and the node's prefix. The slot has to lie within the page.
+pub(crate) fn packed_entry<'p>(page: &'p [u8], header: &Header, index: u16) -> (u64, &'p [u8]) {
+    let prefix_len = header.key_prefix_len() as usize;
+    let suffix_len = PACKED_KEY_SIZE as usize - prefix_len;
+    let slot_size = header.slot_size() as usize;
+    let pos = header.slots_start() as usize + slot_size * index as usize;
+
+    let mut key = [0; PACKED_KEY_SIZE as usize];
+    key[..suffix_len].copy_from_slice(&page[pos..pos + suffix_len]);
+    key[suffix_len..].copy_from_slice(&page[HEADER_SIZE as usize..][..prefix_len]);
+    (
+        u64::from_le_bytes(key),
+        &page[pos + suffix_len..pos + slot_size],
+    )
+}
+
+impl<'a> Node<'a> {
+    pub fn is_packed(&self) -> Result<bool, BTreeError> {
+        Ok(self.read_header()?.is_packed())
+    }
+
+    pub(crate) fn packed_key_at(&self, index: u16) -> Result<u64, BTreeError> {
+        self.check_key_index(index)?;
+        Ok(packed_entry(self.page, self.read_header()?, index).0)
+    }
+
+    pub(crate) fn packed_value_at(&self, index: u16) -> Result<&[u8], BTreeError> {
+        self.check_key_index(index)?;
+        Ok(packed_entry(self.page, self.read_header()?, index).1)
+    }
+
+    /// Every entry of a packed node as its full 8 byte key followed by its value
+    fn packed_entries(&self) -> Result<Vec<u8>, BTreeError> {
+        let header = self.read_header()?;
+        let num_keys = header.num_keys.get();
+        let mut entries = try_with_capacity(
+            num_keys as usize * (PACKED_KEY_SIZE as usize + header.packed_width as usize),
+        )?;
+        for idx in 0..num_keys {
+            entries.extend_from_slice(&self.packed_key_at(idx)?.to_le_bytes());
+            entries.extend_from_slice(self.packed_value_at(idx)?);
+        }
+        Ok(entries)
+    }
+
+    /// Rewrites the node as packed slots of `width` byte values holding `entries`, full keys
+    /// followed by their values, with keys sharing their last `prefix_len` bytes. The caller
+    /// makes sure they fit.
+    fn write_packed(
+        &mut self,
+        entries: &[u8],
+        width: u8,
+        prefix_len: u8,
+    ) -> Result<(), BTreeError> {
+        let entry_size = PACKED_KEY_SIZE as usize + width as usize;
+        let suffix_len = (PACKED_KEY_SIZE - prefix_len as u16) as usize;
+        let content_end = self.content_end()?;
+        self.scrub(HEADER_SIZE.into(), (content_end - HEADER_SIZE).into());
+
+        let mut pos = HEADER_SIZE as usize;
+        if let Some(first) = entries.get(..PACKED_KEY_SIZE as usize) {
+            self.get_mut_page_slice(pos, prefix_len.into())
+                .copy_from_slice(&first[suffix_len..]);
+        }
+        pos += prefix_len as usize;
+        for entry in entries.chunks(entry_size) {
+            let (key, value) = entry.split_at(PACKED_KEY_SIZE.into());
+            self.get_mut_page_slice(pos, suffix_len)
+                .copy_from_slice(&key[..suffix_len]);
+            self.get_mut_page_slice(pos + suffix_len, value.len())
+                .copy_from_slice(value);
+            pos += suffix_len + value.len();
+        }
+
+        let header = self.mutate_header()?;
+        header.flags |= FLAG_PACKED;
+        header.set_key_prefix_len(prefix_len);
+        header.packed_width = width;
+        header.num_keys.set((entries.len() / entry_size) as u16);
+        header.free_start.set(pos as u16);
+        header.free_end.set(content_end);
+        header.first_freeblock.set(0);
+        header.fragmented_bytes = 0;
+        Ok(())
+    }
+
+    /// Shortens the key prefix so that `key` shares it. Returns false, changing nothing, if
+    /// the longer slots wouldn't leave room for one more.
+    fn share_prefix_with(&mut self, key: u64) -> Result<bool, BTreeError> {
+        let header = self.read_header()?;
+        let (num_keys, prefix_len) = (header.num_keys.get(), header.key_prefix_len() as u8);
+        let Some(last) = num_keys.checked_sub(1) else {
+            return Ok(true);
+        };
+        let shared = shared_prefix_len(key, self.packed_key_at(0)?)
+            .min(shared_prefix_len(key, self.packed_key_at(last)?))
+            .min(prefix_len);
+        if shared == prefix_len {
+            return Ok(true);
+        }
+
+        let slot_size = PACKED_KEY_SIZE - shared as u16 + header.packed_width as u16;
+        let required =
+            (HEADER_SIZE + shared as u16) as usize + slot_size as usize * (num_keys as usize + 1);
+        if required > self.content_end()?.into() {
+            return Ok(false);
+        }
+        let width = header.packed_width;
+        let entries = self.packed_entries()?;
+        self.write_packed(&entries, width, shared)?;
+        Ok(true)
+    }
+
+    pub(crate) fn insert_packed(
+        &mut self,
+        idx: u16,
+        exists: bool,
+        key: u64,
+        value: &[u8],
+    ) -> Result<PackedInsert, BTreeError> {
+        let header = self.read_header()?;
+        let empty = header.num_keys.get() == 0;
+        let fits_packed = value.len() <= MAX_PACKED_WIDTH.into();
+
+        if empty
+            && fits_packed
+            && (header.is_packed() || (self.config.adaptive_layout && !self.config.align_values))
+        {
+            // Nothing to preserve, so the node can be (re)started with this value's width
+            // and as much of this key as a prefix as slots can leave out
+            let prefix_len = if self.config.prefix_compression {
+                MAX_KEY_PREFIX
+            } else {
+                0
+            };
+            let mut entry = try_with_capacity(PACKED_KEY_SIZE as usize + value.len())?;
+            entry.extend_from_slice(&key.to_le_bytes());
+            entry.extend_from_slice(value);
+            self.write_packed(&entry, value.len() as u8, prefix_len)?;
+            return Ok(PackedInsert::Done(None));
+        }
+
+        let header = self.read_header()?;
+        if !header.is_packed() || value.len() != header.packed_width as usize {
+            return Ok(PackedInsert::Unsupported);
+        }
+
+        if exists {
+            let old_value = try_to_vec(self.packed_value_at(idx)?)?;
+            let (pos, width) = self.packed_value_pos(idx)?;
+            self.get_mut_page_slice(pos, width).copy_from_slice(value);
+            return Ok(PackedInsert::Done(Some(KeyValuePair {
+                key,
+                value: old_value,
+            })));
+        }
+
+        if !self.share_prefix_with(key)? {
+            return Ok(PackedInsert::Unsupported);
+        }
+        let header = self.read_header()?;
+        let slot_size = header.slot_size();
+        if self.unallocated_space()? < slot_size {
+            return Ok(PackedInsert::Unsupported);
+        }
+
+        let suffix_len = (PACKED_KEY_SIZE - header.key_prefix_len()) as usize;
+        let free_start = header.free_start.get() as usize;
+        let pos = header.slots_start() as usize + slot_size as usize * idx as usize;
+        self.page
+            .copy_within(pos..free_start, pos + slot_size as usize);
+        self.get_mut_page_slice(pos, suffix_len)
+            .copy_from_slice(&key.to_le_bytes()[..suffix_len]);
+        self.get_mut_page_slice(pos + suffix_len, value.len())
+            .copy_from_slice(value);
+
+        let header = self.mutate_header()?;
+        header.free_start += slot_size;
+        header.num_keys += 1;
+        Ok(PackedInsert::Done(None))
+    }
+
+    /// Position and width of the value in packed slot `index`
+    fn packed_value_pos(&self, index: u16) -> Result<(usize, usize), BTreeError> {
+        self.check_key_index(index)?;
+        let header = self.read_header()?;
+        let pos = header.slots_start() as usize
+            + header.slot_size() as usize * index as usize
+            + (PACKED_KEY_SIZE - header.key_prefix_len()) as usize;
+        Ok((pos, header.packed_width.into()))
+    }
+
+    pub(crate) fn delete_packed(&mut self, idx: u16) -> Result<KeyValuePair, BTreeError> {
+        let deleted = KeyValuePair {
+            key: self.packed_key_at(idx)?,
+            value: try_to_vec(self.packed_value_at(idx)?)?,
+        };
+
+        let header = self.read_header()?;
+        let slot_size = header.slot_size() as usize;
+        let free_start = header.free_start.get() as usize;
+        let pos = header.slots_start() as usize + slot_size * idx as usize;
+
+        self.page.copy_within(pos + slot_size..free_start, pos);
+        self.scrub(free_start - slot_size, slot_size);
+
+        let header = self.mutate_header()?;
+        header.free_start -= slot_size as u16;
+        header.num_keys -= 1;
+        Ok(deleted)
+    }
+
+    /// Switches a packed node to the offset layout. Fails if the entries don't fit
+    /// the larger key records, leaving the node untouched.
+    pub fn unpack(&mut self) -> Result<(), BTreeError> {
+        let header = self.read_header()?;
+        if !header.is_packed() {
+            return Ok(());
+        }
+        let num_keys = header.num_keys.get();
+        let width = header.packed_width as u16;
+
+        let content_end = self.content_end()?;
+        let required = num_keys as usize * (KEY_SIZE + width) as usize;
+        let available = (content_end - HEADER_SIZE) as usize;
+        if required > available {
+            return Err(BTreeError::NotEnoughSpace {
+                required,
+                actual: available,
+            });
+        }
+
+        let entries = self.packed_entries()?;
+
+        self.scrub(HEADER_SIZE.into(), (content_end - HEADER_SIZE).into());
+        let header = self.mutate_header()?;
+        header.flags &= !FLAG_PACKED;
+        header.set_key_prefix_len(0);
+        header.packed_width = 0;
+        header.num_keys.set(0);
+        header.free_start.set(HEADER_SIZE);
+        header.free_end.set(content_end);
+        header.first_freeblock.set(0);
+        header.fragmented_bytes = 0;
+
+        for (idx, entry) in entries.chunks((PACKED_KEY_SIZE + width).into()).enumerate() {
+            let (key, value) = entry.split_at(PACKED_KEY_SIZE.into());
+            let key = u64::from_le_bytes(key.try_into().expect("Shouldn't fail, hardcoded"));
+            let offset = self.prepend_value(value)?;
+            self.insert_key_at(idx as u16, key, 0, offset, width)?;
+        }
+        Ok(())
+    }
+
+    /// Switches the node to the packed layout if all its values share a width of at most
+    /// MAX_PACKED_WIDTH bytes. Returns whether the node is packed afterwards.
+    pub fn pack(&mut self) -> Result<bool, BTreeError> {
+        let header = self.read_header()?;
+        if header.is_packed() {
+            return Ok(true);
+        }
+        if header.has_byte_keys() {
+            return Ok(false);
+        }
+        let num_keys = header.num_keys.get();
+        if num_keys == 0 {
+            return Ok(false);
+        }
+
+        let width = self.read_key_at(0)?.value_len.get();
+        if width > MAX_PACKED_WIDTH.into() {
+            return Ok(false);
+        }
+        let mut entries =
+            try_with_capacity(num_keys as usize * (PACKED_KEY_SIZE + width) as usize)?;
+        for idx in 0..num_keys {
+            let key = self.read_key_at(idx)?;
+            if key.value_len.get() != width {
+                return Ok(false);
+            }
+            entries.extend_from_slice(&key.key.get().to_le_bytes());
+            entries.extend_from_slice(self.value_at(idx)?);
+        }
+
+        let prefix_len = if self.config.prefix_compression {
+            shared_prefix_len(self.key_at(0)?, self.key_at(num_keys - 1)?)
+        } else {
+            0
+        };
+        self.write_packed(&entries, width as u8, prefix_len)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::verify::validate_file;
+    use super::super::{NodeConfig, NodeView, PAGE_SIZE};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn adaptive() -> NodeConfig {
+        NodeConfig {
+            adaptive_layout: true,
+            ..Default::default()
+        }
+    }
+
+    fn fill(node: &mut Node, value: &[u8]) -> u64 {
+        let mut key = 0;
+        while node.insert(key, value).is_ok() {
+            key += 1;
+        }
+        key
+    }
+
+    #[test]
+    fn packed_layout_is_denser() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        let offset_entries = fill(&mut node, &[1]);
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, adaptive()).unwrap();
+        let packed_entries = fill(&mut node, &[1]);
+
+        assert!(node.is_packed().unwrap());
+        assert_eq!(
+            packed_entries,
+            ((PAGE_SIZE - HEADER_SIZE) / (PACKED_KEY_SIZE + 1)) as u64
+        );
+        assert!(packed_entries > offset_entries);
+        for key in 0..packed_entries {
+            assert_eq!(node.get(key).unwrap(), Some([1u8].as_slice()));
+        }
+    }
+
+    #[test]
+    fn switches_layout_on_value_width() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, adaptive()).unwrap();
+
+        for key in [5, 1, 3] {
+            node.insert(key, &(key as u32).to_le_bytes()).unwrap();
+        }
+        assert!(node.is_packed().unwrap());
+        assert_eq!(node.find_le_key_idx(3).unwrap(), (1, true));
+
+        node.insert(2, b"a much longer value").unwrap();
+        assert!(!node.is_packed().unwrap());
+        for key in [5, 1, 3] {
+            assert_eq!(node.get(key).unwrap().unwrap(), (key as u32).to_le_bytes());
+        }
+
+        node.delete(2).unwrap();
+        node.defrag().unwrap();
+        assert!(node.is_packed().unwrap());
+        assert_eq!(node.get(5).unwrap().unwrap(), 5u32.to_le_bytes());
+        assert_eq!(node.get(2).unwrap(), None);
+    }
+
+    #[test]
+    fn packed_delete_and_replace() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, adaptive()).unwrap();
+
+        for key in 1..=5 {
+            node.insert(key, &[key as u8; 2]).unwrap();
+        }
+        let deleted = node.delete(3).unwrap().unwrap();
+        assert_eq!((deleted.key, deleted.value), (3, vec![3, 3]));
+
+        let old = node.insert(4, &[9, 9]).unwrap().unwrap();
+        assert_eq!(old.value, vec![4, 4]);
+
+        let entries: Vec<_> = node
+            .iter_snapshot()
+            .iter()
+            .map(|(k, v)| (k, v.to_vec()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (1, vec![1, 1]),
+                (2, vec![2, 2]),
+                (4, vec![9, 9]),
+                (5, vec![5, 5])
+            ]
+        );
+    }
+
+    #[test]
+    fn unpack_fails_when_entries_dont_fit() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, adaptive()).unwrap();
+        let entries = fill(&mut node, &[7]);
+
+        assert!(matches!(
+            node.insert(entries, b"wide"),
+            Err(BTreeError::NotEnoughSpace { .. })
+        ));
+        assert!(node.is_packed().unwrap());
+        assert_eq!(node.get(0).unwrap(), Some([7u8].as_slice()));
+    }
+
+    #[test]
+    fn prefix_compression_fits_more_keys() {
+        let compressed = NodeConfig {
+            prefix_compression: true,
+            ..adaptive()
+        };
+        let base = 0xabcd_ef01_2345_0000;
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, compressed).unwrap();
+        node.insert(base + 1, &[0]).unwrap();
+        assert_eq!(node.read_header().unwrap().key_prefix_len(), 7);
+        let mut entries = 1;
+        while node
+            .insert(base + 1 + entries * 3, &[entries as u8])
+            .is_ok()
+        {
+            entries += 1;
+        }
+
+        // Two byte suffixes cover the keys, so slots take 3 instead of 9 bytes
+        let header = node.read_header().unwrap();
+        assert_eq!(header.key_prefix_len(), 6);
+        assert_eq!(entries, ((PAGE_SIZE - HEADER_SIZE - 6) / 3) as u64);
+        assert!(entries > ((PAGE_SIZE - HEADER_SIZE) / (PACKED_KEY_SIZE + 1)) as u64);
+        for idx in [0, 1, entries / 2, entries - 1] {
+            let key = base + 1 + idx * 3;
+            assert_eq!(node.find_le_key_idx(key).unwrap(), (idx as usize, true));
+            assert_eq!(node.get(key).unwrap(), Some([idx as u8].as_slice()));
+            assert_eq!(node.get(key + 1).unwrap(), None);
+        }
+        let keys: Vec<_> = node.iter_range(base + 4..=base + 10).unwrap().collect();
+        assert_eq!(
+            keys,
+            vec![
+                (base + 4, &[1][..]),
+                (base + 7, &[2][..]),
+                (base + 10, &[3][..])
+            ]
+        );
+        drop(node);
+        #[cfg(feature = "std")]
+        assert!(validate_file(&page[..]).unwrap().is_ok());
+        let view = NodeView::load(&page).unwrap();
+        assert_eq!(view.entry_at(2), (base + 7, &[2][..]));
+
+        // A key outside the prefix needs longer slots, which no longer fit
+        let mut node = Node::load_with_config(&mut page, compressed).unwrap();
+        assert!(node.insert(7, &[0]).is_err());
+        node.delete(base + 1).unwrap();
+        node.delete(base + 4).unwrap();
+        assert_eq!(node.get(base + 7).unwrap(), Some([2u8].as_slice()));
+    }
+
+    #[test]
+    fn prefix_shrinks_for_other_keys() {
+        let compressed = NodeConfig {
+            prefix_compression: true,
+            ..adaptive()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, compressed).unwrap();
+        let keys = [
+            0x1122_3344_5566_7788,
+            0x1122_3344_5566_7701,
+            0x1122_3344_0000_0000,
+            9,
+        ];
+        let prefixes = [7, 7, 4, 0];
+        for (key, prefix) in keys.into_iter().zip(prefixes) {
+            node.insert(key, &key.to_le_bytes()[..2]).unwrap();
+            assert_eq!(node.read_header().unwrap().key_prefix_len(), prefix);
+        }
+        let entries: Vec<_> = node.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(entries, vec![9, keys[2], keys[1], keys[0]]);
+        node.delete(9).unwrap();
+
+        // Unpacking puts the keys back together, packing again finds the longer prefix
+        node.insert(keys[2] + 1, b"wide").unwrap();
+        assert!(!node.is_packed().unwrap());
+        node.delete(keys[2] + 1).unwrap();
+        assert!(node.pack().unwrap());
+        assert_eq!(node.read_header().unwrap().key_prefix_len(), 4);
+        for key in &keys[..3] {
+            assert_eq!(node.get(*key).unwrap(), Some(&key.to_le_bytes()[..2]));
+        }
+        drop(node);
+        #[cfg(feature = "std")]
+        assert!(validate_file(&page[..]).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,237 @@
+/*
+Comparators and merge operators supplied as C function pointers, so code outside of Rust can
+plug into the node format. A comparator orders the keys of byte keyed nodes in place of the
+bytewise order, a merge operator combines the stored value of a key with an operand.
+
+Both carry a name. Nodes ordered by a comparator are flagged, and trees record the names of
+their plugins in the catalog, so opening them with other plugins fails instead of reading
+keys in the wrong order.
+*/
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ptr;
+
+use super::errors::{BTreeError, LimitError};
+use super::fallible::try_zeroed;
+
+/// Compares the byte strings `a` and `b` like memcmp: negative if `a` sorts first, zero if
+/// they are equal, positive otherwise
+pub type CompareFn =
+    unsafe extern "C" fn(a: *const u8, a_len: usize, b: *const u8, b_len: usize) -> i32;
+
+/// Writes the merge of `existing`, null if the key is absent, and `operand` to `out`, which
+/// has room for `out_len` bytes. Returns the length of the merged value, a negative number if
+/// the operands can't be merged.
+pub type MergeFn = unsafe extern "C" fn(
+    existing: *const u8,
+    existing_len: usize,
+    operand: *const u8,
+    operand_len: usize,
+    out: *mut u8,
+    out_len: usize,
+) -> isize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Comparator {
+    name: &'static str,
+    compare: CompareFn,
+}
+
+impl Comparator {
+    /// # Safety
+    /// `compare` has to be safe to call with any two valid byte strings and must not hold on
+    /// to the pointers. It has to be a total order, and stay the same for a given `name`.
+    pub const unsafe fn new(name: &'static str, compare: CompareFn) -> Self {
+        Self { name, compare }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        // Safety: both slices are valid for their lengths, new's contract covers the rest
+        let result = unsafe { (self.compare)(a.as_ptr(), a.len(), b.as_ptr(), b.len()) };
+        result.cmp(&0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MergeOperator {
+    name: &'static str,
+    merge: MergeFn,
+}
+
+impl MergeOperator {
+    /// # Safety
+    /// `merge` has to be safe to call with valid inputs, must write at most `out_len` bytes
+    /// to `out` and must not hold on to any of the pointers.
+    pub const unsafe fn new(name: &'static str, merge: MergeFn) -> Self {
+        Self { name, merge }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Merges `operand` into the value `existing` of `key`. Merged values can be at most
+    /// `limit` bytes long.
+    pub fn merge(
+        &self,
+        key: u64,
+        existing: Option<&[u8]>,
+        operand: &[u8],
+        limit: usize,
+    ) -> Result<Vec<u8>, BTreeError> {
+        let mut out = try_zeroed(limit)?;
+        let (existing, existing_len) = existing.map_or((ptr::null(), 0), |v| (v.as_ptr(), v.len()));
+        // Safety: the inputs are valid for their lengths and `out` for `limit` bytes, new's
+        // contract covers the rest
+        let len = unsafe {
+            (self.merge)(
+                existing,
+                existing_len,
+                operand.as_ptr(),
+                operand.len(),
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        };
+        let len = usize::try_from(len).map_err(|_| BTreeError::MergeFailed { key })?;
+        if len > limit {
+            return Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                limit,
+                actual: len,
+            }));
+        }
+        out.truncate(len);
+        Ok(out)
+    }
+}
+
+/// Fails with PluginMismatch unless a tree recorded with plugin `found` is opened with
+/// `expected`
+#[cfg(feature = "pager")]
+pub(crate) fn check_plugin(
+    plugin: &'static str,
+    expected: Option<&str>,
+    found: Option<&str>,
+) -> Result<(), BTreeError> {
+    if expected != found {
+        return Err(BTreeError::PluginMismatch {
+            plugin,
+            expected: expected.map(str::to_owned),
+            found: found.map(str::to_owned),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    #[cfg(feature = "std")]
+    use super::super::verify::validate_file;
+    use super::super::{Node, NodeConfig, PAGE_SIZE};
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::slice;
+
+    unsafe extern "C" fn reverse(a: *const u8, a_len: usize, b: *const u8, b_len: usize) -> i32 {
+        let (a, b) = unsafe {
+            (
+                slice::from_raw_parts(a, a_len),
+                slice::from_raw_parts(b, b_len),
+            )
+        };
+        b.cmp(a) as i32
+    }
+
+    /// Adds little endian u64 counters, an absent value counts as 0
+    unsafe extern "C" fn add(
+        existing: *const u8,
+        existing_len: usize,
+        operand: *const u8,
+        operand_len: usize,
+        out: *mut u8,
+        out_len: usize,
+    ) -> isize {
+        let read = |ptr: *const u8, len: usize| {
+            let bytes = unsafe { slice::from_raw_parts(ptr, len) };
+            bytes.try_into().ok().map(u64::from_le_bytes)
+        };
+        let existing = if existing.is_null() {
+            Some(0)
+        } else {
+            read(existing, existing_len)
+        };
+        let (Some(existing), Some(operand)) = (existing, read(operand, operand_len)) else {
+            return -1;
+        };
+        if out_len < 8 {
+            return 8;
+        }
+        let sum = (existing + operand).to_le_bytes();
+        unsafe { ptr::copy_nonoverlapping(sum.as_ptr(), out, 8) };
+        8
+    }
+
+    pub(crate) const REVERSE: Comparator = unsafe { Comparator::new("reverse", reverse) };
+    pub(crate) const ADD: MergeOperator = unsafe { MergeOperator::new("add", add) };
+
+    #[test]
+    fn comparator_orders_byte_keys() {
+        let config = NodeConfig {
+            comparator: Some(REVERSE),
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_byte_keys(&mut page, config).unwrap();
+        for key in [&b"b"[..], b"abe", b"c", b"a"] {
+            node.insert_bytes(key, key).unwrap();
+        }
+        let keys: Vec<_> = (0..4).map(|idx| node.byte_key_at(idx).unwrap()).collect();
+        assert_eq!(keys, vec![&b"c"[..], b"b", b"abe", b"a"]);
+        assert_eq!(node.get_bytes(b"abe").unwrap(), Some(&b"abe"[..]));
+        drop(node);
+        #[cfg(feature = "std")]
+        assert!(validate_file(&page[..]).unwrap().is_ok());
+
+        // The bytewise order can't read the node and the other way around
+        assert!(matches!(
+            Node::load(&mut page),
+            Err(BTreeError::PluginMismatch { .. })
+        ));
+        let mut bytewise = [0u8; PAGE_SIZE as usize];
+        Node::new_with_byte_keys(&mut bytewise, NodeConfig::default()).unwrap();
+        assert!(Node::load_with_config(&mut bytewise, config).is_err());
+    }
+
+    #[test]
+    fn merge_operator_combines_values() {
+        let config = NodeConfig {
+            merge_operator: Some(ADD),
+            ..Default::default()
+        };
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new_with_config(&mut page, config).unwrap();
+        node.merge(1, &5u64.to_le_bytes()).unwrap();
+        node.merge(1, &7u64.to_le_bytes()).unwrap();
+        assert_eq!(node.get(1).unwrap(), Some(&12u64.to_le_bytes()[..]));
+        assert!(matches!(
+            node.merge(1, b"short"),
+            Err(BTreeError::MergeFailed { key: 1 })
+        ));
+        assert!(matches!(
+            ADD.merge(2, None, &1u64.to_le_bytes(), 4),
+            Err(BTreeError::LimitExceeded(_))
+        ));
+
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        assert!(matches!(
+            node.merge(1, &5u64.to_le_bytes()),
+            Err(BTreeError::NoMergeOperator)
+        ));
+    }
+}
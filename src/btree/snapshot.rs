@@ -0,0 +1,250 @@
+use alloc::boxed::Box;
+#[cfg(feature = "pager")]
+use alloc::vec::Vec;
+#[cfg(feature = "pager")]
+use core::marker::PhantomData;
+
+#[cfg(feature = "pager")]
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::header::{Header, HEADER_SIZE};
+use super::key::{Key, KEY_SIZE};
+use super::packed::packed_entry;
+use super::Node;
+
+/// Owned copy of a node's page. It stays consistent no matter what happens to the
+/// original page afterwards, so it can be iterated after the node has been released.
+pub struct NodeSnapshot {
+    page: Box<[u8]>,
+}
+
+pub struct SnapshotIter<'s> {
+    snapshot: &'s NodeSnapshot,
+    idx: u16,
+    end: u16,
+}
+
+impl NodeSnapshot {
+    fn header(&self) -> Result<&Header, BTreeError> {
+        let header_bytes: &[u8; HEADER_SIZE as usize] = self.page[..HEADER_SIZE as usize]
+            .try_into()
+            .expect("This should never fail, as the sizes are hardcoded to be the same");
+        Header::intepret_from_bytes(header_bytes)
+    }
+
+    fn entry_at(&self, index: u16) -> (u64, &[u8]) {
+        let header = self.header().expect("Only valid headers yield entries");
+        if header.is_packed() {
+            return packed_entry(&self.page, header, index);
+        }
+
+        let pos = (HEADER_SIZE + header.slot_size() * index) as usize;
+
+        let key_bytes: &[u8; KEY_SIZE as usize] = self.page[pos..pos + KEY_SIZE as usize]
+            .try_into()
+            .expect("Shouldn't fail, hardcoded");
+        let key = Key::intepret_from_bytes(key_bytes).expect("Every bit pattern is a valid key");
+        if key.is_inline() {
+            return (key.key.get(), key.inline_value());
+        }
+        let offset = key.value_offset.get() as usize;
+        let len = key.value_len.get() as usize;
+        (key.key.get(), &self.page[offset..offset + len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.header()
+            .map_or(0, |header| header.num_keys.get().into())
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn page_size(&self) -> usize {
+        self.page.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: u64) -> Option<&[u8]> {
+        let mut low = 0;
+        let mut high = self.len() as u16;
+        while low < high {
+            let mid = (low + high) / 2;
+            let (current_key, value) = self.entry_at(mid);
+            match current_key.cmp(&key) {
+                core::cmp::Ordering::Equal => return Some(value),
+                core::cmp::Ordering::Less => low = mid + 1,
+                core::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        SnapshotIter {
+            snapshot: self,
+            idx: 0,
+            end: self.len() as u16,
+        }
+    }
+}
+
+impl<'s> Iterator for SnapshotIter<'s> {
+    type Item = (u64, &'s [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.end {
+            return None;
+        }
+        let entry = self.snapshot.entry_at(self.idx);
+        self.idx += 1;
+        Some(entry)
+    }
+}
+
+impl<'a> Node<'a> {
+    pub fn iter_snapshot(&self) -> NodeSnapshot {
+        NodeSnapshot {
+            page: self.page.to_vec().into_boxed_slice(),
+        }
+    }
+}
+
+/// Owned copy of the entries of a tree, with their values decoded. It stays as it was
+/// through later writes, commits and rollbacks of the tree.
+#[cfg(feature = "pager")]
+pub struct TreeSnapshot<K: KeyCodec = u64> {
+    entries: Vec<(u64, Vec<u8>)>,
+    leaves: usize,
+    key: PhantomData<K>,
+}
+
+#[cfg(feature = "pager")]
+impl<K: KeyCodec> TreeSnapshot<K> {
+    /// Takes `entries` in any order, copied from `leaves` leaves
+    pub(super) fn new(mut entries: Vec<(u64, Vec<u8>)>, leaves: usize) -> Self {
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        Self {
+            entries,
+            leaves,
+            key: PhantomData,
+        }
+    }
+
+    /// Number of leaves the entries were copied from
+    pub fn leaves(&self) -> usize {
+        self.leaves
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: K) -> Option<&[u8]> {
+        let key = key.encode();
+        let idx = self
+            .entries
+            .binary_search_by_key(&key, |(key, _)| *key)
+            .ok()?;
+        Some(&self.entries[idx].1)
+    }
+
+    /// Entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (K, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (K::decode(*key), value.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn snapshot_outlives_node() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let snapshot = {
+            let mut node = Node::new(&mut page).unwrap();
+            node.insert(2, b"two").unwrap();
+            node.insert(1, b"one").unwrap();
+            node.insert(3, b"three").unwrap();
+            node.iter_snapshot()
+        };
+
+        {
+            let mut node = Node::load(&mut page).unwrap();
+            node.delete(2).unwrap();
+            node.insert(4, b"four").unwrap();
+            node.defrag().unwrap();
+        }
+
+        let entries: Vec<_> = snapshot.iter().collect();
+        assert_eq!(
+            entries,
+            vec![
+                (1, b"one".as_slice()),
+                (2, b"two".as_slice()),
+                (3, b"three".as_slice())
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let node = Node::new(&mut page).unwrap();
+        let snapshot = node.iter_snapshot();
+        assert!(snapshot.is_empty());
+        assert_eq!(snapshot.iter().next(), None);
+    }
+
+    #[cfg(feature = "wal")]
+    #[test]
+    fn tree_snapshot_outlives_transaction() {
+        use super::super::BTree;
+        use crate::log::WalStore;
+        use crate::page::MemoryStore;
+        use tempfile::tempdir;
+
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let wal = WalStore::open(
+            MemoryStore::new(PAGE_SIZE.into()),
+            log_path.to_str().unwrap(),
+        );
+        let mut tree = BTree::create(wal.unwrap()).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &key.to_be_bytes()).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let (snapshot, committed) = {
+            let mut txn = tree.transaction();
+            txn.delete(1).unwrap();
+            txn.insert(5000, b"uncommitted").unwrap();
+            (
+                txn.iter_snapshot().unwrap(),
+                txn.iter_committed_snapshot().unwrap(),
+            )
+        };
+        tree.delete(2).unwrap();
+        tree.commit().unwrap();
+
+        assert_eq!(snapshot.len(), 1000);
+        assert_eq!(snapshot.get(1), None);
+        assert_eq!(snapshot.get(2), Some(2u64.to_be_bytes().as_slice()));
+        assert_eq!(snapshot.get(5000), Some(b"uncommitted".as_slice()));
+        assert_eq!(committed.len(), 1000);
+        assert_eq!(committed.get(1), Some(1u64.to_be_bytes().as_slice()));
+        let keys: Vec<u64> = committed.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+    }
+}
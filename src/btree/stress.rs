@@ -0,0 +1,243 @@
+/*
+Stress tests for a tree shared between threads, the way embedders share it: behind a mutex,
+over a WalStore, with every write in a transaction. Writers put and delete registers and move
+amounts between accounts, rolling some transfers back. Readers read registers and total up
+every account.
+
+Every call records when it was invoked and when it returned on a global clock, and what it
+saw. Afterwards the history of every register is checked for linearizability with the
+Wing & Gong search: some order of the calls has to agree with the clock, calls that returned
+before another one was invoked come first, and explain every read by the write before it.
+Linearizability is local, so checking registers one by one covers the history as a whole.
+Account totals have to add up in every read, rolled back transfers and half finished ones
+must never show.
+*/
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::{BTree, PAGE_SIZE};
+use crate::log::WalStore;
+use crate::page::MemoryStore;
+use pretty_assertions::assert_eq;
+
+const REGISTERS: u64 = 64;
+/// Accounts live above the registers
+const ACCOUNTS: u64 = 8;
+const BALANCE: u64 = 1000;
+/// Pads values so the tree splits and merges while the test runs
+const VALUE_LEN: usize = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Read(Option<u64>),
+    Write(Option<u64>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Call {
+    key: u64,
+    op: Op,
+    invoked: u64,
+    returned: u64,
+}
+
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn encode(value: u64) -> Vec<u8> {
+    let mut bytes = value.to_le_bytes().to_vec();
+    bytes.resize(VALUE_LEN, value as u8);
+    bytes
+}
+
+fn decode(bytes: Option<Vec<u8>>) -> Option<u64> {
+    bytes.map(|bytes| u64::from_le_bytes(bytes[..8].try_into().unwrap()))
+}
+
+/// Whether the calls on a single register, starting out empty, can be put in an order that
+/// respects their real time order and explains every read
+fn linearizable(calls: &[Call]) -> bool {
+    fn search(
+        calls: &[Call],
+        done: &mut Vec<bool>,
+        state: Option<u64>,
+        seen: &mut HashSet<(Vec<bool>, Option<u64>)>,
+    ) -> bool {
+        let pending = || (0..calls.len()).filter(|&i| !done[i]);
+        // Calls invoked after a pending call returned can't go before it
+        let Some(deadline) = pending().map(|i| calls[i].returned).min() else {
+            return true;
+        };
+        for i in pending()
+            .filter(|&i| calls[i].invoked < deadline)
+            .collect::<Vec<_>>()
+        {
+            let next = match calls[i].op {
+                Op::Read(value) if value != state => continue,
+                Op::Read(_) => state,
+                Op::Write(value) => value,
+            };
+            done[i] = true;
+            if seen.insert((done.clone(), next)) && search(calls, done, next, seen) {
+                return true;
+            }
+            done[i] = false;
+        }
+        false
+    }
+    search(
+        calls,
+        &mut vec![false; calls.len()],
+        None,
+        &mut HashSet::new(),
+    )
+}
+
+#[test]
+fn checker_rejects_stale_reads() {
+    let call = |op, invoked, returned| Call {
+        key: 0,
+        op,
+        invoked,
+        returned,
+    };
+    // Overlapping calls may go either way
+    let overlapping = [
+        call(Op::Write(Some(1)), 0, 3),
+        call(Op::Read(None), 1, 4),
+        call(Op::Read(Some(1)), 2, 5),
+    ];
+    assert!(linearizable(&overlapping));
+    // A read that starts after a write returned has to see it
+    let stale = [call(Op::Write(Some(1)), 0, 1), call(Op::Read(None), 2, 3)];
+    assert!(!linearizable(&stale));
+    // Once a later value was seen the earlier one can't come back
+    let flapping = [
+        call(Op::Write(Some(1)), 0, 1),
+        call(Op::Write(Some(2)), 2, 3),
+        call(Op::Read(Some(2)), 4, 5),
+        call(Op::Read(Some(1)), 6, 7),
+    ];
+    assert!(!linearizable(&flapping));
+}
+
+#[test]
+fn concurrent_history_is_linearizable() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("wal");
+    let store = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log.to_str().unwrap());
+    let mut tree = BTree::create(store.unwrap()).unwrap();
+    for account in 0..ACCOUNTS {
+        tree.insert(REGISTERS + account, &encode(BALANCE)).unwrap();
+    }
+    tree.commit().unwrap();
+
+    let tree = Mutex::new(tree);
+    let clock = AtomicU64::new(0);
+    let tick = || clock.fetch_add(1, Ordering::SeqCst);
+    let calls = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for writer in 0..4u64 {
+            let (tree, calls) = (&tree, &calls);
+            scope.spawn(move || {
+                let mut random = 0x9e3779b97f4a7c15 ^ (writer + 1);
+                for seq in 0..250 {
+                    let roll = next_random(&mut random);
+                    let key = roll % REGISTERS;
+                    if roll % 10 < 6 {
+                        // Unique values, so every read points at the write it saw
+                        let value = (!roll.is_multiple_of(4)).then_some(writer << 32 | seq);
+                        let invoked = tick();
+                        let mut tree = tree.lock().unwrap();
+                        let mut transaction = tree.transaction();
+                        match value {
+                            Some(value) => drop(transaction.insert(key, &encode(value)).unwrap()),
+                            None => drop(transaction.delete(key).unwrap()),
+                        }
+                        transaction.commit().unwrap();
+                        drop(tree);
+                        let returned = tick();
+                        calls.lock().unwrap().push(Call {
+                            key,
+                            op: Op::Write(value),
+                            invoked,
+                            returned,
+                        });
+                    } else {
+                        let from = REGISTERS + roll % ACCOUNTS;
+                        let to = REGISTERS + (roll >> 8) % ACCOUNTS;
+                        let mut tree = tree.lock().unwrap();
+                        let mut transaction = tree.transaction();
+                        let balance = decode(transaction.get(from).unwrap()).unwrap();
+                        let amount = (roll >> 16) % (balance + 1);
+                        transaction.insert(from, &encode(balance - amount)).unwrap();
+                        let balance = decode(transaction.get(to).unwrap()).unwrap();
+                        transaction.insert(to, &encode(balance + amount)).unwrap();
+                        if roll.is_multiple_of(5) {
+                            transaction.rollback();
+                        } else {
+                            transaction.commit().unwrap();
+                        }
+                    }
+                }
+            });
+        }
+        for reader in 0..4u64 {
+            let (tree, calls) = (&tree, &calls);
+            scope.spawn(move || {
+                let mut random = 0x2545f4914f6cdd1d ^ (reader + 1);
+                for _ in 0..250 {
+                    let roll = next_random(&mut random);
+                    if roll % 10 < 7 {
+                        let key = roll % REGISTERS;
+                        let invoked = tick();
+                        let value = decode(tree.lock().unwrap().get(key).unwrap());
+                        let returned = tick();
+                        calls.lock().unwrap().push(Call {
+                            key,
+                            op: Op::Read(value),
+                            invoked,
+                            returned,
+                        });
+                    } else {
+                        let mut tree = tree.lock().unwrap();
+                        let total: u64 = (REGISTERS..REGISTERS + ACCOUNTS)
+                            .map(|key| decode(tree.get(key).unwrap()).unwrap())
+                            .sum();
+                        assert_eq!(total, ACCOUNTS * BALANCE);
+                    }
+                }
+            });
+        }
+    });
+
+    // Reads after every thread is done pin down the final state as well
+    let mut tree = tree.into_inner().unwrap();
+    let mut calls = calls.into_inner().unwrap();
+    for key in 0..REGISTERS {
+        let invoked = tick();
+        let value = decode(tree.get(key).unwrap());
+        calls.push(Call {
+            key,
+            op: Op::Read(value),
+            invoked,
+            returned: tick(),
+        });
+    }
+    for key in 0..REGISTERS {
+        let history: Vec<Call> = calls.iter().filter(|c| c.key == key).copied().collect();
+        assert!(linearizable(&history), "register {key}: {history:#?}");
+    }
+    let total: u64 = (REGISTERS..REGISTERS + ACCOUNTS)
+        .map(|key| decode(tree.get(key).unwrap()).unwrap())
+        .sum();
+    assert_eq!(total, ACCOUNTS * BALANCE);
+}
@@ -0,0 +1,338 @@
+/*
+Trace of page mutations, used to reproduce corruption reports deterministically. Every mutation
+applied through MutationTrace appends one record to the trace file
+--------------------------------------------------------------------------------------------
+| page id (4 bytes) | op (1 byte) | key (8 bytes) | value len (2 bytes) | value | before | after |
+--------------------------------------------------------------------------------------------
+before and after are 8 byte FNV-1a hashes of the whole page. Delete records have an empty value.
+replay reapplies the records to a page file and stops at the first page whose hash diverges
+from the one that was recorded.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::batch::BatchOp;
+use super::errors::BTreeError;
+use super::{KeyValuePair, Node};
+use crate::cancel::{Budget, Interrupted};
+use crate::page::PageStore;
+
+const OP_INSERT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    BTree(BTreeError),
+    Malformed(String),
+}
+
+impl From<io::Error> for TraceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<Interrupted> for TraceError {
+    fn from(interrupted: Interrupted) -> Self {
+        TraceError::BTree(interrupted.into())
+    }
+}
+
+impl From<BTreeError> for TraceError {
+    fn from(err: BTreeError) -> Self {
+        Self::BTree(err)
+    }
+}
+
+pub struct TraceRecord {
+    pub page_id: u32,
+    pub op: BatchOp,
+    pub before: u64,
+    pub after: u64,
+}
+
+pub struct MutationTrace {
+    writer: BufWriter<File>,
+}
+
+impl MutationTrace {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Applies `op` to the node stored in page `page_id` and records it. Failed
+    /// operations aren't recorded.
+    pub fn apply(
+        &mut self,
+        node: &mut Node,
+        page_id: u32,
+        op: BatchOp,
+    ) -> Result<Option<KeyValuePair>, TraceError> {
+        let before = page_hash(node.page);
+        let old = apply_op(node, &op)?;
+        let after = page_hash(node.page);
+
+        self.write_record(&TraceRecord {
+            page_id,
+            op,
+            before,
+            after,
+        })?;
+        Ok(old)
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> Result<(), io::Error> {
+        let (op, key, value): (u8, u64, &[u8]) = match &record.op {
+            BatchOp::Insert { key, value } => (OP_INSERT, *key, value),
+            BatchOp::Delete { key } => (OP_DELETE, *key, &[]),
+        };
+        let len: u16 = value
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Traced value too large"))?;
+
+        self.writer.write_all(&record.page_id.to_le_bytes())?;
+        self.writer.write_all(&[op])?;
+        self.writer.write_all(&key.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(value)?;
+        self.writer.write_all(&record.before.to_le_bytes())?;
+        self.writer.write_all(&record.after.to_le_bytes())
+    }
+}
+
+impl Drop for MutationTrace {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// Reads every record of a trace file
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+
+    let mut page_id = [0u8; 4];
+    loop {
+        match reader.read_exact(&mut page_id) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        records.push(read_record(&mut reader, u32::from_le_bytes(page_id))?);
+    }
+    Ok(records)
+}
+
+fn read_record(reader: &mut impl Read, page_id: u32) -> Result<TraceRecord, TraceError> {
+    let truncated = |_| TraceError::Malformed(format!("Truncated record for page {page_id}"));
+
+    let mut head = [0u8; 1 + 8 + 2];
+    reader.read_exact(&mut head).map_err(truncated)?;
+    let key = u64::from_le_bytes(head[1..9].try_into().expect("Slice is 8 bytes"));
+    let len = u16::from_le_bytes(head[9..11].try_into().expect("Slice is 2 bytes"));
+
+    let mut value = vec![0u8; len.into()];
+    reader.read_exact(&mut value).map_err(truncated)?;
+
+    let op = match head[0] {
+        OP_INSERT => BatchOp::Insert { key, value },
+        OP_DELETE => BatchOp::Delete { key },
+        op => return Err(TraceError::Malformed(format!("Unknown trace op {op}"))),
+    };
+
+    let mut hashes = [0u8; 16];
+    reader.read_exact(&mut hashes).map_err(truncated)?;
+    Ok(TraceRecord {
+        page_id,
+        op,
+        before: u64::from_le_bytes(hashes[..8].try_into().expect("Slice is 8 bytes")),
+        after: u64::from_le_bytes(hashes[8..].try_into().expect("Slice is 8 bytes")),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Divergence {
+    /// The page didn't match the recorded state before the mutation
+    Before { record: usize, page_id: u32 },
+    /// Reapplying the mutation produced a different page than recorded
+    After { record: usize, page_id: u32 },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub applied: usize,
+    pub divergence: Option<Divergence>,
+}
+
+/// Reapplies the trace at `path` to the pages in `pages`. Pages are expected to be in the
+/// state they were in when tracing started.
+pub fn replay<S: PageStore>(
+    path: impl AsRef<Path>,
+    pages: &mut S,
+) -> Result<ReplayReport, TraceError> {
+    replay_within(path, pages, &Budget::unlimited())
+}
+
+/// Like `replay`, but checks `budget` before every record. Records applied until then
+/// stay applied.
+pub fn replay_within<S: PageStore>(
+    path: impl AsRef<Path>,
+    pages: &mut S,
+    budget: &Budget,
+) -> Result<ReplayReport, TraceError> {
+    let mut report = ReplayReport::default();
+
+    for (index, record) in read_trace(path)?.into_iter().enumerate() {
+        budget.check()?;
+        let page_id = record.page_id;
+        let mut page = pages.read_page(page_id as usize)?;
+
+        if page_hash(page.read()) != record.before {
+            report.divergence = Some(Divergence::Before {
+                record: index,
+                page_id,
+            });
+            break;
+        }
+
+        let mut node = Node::load(page.mutate())?;
+        apply_op(&mut node, &record.op)?;
+        drop(node);
+        if page_hash(page.read()) != record.after {
+            report.divergence = Some(Divergence::After {
+                record: index,
+                page_id,
+            });
+            break;
+        }
+
+        pages.write_page(page_id as usize, &page)?;
+        report.applied += 1;
+    }
+    Ok(report)
+}
+
+fn apply_op(node: &mut Node, op: &BatchOp) -> Result<Option<KeyValuePair>, BTreeError> {
+    match op {
+        BatchOp::Insert { key, value } => node.insert(*key, value),
+        BatchOp::Delete { key } => node.delete(*key),
+    }
+}
+
+/// 64 bit FNV-1a, stable across platforms and compiler versions unlike std's hasher
+fn page_hash(page: &[u8]) -> u64 {
+    page.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::{Page, PageManager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn empty_pages(path: &Path, n: usize) -> PageManager {
+        let mut pages = PageManager::new(path.to_str().unwrap(), PAGE_SIZE.into()).unwrap();
+        for _ in 0..n {
+            let mut page = Page::new(PAGE_SIZE.into());
+            Node::new(page.mutate()).unwrap();
+            pages.append_page(&page).unwrap();
+        }
+        pages
+    }
+
+    #[test]
+    fn replay_reproduces_pages() {
+        let dir = tempdir().unwrap();
+        let trace_path = dir.path().join("trace.bin");
+        let mut original = empty_pages(&dir.path().join("original.bin"), 2);
+        let mut replayed = empty_pages(&dir.path().join("replayed.bin"), 2);
+
+        {
+            let mut trace = MutationTrace::create(&trace_path).unwrap();
+            let mut pages = [
+                original.read_page(0).unwrap(),
+                original.read_page(1).unwrap(),
+            ];
+            for key in 0..20u64 {
+                let page_id = (key % 2) as usize;
+                let mut node = Node::load(pages[page_id].mutate()).unwrap();
+                let op = if key % 5 == 4 {
+                    BatchOp::Delete { key: key - 2 }
+                } else {
+                    BatchOp::Insert {
+                        key,
+                        value: vec![key as u8; key as usize],
+                    }
+                };
+                trace.apply(&mut node, page_id as u32, op).unwrap();
+            }
+            original.write_page(0, &pages[0]).unwrap();
+            original.write_page(1, &pages[1]).unwrap();
+        }
+
+        let report = replay(&trace_path, &mut replayed).unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                applied: 20,
+                divergence: None
+            }
+        );
+        for page_id in 0..2 {
+            assert_eq!(
+                replayed.read_page(page_id).unwrap().read(),
+                original.read_page(page_id).unwrap().read()
+            );
+        }
+    }
+
+    #[test]
+    fn replay_stops_at_divergence() {
+        let dir = tempdir().unwrap();
+        let trace_path = dir.path().join("trace.bin");
+        let mut pages = empty_pages(&dir.path().join("pages.bin"), 1);
+
+        {
+            let mut trace = MutationTrace::create(&trace_path).unwrap();
+            let mut page = pages.read_page(0).unwrap();
+            let mut node = Node::load(page.mutate()).unwrap();
+            for key in 0..3 {
+                let value = b"value".to_vec();
+                trace
+                    .apply(&mut node, 0, BatchOp::Insert { key, value })
+                    .unwrap();
+            }
+        }
+
+        // Page on disk already contains a key the trace doesn't know about
+        let mut page = pages.read_page(0).unwrap();
+        Node::load(page.mutate()).unwrap().insert(99, b"x").unwrap();
+        pages.write_page(0, &page).unwrap();
+
+        let report = replay(&trace_path, &mut pages).unwrap();
+        assert_eq!(
+            report.divergence,
+            Some(Divergence::Before {
+                record: 0,
+                page_id: 0
+            })
+        );
+        assert_eq!(report.applied, 0);
+        assert_eq!(read_trace(&trace_path).unwrap().len(), 3);
+    }
+}
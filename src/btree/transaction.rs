@@ -0,0 +1,481 @@
+/*
+Transactions over a tree whose store is a WalStore. The store buffers every page written
+during the transaction, so reads through the transaction see its own writes while the file
+stays untouched. Committing logs the buffered pages and writes them to the file atomically,
+rolling back throws them away. A transaction that is dropped without committing rolls back.
+
+Changes made on the tree since its last commit, before the transaction was started, become
+part of the transaction.
+
+Optimistic callers read without a transaction, recording what they saw in a ReadSet, compute
+their writes and only then write them in a transaction. commit_if_unchanged looks every key
+of the read set up again as of the last commit, before the transaction's own changes, and
+rolls back with a Conflict if one no longer has the value that was read.
+
+Savepoints mark a point within a transaction to roll back to without giving up the rest. A
+savepoint keeps shadow copies of the pages written so far and of the tree's free pages,
+rolling back to it puts them back in place. Savepoints nest: rolling back to one or releasing
+it drops the savepoints set after it, and names may repeat, the latest savepoint of a name
+is the one meant. Pages appended after a savepoint that is rolled back to stay unused, like
+after a rollback.
+
+prepare is the first phase of a two-phase commit: it makes the transaction's changes durable
+in the log without committing them, commit_prepared or rollback_prepared decide their fate.
+Preparing drops the savepoints, the changes before them are out of the transaction's hands.
+Dropping a prepared transaction only rolls back the changes made since it was prepared, the
+prepared ones wait in the log, across crashes too, for BTree::commit_prepared or
+BTree::rollback_prepared.
+*/
+
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::tree::ShadowState;
+use super::BTree;
+use crate::log::{PendingStats, WalStore};
+use crate::page::PageStore;
+
+/// A transaction in progress on a tree. Derefs to the tree for reads and writes.
+pub struct Transaction<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<WalStore<S>, K>,
+    /// Names and shadow copies of the savepoints, oldest first. Those of nested
+    /// transactions have no name.
+    savepoints: Vec<(Option<String>, ShadowState)>,
+    /// The id the transaction was prepared as
+    prepared: Option<u64>,
+}
+
+/// Keys read by an optimistic caller and the values they had
+pub struct ReadSet<K: KeyCodec> {
+    reads: Vec<(u64, Option<Vec<u8>>)>,
+    key: PhantomData<K>,
+}
+
+impl<K: KeyCodec> ReadSet<K> {
+    pub fn new() -> Self {
+        Self {
+            reads: Vec::new(),
+            key: PhantomData,
+        }
+    }
+
+    /// Records that `key` was read as `value`, `None` if it didn't exist
+    pub fn record(&mut self, key: K, value: Option<&[u8]>) {
+        self.reads.push((key.encode(), value.map(<[u8]>::to_vec)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+}
+
+impl<K: KeyCodec> Default for ReadSet<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<WalStore<S>, K> {
+    pub fn transaction(&mut self) -> Transaction<'_, S, K> {
+        Transaction {
+            tree: self,
+            savepoints: Vec::new(),
+            prepared: None,
+        }
+    }
+
+    /// Looks up `key` like get and records the result in `reads`
+    pub fn get_recorded(
+        &mut self,
+        key: K,
+        reads: &mut ReadSet<K>,
+    ) -> Result<Option<Vec<u8>>, BTreeError> {
+        let encoded = key.encode();
+        let value = self.get(key)?;
+        reads.reads.push((encoded, value.clone()));
+        Ok(value)
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Transaction<'_, S, K> {
+    /// Makes the changes of the transaction durable. Returns the commit's lsn.
+    pub fn commit(self) -> Result<u64, BTreeError> {
+        let lsn = self.tree.commit();
+        // On failure dropping rolls back the changes the store still holds as pending. If
+        // writing to the store failed after the log took the commit, the commit stands and
+        // there is nothing left to roll back.
+        if lsn.is_ok() {
+            std::mem::forget(self);
+        }
+        lsn
+    }
+
+    /// Commits only if every key in `reads` still has the value that was read, as of the
+    /// last commit. Otherwise rolls back and fails with the first key that changed.
+    pub fn commit_if_unchanged(self, reads: &ReadSet<K>) -> Result<u64, BTreeError> {
+        for (key, read) in &reads.reads {
+            if self.tree.get_committed(K::decode(*key))? != *read {
+                return Err(BTreeError::Conflict { key: *key });
+            }
+        }
+        self.commit()
+    }
+
+    pub fn rollback(self) {
+        // Dropping does the work
+    }
+
+    /// Makes the changes of the transaction durable without committing them, to be
+    /// committed or rolled back as `txn_id`. Returns the lsn of the prepare record.
+    pub fn prepare(&mut self, txn_id: u64) -> Result<u64, BTreeError> {
+        let lsn = self.tree.prepare(txn_id)?;
+        self.savepoints.clear();
+        self.prepared = Some(txn_id);
+        Ok(lsn)
+    }
+
+    /// Commits the changes the transaction prepared. Changes made since stay pending on the
+    /// tree. Returns the commit's lsn.
+    pub fn commit_prepared(self) -> Result<u64, BTreeError> {
+        let txn_id = self.prepared_id()?;
+        let lsn = self.tree.commit_prepared(txn_id);
+        if lsn.is_ok() {
+            std::mem::forget(self);
+        }
+        lsn
+    }
+
+    /// Rolls back the changes the transaction prepared and the ones made since
+    pub fn rollback_prepared(self) -> Result<(), BTreeError> {
+        let txn_id = self.prepared_id()?;
+        self.tree.rollback_prepared(txn_id)
+    }
+
+    fn prepared_id(&self) -> Result<u64, BTreeError> {
+        self.prepared.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The transaction isn't prepared",
+            )
+            .into()
+        })
+    }
+
+    /// Sets a savepoint called `name` to roll back to later
+    pub fn savepoint(&mut self, name: &str) {
+        let state = self.tree.shadow_state();
+        self.savepoints.push((Some(name.to_string()), state));
+    }
+
+    /// Undoes every change since the savepoint `name`, which stays set. Savepoints set after
+    /// it are dropped.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), BTreeError> {
+        let pos = self.find_savepoint(name)?;
+        self.savepoints.truncate(pos + 1);
+        let (_, state) = &self.savepoints[pos];
+        self.tree.reset_state(state.clone());
+        Ok(())
+    }
+
+    /// Drops the savepoint `name` and the ones set after it, keeping their changes
+    pub fn release(&mut self, name: &str) -> Result<(), BTreeError> {
+        let pos = self.find_savepoint(name)?;
+        self.savepoints.truncate(pos);
+        Ok(())
+    }
+
+    /// Runs `f` as a transaction nested in this one: its changes are kept if it succeeds
+    /// and undone if it fails, the rest of the transaction stays either way
+    pub fn nested<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, BTreeError>,
+    ) -> Result<T, BTreeError> {
+        let pos = self.savepoints.len();
+        self.savepoints.push((None, self.tree.shadow_state()));
+        let result = f(self);
+        // Gone if `f` rolled back to or released a savepoint from before, or prepared
+        let pos = pos.min(self.savepoints.len());
+        let savepoint = self.savepoints.drain(pos..).next();
+        if let (Err(_), Some((_, state))) = (&result, savepoint) {
+            self.tree.reset_state(state);
+        }
+        result
+    }
+
+    fn find_savepoint(&self, name: &str) -> Result<usize, BTreeError> {
+        self.savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint.as_deref() == Some(name))
+            .ok_or_else(|| BTreeError::UnknownSavepoint(name.to_string()))
+    }
+
+    /// Pages written by the transaction so far
+    pub fn dirty_pages(&self) -> usize {
+        self.tree.store().dirty_pages()
+    }
+
+    /// The keys and value bytes written so far and the pages they changed, see
+    /// BTree::pending_stats
+    pub fn pending_stats(&self) -> PendingStats {
+        self.tree.pending_stats()
+    }
+
+    /// Bytes committing appends to the log at most
+    pub fn estimated_commit_size(&self) -> usize {
+        self.tree.store().estimated_commit_size()
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Drop for Transaction<'_, S, K> {
+    fn drop(&mut self) {
+        self.tree.rollback();
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Deref for Transaction<'_, S, K> {
+    type Target = BTree<WalStore<S>, K>;
+
+    fn deref(&self) -> &Self::Target {
+        self.tree
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> DerefMut for Transaction<'_, S, K> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn commit_rollback_and_drop() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        let mut tree = BTree::create(wal).unwrap();
+        tree.commit().unwrap();
+
+        let mut txn = tree.transaction();
+        for key in 0..500 {
+            txn.insert(key, &[key as u8; 40]).unwrap();
+        }
+        // Reads see the transaction's own writes
+        assert_eq!(txn.get(77).unwrap(), Some(vec![77; 40]));
+        assert!(txn.dirty_pages() > 1);
+        txn.savepoint("before delete");
+        txn.delete(3).unwrap();
+        assert_eq!(
+            txn.pending_stats(),
+            PendingStats {
+                entries: 501,
+                bytes: 500 * 40,
+                pages_dirtied: txn.dirty_pages(),
+            }
+        );
+        txn.rollback_to("before delete").unwrap();
+        assert_eq!(txn.pending_stats().entries, 500);
+        assert!(txn.estimated_commit_size() > txn.dirty_pages() * usize::from(PAGE_SIZE));
+        txn.commit().unwrap();
+        assert_eq!(tree.pending_stats(), PendingStats::default());
+        assert_eq!(tree.store().dirty_pages(), 0);
+
+        let mut txn = tree.transaction();
+        txn.delete(5).unwrap();
+        txn.insert(1000, b"gone").unwrap();
+        assert_eq!(txn.get(5).unwrap(), None);
+        txn.rollback();
+        assert_eq!(tree.get(5).unwrap(), Some(vec![5; 40]));
+        assert_eq!(tree.get(1000).unwrap(), None);
+
+        {
+            let mut txn = tree.transaction();
+            txn.insert(2000, b"dropped").unwrap();
+        }
+        assert_eq!(tree.get(2000).unwrap(), None);
+
+        // Only committed changes reach the store
+        let root = tree.root();
+        let store = tree.into_store().into_inner();
+        let wal = WalStore::open(store, log_path).unwrap();
+        let mut tree = BTree::open(wal, root).unwrap();
+        for key in 0..500 {
+            assert_eq!(tree.get(key).unwrap(), Some(vec![key as u8; 40]));
+        }
+    }
+
+    #[test]
+    fn optimistic_commits_fail_on_conflicts() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let wal = WalStore::open(
+            MemoryStore::new(PAGE_SIZE.into()),
+            log_path.to_str().unwrap(),
+        );
+        let mut tree = BTree::create(wal.unwrap()).unwrap();
+        tree.insert(1, &5u64.to_le_bytes()).unwrap();
+        tree.commit().unwrap();
+
+        // Increments a counter optimistically, key 2 is read but doesn't exist
+        let increment = |tree: &mut BTree<WalStore<MemoryStore>>, interfere: bool| {
+            let mut reads = ReadSet::new();
+            let count = tree.get_recorded(1, &mut reads).unwrap().unwrap();
+            tree.get_recorded(2, &mut reads).unwrap();
+            let count = u64::from_le_bytes(count.try_into().unwrap());
+            if interfere {
+                tree.insert(2, b"someone else").unwrap();
+                tree.commit().unwrap();
+            }
+            let mut txn = tree.transaction();
+            // The transaction's own write to a key it read is no conflict
+            txn.insert(1, &(count + 1).to_le_bytes()).unwrap();
+            txn.commit_if_unchanged(&reads)
+        };
+
+        increment(&mut tree, false).unwrap();
+        assert_eq!(tree.get(1).unwrap(), Some(6u64.to_le_bytes().to_vec()));
+        let err = increment(&mut tree, true).unwrap_err();
+        assert!(matches!(err, BTreeError::Conflict { key: 2 }));
+        // Rolled back
+        assert_eq!(tree.get(1).unwrap(), Some(6u64.to_le_bytes().to_vec()));
+        assert_eq!(tree.store().dirty_pages(), 0);
+    }
+
+    #[test]
+    fn savepoints_undo_part_of_a_transaction() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let wal = WalStore::open(
+            MemoryStore::new(PAGE_SIZE.into()),
+            log_path.to_str().unwrap(),
+        );
+        let mut tree = BTree::create(wal.unwrap()).unwrap();
+        tree.commit().unwrap();
+
+        let mut txn = tree.transaction();
+        for key in 0..100 {
+            txn.insert(key, &[1; 100]).unwrap();
+        }
+        txn.savepoint("a");
+        // Enough to split and merge leaves, freeing and appending pages
+        for key in 100..600 {
+            txn.insert(key, &[2; 100]).unwrap();
+        }
+        txn.savepoint("b");
+        for key in 0..600 {
+            txn.delete(key).unwrap();
+        }
+        txn.rollback_to("b").unwrap();
+        assert_eq!(txn.get(300).unwrap(), Some(vec![2; 100]));
+        txn.rollback_to("a").unwrap();
+        assert_eq!(txn.get(300).unwrap(), None);
+        assert_eq!(txn.get(50).unwrap(), Some(vec![1; 100]));
+        // Rolling back to "a" dropped "b", "a" stays
+        assert!(matches!(
+            txn.rollback_to("b"),
+            Err(BTreeError::UnknownSavepoint(name)) if name == "b"
+        ));
+        txn.insert(1000, b"after a").unwrap();
+        txn.release("a").unwrap();
+        assert!(txn.release("a").is_err());
+
+        // A failing nested transaction leaves the rest alone
+        let err = txn.nested(|txn| {
+            txn.insert(2000, b"nested")?;
+            txn.update(3000, b"missing")
+        });
+        assert!(matches!(err, Err(BTreeError::KeyNotFound { key: 3000 })));
+        assert_eq!(txn.get(2000).unwrap(), None);
+        txn.nested(|txn| txn.insert(2001, b"kept").map(|_| ()))
+            .unwrap();
+        txn.commit().unwrap();
+
+        for key in 0..100 {
+            assert_eq!(tree.get(key).unwrap(), Some(vec![1; 100]));
+        }
+        assert_eq!(tree.get(100).unwrap(), None);
+        assert_eq!(tree.get(1000).unwrap(), Some(b"after a".to_vec()));
+        assert_eq!(tree.get(2001).unwrap(), Some(b"kept".to_vec()));
+        assert_eq!(tree.iter().count(), 102);
+    }
+
+    #[test]
+    fn prepared_transactions_survive_a_reopen() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        let mut tree = BTree::create(wal).unwrap();
+        tree.insert(1, b"committed").unwrap();
+        tree.commit().unwrap();
+
+        let mut txn = tree.transaction();
+        for key in 10..300 {
+            txn.insert(key, &[7; 40]).unwrap();
+        }
+        txn.prepare(42).unwrap();
+        assert!(txn.prepare(43).is_err());
+        drop(txn);
+        assert_eq!(tree.store().prepared_transaction(), Some(42));
+        assert_eq!(tree.get(100).unwrap(), Some(vec![7; 40]));
+        assert_eq!(tree.get_committed(100).unwrap(), None);
+        // Nothing is committed on top of a prepared transaction
+        tree.insert(1000, b"later").unwrap();
+        assert!(tree.commit().is_err());
+        tree.rollback();
+        tree.checkpoint().unwrap();
+
+        let root = tree.root();
+        let store = tree.into_store().into_inner();
+        let wal = WalStore::open(store, log_path).unwrap();
+        assert_eq!(wal.prepared_transaction(), Some(42));
+        let mut tree = BTree::open(wal, root).unwrap();
+        assert_eq!(tree.get_committed(100).unwrap(), None);
+        assert!(tree.commit_prepared(7).is_err());
+        tree.commit_prepared(42).unwrap();
+        assert_eq!(tree.store().prepared_transaction(), None);
+
+        // Rolled back, before and after a reopen
+        let mut txn = tree.transaction();
+        txn.delete(1).unwrap();
+        txn.prepare(43).unwrap();
+        txn.rollback_prepared().unwrap();
+        let mut txn = tree.transaction();
+        txn.insert(2000, b"rolled back").unwrap();
+        txn.prepare(44).unwrap();
+        drop(txn);
+
+        let store = tree.into_store().into_inner();
+        let wal = WalStore::open(store, log_path).unwrap();
+        assert_eq!(wal.prepared_transaction(), Some(44));
+        let mut tree = BTree::open(wal, root).unwrap();
+        tree.rollback_prepared(44).unwrap();
+        assert_eq!(tree.get(2000).unwrap(), None);
+
+        let store = tree.into_store().into_inner();
+        let wal = WalStore::open(store, log_path).unwrap();
+        assert_eq!(wal.prepared_transaction(), None);
+        let mut tree = BTree::open(wal, root).unwrap();
+        assert_eq!(tree.get(1).unwrap(), Some(b"committed".to_vec()));
+        assert_eq!(tree.get(2000).unwrap(), None);
+        for key in 10..300 {
+            assert_eq!(tree.get(key).unwrap(), Some(vec![7; 40]));
+        }
+    }
+}
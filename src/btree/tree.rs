@@ -0,0 +1,3745 @@
+/*
+A B-tree spread over the pages of a PageStore. Leaves hold the values, internal nodes hold
+separator keys whose left_child_page points to the subtree with all keys <= the separator.
+Keys greater than the last separator live under rightmost_child_page.
+
+The root never moves. When it splits its content is moved to a new page and the root becomes
+an internal node above it, when it shrinks to a single child that child is pulled back up.
+Nodes that overflow are rewritten as two (or, for leaves with huge values, more) nodes. Nodes
+that drop below a quarter of the page are merged with a sibling, or share its entries if the
+two don't fit into one page.
+
+With the tracing feature, inserts, deletes, commits and checkpoints run in debug spans, page
+I/O in trace spans within them, and splits, merges and defrags are debug events, so a
+subscriber sees where the time of an operation went.
+
+Leaves don't use rightmost_child_page, except for leaves split by a ConcurrentTree under their
+latch alone: there it links the leaf to the right half split off it, whose separator isn't in
+the parent yet (a B-link). Keys from the first key of the right half on live there. Links only
+exist in the ConcurrentTree's pool, it posts the separators and clears the links before leaves
+reach the store.
+*/
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "histogram")]
+use std::time::Instant;
+
+use super::checksum::CHECKSUM_SIZE;
+use super::codec::{schema_fingerprint, KeyCodec, SchemaName};
+use super::compression::{decode_value, encode_value, Compression};
+use super::errors::{BTreeError, CorruptionError, LimitError};
+use super::fallible::{try_to_vec, try_with_capacity};
+use super::header::{NodeType, HEADER_SIZE, RESERVED_TAIL};
+use super::heat::AccessTracker;
+use super::key::KEY_SIZE;
+#[cfg(feature = "histogram")]
+use super::latency::{record, LatencyStats};
+use super::negcache::NegativeCache;
+use super::packed::{shared_prefix_len, MAX_KEY_PREFIX, MAX_PACKED_WIDTH, PACKED_KEY_SIZE};
+use super::plugin::check_plugin;
+use super::snapshot::TreeSnapshot;
+use super::verify::{verify_pages, PageVerifier};
+use super::watch::KeyWatcher;
+use super::{check_page_size, KeyValuePair, Limits, Node, NodeConfig, MAX_DEPTH, VALUE_ALIGNMENT};
+use crate::limits::ResourceLimits;
+#[cfg(feature = "wal")]
+use crate::log::{Checkpoint, DurabilityToken, PendingStats, WalStore};
+use crate::page::{CacheStats, Page, PageStore, Pager, SharedRead, TreeMeta};
+
+pub(super) type LeafEntries = Vec<(u64, Vec<u8>)>;
+/// Pages written since the last commit, free pages and write counts of a tree on a WalStore
+#[cfg(feature = "wal")]
+pub(super) type ShadowState = (BTreeMap<usize, Page>, Vec<u32>, (usize, usize));
+
+/// Keys written and value bytes stored since the last commit, see pending_stats. Atomic so
+/// ConcurrentTree writers count through a shared reference.
+#[derive(Debug, Default)]
+struct WriteCounts {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl WriteCounts {
+    /// Counts a key written with a value of `bytes` bytes, 0 for deletes
+    fn count(&self, bytes: usize) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "wal")]
+    fn get(&self) -> (usize, usize) {
+        let entries = self.entries.load(Ordering::Relaxed);
+        (entries, self.bytes.load(Ordering::Relaxed))
+    }
+
+    #[cfg(feature = "wal")]
+    fn set(&self, (entries, bytes): (usize, usize)) {
+        self.entries.store(entries, Ordering::Relaxed);
+        self.bytes.store(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Entries {
+    Leaf(LeafEntries),
+    Internal {
+        children: Vec<(u64, u32)>,
+        rightmost: u32,
+    },
+}
+
+impl Entries {
+    fn cost(&self, config: NodeConfig) -> usize {
+        match self {
+            Entries::Leaf(entries) => match packed_cost(entries, config) {
+                Some(cost) => cost * entries.len(),
+                None => {
+                    let align = value_alignment(config);
+                    entries.iter().map(|entry| leaf_cost(entry, align)).sum()
+                }
+            },
+            Entries::Internal { children, .. } => children.len() * KEY_SIZE as usize,
+        }
+    }
+
+    fn last_key(&self) -> Option<u64> {
+        match self {
+            Entries::Leaf(entries) => entries.last().map(|(key, _)| *key),
+            Entries::Internal { children, .. } => children.last().map(|(key, _)| *key),
+        }
+    }
+}
+
+/// Bytes an entry takes up in a leaf whose values are aligned to `align`
+fn leaf_cost((_, value): &(u64, Vec<u8>), align: usize) -> usize {
+    KEY_SIZE as usize + value.len().next_multiple_of(align)
+}
+
+/// Bytes every one of `entries` takes up if they fill a leaf that packs them: the key, or
+/// what's left of it with prefix compression, and the value
+fn packed_cost(entries: &[(u64, Vec<u8>)], config: NodeConfig) -> Option<usize> {
+    if !config.adaptive_layout || config.align_values {
+        return None;
+    }
+    let ((first, value), (last, _)) = (entries.first()?, entries.last()?);
+    let width = value.len();
+    if width > MAX_PACKED_WIDTH.into() || entries.iter().any(|(_, v)| v.len() != width) {
+        return None;
+    }
+    // Pieces of sorted entries share at least the prefix of all of them
+    let prefix_len = if config.prefix_compression {
+        shared_prefix_len(*first, *last)
+    } else {
+        0
+    };
+    Some(PACKED_KEY_SIZE as usize - prefix_len as usize + width)
+}
+
+/// Alignment of value offsets in leaves of `config`
+fn value_alignment(config: NodeConfig) -> usize {
+    if config.align_values {
+        VALUE_ALIGNMENT.into()
+    } else {
+        1
+    }
+}
+
+/// Splits leaf entries into pieces that fit a page each, as evenly as two pieces allow.
+/// Only if no two-way split fits (huge values) are the pieces filled one after another.
+fn split_leaf(
+    mut entries: Vec<(u64, Vec<u8>)>,
+    mut capacity: usize,
+    config: NodeConfig,
+) -> Vec<Vec<(u64, Vec<u8>)>> {
+    let align = value_alignment(config);
+    let packed = packed_cost(&entries, config);
+    if packed.is_some() && config.prefix_compression {
+        capacity -= MAX_KEY_PREFIX as usize;
+    }
+    let leaf_cost = |entry: &(u64, Vec<u8>)| packed.unwrap_or_else(|| leaf_cost(entry, align));
+    let total: usize = entries.iter().map(leaf_cost).sum();
+    if total <= capacity {
+        return vec![entries];
+    }
+
+    let mut best: Option<(usize, usize)> = None;
+    let mut left = 0;
+    for idx in 1..entries.len() {
+        left += leaf_cost(&entries[idx - 1]);
+        let right = total - left;
+        if left <= capacity && right <= capacity {
+            let imbalance = left.abs_diff(right);
+            if best.is_none_or(|(_, best_imbalance)| imbalance < best_imbalance) {
+                best = Some((idx, imbalance));
+            }
+        }
+    }
+    if let Some((idx, _)) = best {
+        let right = entries.split_off(idx);
+        return vec![entries, right];
+    }
+
+    let mut pieces = Vec::new();
+    let mut current = Vec::new();
+    let mut used = 0;
+    for entry in entries {
+        let cost = leaf_cost(&entry);
+        if used + cost > capacity && !current.is_empty() {
+            pieces.push(mem::take(&mut current));
+            used = 0;
+        }
+        used += cost;
+        current.push(entry);
+    }
+    pieces.push(current);
+    pieces
+}
+
+/// Inserts `key` into the sorted leaf `entries`, or replaces its value. Returns the pair
+/// replaced.
+fn insert_entry(entries: &mut LeafEntries, key: u64, value: &[u8]) -> Option<KeyValuePair> {
+    match entries.binary_search_by_key(&key, |(k, _)| *k) {
+        Ok(idx) => {
+            let old = mem::replace(&mut entries[idx].1, value.to_vec());
+            Some(KeyValuePair { key, value: old })
+        }
+        Err(idx) => {
+            entries.insert(idx, (key, value.to_vec()));
+            None
+        }
+    }
+}
+
+/// Splits `entries` into pieces that fit a page each. Returns the pieces together with
+/// the separators between them.
+/// `node`, a load of leaf `page_id`, counting its accesses in `tracker` if there is one
+fn track_leaf<'a>(node: Node<'a>, tracker: Option<&'a AccessTracker>, page_id: u32) -> Node<'a> {
+    match tracker {
+        Some(tracker) => node.with_access_tracker(tracker, page_id),
+        None => node,
+    }
+}
+
+fn split(entries: Entries, capacity: usize, config: NodeConfig) -> (Vec<Entries>, Vec<u64>) {
+    match entries {
+        Entries::Leaf(entries) => {
+            let pieces: Vec<_> = split_leaf(entries, capacity, config)
+                .into_iter()
+                .map(Entries::Leaf)
+                .collect();
+            let separators = pieces[..pieces.len() - 1]
+                .iter()
+                .map(|piece| piece.last_key().expect("Pieces aren't empty"))
+                .collect();
+            (pieces, separators)
+        }
+        Entries::Internal {
+            mut children,
+            rightmost,
+        } => {
+            if children.len() * KEY_SIZE as usize <= capacity {
+                return (
+                    vec![Entries::Internal {
+                        children,
+                        rightmost,
+                    }],
+                    Vec::new(),
+                );
+            }
+            // The middle separator moves up, its child becomes the left rightmost
+            let mid = children.len() / 2;
+            let right = children.split_off(mid + 1);
+            let (separator, left_rightmost) = children.pop().expect("mid is in range");
+            let pieces = vec![
+                Entries::Internal {
+                    children,
+                    rightmost: left_rightmost,
+                },
+                Entries::Internal {
+                    children: right,
+                    rightmost,
+                },
+            ];
+            (pieces, vec![separator])
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    fn entries(&self) -> Result<Entries, BTreeError> {
+        let header = self.read_header()?;
+        let num_keys = header.num_keys.get();
+        if header.node_type == NodeType::Leaf {
+            let mut entries = try_with_capacity(num_keys.into())?;
+            for idx in 0..num_keys {
+                entries.push((self.key_at(idx)?, try_to_vec(self.value_at(idx)?)?));
+            }
+            return Ok(Entries::Leaf(entries));
+        }
+        let rightmost = header.rightmost_child_page.get();
+        let mut children = try_with_capacity(num_keys.into())?;
+        for idx in 0..num_keys {
+            let key = self.read_key_at(idx)?;
+            children.push((key.key.get(), key.left_child_page.get()));
+        }
+        Ok(Entries::Internal {
+            children,
+            rightmost,
+        })
+    }
+}
+
+/// Pages an overflowing node was written to. The parent inserts the separators, each with
+/// the page holding the keys up to it, in front of its pointer to the node, which has to
+/// point to `last` afterwards.
+struct Split {
+    separators: Vec<(u64, u32)>,
+    last: u32,
+}
+
+/// Pages that are allocated but hold no live data. A healthy tree has none, rebalancing
+/// merges empty leaves away and hands freed pages back.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HuskReport {
+    /// Leaves other than the root without any key
+    pub empty_leaves: Vec<u32>,
+    /// Pages no node points to that the store doesn't account for either
+    pub orphaned: Vec<u32>,
+}
+
+impl HuskReport {
+    pub fn is_empty(&self) -> bool {
+        self.empty_leaves.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// Pages of a file that nothing accounts for, see BTree::gc_unreachable
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LeakReport {
+    /// Roots of the trees walked, in page order
+    pub roots: Vec<u32>,
+    /// Pages of those trees
+    pub reachable: usize,
+    /// Pages neither in a tree nor reserved by the file, in page order
+    pub leaked: Vec<u32>,
+    /// Whether the leaked pages went on the freelist, false in a dry run
+    pub reclaimed: bool,
+}
+
+/// Free space of a leaf that only defragmenting turns back into room for any value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeafFragmentation {
+    pub page_id: u32,
+    /// Gaps too small for a freeblock, the header's counter
+    pub fragmented_bytes: u16,
+    /// Bytes in the freeblock chain
+    pub freeblock_bytes: u16,
+}
+
+impl LeafFragmentation {
+    /// Bytes defragmenting the leaf reclaims
+    pub fn score(&self) -> u32 {
+        u32::from(self.fragmented_bytes) + u32::from(self.freeblock_bytes)
+    }
+}
+
+/// Work a tree did since it was opened or its stats were reset, and its shape now, see
+/// BTree::stats. Only operations on `&mut BTree` are counted, get_shared and the leaf
+/// operations of a ConcurrentTree aren't.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub page_reads: u64,
+    /// Pages written or appended
+    pub page_writes: u64,
+    /// Reads the store's page cache served, see PageStore::cache_stats
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Nodes split into two or more, the root included
+    pub splits: u64,
+    /// Nodes merged into a sibling
+    pub merges: u64,
+    /// Leaves defragmented, to make room for an insert or through defrag_leaf
+    pub defrags: u64,
+    /// Bytes defragmenting every leaf would reclaim, see fragmentation
+    pub fragmented_bytes: u64,
+    pub height: usize,
+    pub entries: u64,
+}
+
+/// `config` with the page tail reserved if `store` keeps it for itself
+pub(super) fn config_for(store: &impl PageStore, config: NodeConfig) -> NodeConfig {
+    NodeConfig {
+        reserve_tail: config.reserve_tail || store.reserves_tail(),
+        ..config
+    }
+}
+
+/// The config a tree loads its nodes with and the limits it enforces on the whole tree.
+/// The nodes don't get the limits, they would apply them to each node.
+fn tree_config(store: &impl PageStore, config: NodeConfig) -> (NodeConfig, Limits) {
+    let config = config_for(store, config);
+    let node_config = NodeConfig {
+        limits: Limits::default(),
+        ..config
+    };
+    (node_config, config.limits)
+}
+
+/// `limits` with descents capped at MAX_DEPTH and the depth `quota` allows
+fn capped(limits: ResourceLimits, quota: Limits) -> ResourceLimits {
+    let max_depth = quota.max_depth.unwrap_or(MAX_DEPTH);
+    ResourceLimits {
+        max_depth: limits.max_depth.min(MAX_DEPTH).min(max_depth),
+        ..limits
+    }
+}
+
+/// The nodes visited on the way to a leaf
+pub(super) struct Path {
+    /// (page id, child index) of every internal node above the leaf, root first
+    internal: Vec<(u32, u16)>,
+    leaf: u32,
+}
+
+/// Tree over `store` whose keys are encoded with `K`, plain u64 keys by default
+pub struct BTree<S: PageStore, K: KeyCodec = u64> {
+    store: S,
+    root: u32,
+    config: NodeConfig,
+    limits: ResourceLimits,
+    /// Pages released by merges, reused before the store is grown. Only used if the
+    /// store doesn't keep a freelist itself.
+    free_pages: Vec<u32>,
+    #[cfg(feature = "histogram")]
+    latency: LatencyStats,
+    /// Counters of the tree's work, the rest of Stats is filled in by stats
+    stats: Stats,
+    /// The store's cache stats when the tree's stats were last reset
+    cache_base: CacheStats,
+    /// Notified of every insert and delete, see with_watcher
+    watcher: Option<Arc<KeyWatcher>>,
+    /// Key ranges gets found missing, see with_negative_cache
+    negative_cache: Option<Arc<NegativeCache>>,
+    /// Counts the accesses to every leaf, see with_access_tracker
+    access_tracker: Option<Arc<AccessTracker>>,
+    /// Limits of the whole tree, NodeConfig::limits when the tree was created or opened
+    quota: Limits,
+    /// Keys in the tree, counted once a max_keys quota needs it and kept up to date by
+    /// insert_at and delete_at. `None` until then and after rollbacks.
+    key_count: Option<u64>,
+    writes: WriteCounts,
+    key: PhantomData<K>,
+}
+
+impl<S: PageStore> BTree<S> {
+    /// Creates an empty tree whose root is appended to `store`
+    pub fn create(store: S) -> Result<Self, BTreeError> {
+        Self::create_with_config(store, NodeConfig::default())
+    }
+
+    pub fn create_with_config(store: S, config: NodeConfig) -> Result<Self, BTreeError> {
+        check_page_size(store.page_size())?;
+        let (config, quota) = tree_config(&store, config);
+        let cache_base = store.cache_stats();
+        let mut tree = Self {
+            store,
+            root: 0,
+            config,
+            limits: capped(ResourceLimits::default(), quota),
+            free_pages: Vec::new(),
+            #[cfg(feature = "histogram")]
+            latency: LatencyStats::default(),
+            stats: Stats::default(),
+            cache_base,
+            watcher: None,
+            negative_cache: None,
+            access_tracker: None,
+            quota,
+            key_count: None,
+            writes: WriteCounts::default(),
+            key: PhantomData,
+        };
+        tree.root = tree.allocate()?;
+        tree.write_entries(tree.root, &Entries::Leaf(Vec::new()))?;
+        Ok(tree)
+    }
+
+    /// Opens the tree rooted at page `root` of `store`
+    pub fn open(store: S, root: u32) -> Result<Self, BTreeError> {
+        Self::open_with_config(store, root, NodeConfig::default())
+    }
+
+    pub fn open_with_config(store: S, root: u32, config: NodeConfig) -> Result<Self, BTreeError> {
+        Self::open_with_limits(store, root, config, ResourceLimits::default())
+    }
+
+    /// Opens a tree from a file that can't be trusted. Every descent is bounded by
+    /// `limits.max_depth` and MAX_DEPTH, so a cycle of child pointers errors instead of
+    /// looping forever or growing a stack without end.
+    pub fn open_with_limits(
+        store: S,
+        root: u32,
+        config: NodeConfig,
+        limits: ResourceLimits,
+    ) -> Result<Self, BTreeError> {
+        check_page_size(store.page_size())?;
+        let (config, quota) = tree_config(&store, config);
+        let limits = capped(limits, quota);
+        let cache_base = store.cache_stats();
+        let n_pages = store.n_pages()?;
+        if n_pages > limits.max_pages {
+            return Err(BTreeError::LimitExceeded(LimitError::MaxPages {
+                limit: limits.max_pages,
+                actual: n_pages,
+            }));
+        }
+        Ok(Self {
+            store,
+            root,
+            config,
+            limits,
+            free_pages: Vec::new(),
+            #[cfg(feature = "histogram")]
+            latency: LatencyStats::default(),
+            stats: Stats::default(),
+            cache_base,
+            watcher: None,
+            negative_cache: None,
+            access_tracker: None,
+            quota,
+            key_count: None,
+            writes: WriteCounts::default(),
+            key: PhantomData,
+        })
+    }
+
+    /// Uses the tree with keys of type `K`. The tree has to hold keys encoded with `K`
+    /// already or be empty, decoding keys with another codec than they were written with
+    /// yields garbage.
+    pub fn keyed<K: KeyCodec>(self) -> BTree<S, K> {
+        BTree {
+            store: self.store,
+            root: self.root,
+            config: self.config,
+            limits: self.limits,
+            free_pages: self.free_pages,
+            #[cfg(feature = "histogram")]
+            latency: self.latency,
+            stats: self.stats,
+            cache_base: self.cache_base,
+            watcher: self.watcher,
+            negative_cache: self.negative_cache,
+            access_tracker: self.access_tracker,
+            quota: self.quota,
+            key_count: self.key_count,
+            writes: self.writes,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// Builds a tree from `entries`, which have to be sorted by key, with completely filled
+    /// pages
+    pub fn bulk_load<V: AsRef<[u8]>>(
+        store: S,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, BTreeError> {
+        Self::bulk_load_with_config(store, NodeConfig::default(), 1.0, entries)
+    }
+
+    /// Builds a tree bottom-up from `entries` sorted by key, without descending from the
+    /// root for every key. Pages are filled to `fill` (a fraction of the page) to leave room
+    /// for later inserts. Fails with UnsortedInput if a key doesn't sort after the one
+    /// before it.
+    pub fn bulk_load_with_config<V: AsRef<[u8]>>(
+        store: S,
+        config: NodeConfig,
+        fill: f64,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, BTreeError> {
+        check_page_size(store.page_size())?;
+        let (config, quota) = tree_config(&store, config);
+        let cache_base = store.cache_stats();
+        let mut tree = Self {
+            store,
+            root: 0,
+            config,
+            limits: capped(ResourceLimits::default(), quota),
+            free_pages: Vec::new(),
+            #[cfg(feature = "histogram")]
+            latency: LatencyStats::default(),
+            stats: Stats::default(),
+            cache_base,
+            watcher: None,
+            negative_cache: None,
+            access_tracker: None,
+            quota,
+            key_count: None,
+            writes: WriteCounts::default(),
+            key: PhantomData,
+        };
+        let target = (tree.capacity() as f64 * fill.clamp(0.0, 1.0)) as usize;
+        let align = tree.value_alignment();
+
+        // (largest key, page) of every node of the level built last
+        let mut pointers = Vec::new();
+        // The last full leaf is held back, so it can be evened out with the final one
+        let mut full: Option<LeafEntries> = None;
+        let mut leaf = Vec::new();
+        let mut used = 0;
+        let mut previous = None;
+        let mut keys = 0;
+        for (key, value) in entries {
+            let key = key.encode();
+            if let Some(previous) = previous.filter(|&previous| previous >= key) {
+                return Err(BTreeError::UnsortedInput { previous, key });
+            }
+            previous = Some(key);
+            keys += 1;
+            tree.check_key_quota(keys)?;
+
+            let value = tree.encode(value.as_ref())?;
+            let entry = (key, try_to_vec(&value)?);
+            if used + leaf_cost(&entry, align) > target && !leaf.is_empty() {
+                if let Some(full) = full.replace(mem::take(&mut leaf)) {
+                    tree.write_leaf(full, &mut pointers)?;
+                }
+                used = 0;
+            }
+            used += leaf_cost(&entry, align);
+            leaf.push(entry);
+        }
+        let last = match full {
+            Some(mut full) if used < tree.underflow() => {
+                full.append(&mut leaf);
+                split_leaf(full, tree.capacity(), config)
+            }
+            Some(full) => vec![full, leaf],
+            None => vec![leaf],
+        };
+        for leaf in last {
+            tree.write_leaf(leaf, &mut pointers)?;
+        }
+
+        // Spread every level's pointers evenly over as few nodes as the fill allows
+        let max_children = (target / KEY_SIZE as usize + 1).max(4);
+        let mut depth = 1;
+        while pointers.len() > 1 {
+            depth += 1;
+            if depth > tree.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: tree.limits.max_depth,
+                }));
+            }
+            let nodes = pointers.len().div_ceil(max_children);
+            let mut level = Vec::with_capacity(nodes);
+            let mut rest = pointers.as_slice();
+            for node in 0..nodes {
+                let (children, tail) = rest.split_at(rest.len() / (nodes - node));
+                rest = tail;
+                let (&(last_key, rightmost), separators) = children
+                    .split_last()
+                    .expect("Nodes get at least two children");
+                let page_id = tree.allocate()?;
+                let entries = Entries::Internal {
+                    children: separators.to_vec(),
+                    rightmost,
+                };
+                tree.write_entries(page_id, &entries)?;
+                level.push((last_key, page_id));
+            }
+            pointers = level;
+        }
+        tree.root = pointers[0].1;
+        tree.key_count = Some(keys);
+        Ok(tree)
+    }
+
+    fn write_leaf(
+        &mut self,
+        leaf: LeafEntries,
+        pointers: &mut Vec<(u64, u32)>,
+    ) -> Result<(), BTreeError> {
+        let last_key = leaf.last().map_or(0, |(key, _)| *key);
+        let page_id = self.allocate()?;
+        self.write_entries(page_id, &Entries::Leaf(leaf))?;
+        pointers.push((last_key, page_id));
+        Ok(())
+    }
+
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Checks the pages of the store one at a time as the verifier is iterated
+    pub fn verify(&mut self) -> Result<PageVerifier<'_, S>, BTreeError> {
+        Ok(verify_pages(&mut self.store)?)
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Publishes every successful insert and delete on this tree to `watcher`. Other
+    /// threads wait on the watcher itself, or on a ConcurrentTree around the tree.
+    pub fn with_watcher(mut self, watcher: Arc<KeyWatcher>) -> Self {
+        self.watcher = Some(watcher);
+        self
+    }
+
+    pub fn watcher(&self) -> Option<&Arc<KeyWatcher>> {
+        self.watcher.as_ref()
+    }
+
+    /// Blocks until `key` is written after this call, see KeyWatcher::wait_for. Returns
+    /// false on timeout, and right away if the tree has no watcher.
+    pub fn wait_for(&self, key: K, timeout: Duration) -> bool {
+        self.watcher
+            .as_ref()
+            .is_some_and(|watcher| watcher.wait_for(key.encode(), timeout))
+    }
+
+    pub(super) fn notify_watcher(&self, key: u64) {
+        if let Some(watcher) = &self.watcher {
+            watcher.notify(key);
+        }
+    }
+
+    /// Answers gets of keys `cache` knows to be missing, and caches the gaps between the
+    /// keys of a leaf that gets find missing. Inserts invalidate them, rollbacks clear the
+    /// cache. Trees with a comparator don't use it, their keys don't sort as numbers.
+    pub fn with_negative_cache(mut self, cache: Arc<NegativeCache>) -> Self {
+        self.negative_cache = Some(cache);
+        self
+    }
+
+    /// Counts every get, insert and delete in a leaf as an access to it in `tracker`,
+    /// including those of a ConcurrentTree over this tree
+    pub fn with_access_tracker(mut self, tracker: Arc<AccessTracker>) -> Self {
+        self.access_tracker = Some(tracker);
+        self
+    }
+
+    pub fn access_tracker(&self) -> Option<&Arc<AccessTracker>> {
+        self.access_tracker.as_ref()
+    }
+
+    /// Counts an access to leaf `page_id` that went around Node, like inserts that split it
+    fn record_leaf_access(&self, page_id: u32, entries: &LeafEntries) {
+        if let Some(tracker) = &self.access_tracker {
+            let keys = entries.first().zip(entries.last());
+            tracker.record(page_id, keys.map(|((first, _), (last, _))| *first..=*last));
+        }
+    }
+
+    pub(super) fn invalidate_absent(&self, key: u64) {
+        if let Some(cache) = &self.negative_cache {
+            cache.invalidate(key);
+        }
+    }
+
+    /// get_raw through the negative cache
+    fn get_cached(&mut self, key: u64) -> Result<Option<Vec<u8>>, BTreeError> {
+        let cache = self.negative_cache.clone();
+        let Some(cache) = cache.filter(|_| self.config.comparator.is_none()) else {
+            return self.get_raw(key);
+        };
+        if cache.contains(key) {
+            return Ok(None);
+        }
+        let (path, stored) = self.lookup(key)?;
+        if stored.is_none() {
+            // The gap ends at the keys the leaf may hold, the neighbours may be in other leaves
+            let low = self
+                .lower_bound(&path)?
+                .map_or(0, |bound| bound.saturating_add(1));
+            let high = self.upper_bound(&path)?.unwrap_or(u64::MAX);
+            let mut page = self.read_store_page(path.leaf as usize)?;
+            let gap = Node::load_with_config(page.mutate(), self.config)
+                .and_then(|node| node.absent_gap(node.find_le_key_idx(key)?.0 as u16))
+                .map_err(self.in_page(path.leaf))?;
+            cache.insert(low.max(*gap.start())..=high.min(*gap.end()));
+        }
+        Ok(stored)
+    }
+
+    /// Number of levels, 1 for a tree that only has its root leaf
+    pub fn depth(&mut self) -> Result<usize, BTreeError> {
+        Ok(self.find_path(0)?.internal.len() + 1)
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        #[cfg(feature = "histogram")]
+        let start = Instant::now();
+        let key = key.encode();
+        let stored = self.get_cached(key);
+        let value =
+            stored.and_then(|stored| stored.map(|stored| self.decode(key, stored)).transpose());
+        #[cfg(feature = "histogram")]
+        record(&mut self.latency.get, start);
+        value
+    }
+
+    /// Inserts or replaces `key`. The returned pair carries the encoded key.
+    pub fn insert(&mut self, key: K, value: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
+        #[cfg(feature = "histogram")]
+        let start = Instant::now();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("insert", key = key.encode(), len = value.len()).entered();
+        let old = self
+            .encode(value)
+            .and_then(|value| self.insert_raw(key.encode(), &value))
+            .and_then(|old| self.decode_pair(old));
+        #[cfg(feature = "histogram")]
+        record(&mut self.latency.insert, start);
+        old
+    }
+
+    /// How long gets, inserts, commits and checkpoints took since the tree was opened or
+    /// the stats were reset
+    #[cfg(feature = "histogram")]
+    pub fn latency_stats(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    #[cfg(feature = "histogram")]
+    pub fn reset_latency_stats(&mut self) {
+        self.latency = LatencyStats::default();
+    }
+
+    /// The tree's counters, and its height, entry count and fragmentation, which take a
+    /// walk over every node. The walk itself isn't counted.
+    pub fn stats(&mut self) -> Result<Stats, BTreeError> {
+        let counters = self.stats;
+        let cache = self.store.cache_stats();
+        let mut stats = Stats {
+            cache_hits: cache.hits.saturating_sub(self.cache_base.hits),
+            cache_misses: cache.misses.saturating_sub(self.cache_base.misses),
+            ..counters
+        };
+        let walked = self.for_each_leaf(|_, depth, node| {
+            let header = node.read_header()?;
+            stats.height = stats.height.max(depth);
+            stats.entries += u64::from(header.num_keys.get());
+            stats.fragmented_bytes +=
+                u64::from(header.fragmented_bytes) + u64::from(node.freeblock_bytes()?);
+            Ok(())
+        });
+        self.stats = counters;
+        let after = self.store.cache_stats();
+        self.cache_base.hits += after.hits - cache.hits;
+        self.cache_base.misses += after.misses - cache.misses;
+        walked?;
+        Ok(stats)
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.cache_base = self.store.cache_stats();
+    }
+
+    pub fn delete(&mut self, key: K) -> Result<Option<KeyValuePair>, BTreeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("delete", key = key.encode()).entered();
+        let old = self.delete_raw(key.encode())?;
+        self.decode_pair(old)
+    }
+
+    /// Replaces the value of `key`, returning the old one. Fails with KeyNotFound, changing
+    /// nothing, if `key` doesn't exist.
+    pub fn update(&mut self, key: K, value: &[u8]) -> Result<Vec<u8>, BTreeError> {
+        let key = key.encode();
+        if self.get_raw(key)?.is_none() {
+            return Err(BTreeError::KeyNotFound { key });
+        }
+        let value = self.encode(value)?;
+        let old = self.insert_raw(key, &value)?;
+        let old = self.decode_pair(old)?;
+        Ok(old.expect("Key exists, checked above").value)
+    }
+
+    /// Stores the merge of the value of `key`, if any, and `operand` through the configured
+    /// merge operator, returning the old entry
+    pub fn merge(&mut self, key: K, operand: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
+        let key = key.encode();
+        let operator = self
+            .config
+            .merge_operator
+            .ok_or(BTreeError::NoMergeOperator)?;
+        let existing = self.get_raw(key)?;
+        let existing = existing
+            .map(|stored| self.decode(key, stored))
+            .transpose()?;
+        let limit = self.capacity() - KEY_SIZE as usize;
+        let merged = operator.merge(key, existing.as_deref(), operand, limit)?;
+        let merged = self.encode(&merged)?;
+        let old = self.insert_raw(key, &merged)?;
+        self.decode_pair(old)
+    }
+
+    /// Moves the value of `old` to `new`, replacing whatever `new` held. Returns false if
+    /// `old` doesn't exist. If both keys live in the same leaf the leaf is rewritten once.
+    /// Otherwise `new` is written before `old` is removed and put back if removing `old`
+    /// fails, so an error leaves both keys as they were. If putting `new` back fails as
+    /// well, that error is returned and `new` may hold the value twice; wrap the tree in a
+    /// WalStore for all or nothing.
+    pub fn rename(&mut self, old: K, new: K) -> Result<bool, BTreeError> {
+        let (old, new) = (old.encode(), new.encode());
+        if old == new {
+            return Ok(self.get_raw(old)?.is_some());
+        }
+
+        let path = self.find_path(old)?;
+        if self.find_path(new)?.leaf == path.leaf {
+            let mut page = self.read_store_page(path.leaf as usize)?;
+            let mut node = Node::load_with_config(page.mutate(), self.config)?;
+            let Some(moved) = node.delete(old)? else {
+                return Ok(false);
+            };
+            // The page is a copy, if the leaf has to split nothing was written yet
+            if let Ok(replaced) = node.insert(new, &moved.value) {
+                self.count_defrags(path.leaf, node.defrags());
+                drop(node);
+                self.write_store_page(path.leaf as usize, &page)?;
+                if let (Some(_), Some(count)) = (replaced, &mut self.key_count) {
+                    *count -= 1;
+                }
+                self.writes.count(0);
+                self.writes.count(moved.value.len());
+                self.invalidate_absent(new);
+                self.notify_watcher(old);
+                self.notify_watcher(new);
+                return Ok(true);
+            }
+        }
+
+        let Some(value) = self.get_raw(old)? else {
+            return Ok(false);
+        };
+        let replaced = self.insert_raw(new, &value)?;
+        if let Err(err) = self.delete_raw(old) {
+            match replaced {
+                Some(pair) => self.insert_raw(new, &pair.value)?,
+                None => self.delete_raw(new)?,
+            };
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Exchanges the values of `a` and `b`. Returns false, changing nothing, unless both
+    /// exist. Like rename, keys sharing a leaf are swapped with a single page write.
+    /// Otherwise `a` is written first and gets its value back if writing `b` fails, so an
+    /// error leaves both values where they were. If restoring `a` fails as well, that error
+    /// is returned and `a` is left with the value of `b`; wrap the tree in a WalStore for
+    /// all or nothing.
+    pub fn swap(&mut self, a: K, b: K) -> Result<bool, BTreeError> {
+        let (a, b) = (a.encode(), b.encode());
+        let (Some(value_a), Some(value_b)) = (self.get_raw(a)?, self.get_raw(b)?) else {
+            return Ok(false);
+        };
+        if a == b {
+            return Ok(true);
+        }
+
+        let path = self.find_path(a)?;
+        if self.find_path(b)?.leaf == path.leaf {
+            let mut page = self.read_store_page(path.leaf as usize)?;
+            let mut node = Node::load_with_config(page.mutate(), self.config)?;
+            if node.insert(a, &value_b).is_ok() && node.insert(b, &value_a).is_ok() {
+                self.count_defrags(path.leaf, node.defrags());
+                drop(node);
+                self.write_store_page(path.leaf as usize, &page)?;
+                self.writes.count(value_b.len());
+                self.writes.count(value_a.len());
+                self.invalidate_absent(a);
+                self.invalidate_absent(b);
+                self.notify_watcher(a);
+                self.notify_watcher(b);
+                return Ok(true);
+            }
+        }
+
+        self.insert_raw(a, &value_b)?;
+        if let Err(err) = self.insert_raw(b, &value_a) {
+            self.insert_raw(a, &value_a)?;
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// The value stored as `stored`, decompressed if the tree has a codec
+    pub(super) fn encode<'v>(&self, value: &'v [u8]) -> Result<Cow<'v, [u8]>, BTreeError> {
+        if let Some(limit) = self.quota.max_value_size {
+            if value.len() > limit.into() {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                    limit: limit.into(),
+                    actual: value.len(),
+                }));
+            }
+        }
+        encode_value(self.config.compression, value)
+    }
+
+    pub(super) fn decode(&self, key: u64, stored: Vec<u8>) -> Result<Vec<u8>, BTreeError> {
+        decode_value(self.config.compression, key, stored, self.limits.max_memory)
+    }
+
+    fn decode_pair(&self, pair: Option<KeyValuePair>) -> Result<Option<KeyValuePair>, BTreeError> {
+        pair.map(|KeyValuePair { key, value }| {
+            let value = self.decode(key, value)?;
+            Ok(KeyValuePair { key, value })
+        })
+        .transpose()
+    }
+
+    /// The stored bytes of `key`'s value
+    pub(super) fn get_raw(&mut self, key: u64) -> Result<Option<Vec<u8>>, BTreeError> {
+        Ok(self.lookup(key)?.1)
+    }
+
+    /// The path to the leaf that holds or would hold `key`, and the stored value of `key`
+    pub(super) fn lookup(&mut self, key: u64) -> Result<(Path, Option<Vec<u8>>), BTreeError> {
+        let path = self.find_path(key)?;
+        let mut page = self.read_store_page(path.leaf as usize)?;
+        let stored = Node::load_with_config(page.mutate(), self.config)
+            .map(|node| track_leaf(node, self.access_tracker.as_deref(), path.leaf))
+            .and_then(|node| node.get(key)?.map(try_to_vec).transpose())
+            .map_err(self.in_page(path.leaf))?;
+        Ok((path, stored))
+    }
+
+    /// The leaf page holding `key`, along with where its value lies in that page
+    pub(crate) fn get_in_page(
+        &mut self,
+        key: K,
+    ) -> Result<Option<(Page, Range<usize>)>, BTreeError> {
+        let key = key.encode();
+        let path = self.find_path(key)?;
+        let mut page = self.read_store_page(path.leaf as usize)?;
+        let range = Node::load_with_config(page.mutate(), self.config)
+            .and_then(|node| node.value_range(key))
+            .map_err(self.in_page(path.leaf))?;
+        let Some(range) = range else {
+            return Ok(None);
+        };
+        if self.config.compression.is_none() {
+            return Ok(Some((page, range)));
+        }
+        // Raw values are still read in place, compressed ones from a page of their own
+        let stored = &page.read()[range.clone()];
+        if Compression::is_raw(stored) {
+            return Ok(Some((page, range.start + 1..range.end)));
+        }
+        let value = self.decode(key, try_to_vec(stored)?)?;
+        let len = value.len();
+        Ok(Some((Page::from_vec(value, len), 0..len)))
+    }
+
+    /// Looks up `key` in `page`, a copy of leaf `page_id`
+    pub(super) fn get_in_leaf(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+        key: u64,
+    ) -> Result<Option<Vec<u8>>, BTreeError> {
+        let node =
+            Node::load_with_config(page.mutate(), self.config).map_err(self.in_page(page_id))?;
+        let node = track_leaf(node, self.access_tracker.as_deref(), page_id);
+        let stored = node.get(key).map_err(self.in_page(page_id))?;
+        let stored = stored.map(try_to_vec).transpose()?;
+        stored.map(|stored| self.decode(key, stored)).transpose()
+    }
+
+    /// Inserts into `page`, a copy of leaf `page_id`, for writers that write the page back
+    /// themselves. `None` if the leaf would have to split, the page is left as it was then.
+    pub(super) fn insert_in_leaf(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+        key: u64,
+        value: &[u8],
+    ) -> Result<Option<Option<KeyValuePair>>, BTreeError> {
+        let value = self.encode(value)?;
+        let node =
+            Node::load_with_config(page.mutate(), self.config).map_err(self.in_page(page_id))?;
+        let mut node = track_leaf(node, self.access_tracker.as_deref(), page_id);
+        match node.insert(key, &value) {
+            Ok(old) => {
+                self.writes.count(value.len());
+                Ok(Some(self.decode_pair(old)?))
+            }
+            Err(BTreeError::NotEnoughSpace { .. }) => Ok(None),
+            Err(err) => Err(self.in_page(page_id)(err)),
+        }
+    }
+
+    /// Deletes from `page` like insert_in_leaf. `None` if the leaf would underflow and has to
+    /// be rebalanced.
+    pub(super) fn delete_in_leaf(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+        key: u64,
+    ) -> Result<Option<Option<KeyValuePair>>, BTreeError> {
+        let in_page = self.in_page(page_id);
+        let mut copy = page.clone();
+        let node = Node::load_with_config(copy.mutate(), self.config).map_err(&in_page)?;
+        let mut node = track_leaf(node, self.access_tracker.as_deref(), page_id);
+        let Some(deleted) = node.delete(key).map_err(&in_page)? else {
+            return Ok(Some(None));
+        };
+        // Only leaves below the root are rebalanced, see rebalance_path
+        let cost = node.entries().map_err(&in_page)?.cost(self.config);
+        if page_id != self.root && cost < self.underflow() {
+            return Ok(None);
+        }
+        drop(node);
+        *page = copy;
+        self.writes.count(0);
+        Ok(Some(self.decode_pair(Some(deleted))?))
+    }
+
+    /// Splits `page`, a copy of the leaf `page_id` that insert_in_leaf found full, with `key`
+    /// inserted. `page` keeps the left half and links to the right half, which is returned
+    /// for page `right`. `None` if the entries don't split in two, `page` is left as it was.
+    pub(super) fn split_in_leaf(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+        key: u64,
+        value: &[u8],
+        right: u32,
+    ) -> Result<Option<(Page, Option<KeyValuePair>)>, BTreeError> {
+        let in_page = self.in_page(page_id);
+        let value = self.encode(value)?;
+        let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        let link = node
+            .read_header()
+            .map_err(&in_page)?
+            .rightmost_child_page
+            .get();
+        let Entries::Leaf(mut entries) = node.entries().map_err(&in_page)? else {
+            unreachable!("Only leaves are split in place");
+        };
+        drop(node);
+        let old = insert_entry(&mut entries, key, &value);
+        let Ok([left, right_entries]) =
+            <[_; 2]>::try_from(split_leaf(entries, self.capacity(), self.config))
+        else {
+            return Ok(None);
+        };
+        self.record_leaf_access(page_id, &left);
+        let right_page = self.leaf_page(right_entries, link)?;
+        *page = self.leaf_page(left, right)?;
+        self.writes.count(value.len());
+        Ok(Some((right_page, self.decode_pair(old)?)))
+    }
+
+    /// The page the leaf `page_id` links to, 0 if none, see split_in_leaf
+    pub(super) fn leaf_link(&self, page_id: u32, page: &mut Page) -> Result<u32, BTreeError> {
+        let in_page = self.in_page(page_id);
+        let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        let header = node.read_header().map_err(&in_page)?;
+        Ok(header.rightmost_child_page.get())
+    }
+
+    pub(super) fn set_leaf_link(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+        link: u32,
+    ) -> Result<(), BTreeError> {
+        let in_page = self.in_page(page_id);
+        let mut node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        let header = node.mutate_header().map_err(&in_page)?;
+        header.rightmost_child_page.set(link);
+        Ok(())
+    }
+
+    /// The smallest key in `page`, a copy of leaf `page_id`
+    pub(super) fn first_key_in_leaf(
+        &self,
+        page_id: u32,
+        page: &mut Page,
+    ) -> Result<Option<u64>, BTreeError> {
+        let in_page = self.in_page(page_id);
+        let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        if node.read_header().map_err(&in_page)?.num_keys.get() == 0 {
+            return Ok(None);
+        }
+        node.key_at(0).map(Some).map_err(in_page)
+    }
+
+    /// Hooks `rights`, the leaves linked one after another from leaf `head` with their first
+    /// keys, into the parents of `head`. The links have to be cleared and the pages written.
+    pub(super) fn post_links(
+        &mut self,
+        head: u32,
+        rights: &[(u32, u64)],
+    ) -> Result<(), BTreeError> {
+        let (Some(&(_, first)), Some(&(last, _))) = (rights.first(), rights.last()) else {
+            return Ok(());
+        };
+        let path = self.find_path(first)?;
+        // Keys of a right half are above some key of the leaf it was split off, so never 0
+        let lefts = std::iter::once(head).chain(rights.iter().map(|&(page_id, _)| page_id));
+        let separators = rights.iter().map(|&(_, first)| first - 1).zip(lefts);
+        let split = Split {
+            separators: separators.collect(),
+            last,
+        };
+        self.split_path(&path, Some(split))
+    }
+
+    /// Writes page `page_id` as is, for pages changed through insert_in_leaf or delete_in_leaf
+    pub(super) fn write_page(&mut self, page_id: u32, page: &Page) -> Result<(), BTreeError> {
+        Ok(self.write_store_page(page_id as usize, page)?)
+    }
+
+    /// Page I/O of the tree goes through these, so that it's counted in the stats and traced
+    fn read_store_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("read_page", page = index).entered();
+        self.stats.page_reads += 1;
+        self.store.read_page(index)
+    }
+
+    fn write_store_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("write_page", page = index).entered();
+        self.stats.page_writes += 1;
+        self.store.write_page(index, page)
+    }
+
+    fn append_store_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("append_page").entered();
+        self.stats.page_writes += 1;
+        self.store.append_page(page)
+    }
+
+    /// Counts the defrags of the node in page `page_id` before it's written
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn count_defrags(&mut self, page_id: u32, defrags: u32) {
+        self.stats.defrags += u64::from(defrags);
+        #[cfg(feature = "tracing")]
+        if defrags > 0 {
+            tracing::debug!(name: "defrag", page = page_id, defrags, "defragmented node");
+        }
+    }
+
+    fn insert_raw(&mut self, key: u64, value: &[u8]) -> Result<Option<KeyValuePair>, BTreeError> {
+        let path = self.find_path(key)?;
+        self.insert_at(&path, key, value)
+    }
+
+    /// Inserts `key` into the leaf at the end of `path`, which has to be the one for `key`
+    pub(super) fn insert_at(
+        &mut self,
+        path: &Path,
+        key: u64,
+        value: &[u8],
+    ) -> Result<Option<KeyValuePair>, BTreeError> {
+        self.invalidate_absent(key);
+        let mut page = self.read_store_page(path.leaf as usize)?;
+        let in_page = self.in_page(path.leaf);
+        // A clone, the node can't borrow the tree while its defrags are counted
+        let tracker = self.access_tracker.clone();
+        let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        let mut node = track_leaf(node, tracker.as_deref(), path.leaf);
+        let (old, entries) = match node.insert(key, value) {
+            Ok(old) => {
+                if old.is_none() {
+                    self.check_new_key()?;
+                }
+                self.count_defrags(path.leaf, node.defrags());
+                drop(node);
+                self.write_store_page(path.leaf as usize, &page)?;
+                if old.is_none() {
+                    self.count_added_key();
+                }
+                self.writes.count(value.len());
+                self.notify_watcher(key);
+                return Ok(old);
+            }
+            Err(BTreeError::NotEnoughSpace { .. }) => {
+                let Entries::Leaf(mut entries) = node.entries().map_err(&in_page)? else {
+                    unreachable!("Paths end in leaves");
+                };
+                let old = insert_entry(&mut entries, key, value);
+                (old, Entries::Leaf(entries))
+            }
+            Err(err) => return Err(in_page(err)),
+        };
+
+        drop(node);
+        if old.is_none() {
+            self.check_new_key()?;
+        }
+        self.check_growth(path, &entries)?;
+        let split = self.write_split(path.leaf, entries)?;
+        if old.is_none() {
+            self.count_added_key();
+        }
+        self.writes.count(value.len());
+        self.split_path(path, split)?;
+        self.notify_watcher(key);
+        Ok(old)
+    }
+
+    fn delete_raw(&mut self, key: u64) -> Result<Option<KeyValuePair>, BTreeError> {
+        let path = self.find_path(key)?;
+        self.delete_at(&path, key)
+    }
+
+    /// Deletes `key` from the leaf at the end of `path`, which has to be the one for `key`
+    pub(super) fn delete_at(
+        &mut self,
+        path: &Path,
+        key: u64,
+    ) -> Result<Option<KeyValuePair>, BTreeError> {
+        let mut page = self.read_store_page(path.leaf as usize)?;
+        let deleted = Node::load_with_config(page.mutate(), self.config)
+            .and_then(|node| {
+                track_leaf(node, self.access_tracker.as_deref(), path.leaf).delete(key)
+            })
+            .map_err(self.in_page(path.leaf))?;
+        let Some(deleted) = deleted else {
+            return Ok(None);
+        };
+        self.write_store_page(path.leaf as usize, &page)?;
+        if let Some(count) = &mut self.key_count {
+            *count -= 1;
+        }
+        self.writes.count(0);
+
+        self.rebalance_path(path, self.underflow())?;
+        self.notify_watcher(key);
+        Ok(Some(deleted))
+    }
+
+    /// Whether inserts and deletes have to go through insert_at and delete_at, which keep
+    /// the tree within its max_keys and max_depth quotas
+    pub(super) fn has_quota(&self) -> bool {
+        self.quota.max_keys.is_some() || self.quota.max_depth.is_some()
+    }
+
+    /// Checks the max_keys quota for a key about to be added. Counts the keys of every leaf
+    /// the first time.
+    fn check_new_key(&mut self) -> Result<(), BTreeError> {
+        if self.quota.max_keys.is_none() {
+            return Ok(());
+        }
+        let count = match self.key_count {
+            Some(count) => count,
+            None => {
+                let mut count = 0;
+                self.for_each_leaf(|_, _, node| {
+                    count += u64::from(node.read_header()?.num_keys.get());
+                    Ok(())
+                })?;
+                count
+            }
+        };
+        self.key_count = Some(count);
+        self.check_key_quota(count + 1)
+    }
+
+    /// Counts a key insert_at added, once the keys are counted
+    fn count_added_key(&mut self) {
+        if let Some(count) = &mut self.key_count {
+            *count += 1;
+        }
+    }
+
+    /// Fails with MaxKeys if the tree may not hold `keys` keys
+    fn check_key_quota(&self, keys: u64) -> Result<(), BTreeError> {
+        match self.quota.max_keys {
+            Some(limit) if keys > limit.into() => {
+                Err(BTreeError::LimitExceeded(LimitError::MaxKeys { limit }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with MaxDepth if splitting the leaf at the end of `path` into `leaf` would
+    /// split every node above it, growing the tree past its max_depth
+    fn check_growth(&mut self, path: &Path, leaf: &Entries) -> Result<(), BTreeError> {
+        if path.internal.len() + 1 < self.limits.max_depth {
+            return Ok(());
+        }
+        let Entries::Leaf(entries) = leaf else {
+            unreachable!("Paths end in leaves");
+        };
+        let mut separators = split_leaf(entries.clone(), self.capacity(), self.config).len() - 1;
+        for &(page_id, _) in path.internal.iter().rev() {
+            let Entries::Internal { children, .. } = self.read_entries(page_id)? else {
+                unreachable!("Parents are internal nodes");
+            };
+            if (children.len() + separators) * KEY_SIZE as usize <= self.capacity() {
+                return Ok(());
+            }
+            // Internal nodes split in two
+            separators = 1;
+        }
+        match separators {
+            0 => Ok(()),
+            _ => Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                limit: self.limits.max_depth,
+            })),
+        }
+    }
+
+    /// Merges leaves that are filled less than `min_fill` (a fraction of the page) with a
+    /// sibling. Values never span pages, so updates that shrink values don't leave partly
+    /// used overflow pages behind, but they can leave leaves mostly empty, which inserts
+    /// never rebalance. Returns the number of pages freed.
+    pub fn compact(&mut self, min_fill: f64) -> Result<usize, BTreeError> {
+        let threshold = (self.capacity() as f64 * min_fill.clamp(0.0, 1.0)) as usize;
+        let mut freed = 0;
+        let mut key = 0;
+        loop {
+            let path = self.find_path(key)?;
+            freed += self.rebalance_path(&path, threshold)?;
+
+            // Continue behind the leaf `key` ended up in
+            let path = self.find_path(key)?;
+            let Some(next) = self
+                .upper_bound(&path)?
+                .and_then(|bound| bound.checked_add(1))
+            else {
+                break;
+            };
+            key = next;
+        }
+        Ok(freed)
+    }
+
+    /// Checks every structural invariant of the tree, failing with the first one violated:
+    /// every node passes Node::validate, its keys lie within the separators above it, all
+    /// leaves are equally deep and no page is reached twice. Reads every page of the tree.
+    pub fn validate(&mut self) -> Result<(), BTreeError> {
+        let mut reached = HashSet::new();
+        let mut leaf_depth = None;
+        // Pages with their depth and the keys routed to them, above the first bound and up
+        // to the second
+        let mut stack = vec![(self.root, 1, None, None)];
+        while let Some((page_id, depth, low, high)) = stack.pop() {
+            if depth > self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            let in_page = self.in_page(page_id);
+            let corrupt = |err| in_page(BTreeError::Corrupt(err));
+            if !reached.insert(page_id) {
+                return Err(corrupt(CorruptionError::SharedPage));
+            }
+            let mut page = self.read_store_page(page_id as usize)?;
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            node.validate().map_err(&in_page)?;
+
+            let num_keys = node.read_header().map_err(&in_page)?.num_keys.get();
+            let mut keys = Vec::with_capacity(num_keys.into());
+            for idx in 0..num_keys {
+                let key = node.key_at(idx).map_err(&in_page)?;
+                if low.is_some_and(|low| key <= low) || high.is_some_and(|high| key > high) {
+                    return Err(corrupt(CorruptionError::KeyOutsideSeparators { key }));
+                }
+                keys.push(key);
+            }
+            if node.is_leaf().map_err(&in_page)? {
+                let expected = *leaf_depth.get_or_insert(depth);
+                if depth != expected {
+                    return Err(corrupt(CorruptionError::UnevenLeaves { depth, expected }));
+                }
+                continue;
+            }
+            for idx in 0..=num_keys {
+                let child = node.child_at(idx).map_err(&in_page)?;
+                let child_low = idx
+                    .checked_sub(1)
+                    .map_or(low, |prev| Some(keys[prev as usize]));
+                let child_high = keys.get(idx as usize).copied().or(high);
+                stack.push((child, depth + 1, child_low, child_high));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists empty leaves and pages that are neither reachable from the root nor reserved
+    /// by the store. Meant as a check before vacuuming and after rebalancing.
+    pub fn husks(&mut self) -> Result<HuskReport, BTreeError> {
+        let mut report = HuskReport::default();
+        let mut reachable = HashSet::new();
+        let mut stack = vec![(self.root, 1)];
+        while let Some((page_id, depth)) = stack.pop() {
+            if depth > self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            // A page reached twice is corruption, not a husk, and would loop forever
+            if !reachable.insert(page_id) {
+                continue;
+            }
+            let mut page = self.read_store_page(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            let num_keys = node.read_header().map_err(&in_page)?.num_keys.get();
+            if node.is_leaf().map_err(&in_page)? {
+                if num_keys == 0 && page_id != self.root {
+                    report.empty_leaves.push(page_id);
+                }
+                continue;
+            }
+            for idx in 0..=num_keys {
+                stack.push((node.child_at(idx).map_err(&in_page)?, depth + 1));
+            }
+        }
+        report.empty_leaves.sort_unstable();
+
+        let reserved: HashSet<usize> = self.store.reserved_pages()?.into_iter().collect();
+        for page_id in 0..self.store.n_pages()? {
+            let Ok(page_no) = u32::try_from(page_id) else {
+                break;
+            };
+            if !reachable.contains(&page_no)
+                && !reserved.contains(&page_id)
+                && !self.free_pages.contains(&page_no)
+            {
+                report.orphaned.push(page_no);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fragmentation of every leaf, in no particular order
+    pub fn fragmentation(&mut self) -> Result<Vec<LeafFragmentation>, BTreeError> {
+        let mut leaves = Vec::new();
+        self.for_each_leaf(|page_id, _, node| {
+            leaves.push(LeafFragmentation {
+                page_id,
+                fragmented_bytes: node.read_header()?.fragmented_bytes.into(),
+                freeblock_bytes: node.freeblock_bytes()?,
+            });
+            Ok(())
+        })?;
+        Ok(leaves)
+    }
+
+    /// Calls `f` with every leaf, its page and its depth, the root's being 1
+    fn for_each_leaf(
+        &mut self,
+        mut f: impl FnMut(u32, usize, &Node) -> Result<(), BTreeError>,
+    ) -> Result<(), BTreeError> {
+        let mut stack = vec![(self.root, 1)];
+        while let Some((page_id, depth)) = stack.pop() {
+            if depth > self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            let mut page = self.read_store_page(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            if node.is_leaf().map_err(&in_page)? {
+                f(page_id, depth, &node).map_err(&in_page)?;
+                continue;
+            }
+            let num_keys = node.read_header().map_err(&in_page)?.num_keys.get();
+            for idx in 0..=num_keys {
+                stack.push((node.child_at(idx).map_err(&in_page)?, depth + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `n` leaves with the most bytes to reclaim, worst first, for maintenance that
+    /// defragments a few leaves at a time. Leaves without fragmentation are left out.
+    pub fn most_fragmented(&mut self, n: usize) -> Result<Vec<LeafFragmentation>, BTreeError> {
+        let mut leaves = self.fragmentation()?;
+        leaves.retain(|leaf| leaf.score() > 0);
+        leaves.sort_unstable_by_key(|leaf| (std::cmp::Reverse(leaf.score()), leaf.page_id));
+        leaves.truncate(n);
+        Ok(leaves)
+    }
+
+    /// Defragments the leaf `page_id`, one of those listed by most_fragmented
+    pub fn defrag_leaf(&mut self, page_id: u32) -> Result<(), BTreeError> {
+        let mut page = self.read_store_page(page_id as usize)?;
+        let in_page = self.in_page(page_id);
+        let mut node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+        if !node.is_leaf().map_err(&in_page)? {
+            return Err(BTreeError::NotLeaf);
+        }
+        node.defrag().map_err(&in_page)?;
+        self.count_defrags(page_id, node.defrags());
+        drop(node);
+        Ok(self.write_store_page(page_id as usize, &page)?)
+    }
+
+    pub(super) fn find_path(&mut self, key: u64) -> Result<Path, BTreeError> {
+        let mut internal = Vec::new();
+        let mut page_id = self.root;
+        loop {
+            let mut page = self.read_store_page(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            if node.is_leaf().map_err(&in_page)? {
+                return Ok(Path {
+                    internal,
+                    leaf: page_id,
+                });
+            }
+            if internal.len() + 1 >= self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            let child_idx = node.child_index(key).map_err(&in_page)?;
+            internal.push((page_id, child_idx));
+            page_id = node.child_at(child_idx).map_err(&in_page)?;
+        }
+    }
+
+    /// The path to the leaf that may hold `key` and the leaf's entries
+    pub(super) fn leaf_at(&mut self, key: u64) -> Result<(Path, LeafEntries), BTreeError> {
+        let path = self.find_path(key)?;
+        let Entries::Leaf(mut entries) = self.read_entries(path.leaf)? else {
+            unreachable!("Paths end in leaves");
+        };
+        if self.config.compression.is_some() {
+            for (key, value) in &mut entries {
+                *value = self.decode(*key, mem::take(value))?;
+            }
+        }
+        Ok((path, entries))
+    }
+
+    /// Largest key the leaf before the one at the end of `path` may hold, `None` for the
+    /// leftmost leaf
+    pub(super) fn lower_bound(&mut self, path: &Path) -> Result<Option<u64>, BTreeError> {
+        for &(page_id, child_idx) in path.internal.iter().rev() {
+            if child_idx == 0 {
+                continue;
+            }
+            let Entries::Internal { children, .. } = self.read_entries(page_id)? else {
+                unreachable!("Paths only go through internal nodes");
+            };
+            return Ok(Some(children[child_idx as usize - 1].0));
+        }
+        Ok(None)
+    }
+
+    /// Largest key the leaf at the end of `path` may hold, `None` for the rightmost leaf
+    pub(super) fn upper_bound(&mut self, path: &Path) -> Result<Option<u64>, BTreeError> {
+        for &(page_id, child_idx) in path.internal.iter().rev() {
+            let Entries::Internal { children, .. } = self.read_entries(page_id)? else {
+                unreachable!("Paths only go through internal nodes");
+            };
+            if let Some((separator, _)) = children.get(child_idx as usize) {
+                return Ok(Some(*separator));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls `f` with the encoded key and the value of every entry within `range` in key
+    /// order, reading one leaf at a time
+    pub(crate) fn for_each_in_range(
+        &mut self,
+        range: impl RangeBounds<K>,
+        mut f: impl FnMut(u64, &[u8]) -> Result<(), BTreeError>,
+    ) -> Result<(), BTreeError> {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.encode()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let range = (encode(range.start_bound()), encode(range.end_bound()));
+        let mut key = match range.0 {
+            Bound::Included(start) => start,
+            Bound::Excluded(start) => match start.checked_add(1) {
+                Some(start) => start,
+                None => return Ok(()),
+            },
+            Bound::Unbounded => 0,
+        };
+
+        loop {
+            let path = self.find_path(key)?;
+            let mut page = self.read_store_page(path.leaf as usize)?;
+            let in_page = self.in_page(path.leaf);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            for (key, value) in node.iter_range(range).map_err(&in_page)? {
+                match self.config.compression {
+                    Some(_) => f(key, &self.decode(key, try_to_vec(value)?)?)?,
+                    None => f(key, value)?,
+                }
+            }
+
+            let Some(bound) = self.upper_bound(&path)? else {
+                return Ok(());
+            };
+            // The next leaf starts right behind the bound
+            let past_end = match range.1 {
+                Bound::Included(end) => end <= bound,
+                Bound::Excluded(end) => end <= bound.saturating_add(1),
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return Ok(());
+            }
+            match bound.checked_add(1) {
+                Some(next) => key = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Bytes available for key records and values in a page
+    fn capacity(&self) -> usize {
+        let mut reserved = HEADER_SIZE;
+        if self.config.checksums {
+            reserved += CHECKSUM_SIZE;
+        }
+        if self.config.reserve_tail {
+            reserved += RESERVED_TAIL;
+        }
+        // The first aligned value may have to leave a gap at the end of the content area
+        self.store.page_size() - reserved as usize - (self.value_alignment() - 1)
+    }
+
+    /// Alignment of value offsets in the tree's leaves
+    fn value_alignment(&self) -> usize {
+        value_alignment(self.config)
+    }
+
+    /// Nodes filled less than this are merged with a sibling
+    fn underflow(&self) -> usize {
+        self.capacity() / 4
+    }
+
+    pub(super) fn allocate(&mut self) -> Result<u32, BTreeError> {
+        if let Some(page_id) = self.free_pages.pop() {
+            return Ok(page_id);
+        }
+        let page_id = self.append_store_page(&Page::new(self.store.page_size()))?;
+        page_id.try_into().map_err(|_| BTreeError::NotEnoughSpace {
+            required: page_id,
+            actual: u32::MAX as usize,
+        })
+    }
+
+    /// Allocates an empty tree next to this one in the same store, returning its root
+    pub(super) fn create_subtree(&mut self) -> Result<u32, BTreeError> {
+        let root = self.allocate()?;
+        self.write_entries(root, &Entries::Leaf(Vec::new()))?;
+        Ok(root)
+    }
+
+    /// Runs `f` on the tree rooted at `root` in the same store, as `create_subtree` made it
+    pub(super) fn in_subtree<T>(
+        &mut self,
+        root: u32,
+        f: impl FnOnce(&mut Self) -> Result<T, BTreeError>,
+    ) -> Result<T, BTreeError> {
+        let own = mem::replace(&mut self.root, root);
+        let watcher = self.watcher.take();
+        let quota = mem::take(&mut self.quota);
+        let key_count = self.key_count.take();
+        let result = f(self);
+        self.root = own;
+        self.watcher = watcher;
+        self.quota = quota;
+        self.key_count = key_count;
+        result
+    }
+
+    /// Returns `page_id` to the store, or keeps it for reuse if the store has no freelist
+    pub(super) fn release(&mut self, page_id: u32) -> Result<(), BTreeError> {
+        if !self.store.release_page(page_id as usize)? {
+            self.free_pages.push(page_id);
+        }
+        Ok(())
+    }
+
+    fn read_entries(&mut self, page_id: u32) -> Result<Entries, BTreeError> {
+        let mut page = self.read_store_page(page_id as usize)?;
+        let entries = Node::load_with_config(page.mutate(), self.config)
+            .and_then(|node| node.entries())
+            .map_err(self.in_page(page_id));
+        entries
+    }
+
+    /// Attributes errors about a node's contents to page `page_id`
+    fn in_page(&self, page_id: u32) -> impl Fn(BTreeError) -> BTreeError {
+        let offset = page_id as u64 * self.store.page_size() as u64;
+        move |err| err.in_page(page_id, offset)
+    }
+
+    /// Rewrites page `page_id` from scratch so it holds exactly `entries`
+    fn write_entries(&mut self, page_id: u32, entries: &Entries) -> Result<(), BTreeError> {
+        let page = self.entries_page(entries)?;
+        self.write_store_page(page_id as usize, &page)?;
+        Ok(())
+    }
+
+    /// A leaf holding exactly `entries` that links to `link`
+    fn leaf_page(&self, entries: LeafEntries, link: u32) -> Result<Page, BTreeError> {
+        let mut page = self.entries_page(&Entries::Leaf(entries))?;
+        let mut node = Node::load_with_config(page.mutate(), self.config)?;
+        node.mutate_header()?.rightmost_child_page.set(link);
+        drop(node);
+        Ok(page)
+    }
+
+    fn entries_page(&self, entries: &Entries) -> Result<Page, BTreeError> {
+        let mut page = Page::new(self.store.page_size());
+        let mut node = Node::new_with_config(page.mutate(), self.config)?;
+        match entries {
+            Entries::Leaf(entries) => {
+                for (key, value) in entries {
+                    node.insert(*key, value)?;
+                }
+            }
+            Entries::Internal {
+                children,
+                rightmost,
+            } => {
+                let header = node.mutate_header()?;
+                header.node_type = NodeType::Internal;
+                header.rightmost_child_page.set(*rightmost);
+                for (key, child) in children {
+                    node.insert_separator(*key, *child)?;
+                }
+            }
+        }
+        drop(node);
+        Ok(page)
+    }
+
+    /// Writes `entries` to `page_id`, spilling into new pages if they don't fit
+    fn write_split(&mut self, page_id: u32, entries: Entries) -> Result<Option<Split>, BTreeError> {
+        let (pieces, separators) = split(entries, self.capacity(), self.config);
+        let mut page_ids = vec![page_id];
+        for _ in 1..pieces.len() {
+            page_ids.push(self.allocate()?);
+        }
+        for (piece, page_id) in pieces.iter().zip(&page_ids) {
+            self.write_entries(*page_id, piece)?;
+        }
+        // Leaves split by an insert, the leaf keeps the first piece
+        if let Entries::Leaf(entries) = &pieces[0] {
+            self.record_leaf_access(page_id, entries);
+        }
+
+        let last = page_ids.pop().expect("There is at least one piece");
+        if separators.is_empty() {
+            return Ok(None);
+        }
+        self.stats.splits += 1;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(name: "split", page = page_id, pieces = pieces.len(), "split node");
+        Ok(Some(Split {
+            separators: separators.into_iter().zip(page_ids).collect(),
+            last,
+        }))
+    }
+
+    /// Hooks the pieces of a split child at `child_idx` into `parent`, which may split in turn
+    fn insert_separators(
+        &mut self,
+        parent: u32,
+        child_idx: u16,
+        split: Split,
+    ) -> Result<Option<Split>, BTreeError> {
+        let Entries::Internal {
+            mut children,
+            mut rightmost,
+        } = self.read_entries(parent)?
+        else {
+            unreachable!("Parents are internal nodes");
+        };
+
+        let idx = child_idx as usize;
+        if idx == children.len() {
+            rightmost = split.last;
+        } else {
+            children[idx].1 = split.last;
+        }
+        children.splice(idx..idx, split.separators);
+
+        self.write_split(
+            parent,
+            Entries::Internal {
+                children,
+                rightmost,
+            },
+        )
+    }
+
+    /// Hooks the pieces of the split leaf at the end of `path` into the nodes above it
+    fn split_path(&mut self, path: &Path, mut split: Option<Split>) -> Result<(), BTreeError> {
+        for &(page_id, child_idx) in path.internal.iter().rev() {
+            let Some(child_split) = split else {
+                break;
+            };
+            split = self.insert_separators(page_id, child_idx, child_split)?;
+        }
+        if let Some(split) = split {
+            self.grow_root(split)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the split root's first piece out of the root page and puts a new internal
+    /// root above the pieces
+    fn grow_root(&mut self, mut split: Split) -> Result<(), BTreeError> {
+        let first = self.allocate()?;
+        let page = self.read_store_page(self.root as usize)?;
+        self.write_store_page(first as usize, &page)?;
+
+        split.separators[0].1 = first;
+        let root = Entries::Internal {
+            children: split.separators,
+            rightmost: split.last,
+        };
+        self.write_entries(self.root, &root)
+    }
+
+    /// Rebalances the leaf at the end of `path` if it's filled less than `threshold`,
+    /// and every node above it that underflows in turn. Returns the number of pages freed.
+    fn rebalance_path(&mut self, path: &Path, threshold: usize) -> Result<usize, BTreeError> {
+        let mut freed = 0;
+        let mut child = path.leaf;
+        let mut threshold = threshold;
+        for &(parent, child_idx) in path.internal.iter().rev() {
+            if self.read_entries(child)?.cost(self.config) >= threshold {
+                break;
+            }
+            if self.rebalance(parent, child_idx)? {
+                freed += 1;
+            }
+            child = parent;
+            threshold = self.underflow();
+        }
+        Ok(freed + self.shrink_root()?)
+    }
+
+    /// Pulls the only child of an internal root without keys up into the root page.
+    /// Returns the number of pages freed.
+    fn shrink_root(&mut self) -> Result<usize, BTreeError> {
+        let mut freed = 0;
+        while let Entries::Internal {
+            children,
+            rightmost,
+        } = self.read_entries(self.root)?
+        {
+            if !children.is_empty() {
+                break;
+            }
+            let page = self.read_store_page(rightmost as usize)?;
+            self.write_store_page(self.root as usize, &page)?;
+            self.release(rightmost)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    /// Merges the child at `child_idx` of `parent` with a sibling, or evens out their
+    /// entries if they don't fit into one page. Returns whether the two were merged.
+    fn rebalance(&mut self, parent: u32, child_idx: u16) -> Result<bool, BTreeError> {
+        let Entries::Internal {
+            mut children,
+            rightmost,
+        } = self.read_entries(parent)?
+        else {
+            unreachable!("Parents are internal nodes");
+        };
+        if children.is_empty() {
+            return Ok(false);
+        }
+
+        let left_idx = (child_idx as usize).saturating_sub(1);
+        let (separator, left_page) = children[left_idx];
+        let right_page = children
+            .get(left_idx + 1)
+            .map_or(rightmost, |(_, page)| *page);
+
+        let combined = match (
+            self.read_entries(left_page)?,
+            self.read_entries(right_page)?,
+        ) {
+            (Entries::Leaf(mut left), Entries::Leaf(right)) => {
+                left.extend(right);
+                Entries::Leaf(left)
+            }
+            (
+                Entries::Internal {
+                    children: mut left,
+                    rightmost: left_rightmost,
+                },
+                Entries::Internal {
+                    children: right,
+                    rightmost: right_rightmost,
+                },
+            ) => {
+                // The separator comes down between the two halves
+                left.push((separator, left_rightmost));
+                left.extend(right);
+                Entries::Internal {
+                    children: left,
+                    rightmost: right_rightmost,
+                }
+            }
+            _ => unreachable!("Siblings are on the same level"),
+        };
+
+        let (pieces, separators) = split(combined, self.capacity(), self.config);
+        let merged = match <[Entries; 2]>::try_from(pieces) {
+            Ok([left, right]) => {
+                self.write_entries(left_page, &left)?;
+                self.write_entries(right_page, &right)?;
+                children[left_idx].0 = separators[0];
+                false
+            }
+            Err(mut pieces) => {
+                debug_assert_eq!(pieces.len(), 1, "Two pages worth of entries fit two pages");
+                let merged = pieces.pop().expect("There is at least one piece");
+                self.write_entries(right_page, &merged)?;
+                children.remove(left_idx);
+                self.release(left_page)?;
+                self.stats.merges += 1;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(name: "merge", page = right_page, from = left_page, "merged nodes");
+                true
+            }
+        };
+
+        self.write_entries(
+            parent,
+            &Entries::Internal {
+                children,
+                rightmost,
+            },
+        )?;
+        Ok(merged)
+    }
+}
+
+impl<S: SharedRead, K: KeyCodec> BTree<S, K> {
+    /// Looks up `key` like get, but through shared reads of the store, so that threads
+    /// sharing the tree can look up keys at once. Isn't timed in the latency stats.
+    pub fn get_shared(&self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        let key = key.encode();
+        let (leaf, mut page) = self.leaf_shared(key)?;
+        self.get_in_leaf(leaf, &mut page, key)
+    }
+
+    /// The leaf that may hold `key` and its page, found through shared reads
+    pub(super) fn leaf_shared(&self, key: u64) -> Result<(u32, Page), BTreeError> {
+        let mut page_id = self.root;
+        for _ in 0..self.limits.max_depth {
+            let mut page = self.store.read_page_shared(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            if node.is_leaf().map_err(&in_page)? {
+                drop(node);
+                return Ok((page_id, page));
+            }
+            let child_idx = node.child_index(key).map_err(&in_page)?;
+            page_id = node.child_at(child_idx).map_err(&in_page)?;
+        }
+        Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+            limit: self.limits.max_depth,
+        }))
+    }
+
+    /// Copies every entry into a snapshot that doesn't borrow the tree, so it outlives
+    /// transactions and the tree itself. It's a copy, not a view of pinned pages: it reads
+    /// every leaf and decodes every value before returning, taking time and memory in
+    /// proportion to the whole tree. It only needs the tree shared, like other readers.
+    /// Over a WalStore it holds the changes since the last commit too,
+    /// iter_committed_snapshot leaves them out.
+    pub fn iter_snapshot(&self) -> Result<TreeSnapshot<K>, BTreeError> {
+        self.snapshot_pages(|page_id| self.store.read_page_shared(page_id))
+    }
+
+    /// Copies the entries of the tree whose pages `read` returns into a snapshot
+    fn snapshot_pages(
+        &self,
+        read: impl Fn(usize) -> Result<Page, io::Error>,
+    ) -> Result<TreeSnapshot<K>, BTreeError> {
+        let mut entries = Vec::new();
+        let mut leaves = 0;
+        let mut stack = vec![(self.root, 1)];
+        while let Some((page_id, depth)) = stack.pop() {
+            if depth > self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            let mut page = read(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            if node.is_leaf().map_err(&in_page)? {
+                leaves += 1;
+                for (key, value) in node.iter().map_err(&in_page)? {
+                    entries.push((key, self.decode(key, try_to_vec(value)?)?));
+                }
+                continue;
+            }
+            let num_keys = node.read_header().map_err(&in_page)?.num_keys.get();
+            for idx in 0..=num_keys {
+                stack.push((node.child_at(idx).map_err(&in_page)?, depth + 1));
+            }
+        }
+        Ok(TreeSnapshot::new(entries, leaves))
+    }
+}
+
+#[cfg(feature = "wal")]
+impl<S: SharedRead, K: KeyCodec> BTree<WalStore<S>, K> {
+    /// Like iter_snapshot, as of the last commit
+    pub fn iter_committed_snapshot(&self) -> Result<TreeSnapshot<K>, BTreeError> {
+        self.snapshot_pages(|page_id| self.store.read_committed_shared(page_id))
+    }
+}
+
+impl<K: KeyCodec> BTree<Pager, K> {
+    /// Returns the free pages of the file to the file system. Pages of the tree at the end of
+    /// the file are moved into free pages before them, then the free pages at the end are cut
+    /// off, see Pager::truncate_free_tail. Every page has to be free or reached by this
+    /// tree, files with other trees in their catalog can't shrink. Returns the number of
+    /// pages cut off.
+    pub fn shrink_to_fit(&mut self) -> Result<u32, BTreeError> {
+        let mut reachable = HashSet::new();
+        let internal = self.reach(self.root, &mut reachable)?;
+        let reserved: HashSet<usize> = self.store.reserved_pages()?.into_iter().collect();
+        let n_pages = self.store.n_pages()?;
+        let foreign = (0..n_pages as u32).find(|page_id| {
+            !reserved.contains(&(*page_id as usize))
+                && !reachable.contains(page_id)
+                && !self.free_pages.contains(page_id)
+        });
+        if let Some(page_id) = foreign {
+            return Err(BTreeError::ForeignPage(page_id));
+        }
+
+        // The lowest free pages are handed out first, there are as many before `end` as
+        // pages of the tree after it
+        self.store.sort_freelist()?;
+        let end = n_pages - self.store.free_pages() as usize;
+        let mut tail: Vec<u32> = reachable
+            .into_iter()
+            .filter(|&page_id| page_id as usize >= end)
+            .collect();
+        tail.sort_unstable();
+        let mut moved = BTreeMap::new();
+        for &page_id in &tail {
+            let page = self.read_store_page(page_id as usize)?;
+            moved.insert(page_id, self.append_store_page(&page)? as u32);
+        }
+        let new_id = |page_id: u32| moved.get(&page_id).copied().unwrap_or(page_id);
+        for parent in internal {
+            let Entries::Internal {
+                mut children,
+                rightmost,
+            } = self.read_entries(new_id(parent))?
+            else {
+                unreachable!("Listed as internal above");
+            };
+            let points_at_moved = children.iter().map(|&(_, child)| child).chain([rightmost]);
+            if !points_at_moved
+                .into_iter()
+                .any(|child| moved.contains_key(&child))
+            {
+                continue;
+            }
+            for (_, child) in &mut children {
+                *child = new_id(*child);
+            }
+            let entries = Entries::Internal {
+                children,
+                rightmost: new_id(rightmost),
+            };
+            self.write_entries(new_id(parent), &entries)?;
+        }
+        self.move_root(new_id(self.root))?;
+        for page_id in tail {
+            self.store.free(page_id)?;
+        }
+        Ok(self.store.truncate_free_tail()?)
+    }
+
+    /// Finds the pages of the file that no tree uses and the file doesn't reserve either, the
+    /// ones a crash leaked between allocating a page and linking it into a tree, or between
+    /// unlinking and freeing it. Trees are walked from this tree's root, the one in the file
+    /// header and those in the catalog, with this tree's config. Unless `dry_run`, the leaked
+    /// pages go on the freelist. Pages of trees whose roots aren't recorded anywhere count as
+    /// leaked, record the root of every tree in the file before.
+    pub fn gc_unreachable(&mut self, dry_run: bool) -> Result<LeakReport, BTreeError> {
+        let mut roots = vec![self.root, self.store.root()];
+        roots.extend(self.store.catalog()?.iter().map(|(_, meta)| meta.root));
+        roots.retain(|&root| root != 0);
+        roots.sort_unstable();
+        roots.dedup();
+
+        // Every tree is walked before anything is freed, a tree that can't be read stops it
+        let mut reachable = HashSet::new();
+        for &root in &roots {
+            self.reach(root, &mut reachable)?;
+        }
+        let reserved: HashSet<usize> = self.store.reserved_pages()?.into_iter().collect();
+        let leaked: Vec<u32> = (0..self.store.n_pages()? as u32)
+            .filter(|page_id| {
+                !reserved.contains(&(*page_id as usize))
+                    && !reachable.contains(page_id)
+                    && !self.free_pages.contains(page_id)
+            })
+            .collect();
+        if !dry_run {
+            for &page_id in &leaked {
+                self.store.free(page_id)?;
+            }
+        }
+        Ok(LeakReport {
+            roots,
+            reachable: reachable.len(),
+            leaked,
+            reclaimed: !dry_run,
+        })
+    }
+
+    /// Adds the pages of the tree rooted at `root` to `reachable` and returns the internal
+    /// ones among them. Pages reached before aren't walked again.
+    fn reach(&mut self, root: u32, reachable: &mut HashSet<u32>) -> Result<Vec<u32>, BTreeError> {
+        let mut internal = Vec::new();
+        let mut stack = vec![(root, 1)];
+        while let Some((page_id, depth)) = stack.pop() {
+            if depth > self.limits.max_depth {
+                return Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: self.limits.max_depth,
+                }));
+            }
+            if !reachable.insert(page_id) {
+                continue;
+            }
+            let mut page = self.read_store_page(page_id as usize)?;
+            let in_page = self.in_page(page_id);
+            let node = Node::load_with_config(page.mutate(), self.config).map_err(&in_page)?;
+            if node.is_leaf().map_err(&in_page)? {
+                continue;
+            }
+            internal.push(page_id);
+            let num_keys = node.read_header().map_err(&in_page)?.num_keys.get();
+            for idx in 0..=num_keys {
+                stack.push((node.child_at(idx).map_err(&in_page)?, depth + 1));
+            }
+        }
+        Ok(internal)
+    }
+
+    /// Points the header and catalog entries that name the root at `root` instead
+    fn move_root(&mut self, root: u32) -> Result<(), BTreeError> {
+        if root == self.root {
+            return Ok(());
+        }
+        if self.store.root() == self.root {
+            self.store.set_root(root)?;
+        }
+        let mut catalog = self.store.catalog()?;
+        let mut named = false;
+        for name in catalog
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect::<Vec<_>>()
+        {
+            let meta = catalog.get_mut(&name).expect("Listed above");
+            if meta.root == self.root {
+                meta.root = root;
+                named = true;
+            }
+        }
+        if named {
+            self.store.write_catalog(&catalog)?;
+        }
+        self.root = root;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "wal")]
+impl<S: PageStore, K: KeyCodec> BTree<WalStore<S>, K> {
+    /// Makes every change since the last commit durable at once. Returns the commit's lsn.
+    /// A commit that fails before its log records are durable leaves the changes pending,
+    /// to commit again or roll back.
+    pub fn commit(&mut self) -> Result<u64, BTreeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("commit").entered();
+        #[cfg(feature = "histogram")]
+        let start = Instant::now();
+        let lsn = self.store.commit();
+        #[cfg(feature = "histogram")]
+        record(&mut self.latency.commit, start);
+        let lsn = lsn?;
+        self.writes.set((0, 0));
+        Ok(lsn)
+    }
+
+    /// Commits like commit without waiting for the log to reach the disk, see
+    /// WalStore::commit_pipelined. The token resolves once it has.
+    pub fn commit_pipelined(&mut self) -> Result<DurabilityToken, BTreeError> {
+        let token = self.store.commit_pipelined()?;
+        self.writes.set((0, 0));
+        Ok(token)
+    }
+
+    /// What the tree wrote since the last commit: the keys inserted or deleted, the bytes of
+    /// the values inserted as stored, and the pages changed. See WalStore::estimated_commit_size
+    /// for what committing them appends to the log.
+    pub fn pending_stats(&self) -> PendingStats {
+        let (entries, bytes) = self.writes.get();
+        PendingStats {
+            entries,
+            bytes,
+            pages_dirtied: self.store.dirty_pages(),
+        }
+    }
+
+    /// Discards every change since the last commit. Pages freed in the meantime are
+    /// forgotten instead of reused, the rollback may have brought them back to life.
+    pub fn rollback(&mut self) {
+        self.store.rollback();
+        self.free_pages.clear();
+        self.writes.set((0, 0));
+        self.key_count = None;
+        if let Some(cache) = &self.negative_cache {
+            cache.clear();
+        }
+    }
+
+    /// Makes every change since the last commit durable without committing it, as the first
+    /// phase of a two-phase commit of the transaction `txn_id`, see WalStore::prepare.
+    /// Returns the lsn of the prepare record.
+    pub fn prepare(&mut self, txn_id: u64) -> Result<u64, BTreeError> {
+        let lsn = self.store.prepare(txn_id)?;
+        self.writes.set((0, 0));
+        Ok(lsn)
+    }
+
+    /// Commits the transaction prepared as `txn_id`, also one that recovery found prepared
+    /// when the store was opened. Returns the lsn of the commit record.
+    pub fn commit_prepared(&mut self, txn_id: u64) -> Result<u64, BTreeError> {
+        Ok(self.store.commit_prepared(txn_id)?)
+    }
+
+    /// Rolls back the transaction prepared as `txn_id` and every change made since, like
+    /// rollback
+    pub fn rollback_prepared(&mut self, txn_id: u64) -> Result<(), BTreeError> {
+        self.store.rollback_prepared(txn_id)?;
+        self.rollback();
+        Ok(())
+    }
+
+    /// Copies the committed pages held in the log into the store and truncates the log.
+    /// Changes since the last commit stay where they are.
+    pub fn checkpoint(&mut self) -> Result<Checkpoint, BTreeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("checkpoint").entered();
+        #[cfg(feature = "histogram")]
+        let start = Instant::now();
+        let checkpoint = self.store.checkpoint();
+        #[cfg(feature = "histogram")]
+        record(&mut self.latency.checkpoint, start);
+        Ok(checkpoint?)
+    }
+
+    /// Shadow copies of the pages written since the last commit and of the free pages, to
+    /// go back to with reset_state
+    pub(super) fn shadow_state(&self) -> ShadowState {
+        let writes = self.writes.get();
+        (self.store.shadow_dirty(), self.free_pages.clone(), writes)
+    }
+
+    /// Goes back to a shadow copy. Pages appended since stay unused like on rollback,
+    /// pages freed since are alive again and no longer free.
+    pub(super) fn reset_state(&mut self, (dirty, free_pages, writes): ShadowState) {
+        self.store.reset_dirty(dirty);
+        self.free_pages = free_pages;
+        self.writes.set(writes);
+        self.key_count = None;
+        if let Some(cache) = &self.negative_cache {
+            cache.clear();
+        }
+    }
+
+    /// Looks up `key` as of the last commit, passing over the changes since
+    pub fn get_committed(&mut self, key: K) -> Result<Option<Vec<u8>>, BTreeError> {
+        let key = key.encode();
+        let dirty = self.store.take_dirty();
+        // Not through the negative cache, the keys missing here may be written since
+        let stored = self.get_raw(key);
+        self.store.restore_dirty(dirty);
+        stored?.map(|stored| self.decode(key, stored)).transpose()
+    }
+}
+
+/// What copy_range does with keys that already exist in the destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverwritePolicy {
+    Overwrite,
+    KeepExisting,
+}
+
+/// Copies the entries of `src` within `range` into `dst`, one source leaf at a time, so
+/// values never pass through the caller and only one leaf's entries are held at once.
+/// Returns the number of entries written.
+pub fn copy_range<S1: PageStore, S2: PageStore, K: KeyCodec>(
+    src: &mut BTree<S1, K>,
+    dst: &mut BTree<S2, K>,
+    range: impl RangeBounds<K>,
+    policy: OverwritePolicy,
+) -> Result<usize, BTreeError> {
+    let mut copied = 0;
+    src.for_each_in_range(range, |key, value| {
+        if policy == OverwritePolicy::KeepExisting && dst.get_raw(key)?.is_some() {
+            return Ok(());
+        }
+        let value = dst.encode(value)?;
+        dst.insert_raw(key, &value)?;
+        copied += 1;
+        Ok(())
+    })?;
+    Ok(copied)
+}
+
+/// Creates an empty tree called `name` and records it in the catalog of `pager`, along with
+/// the fingerprint of its key and value types
+pub fn create_tree<K: KeyCodec + SchemaName, V: SchemaName>(
+    pager: Pager,
+    name: &str,
+) -> Result<BTree<Pager, K>, BTreeError> {
+    create_tree_with_config::<K, V>(pager, name, NodeConfig::default())
+}
+
+/// Like `create_tree`, the catalog also records the names of the comparator, merge operator
+/// and value codec of `config`
+pub fn create_tree_with_config<K: KeyCodec + SchemaName, V: SchemaName>(
+    pager: Pager,
+    name: &str,
+    config: NodeConfig,
+) -> Result<BTree<Pager, K>, BTreeError> {
+    let mut tree = BTree::create_with_config(pager, config)?.keyed::<K>();
+    let mut catalog = tree.store.catalog()?;
+    if catalog.get(name).is_some() {
+        return Err(BTreeError::TreeExists(name.to_string()));
+    }
+    let mut meta = TreeMeta::new(tree.root);
+    meta.codec_fingerprint = schema_fingerprint::<K, V>();
+    for (plugin, name) in plugin_names(config) {
+        if let Some(name) = name {
+            meta.properties
+                .insert(plugin.to_owned(), name.as_bytes().to_vec());
+        }
+    }
+    catalog.insert(name, meta);
+    tree.store.write_catalog(&catalog)?;
+    Ok(tree)
+}
+
+/// Opens the tree called `name` from the catalog of `pager`. Fails with SchemaMismatch if the
+/// tree was created with other key or value types, trees without a fingerprint always open.
+pub fn open_tree<K: KeyCodec + SchemaName, V: SchemaName>(
+    pager: Pager,
+    name: &str,
+) -> Result<BTree<Pager, K>, BTreeError> {
+    open_tree_with_config::<K, V>(pager, name, NodeConfig::default())
+}
+
+/// Like `open_tree`, also fails with PluginMismatch if the tree was created with another
+/// comparator, merge operator or value codec than the ones of `config`
+pub fn open_tree_with_config<K: KeyCodec + SchemaName, V: SchemaName>(
+    mut pager: Pager,
+    name: &str,
+    config: NodeConfig,
+) -> Result<BTree<Pager, K>, BTreeError> {
+    let catalog = pager.catalog()?;
+    let meta = catalog
+        .get(name)
+        .ok_or_else(|| BTreeError::UnknownTree(name.to_string()))?;
+    let expected = schema_fingerprint::<K, V>();
+    if meta.codec_fingerprint != 0 && meta.codec_fingerprint != expected {
+        return Err(BTreeError::SchemaMismatch {
+            expected,
+            found: meta.codec_fingerprint,
+        });
+    }
+    for (plugin, name) in plugin_names(config) {
+        let found = meta
+            .properties
+            .get(plugin)
+            .map(|name| String::from_utf8_lossy(name));
+        check_plugin(plugin, name, found.as_deref())?;
+    }
+    Ok(BTree::open_with_config(pager, meta.root, config)?.keyed::<K>())
+}
+
+/// Catalog properties naming the plugins of `config`
+fn plugin_names(config: NodeConfig) -> [(&'static str, Option<&'static str>); 3] {
+    [
+        ("comparator", config.comparator.map(|c| c.name())),
+        ("merge_operator", config.merge_operator.map(|m| m.name())),
+        ("codec", config.compression.map(|c| c.codec.name())),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::plugin::tests::{ADD, REVERSE};
+    use super::super::verify::check_page;
+    #[cfg(feature = "lz4")]
+    use super::super::{Compression, Lz4};
+    use super::super::{MAX_VALUE_SIZE, PAGE_SIZE};
+    use super::*;
+    use crate::page::{MemoryStore, PageStoreExt};
+    use pretty_assertions::assert_eq;
+
+    fn new_tree() -> BTree<MemoryStore> {
+        BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap()
+    }
+
+    fn value(key: u64, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (key as usize + i) as u8).collect()
+    }
+
+    /// Spreads keys over the key space so inserts don't only hit the rightmost leaf
+    fn scrambled(i: u64) -> u64 {
+        i.wrapping_mul(0x9e3779b97f4a7c15) >> 16
+    }
+
+    fn assert_pages_valid(tree: &mut BTree<MemoryStore>) {
+        for page_id in 0..tree.store().n_pages().unwrap() {
+            if tree.free_pages.contains(&(page_id as u32)) {
+                continue;
+            }
+            let page = tree.store.read_page(page_id).unwrap();
+            check_page(page.read(), |issue| {
+                panic!("Page {page_id} is invalid: {issue:?}")
+            });
+        }
+    }
+
+    #[test]
+    fn test_splits_and_gets() {
+        let mut tree = new_tree();
+        for i in 0..2000 {
+            let key = scrambled(i);
+            assert!(tree.insert(key, &value(key, 100)).unwrap().is_none());
+        }
+        assert_eq!(tree.depth().unwrap(), 2);
+        assert_eq!(tree.root(), 0);
+
+        for i in 0..2000 {
+            let key = scrambled(i);
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 100)));
+        }
+        assert_eq!(tree.get(scrambled(2000)).unwrap(), None);
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_internal_nodes_split_and_merge() {
+        let mut tree = new_tree();
+        // Large values keep leaves small, so the internal nodes overflow quickly
+        for i in 0..1200 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 1500)).unwrap();
+        }
+        assert_eq!(tree.depth().unwrap(), 3);
+        assert_pages_valid(&mut tree);
+
+        for i in 0..1200 {
+            let key = scrambled(i);
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 1500)));
+            if i % 8 != 0 {
+                tree.delete(key).unwrap().unwrap();
+            }
+        }
+        assert_eq!(tree.depth().unwrap(), 2);
+        for i in (0..1200).step_by(8) {
+            let key = scrambled(i);
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 1500)));
+        }
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_replace_grows_values() {
+        let mut tree = new_tree();
+        for key in 0..200 {
+            tree.insert(key, &value(key, 10)).unwrap();
+        }
+        for key in 0..200 {
+            let old = tree.insert(key, &value(key, 300)).unwrap().unwrap();
+            assert_eq!(old.value, value(key, 10));
+        }
+        for key in 0..200 {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 300)));
+        }
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_huge_values_split_many_ways() {
+        let mut tree = new_tree();
+        let small: Vec<u64> = (0..20).map(|i| i * 10).collect();
+        for &key in &small {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        // Every huge value needs a page to itself
+        for key in [45, 95, 145] {
+            tree.insert(key, &value(key, MAX_VALUE_SIZE.into()))
+                .unwrap();
+        }
+
+        for &key in &small {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 100)));
+        }
+        for key in [45, 95, 145] {
+            assert_eq!(
+                tree.get(key).unwrap(),
+                Some(value(key, MAX_VALUE_SIZE.into()))
+            );
+        }
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_deletes_merge_and_shrink() {
+        let mut tree = new_tree();
+        for i in 0..2000 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        let grown_pages = tree.store().n_pages().unwrap();
+
+        for i in 0..1990 {
+            let key = scrambled(i);
+            assert_eq!(tree.delete(key).unwrap().unwrap().value, value(key, 100));
+            assert!(tree.delete(key).unwrap().is_none());
+        }
+        assert_eq!(tree.depth().unwrap(), 1);
+        for i in 1990..2000 {
+            let key = scrambled(i);
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 100)));
+        }
+        assert_pages_valid(&mut tree);
+
+        // Released pages are reused before the store grows
+        for i in 0..2000 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        assert!(tree.store().n_pages().unwrap() <= grown_pages + 1);
+    }
+
+    #[test]
+    fn test_husks() {
+        let mut tree = new_tree();
+        for i in 0..2000 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        for i in 0..1500 {
+            tree.delete(scrambled(i)).unwrap();
+        }
+        // Pages released by merges are accounted for
+        assert!(!tree.free_pages.is_empty());
+        assert_eq!(tree.husks().unwrap(), HuskReport::default());
+
+        let orphan = tree
+            .store
+            .append_page(&Page::new(PAGE_SIZE.into()))
+            .unwrap() as u32;
+        let leaf = tree.find_path(scrambled(1999)).unwrap().leaf;
+        tree.write_entries(leaf, &Entries::Leaf(Vec::new()))
+            .unwrap();
+        let report = tree.husks().unwrap();
+        assert_eq!(
+            report,
+            HuskReport {
+                empty_leaves: vec![leaf],
+                orphaned: vec![orphan],
+            }
+        );
+        assert!(!report.is_empty());
+    }
+
+    #[test]
+    fn test_most_fragmented() {
+        let mut tree = new_tree();
+        for key in 0..300 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        assert_eq!(tree.most_fragmented(10).unwrap(), []);
+
+        // Deletes leave freeblocks behind, more of them in the leaves of the lower keys
+        for key in (0..150).filter(|&key| key % 3 == 0 || (key < 40 && key % 3 == 1)) {
+            tree.delete(key).unwrap().unwrap();
+        }
+        let leaves = tree.fragmentation().unwrap();
+        assert!(leaves.len() > 2);
+        let worst = tree.most_fragmented(2).unwrap();
+        assert_eq!(worst.len(), 2);
+        assert!(worst[0].score() >= worst[1].score() && worst[1].score() > 0);
+        assert!(leaves.iter().all(|leaf| leaf.score() <= worst[0].score()));
+        assert_eq!(
+            tree.most_fragmented(usize::MAX).unwrap().len(),
+            leaves.iter().filter(|leaf| leaf.score() > 0).count()
+        );
+
+        tree.defrag_leaf(worst[0].page_id).unwrap();
+        let after = tree.most_fragmented(usize::MAX).unwrap();
+        assert!(after.iter().all(|leaf| leaf.page_id != worst[0].page_id));
+        assert_eq!(after[0], worst[1]);
+        assert!(matches!(
+            tree.defrag_leaf(tree.root()),
+            Err(BTreeError::NotLeaf)
+        ));
+        for key in 150..300 {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 100)));
+        }
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_gc_unreachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        // One tree in the catalog and one in the file header
+        let mut names = create_tree::<u64, u64>(Pager::open(path).unwrap(), "names").unwrap();
+        for key in 0..500 {
+            names.insert(key, &value(key, 100)).unwrap();
+        }
+        let mut tree = BTree::create(names.into_store()).unwrap();
+        for key in 0..500 {
+            tree.insert(key, &value(key, 200)).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+        // Allocated and never linked, like before a crash
+        let leaked: Vec<u32> = (0..3).map(|_| pager.allocate().unwrap()).collect();
+        let free_pages = pager.free_pages();
+        let mut tree = BTree::open(pager, root).unwrap();
+
+        let report = tree.gc_unreachable(true).unwrap();
+        assert_eq!(report.roots.len(), 2);
+        assert_eq!(report.leaked, leaked);
+        assert!(!report.reclaimed);
+        assert_eq!(tree.store().free_pages(), free_pages);
+
+        let report = tree.gc_unreachable(false).unwrap();
+        assert_eq!((report.leaked, report.reclaimed), (leaked, true));
+        assert_eq!(tree.store().free_pages(), free_pages + 3);
+        assert_eq!(tree.gc_unreachable(false).unwrap().leaked, []);
+
+        // Neither tree lost a page
+        for key in 0..500 {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 200)));
+        }
+        let mut names = open_tree::<u64, u64>(tree.into_store(), "names").unwrap();
+        for key in 0..500 {
+            assert_eq!(names.get(key).unwrap(), Some(value(key, 100)));
+        }
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let mut tree = create_tree::<u64, u64>(Pager::open(path).unwrap(), "scores").unwrap();
+        for i in 0..3000 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 300)).unwrap();
+        }
+        // Merges free pages all over the file, the file stays as long
+        for i in (0..3000).filter(|i| i % 10 != 0) {
+            tree.delete(scrambled(i)).unwrap();
+        }
+        let len = std::fs::metadata(path).unwrap().len();
+        assert!(tree.store().free_pages() > 0);
+
+        let cut = tree.shrink_to_fit().unwrap();
+        assert!(cut > 0);
+        assert_eq!(tree.store().free_pages(), 0);
+        let page_size = tree.store().page_size() as u64;
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            len - cut as u64 * page_size
+        );
+        assert!(tree.husks().unwrap().is_empty());
+        assert_eq!(tree.shrink_to_fit().unwrap(), 0);
+        drop(tree);
+
+        // What is left reopens through the catalog
+        let mut tree = open_tree::<u64, u64>(Pager::open(path).unwrap(), "scores").unwrap();
+        for i in 0..3000 {
+            let key = scrambled(i);
+            let expected = (i % 10 == 0).then(|| value(key, 300));
+            assert_eq!(tree.get(key).unwrap(), expected);
+        }
+
+        // Pages of another tree can't be moved without it noticing
+        let pager = tree.into_store();
+        let mut other = create_tree::<u64, u64>(pager, "other").unwrap();
+        assert!(matches!(
+            other.shrink_to_fit(),
+            Err(BTreeError::ForeignPage(_))
+        ));
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let entries = || (0..20_000u64).map(|key| (key * 3, value(key, (key % 120) as usize)));
+        let mut tree = BTree::bulk_load(MemoryStore::new(PAGE_SIZE.into()), entries()).unwrap();
+        assert_eq!(tree.depth().unwrap(), 3);
+        assert_pages_valid(&mut tree);
+        assert_eq!(tree.husks().unwrap(), HuskReport::default());
+        for (key, value) in entries().step_by(7) {
+            assert_eq!(tree.get(key).unwrap(), Some(value));
+        }
+        assert_eq!(tree.get(1).unwrap(), None);
+        let full_pages = tree.store().n_pages().unwrap();
+
+        // The loaded tree takes inserts and deletes like any other
+        for key in 0..500 {
+            tree.insert(key * 3 + 1, b"later").unwrap();
+            tree.delete(key * 3).unwrap();
+        }
+        assert_eq!(tree.get(301).unwrap(), Some(b"later".to_vec()));
+        assert_eq!(tree.get(300).unwrap(), None);
+        assert_pages_valid(&mut tree);
+
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut tree =
+            BTree::bulk_load_with_config(store, NodeConfig::default(), 0.5, entries()).unwrap();
+        assert!(tree.store().n_pages().unwrap() > full_pages * 19 / 10);
+        assert_eq!(tree.get(59_997).unwrap(), Some(value(19_999, 79)));
+
+        let mut tree = BTree::bulk_load(MemoryStore::new(PAGE_SIZE.into()), [(1u64, b"")]).unwrap();
+        assert_eq!(tree.get(1).unwrap(), Some(Vec::new()));
+        let mut tree = BTree::bulk_load(
+            MemoryStore::new(PAGE_SIZE.into()),
+            Vec::<(u64, Vec<u8>)>::new(),
+        )
+        .unwrap();
+        assert_eq!(tree.depth().unwrap(), 1);
+        assert_eq!(tree.get(0).unwrap(), None);
+        assert!(matches!(
+            BTree::bulk_load(
+                MemoryStore::new(PAGE_SIZE.into()),
+                [(1u64, b"a"), (3, b"b"), (3, b"c")]
+            ),
+            Err(BTreeError::UnsortedInput {
+                previous: 3,
+                key: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_compact_after_values_shrink() {
+        let mut tree = new_tree();
+        for key in 0..600 {
+            tree.insert(key, &value(key, 1000)).unwrap();
+        }
+        for key in 0..600 {
+            tree.insert(key, &value(key, 10)).unwrap();
+        }
+        assert_eq!(tree.depth().unwrap(), 3);
+
+        let freed = tree.compact(0.5).unwrap();
+        assert!(freed > 200);
+        assert_eq!(tree.depth().unwrap(), 2);
+        for key in 0..600 {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 10)));
+        }
+        assert_pages_valid(&mut tree);
+        assert_eq!(tree.compact(0.5).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rename_and_swap() {
+        let mut tree = new_tree();
+        for key in 0..2000 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        let n_pages = tree.store().n_pages().unwrap();
+
+        // Within a leaf and across leaves
+        for (old, new) in [(10, 5000), (11, 10), (1500, 3)] {
+            let moved = tree.get(old).unwrap();
+            assert!(tree.rename(old, new).unwrap());
+            assert_eq!(tree.get(old).unwrap(), None);
+            assert_eq!(tree.get(new).unwrap(), moved);
+        }
+        assert!(!tree.rename(1500, 1501).unwrap());
+        assert_eq!(tree.get(1501).unwrap(), Some(value(1501, 100)));
+
+        assert!(tree.swap(20, 21).unwrap());
+        assert!(tree.swap(30, 1900).unwrap());
+        assert_eq!(tree.get(20).unwrap(), Some(value(21, 100)));
+        assert_eq!(tree.get(21).unwrap(), Some(value(20, 100)));
+        assert_eq!(tree.get(30).unwrap(), Some(value(1900, 100)));
+        assert_eq!(tree.get(1900).unwrap(), Some(value(30, 100)));
+        assert!(!tree.swap(40, 1500).unwrap());
+        assert_eq!(tree.get(40).unwrap(), Some(value(40, 100)));
+
+        assert_eq!(tree.store().n_pages().unwrap(), n_pages);
+        assert_pages_valid(&mut tree);
+    }
+
+    /// Fails one write, like a full disk that frees up again
+    struct FailOnce {
+        inner: MemoryStore,
+        /// Writes to let through before the one that fails
+        fail_in: Option<usize>,
+    }
+
+    impl PageStore for FailOnce {
+        fn page_size(&self) -> usize {
+            self.inner.page_size()
+        }
+
+        fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+            self.inner.read_page(index)
+        }
+
+        fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+            match self.fail_in {
+                Some(0) => {
+                    self.fail_in = None;
+                    Err(io::Error::new(io::ErrorKind::StorageFull, "Disk full"))
+                }
+                Some(n) => {
+                    self.fail_in = Some(n - 1);
+                    self.inner.write_page(index, page)
+                }
+                None => self.inner.write_page(index, page),
+            }
+        }
+
+        fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+            self.inner.append_page(page)
+        }
+
+        fn n_pages(&self) -> Result<usize, io::Error> {
+            self.inner.n_pages()
+        }
+
+        fn sync(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_swaps_and_renames_change_nothing() {
+        let store = FailOnce {
+            inner: MemoryStore::new(PAGE_SIZE.into()),
+            fail_in: None,
+        };
+        let mut tree = BTree::create(store).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+
+        // The write of 1900 fails after 30 got its new value
+        tree.store.fail_in = Some(1);
+        assert!(tree.swap(30, 1900).is_err());
+        assert_eq!(tree.get(30).unwrap(), Some(value(30, 100)));
+        assert_eq!(tree.get(1900).unwrap(), Some(value(1900, 100)));
+
+        // Removing 30 fails after 1900 or 5000 got its value, which is put back
+        tree.store.fail_in = Some(1);
+        assert!(tree.rename(30, 1900).is_err());
+        tree.store.fail_in = Some(1);
+        assert!(tree.rename(30, 5000).is_err());
+        assert_eq!(tree.get(30).unwrap(), Some(value(30, 100)));
+        assert_eq!(tree.get(1900).unwrap(), Some(value(1900, 100)));
+        assert_eq!(tree.get(5000).unwrap(), None);
+        assert!(tree.rename(30, 5000).unwrap());
+        assert_eq!(tree.get(5000).unwrap(), Some(value(30, 100)));
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let mut src = new_tree();
+        for i in 0..2000 {
+            let key = scrambled(i);
+            src.insert(key, &value(key, 100)).unwrap();
+        }
+        let mut keys: Vec<_> = (0..2000).map(scrambled).collect();
+        keys.sort_unstable();
+        let (start, end) = (keys[300], keys[1700]);
+
+        let mut dst = new_tree();
+        dst.insert(keys[500], b"existing").unwrap();
+        let copied = copy_range(
+            &mut src,
+            &mut dst,
+            start..end,
+            OverwritePolicy::KeepExisting,
+        );
+        assert_eq!(copied.unwrap(), 1399);
+        assert_eq!(dst.get(keys[500]).unwrap(), Some(b"existing".to_vec()));
+        assert_eq!(dst.get(keys[299]).unwrap(), None);
+        assert_eq!(dst.get(keys[1700]).unwrap(), None);
+        for &key in &keys[300..1700] {
+            if key != keys[500] {
+                assert_eq!(dst.get(key).unwrap(), Some(value(key, 100)));
+            }
+        }
+
+        let copied = copy_range(&mut src, &mut dst, ..=start, OverwritePolicy::Overwrite);
+        assert_eq!(copied.unwrap(), 301);
+        let copied = copy_range(&mut src, &mut dst, keys[500].., OverwritePolicy::Overwrite);
+        assert_eq!(copied.unwrap(), 1500);
+        assert_eq!(dst.get(keys[500]).unwrap(), Some(value(keys[500], 100)));
+        assert_pages_valid(&mut dst);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_copy_range_into_compressed_tree() {
+        let config = NodeConfig {
+            compression: Some(Compression {
+                codec: &Lz4,
+                threshold: 64,
+            }),
+            ..Default::default()
+        };
+        let text = |key: u64| format!("{key:08} ").repeat(40).into_bytes();
+        let mut src = new_tree();
+        for key in 0..500 {
+            src.insert(scrambled(key), &text(key)).unwrap();
+        }
+        let mut dst =
+            BTree::create_with_config(MemoryStore::new(PAGE_SIZE.into()), config).unwrap();
+        let copied = copy_range(&mut src, &mut dst, .., OverwritePolicy::Overwrite);
+        assert_eq!(copied.unwrap(), 500);
+        assert!(dst.store().n_pages().unwrap() * 4 < src.store().n_pages().unwrap());
+        assert_eq!(dst.get(scrambled(7)).unwrap(), Some(text(7)));
+
+        // Copying back out of the compressed tree yields the plain values
+        let mut plain = new_tree();
+        let copied = copy_range(&mut dst, &mut plain, .., OverwritePolicy::Overwrite);
+        assert_eq!(copied.unwrap(), 500);
+        assert_eq!(plain.get(scrambled(7)).unwrap(), Some(text(7)));
+        assert_pages_valid(&mut dst);
+    }
+
+    /// Composite key ordered by user first and sequence number second
+    #[derive(Debug, Clone, Copy)]
+    struct Event {
+        user: u32,
+        seq: u32,
+    }
+
+    impl KeyCodec for Event {
+        fn encode(&self) -> u64 {
+            (self.user as u64) << 32 | self.seq as u64
+        }
+
+        fn decode(raw: u64) -> Self {
+            Event {
+                user: (raw >> 32) as u32,
+                seq: raw as u32,
+            }
+        }
+    }
+
+    #[test]
+    fn test_plugins_checked_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let config = NodeConfig {
+            merge_operator: Some(ADD),
+            ..Default::default()
+        };
+
+        let mut tree =
+            create_tree_with_config::<u64, u64>(Pager::open(path).unwrap(), "counters", config)
+                .unwrap();
+        for key in 0..2000 {
+            tree.merge(key % 500, &(key + 1).to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.get(7).unwrap(), Some(3032u64.to_le_bytes().to_vec()));
+        drop(tree);
+
+        let mut tree =
+            open_tree_with_config::<u64, u64>(Pager::open(path).unwrap(), "counters", config)
+                .unwrap();
+        tree.merge(7, &1u64.to_le_bytes()).unwrap();
+        assert_eq!(tree.get(7).unwrap(), Some(3033u64.to_le_bytes().to_vec()));
+        drop(tree);
+        assert!(matches!(
+            open_tree::<u64, u64>(Pager::open(path).unwrap(), "counters"),
+            Err(BTreeError::PluginMismatch {
+                plugin: "merge_operator",
+                ..
+            })
+        ));
+        let config = NodeConfig {
+            comparator: Some(REVERSE),
+            ..config
+        };
+        assert!(matches!(
+            open_tree_with_config::<u64, u64>(Pager::open(path).unwrap(), "counters", config),
+            Err(BTreeError::PluginMismatch {
+                plugin: "comparator",
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compressed_values() {
+        let config = NodeConfig {
+            compression: Some(Compression {
+                codec: &Lz4,
+                threshold: 64,
+            }),
+            ..Default::default()
+        };
+        let text = |key: u64| format!("{key:08} ").repeat(40).into_bytes();
+        let mut tree =
+            BTree::create_with_config(MemoryStore::new(PAGE_SIZE.into()), config).unwrap();
+        let mut plain = new_tree();
+        for key in 0..500 {
+            tree.insert(scrambled(key), &text(key)).unwrap();
+            plain.insert(scrambled(key), &text(key)).unwrap();
+        }
+        assert!(tree.store().n_pages().unwrap() * 4 < plain.store().n_pages().unwrap());
+        assert_eq!(tree.get(scrambled(7)).unwrap(), Some(text(7)));
+
+        // Short values are stored raw, every value reads back as it was written
+        tree.insert(1, b"short").unwrap();
+        assert_eq!(tree.insert(1, &text(1)).unwrap().unwrap().value, b"short");
+        assert_eq!(tree.update(1, b"short").unwrap(), text(1));
+        assert_eq!(tree.delete(1).unwrap().unwrap().value, b"short");
+
+        // Values larger than a page fit once compressed
+        let huge = vec![9; 3 * PAGE_SIZE as usize];
+        tree.insert(2, &huge).unwrap();
+        let mut cursor = tree.cursor().unwrap();
+        assert_eq!(cursor.seek(2).unwrap(), Some((2, huge.as_slice())));
+
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        create_tree_with_config::<u64, u64>(Pager::open(path).unwrap(), "texts", config).unwrap();
+        assert!(matches!(
+            open_tree::<u64, u64>(Pager::open(path).unwrap(), "texts"),
+            Err(BTreeError::PluginMismatch {
+                plugin: "codec",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_schema_checked_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut tree = create_tree::<i32, String>(Pager::open(path).unwrap(), "users").unwrap();
+        tree.insert(-4, b"abekat").unwrap();
+        drop(tree);
+        assert!(matches!(
+            create_tree::<i32, String>(Pager::open(path).unwrap(), "users"),
+            Err(BTreeError::TreeExists(_))
+        ));
+
+        let mut tree = open_tree::<i32, String>(Pager::open(path).unwrap(), "users").unwrap();
+        assert_eq!(tree.get(-4).unwrap(), Some(b"abekat".to_vec()));
+        drop(tree);
+        assert!(matches!(
+            open_tree::<u32, String>(Pager::open(path).unwrap(), "users"),
+            Err(BTreeError::SchemaMismatch { .. })
+        ));
+        assert!(matches!(
+            open_tree::<i32, Vec<u8>>(Pager::open(path).unwrap(), "users"),
+            Err(BTreeError::SchemaMismatch { .. })
+        ));
+        assert!(matches!(
+            open_tree::<i32, String>(Pager::open(path).unwrap(), "groups"),
+            Err(BTreeError::UnknownTree(_))
+        ));
+    }
+
+    #[test]
+    fn test_typed_keys() {
+        let mut tree = new_tree().keyed::<i64>();
+        for key in -500..500 {
+            tree.insert(key, &key.to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.get(-3).unwrap(), Some((-3i64).to_le_bytes().to_vec()));
+        assert!(tree.delete(-500).unwrap().is_some());
+        assert_eq!(tree.get(-500).unwrap(), None);
+        // Negative keys sort before positive ones
+        let mut root = tree.store.read_page(0).unwrap();
+        let first = Node::load(root.mutate()).unwrap().key_at(0).unwrap();
+        assert!(i64::decode(first) < 0);
+
+        let mut tree = new_tree().keyed::<Event>();
+        for user in 0..20 {
+            for seq in 0..50 {
+                tree.insert(Event { user, seq }, &[user as u8, seq as u8])
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            tree.get(Event { user: 7, seq: 49 }).unwrap(),
+            Some(vec![7, 49])
+        );
+        assert_eq!(tree.get(Event { user: 7, seq: 50 }).unwrap(), None);
+    }
+
+    #[test]
+    fn test_checksummed_pages() {
+        let config = NodeConfig {
+            checksums: true,
+            verify_checksums: true,
+            ..Default::default()
+        };
+        let mut tree =
+            BTree::create_with_config(MemoryStore::new(PAGE_SIZE.into()), config).unwrap();
+        for i in 0..1000 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        for i in 0..500 {
+            tree.delete(scrambled(i)).unwrap();
+        }
+        assert_pages_valid(&mut tree);
+
+        let key = scrambled(700);
+        let leaf = tree.find_path(key).unwrap().leaf as usize;
+        let mut page = tree.store.read_page(leaf).unwrap();
+        page.mutate()[2000] ^= 0x10;
+        tree.store.write_page(leaf, &page).unwrap();
+        let err = tree.get(key).unwrap_err();
+        let BTreeError::PageCorrupted { page, reason, .. } = &err else {
+            panic!("Expected a corrupt page, got {err:?}");
+        };
+        assert_eq!(*page as usize, leaf);
+        assert!(matches!(**reason, BTreeError::ChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn test_errors_name_the_page() {
+        let mut tree = new_tree();
+        for key in 0..500 {
+            tree.insert(key, &value(key, 50)).unwrap();
+        }
+        assert_eq!(tree.update(7, b"seven").unwrap(), value(7, 50));
+        assert_eq!(tree.get(7).unwrap(), Some(b"seven".to_vec()));
+        assert!(matches!(
+            tree.update(500, b"missing"),
+            Err(BTreeError::KeyNotFound { key: 500 })
+        ));
+        assert_eq!(tree.get(500).unwrap(), None);
+
+        // Point the first value of a leaf past the end of its page
+        let leaf = tree.find_path(300).unwrap().leaf;
+        let mut page = tree.store.read_page(leaf as usize).unwrap();
+        let mut node = Node::load(page.mutate()).unwrap();
+        let first = node.key_at(0).unwrap();
+        node.mut_key_at(0).unwrap().value_offset.set(PAGE_SIZE - 10);
+        drop(node);
+        tree.store.write_page(leaf as usize, &page).unwrap();
+
+        let err = tree.get(first).unwrap_err();
+        let offset = leaf as u64 * PAGE_SIZE as u64;
+        assert_eq!(
+            err.to_string(),
+            format!("page {leaf} at offset {offset} is corrupt")
+        );
+        let reason = std::error::Error::source(&err).unwrap().to_string();
+        assert_eq!(
+            reason,
+            format!(
+                "corrupt node: value 0 of 50 bytes at offset {} lies outside the content area",
+                PAGE_SIZE - 10
+            )
+        );
+        assert_eq!(tree.get(first + 1).unwrap(), Some(value(first + 1, 50)));
+    }
+
+    #[test]
+    fn test_aligned_values() {
+        let config = NodeConfig {
+            align_values: true,
+            checksums: true,
+            ..Default::default()
+        };
+        let mut tree =
+            BTree::create_with_config(MemoryStore::new(PAGE_SIZE.into()), config).unwrap();
+        for i in 0..1500 {
+            let key = scrambled(i);
+            tree.insert(key, &value(key, 1 + (i % 150) as usize))
+                .unwrap();
+        }
+        for i in (0..1500).step_by(2) {
+            tree.delete(scrambled(i)).unwrap();
+        }
+        for i in (1..1500).step_by(2) {
+            let key = scrambled(i);
+            assert_eq!(
+                tree.get(key).unwrap(),
+                Some(value(key, 1 + (i % 150) as usize))
+            );
+            let leaf = tree.find_path(key).unwrap().leaf as usize;
+            let mut page = tree.store.read_page(leaf).unwrap();
+            let node = Node::load_with_config(page.mutate(), config).unwrap();
+            let range = node.value_range(key).unwrap().unwrap();
+            assert_eq!(range.start % VALUE_ALIGNMENT as usize, 0);
+        }
+        assert_pages_valid(&mut tree);
+
+        let entries = (0..3000u64).map(|key| (key, value(key, (key % 61) as usize)));
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut tree = BTree::bulk_load_with_config(store, config, 1.0, entries).unwrap();
+        assert_eq!(tree.get(2999).unwrap(), Some(value(2999, 2999 % 61)));
+        assert_pages_valid(&mut tree);
+    }
+
+    #[test]
+    fn test_prefix_compressed_leaves() {
+        let pages = |config| {
+            let mut tree =
+                BTree::create_with_config(MemoryStore::new(PAGE_SIZE.into()), config).unwrap();
+            for key in 0..5000u64 {
+                tree.insert(key << 8, &(key as u16).to_le_bytes()).unwrap();
+            }
+            let mut cursor = tree.cursor().unwrap();
+            for key in 0..5000u64 {
+                assert_eq!(
+                    cursor.current(),
+                    Some((key << 8, &(key as u16).to_le_bytes()[..]))
+                );
+                cursor.next().unwrap();
+            }
+            drop(cursor);
+            assert_pages_valid(&mut tree);
+            tree.store().n_pages().unwrap()
+        };
+        let packed = NodeConfig {
+            adaptive_layout: true,
+            ..Default::default()
+        };
+        let compressed = NodeConfig {
+            prefix_compression: true,
+            ..packed
+        };
+        assert!(pages(compressed) * 3 < pages(packed) * 2);
+    }
+
+    #[test]
+    fn test_open_existing_tree() {
+        let mut tree = new_tree();
+        for key in 0..500 {
+            tree.insert(key, &value(key, 50)).unwrap();
+        }
+        let root = tree.root();
+
+        let mut tree = BTree::open(tree.into_store(), root).unwrap();
+        for key in 0..500 {
+            assert_eq!(tree.get(key).unwrap(), Some(value(key, 50)));
+        }
+    }
+
+    #[test]
+    fn test_leaves_at_density_limit() {
+        for config in [
+            NodeConfig::default(),
+            NodeConfig {
+                adaptive_layout: true,
+                ..Default::default()
+            },
+        ] {
+            let mut tree = BTree::create_with_config(MemoryStore::new(512), config).unwrap();
+            for i in 0..1000 {
+                let key = scrambled(i);
+                tree.insert(key, &value(key, (i % 2) as usize)).unwrap();
+            }
+            for i in (0..1000).step_by(3) {
+                tree.delete(scrambled(i)).unwrap();
+            }
+            for i in 0..1000 {
+                let key = scrambled(i);
+                let expected = (i % 3 != 0).then(|| value(key, (i % 2) as usize));
+                assert_eq!(tree.get(key).unwrap(), expected);
+            }
+            assert_pages_valid(&mut tree);
+        }
+    }
+
+    #[test]
+    fn test_page_sizes() {
+        assert!(matches!(
+            BTree::create(MemoryStore::new(1000)),
+            Err(BTreeError::InvalidPageSize(1000))
+        ));
+        assert!(matches!(
+            BTree::create(MemoryStore::new(65536)),
+            Err(BTreeError::InvalidPageSize(65536))
+        ));
+
+        for page_size in [512, 8192, 32768] {
+            let mut tree = BTree::create(MemoryStore::new(page_size)).unwrap();
+            for key in 0..2000 {
+                tree.insert(key, &value(key, 100)).unwrap();
+            }
+            for key in (0..2000).step_by(3) {
+                tree.delete(key).unwrap();
+            }
+            for key in 0..2000 {
+                let expected = (key % 3 != 0).then(|| value(key, 100));
+                assert_eq!(tree.get(key).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..3000 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        for key in (0..3000).step_by(3) {
+            tree.delete(key).unwrap();
+        }
+        tree.validate().unwrap();
+        let (root, leaf) = (tree.root(), tree.find_path(0).unwrap().leaf);
+
+        // A key the separators route to another leaf
+        let mut store = tree.into_store();
+        let mut page = store.read_page(leaf as usize).unwrap();
+        Node::load(page.mutate())
+            .unwrap()
+            .insert(5000, b"x")
+            .unwrap();
+        store.write_page(leaf as usize, &page).unwrap();
+        let mut tree = BTree::open(store, root).unwrap();
+        let err = tree.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("page {leaf} at offset {} is corrupt", leaf * 4096)
+        );
+        assert!(matches!(
+            err,
+            BTreeError::PageCorrupted { reason, .. } if matches!(
+                *reason,
+                BTreeError::Corrupt(CorruptionError::KeyOutsideSeparators { key: 5000 })
+            )
+        ));
+
+        // A root that is its own child
+        let mut store = tree.into_store();
+        let mut page = store.read_page(root as usize).unwrap();
+        let mut node = Node::load(page.mutate()).unwrap();
+        node.mutate_header().unwrap().rightmost_child_page.set(root);
+        drop(node);
+        store.write_page(root as usize, &page).unwrap();
+        let mut tree = BTree::open(store, root).unwrap();
+        assert!(matches!(
+            tree.validate(),
+            Err(BTreeError::PageCorrupted { page, reason, .. }) if page == root && matches!(
+                *reason,
+                BTreeError::Corrupt(CorruptionError::SharedPage)
+            )
+        ));
+    }
+
+    #[test]
+    fn test_untrusted_trees_are_bounded() {
+        // An internal root that points back to itself
+        let mut store = MemoryStore::new(PAGE_SIZE.into());
+        let mut page = Page::new(PAGE_SIZE.into());
+        let mut node = Node::new(page.mutate()).unwrap();
+        node.mutate_header().unwrap().node_type = NodeType::Internal;
+        drop(node);
+        store.append_page(&page).unwrap();
+
+        let mut tree = BTree::open(store, 0).unwrap();
+        assert!(matches!(
+            tree.get(1),
+            Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                limit: 32
+            }))
+        ));
+
+        // Without limits descents still end at the deepest tree the format allows
+        let unlimited = ResourceLimits::unlimited();
+        let mut tree =
+            BTree::open_with_limits(tree.into_store(), 0, NodeConfig::default(), unlimited)
+                .unwrap();
+        for result in [tree.get(1).map(drop), tree.stats().map(drop)] {
+            assert!(matches!(
+                result,
+                Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+                    limit: MAX_DEPTH
+                }))
+            ));
+        }
+
+        let limits = ResourceLimits {
+            max_pages: 0,
+            ..ResourceLimits::default()
+        };
+        assert!(matches!(
+            BTree::open_with_limits(tree.into_store(), 0, NodeConfig::default(), limits),
+            Err(BTreeError::LimitExceeded(LimitError::MaxPages {
+                limit: 0,
+                actual: 1
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_tree_quotas() {
+        let config = NodeConfig {
+            limits: Limits {
+                max_keys: Some(1000),
+                max_value_size: Some(200),
+                max_depth: Some(2),
+            },
+            ..NodeConfig::default()
+        };
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut tree = BTree::create_with_config(store, config).unwrap();
+        // Far more keys than a leaf holds, the quota counts every leaf
+        for i in 0..1000 {
+            tree.insert(scrambled(i), &value(i, 100)).unwrap();
+        }
+        assert!(matches!(
+            tree.insert(scrambled(1000), b"one too many"),
+            Err(BTreeError::LimitExceeded(LimitError::MaxKeys {
+                limit: 1000
+            }))
+        ));
+        tree.insert(scrambled(0), b"replaced").unwrap();
+        tree.delete(scrambled(1)).unwrap();
+        tree.insert(scrambled(1000), b"fits again").unwrap();
+        assert!(matches!(
+            tree.insert(scrambled(0), &[0; 201]),
+            Err(BTreeError::LimitExceeded(LimitError::MaxValueSize {
+                limit: 200,
+                actual: 201
+            }))
+        ));
+
+        // Two values fill a leaf, the root runs out of children long before 1000 keys
+        let config = NodeConfig {
+            limits: Limits {
+                max_depth: Some(2),
+                ..Limits::default()
+            },
+            ..NodeConfig::default()
+        };
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut tree = BTree::create_with_config(store, config).unwrap();
+        let inserted = (0..1000)
+            .take_while(|&i| match tree.insert(i, &value(i, 1500)) {
+                Ok(_) => true,
+                Err(err) => {
+                    assert!(matches!(
+                        err,
+                        BTreeError::LimitExceeded(LimitError::MaxDepth { limit: 2 })
+                    ));
+                    false
+                }
+            })
+            .count() as u64;
+        assert!(inserted > 100 && inserted < 1000);
+        assert_eq!(tree.depth().unwrap(), 2);
+        tree.validate().unwrap();
+        for i in 0..inserted {
+            assert_eq!(tree.get(i).unwrap(), Some(value(i, 1500)));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let store = MemoryStore::new(PAGE_SIZE.into()).with_cache(8);
+        let mut tree = BTree::create(store).unwrap();
+
+        // Deleting every other value of a single leaf leaves room only a defrag reclaims
+        for key in 0..30 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        for key in (0..30).step_by(2) {
+            tree.delete(key).unwrap();
+        }
+        for key in 30..40 {
+            tree.insert(key, &value(key, 150)).unwrap();
+        }
+        let stats = tree.stats().unwrap();
+        assert!(stats.defrags > 0);
+        assert_eq!((stats.splits, stats.height, stats.entries), (0, 1, 25));
+
+        for i in 0..2000 {
+            tree.insert(scrambled(i), &value(i, 100)).unwrap();
+        }
+        for i in 0..1900 {
+            tree.delete(scrambled(i)).unwrap();
+        }
+        let depth = tree.depth().unwrap();
+        let stats = tree.stats().unwrap();
+        assert!(stats.splits > 30 && stats.merges > 30);
+        assert!(stats.page_reads > 4000 && stats.page_writes > 4000);
+        assert!(stats.cache_hits > 0 && stats.cache_misses > 0);
+        assert!(stats.fragmented_bytes > 0);
+        assert_eq!(stats.height, depth);
+        assert_eq!(stats.entries, 125);
+
+        // Reading the stats isn't counted, but reads are
+        assert_eq!(tree.stats().unwrap(), stats);
+        tree.get(scrambled(1999)).unwrap();
+        // Every node on the way down, then the leaf once more for the value
+        assert_eq!(
+            tree.stats().unwrap().page_reads,
+            stats.page_reads + depth as u64 + 1
+        );
+
+        tree.reset_stats();
+        let stats = tree.stats().unwrap();
+        assert_eq!(stats.page_reads, 0);
+        assert_eq!(
+            (stats.cache_hits, stats.cache_misses, stats.splits),
+            (0, 0, 0)
+        );
+        assert_eq!(stats.entries, 125);
+    }
+
+    #[cfg(all(feature = "histogram", feature = "wal"))]
+    #[test]
+    fn test_latency_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("wal");
+        let store = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log.to_str().unwrap());
+        let mut tree = BTree::create(store.unwrap()).unwrap();
+        for key in 0..100 {
+            tree.insert(key, &value(key, 100)).unwrap();
+        }
+        for key in 0..150 {
+            tree.get(key).unwrap();
+        }
+        tree.commit().unwrap();
+        assert!(tree.checkpoint().unwrap().done);
+
+        let stats = tree.latency_stats();
+        assert_eq!(stats.insert.len(), 100);
+        assert_eq!(stats.get.len(), 150);
+        assert_eq!(stats.commit.len(), 1);
+        assert_eq!(stats.checkpoint.len(), 1);
+        assert!(stats.get.value_at_quantile(0.99) <= stats.get.max());
+
+        tree.reset_latency_stats();
+        assert!(tree.latency_stats().get.is_empty());
+    }
+
+    /// Names of the spans entered and the events seen, in order
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Recorder {
+        spans: std::sync::Mutex<Vec<&'static str>>,
+        seen: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata().name());
+            tracing::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::Id, _: &tracing::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            self.seen.lock().unwrap().push(event.metadata().name());
+        }
+
+        fn enter(&self, span: &tracing::Id) {
+            let name = self.spans.lock().unwrap()[span.into_u64() as usize - 1];
+            self.seen.lock().unwrap().push(name);
+        }
+
+        fn exit(&self, _: &tracing::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        let recorder = std::sync::Arc::new(Recorder::default());
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+            for key in 0..30 {
+                tree.insert(key, &value(key, 100)).unwrap();
+            }
+            for key in (0..30).step_by(2) {
+                tree.delete(key).unwrap();
+            }
+            for key in 30..40 {
+                tree.insert(key, &value(key, 150)).unwrap();
+            }
+            for key in 40..200 {
+                tree.insert(key, &value(key, 100)).unwrap();
+            }
+            for key in 40..200 {
+                tree.delete(key).unwrap();
+            }
+        });
+
+        let seen = recorder.seen.lock().unwrap();
+        let count = |name| seen.iter().filter(|&&seen| seen == name).count();
+        assert_eq!((count("insert"), count("delete")), (200, 175));
+        assert!(count("defrag") > 0 && count("split") > 0 && count("merge") > 0);
+        // Page I/O happens within the operation
+        let first = seen.iter().position(|&seen| seen == "insert").unwrap();
+        assert_eq!(seen[first..first + 2], ["insert", "read_page"]);
+        assert!(count("read_page") > 375 && count("write_page") >= 375);
+    }
+}
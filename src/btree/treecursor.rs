@@ -0,0 +1,235 @@
+/*
+Cursors over a whole tree. A cursor sits on one entry and moves to its neighbours in either
+direction, crossing into the next or previous leaf when it runs off the current one. Leaves
+are found again from the root through the separators that bound the current leaf, so the
+cursor only holds a copy of one leaf and the path to it. Moving past either end leaves the
+cursor on no entry until it is seeked again. Seeking to a key between the first and last key
+of the leaf copy stays in it, so seeks that skip ahead a little, like intersect does, don't
+descend from the root every time.
+*/
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::tree::{LeafEntries, Path};
+use super::treeiter::TreeIter;
+use super::BTree;
+use crate::page::PageStore;
+
+pub struct TreeCursor<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    path: Path,
+    entries: LeafEntries,
+    /// Index of the current entry in `entries`
+    pos: Option<usize>,
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// A cursor on the first entry of the tree
+    pub fn cursor(&mut self) -> Result<TreeCursor<'_, S, K>, BTreeError> {
+        let (path, entries) = self.leaf_at(0)?;
+        let mut cursor = TreeCursor {
+            tree: self,
+            path,
+            entries,
+            pos: None,
+        };
+        cursor.seek_first()?;
+        Ok(cursor)
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> TreeCursor<'_, S, K> {
+    /// The entry the cursor is on, `None` if it moved past either end
+    pub fn current(&self) -> Option<(K, &[u8])> {
+        let (key, value) = &self.entries[self.pos?];
+        Some((K::decode(*key), value))
+    }
+
+    /// Moves to the first entry whose key is at least `key`
+    pub fn seek(&mut self, key: K) -> Result<Option<(K, &[u8])>, BTreeError> {
+        self.seek_encoded(key.encode())
+    }
+
+    pub fn seek_first(&mut self) -> Result<Option<(K, &[u8])>, BTreeError> {
+        self.seek_encoded(0)
+    }
+
+    pub fn seek_last(&mut self) -> Result<Option<(K, &[u8])>, BTreeError> {
+        self.load(u64::MAX)?;
+        if self.entries.is_empty() {
+            self.prev_leaf()?;
+        } else {
+            self.pos = Some(self.entries.len() - 1);
+        }
+        Ok(self.current())
+    }
+
+    /// Moves to the entry after the current one
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(K, &[u8])>, BTreeError> {
+        let Some(pos) = self.pos else {
+            return Ok(None);
+        };
+        if pos + 1 < self.entries.len() {
+            self.pos = Some(pos + 1);
+        } else {
+            self.next_leaf()?;
+        }
+        Ok(self.current())
+    }
+
+    /// Moves to the entry before the current one
+    pub fn prev(&mut self) -> Result<Option<(K, &[u8])>, BTreeError> {
+        match self.pos {
+            None => return Ok(None),
+            Some(0) => self.prev_leaf()?,
+            Some(pos) => self.pos = Some(pos - 1),
+        }
+        Ok(self.current())
+    }
+
+    /// The entry the cursor is on with its encoded key
+    pub(super) fn current_encoded(&self) -> Option<(u64, &[u8])> {
+        let (key, value) = &self.entries[self.pos?];
+        Some((*key, value))
+    }
+
+    pub(super) fn seek_encoded(&mut self, key: u64) -> Result<Option<(K, &[u8])>, BTreeError> {
+        let within = |entries: &LeafEntries| {
+            let (Some((first, _)), Some((last, _))) = (entries.first(), entries.last()) else {
+                return false;
+            };
+            (*first..=*last).contains(&key)
+        };
+        if within(&self.entries) {
+            self.pos = Some(self.entries.partition_point(|(entry, _)| *entry < key));
+            return Ok(self.current());
+        }
+        self.load(key)?;
+        let idx = self.entries.partition_point(|(entry, _)| *entry < key);
+        if idx < self.entries.len() {
+            self.pos = Some(idx);
+        } else {
+            self.next_leaf()?;
+        }
+        Ok(self.current())
+    }
+
+    fn load(&mut self, key: u64) -> Result<(), BTreeError> {
+        (self.path, self.entries) = self.tree.leaf_at(key)?;
+        self.pos = None;
+        Ok(())
+    }
+
+    /// Moves to the first entry of the closest non-empty leaf after the current one
+    fn next_leaf(&mut self) -> Result<(), BTreeError> {
+        loop {
+            let next = self.tree.upper_bound(&self.path)?;
+            let Some(next) = next.and_then(|bound| bound.checked_add(1)) else {
+                self.pos = None;
+                return Ok(());
+            };
+            self.load(next)?;
+            if !self.entries.is_empty() {
+                self.pos = Some(0);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Moves to the last entry of the closest non-empty leaf before the current one
+    fn prev_leaf(&mut self) -> Result<(), BTreeError> {
+        loop {
+            let Some(prev) = self.tree.lower_bound(&self.path)? else {
+                self.pos = None;
+                return Ok(());
+            };
+            self.load(prev)?;
+            if !self.entries.is_empty() {
+                self.pos = Some(self.entries.len() - 1);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Iterates from the current entry on, nothing if the cursor is on no entry
+impl<'t, S: PageStore, K: KeyCodec> IntoIterator for TreeCursor<'t, S, K> {
+    type Item = Result<(K, Vec<u8>), BTreeError>;
+    type IntoIter = TreeIter<'t, S, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TreeIter::starting_at(self.tree, self.path, self.entries, self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn key_of<K: KeyCodec>(entry: Option<(K, &[u8])>) -> Option<K> {
+        entry.map(|(key, _)| key)
+    }
+
+    #[test]
+    fn cursor_moves_both_ways_across_leaves() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..1500 {
+            tree.insert(key * 2, &[key as u8; 500]).unwrap();
+        }
+        for key in 600..800 {
+            tree.delete(key * 2).unwrap();
+        }
+        assert!(tree.depth().unwrap() > 2);
+        let expected: Vec<u64> = (0..1500)
+            .filter(|key| !(600..800).contains(key))
+            .map(|key| key * 2)
+            .collect();
+
+        let mut cursor = tree.cursor().unwrap();
+        let mut forward = Vec::new();
+        while let Some((key, value)) = cursor.current() {
+            assert_eq!(value, [(key / 2) as u8; 500].as_slice());
+            forward.push(key);
+            cursor.next().unwrap();
+        }
+        assert_eq!(forward, expected);
+        // Past the end the cursor stays put
+        assert_eq!(key_of(cursor.prev().unwrap()), None);
+
+        let mut backward = vec![key_of(cursor.seek_last().unwrap()).unwrap()];
+        while let Some(key) = key_of(cursor.prev().unwrap()) {
+            backward.push(key);
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        // Across the deleted gap and back
+        assert_eq!(key_of(cursor.seek(1201).unwrap()), Some(1600));
+        assert_eq!(key_of(cursor.prev().unwrap()), Some(1198));
+        assert_eq!(key_of(cursor.next().unwrap()), Some(1600));
+        assert_eq!(key_of(cursor.seek(2998).unwrap()), Some(2998));
+        assert_eq!(key_of(cursor.seek(2999).unwrap()), None);
+        assert_eq!(key_of(cursor.seek_first().unwrap()), Some(0));
+        assert_eq!(key_of(cursor.prev().unwrap()), None);
+    }
+
+    #[test]
+    fn cursor_on_empty_tree() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into()))
+            .unwrap()
+            .keyed::<i32>();
+        let mut cursor = tree.cursor().unwrap();
+        assert_eq!(key_of(cursor.current()), None);
+        assert_eq!(key_of(cursor.seek_last().unwrap()), None);
+
+        tree.insert(-5, b"negative").unwrap();
+        tree.insert(5, b"positive").unwrap();
+        let mut cursor = tree.cursor().unwrap();
+        assert_eq!(cursor.current(), Some((-5, b"negative".as_slice())));
+        assert_eq!(key_of(cursor.seek(-4).unwrap()), Some(5));
+    }
+}
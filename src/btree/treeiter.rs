@@ -0,0 +1,314 @@
+/*
+Iterators over a whole tree. A range handle names the keys to visit and turns into an iterator
+that walks them lazily from both ends: the front reads leaves upwards from the start of the
+range, the back downwards from its end, each holding a copy of one leaf. The smallest and
+largest key not yet yielded bound both ends, so they stop once they meet and never yield an
+entry twice.
+
+Reading a leaf can fail, so items are results. An error ends the iteration, the iterator is
+fused and returns None from then on.
+*/
+
+use std::collections::VecDeque;
+use std::iter::FusedIterator;
+use std::ops::{Bound, RangeBounds};
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::tree::{LeafEntries, Path};
+use super::BTree;
+use crate::page::PageStore;
+
+/// One end of an iterator: the path to a leaf and the entries of it not yielded yet
+type Leaf = (Path, VecDeque<(u64, Vec<u8>)>);
+
+/// Entries of a tree within a key range, not read yet. Iterate it to read them.
+pub struct TreeRange<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    /// Encoded bounds, inclusive, `None` for a range without keys
+    bounds: Option<(u64, u64)>,
+}
+
+/// Iterator over the entries of a TreeRange in key order, from either end
+pub struct TreeIter<'t, S: PageStore, K: KeyCodec> {
+    tree: &'t mut BTree<S, K>,
+    /// Smallest and largest key that may still be yielded, `None` once done
+    bounds: Option<(u64, u64)>,
+    front: Option<Leaf>,
+    back: Option<Leaf>,
+}
+
+impl<S: PageStore, K: KeyCodec> BTree<S, K> {
+    /// The entries within `range`
+    pub fn range(&mut self, range: impl RangeBounds<K>) -> TreeRange<'_, S, K> {
+        let low = match range.start_bound() {
+            Bound::Included(key) => Some(key.encode()),
+            Bound::Excluded(key) => key.encode().checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let high = match range.end_bound() {
+            Bound::Included(key) => Some(key.encode()),
+            Bound::Excluded(key) => key.encode().checked_sub(1),
+            Bound::Unbounded => Some(u64::MAX),
+        };
+        let bounds = low.zip(high).filter(|(low, high)| low <= high);
+        TreeRange { tree: self, bounds }
+    }
+
+    /// Every entry of the tree
+    pub fn iter(&mut self) -> TreeIter<'_, S, K> {
+        self.range(..).into_iter()
+    }
+}
+
+impl<'t, S: PageStore, K: KeyCodec> IntoIterator for TreeRange<'t, S, K> {
+    type Item = Result<(K, Vec<u8>), BTreeError>;
+    type IntoIter = TreeIter<'t, S, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TreeIter {
+            tree: self.tree,
+            bounds: self.bounds,
+            front: None,
+            back: None,
+        }
+    }
+}
+
+impl<'t, S: PageStore, K: KeyCodec> IntoIterator for &'t mut BTree<S, K> {
+    type Item = Result<(K, Vec<u8>), BTreeError>;
+    type IntoIter = TreeIter<'t, S, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'t, S: PageStore, K: KeyCodec> TreeIter<'t, S, K> {
+    /// An iterator whose front starts at `entries[pos]` of the leaf at the end of `path`
+    pub(super) fn starting_at(
+        tree: &'t mut BTree<S, K>,
+        path: Path,
+        mut entries: LeafEntries,
+        pos: Option<usize>,
+    ) -> Self {
+        let Some(pos) = pos else {
+            return Self {
+                tree,
+                bounds: None,
+                front: None,
+                back: None,
+            };
+        };
+        let entries: VecDeque<_> = entries.drain(pos..).collect();
+        Self {
+            tree,
+            bounds: Some((entries[0].0, u64::MAX)),
+            front: Some((path, entries)),
+            back: None,
+        }
+    }
+
+    /// Reads the leaf that may hold `key`, keeping only the entries still within bounds
+    fn load(&mut self, key: u64) -> Result<Leaf, BTreeError> {
+        let (low, high) = self.bounds.expect("Only loads while not done");
+        let (path, entries) = self.tree.leaf_at(key)?;
+        let entries = entries
+            .into_iter()
+            .filter(|(key, _)| (low..=high).contains(key))
+            .collect();
+        Ok((path, entries))
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(u64, Vec<u8>)>, BTreeError> {
+        while let Some((low, high)) = self.bounds {
+            if self.front.is_none() {
+                self.front = Some(self.load(low)?);
+            }
+            let (path, entries) = self.front.as_mut().expect("Loaded above");
+            match entries.pop_front() {
+                Some((key, value)) if key <= high => {
+                    // Nothing is left once the largest possible key was yielded
+                    self.bounds = key.checked_add(1).map(|low| (low, high));
+                    return Ok(Some((key, value)));
+                }
+                Some(_) => break,
+                None => {
+                    let Some(next) = self.tree.upper_bound(path)?.and_then(|b| b.checked_add(1))
+                    else {
+                        break;
+                    };
+                    self.bounds = Some((next, high)).filter(|(low, high)| low <= high);
+                    self.front = None;
+                }
+            }
+        }
+        self.bounds = None;
+        Ok(None)
+    }
+
+    fn next_back_entry(&mut self) -> Result<Option<(u64, Vec<u8>)>, BTreeError> {
+        while let Some((low, high)) = self.bounds {
+            if self.back.is_none() {
+                self.back = Some(self.load(high)?);
+            }
+            let (path, entries) = self.back.as_mut().expect("Loaded above");
+            match entries.pop_back() {
+                Some((key, value)) if key >= low => {
+                    self.bounds = key.checked_sub(1).map(|high| (low, high));
+                    return Ok(Some((key, value)));
+                }
+                Some(_) => break,
+                None => {
+                    let Some(prev) = self.tree.lower_bound(path)? else {
+                        break;
+                    };
+                    self.bounds = Some((low, prev)).filter(|(low, high)| low <= high);
+                    self.back = None;
+                }
+            }
+        }
+        self.bounds = None;
+        Ok(None)
+    }
+
+    /// Ends the iteration after an error
+    fn fuse(
+        &mut self,
+        entry: Result<Option<(u64, Vec<u8>)>, BTreeError>,
+    ) -> Option<<Self as Iterator>::Item> {
+        match entry {
+            Ok(entry) => entry.map(|(key, value)| Ok((K::decode(key), value))),
+            Err(err) => {
+                self.bounds = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> Iterator for TreeIter<'_, S, K> {
+    type Item = Result<(K, Vec<u8>), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        self.fuse(entry)
+    }
+
+    /// At least the entries left in the leaf copy of either end, the rest of the range is
+    /// unknown until read
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let Some((low, high)) = self.bounds else {
+            return (0, Some(0));
+        };
+        let left = |end: &Option<Leaf>| {
+            end.as_ref().map_or(0, |(_, entries)| {
+                entries
+                    .iter()
+                    .filter(|(key, _)| (low..=high).contains(key))
+                    .count()
+            })
+        };
+        (left(&self.front).max(left(&self.back)), None)
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> DoubleEndedIterator for TreeIter<'_, S, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let entry = self.next_back_entry();
+        self.fuse(entry)
+    }
+}
+
+impl<S: PageStore, K: KeyCodec> FusedIterator for TreeIter<'_, S, K> {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    fn keys<K: KeyCodec>(iter: impl Iterator<Item = Result<(K, Vec<u8>), BTreeError>>) -> Vec<K> {
+        iter.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn ranges_iterate_from_both_ends() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..1500 {
+            tree.insert(key * 2, &[key as u8; 500]).unwrap();
+        }
+        for key in 600..800 {
+            tree.delete(key * 2).unwrap();
+        }
+        assert!(tree.depth().unwrap() > 2);
+        let expected: Vec<u64> = (0..1500)
+            .filter(|key| !(600..800).contains(key))
+            .map(|key| key * 2)
+            .collect();
+
+        assert_eq!(keys(tree.iter()), expected);
+        assert_eq!(
+            keys(tree.iter().rev()),
+            expected.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(keys(tree.range(..).into_iter().take(3)), [0, 2, 4]);
+        assert_eq!(
+            keys(tree.range(1190..=1600).into_iter()),
+            [1190, 1192, 1194, 1196, 1198, 1600]
+        );
+        assert_eq!(keys(tree.range(1199..1600).into_iter()), Vec::<u64>::new());
+        assert_eq!(
+            keys(tree.range(2990..).into_iter().rev()),
+            [2998, 2996, 2994, 2992, 2990]
+        );
+
+        // Both ends meet in the middle without yielding anything twice
+        let mut iter = tree.range(10..=20).into_iter();
+        let mut met = Vec::new();
+        while let (Some(front), back) = (iter.next(), iter.next_back()) {
+            met.push(front.unwrap().0);
+            met.extend(back.map(|back| back.unwrap().0));
+        }
+        met.sort();
+        assert_eq!(met, [10, 12, 14, 16, 18, 20]);
+        assert_eq!(iter.size_hint(), (0, Some(0)));
+        assert!(iter.next().is_none());
+
+        let mut count = 0;
+        for entry in &mut tree {
+            let (key, value) = entry.unwrap();
+            assert_eq!(value, [(key / 2) as u8; 500]);
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+    }
+
+    #[test]
+    fn typed_ranges_and_cursors() {
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into()))
+            .unwrap()
+            .keyed::<i32>();
+        assert_eq!(keys(tree.iter()), Vec::<i32>::new());
+        assert_eq!(tree.iter().size_hint(), (0, None));
+        for key in -300..300 {
+            tree.insert(key, &key.to_le_bytes()).unwrap();
+        }
+
+        assert_eq!(keys(tree.range(-2..2).into_iter()), [-2, -1, 0, 1]);
+        assert_eq!(keys(tree.range(..-298).into_iter()), [-300, -299]);
+        assert_eq!(keys(tree.range(5..5).into_iter()), Vec::<i32>::new());
+        let mut iter = tree.range(i32::MIN..).into_iter();
+        iter.next().unwrap().unwrap();
+        assert!(iter.size_hint().0 > 0);
+
+        // A cursor turns into an iterator from its current entry on
+        let mut cursor = tree.cursor().unwrap();
+        cursor.seek(297).unwrap();
+        assert_eq!(keys(cursor.into_iter()), [297, 298, 299]);
+        let mut cursor = tree.cursor().unwrap();
+        cursor.seek(300).unwrap();
+        assert_eq!(keys(cursor.into_iter()), Vec::<i32>::new());
+    }
+}
@@ -0,0 +1,155 @@
+/*
+Trees of fixed layout values. Values are zerocopy structs stored as their bytes, so a read
+hands out a reference into the leaf page it was found in instead of deserializing anything.
+Values sit at arbitrary offsets in a page, which is why they have to be Unaligned, like the
+little endian integer types of zerocopy the node format itself is built from.
+*/
+
+use std::marker::PhantomData;
+use std::ops::{Deref, Range};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+use super::codec::KeyCodec;
+use super::errors::BTreeError;
+use super::BTree;
+use crate::page::{Page, PageStore};
+
+/// A value borrowed from the leaf page it was read from
+pub struct ValueRef<V> {
+    page: Page,
+    range: Range<usize>,
+    value: PhantomData<V>,
+}
+
+impl<V: FromBytes + KnownLayout + Immutable + Unaligned> Deref for ValueRef<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        V::ref_from_bytes(&self.page.read()[self.range.clone()])
+            .expect("Shouldn't fail, the size is checked on read")
+    }
+}
+
+/// A tree whose values are all `V`
+pub struct TypedTree<S: PageStore, K: KeyCodec, V> {
+    tree: BTree<S, K>,
+    value: PhantomData<V>,
+}
+
+impl<S, K, V> TypedTree<S, K, V>
+where
+    S: PageStore,
+    K: KeyCodec,
+    V: FromBytes + IntoBytes + KnownLayout + Immutable + Unaligned,
+{
+    pub fn new(tree: BTree<S, K>) -> Self {
+        Self {
+            tree,
+            value: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> BTree<S, K> {
+        self.tree
+    }
+
+    /// Reads the value of `key` without copying it out of its page
+    pub fn get(&mut self, key: K) -> Result<Option<ValueRef<V>>, BTreeError> {
+        let Some((page, range)) = self.tree.get_in_page(key)? else {
+            return Ok(None);
+        };
+        check_size::<V>(range.len())?;
+        Ok(Some(ValueRef {
+            page,
+            range,
+            value: PhantomData,
+        }))
+    }
+
+    /// Stores `value` under `key`, returning the value it replaced
+    pub fn insert(&mut self, key: K, value: &V) -> Result<Option<V>, BTreeError> {
+        self.tree
+            .insert(key, value.as_bytes())?
+            .map(|old| decode(&old.value))
+            .transpose()
+    }
+
+    pub fn delete(&mut self, key: K) -> Result<Option<V>, BTreeError> {
+        self.tree
+            .delete(key)?
+            .map(|old| decode(&old.value))
+            .transpose()
+    }
+}
+
+/// Values of another size were stored through the untyped tree
+fn check_size<V>(len: usize) -> Result<(), BTreeError> {
+    if len != size_of::<V>() {
+        return Err(BTreeError::UnexpectedData {
+            expected: size_of::<V>(),
+            actual: len,
+        });
+    }
+    Ok(())
+}
+
+fn decode<V: FromBytes>(bytes: &[u8]) -> Result<V, BTreeError> {
+    check_size::<V>(bytes.len())?;
+    Ok(V::read_from_bytes(bytes).expect("Shouldn't fail, the size is checked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+    use zerocopy::little_endian::{U32, U64};
+
+    #[derive(Debug, PartialEq, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    struct Account {
+        balance: U64,
+        owner: U32,
+        flags: u8,
+    }
+
+    fn account(key: u64) -> Account {
+        Account {
+            balance: (key * 100).into(),
+            owner: (key as u32 % 7).into(),
+            flags: key as u8,
+        }
+    }
+
+    #[test]
+    fn values_are_read_from_the_page() {
+        let tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        let mut tree = TypedTree::<_, u64, Account>::new(tree);
+        for key in 0..2000 {
+            assert_eq!(tree.insert(key, &account(key)).unwrap(), None);
+        }
+        for key in (0..2000).step_by(13) {
+            let value = tree.get(key).unwrap().unwrap();
+            assert_eq!(*value, account(key));
+            assert_eq!(value.balance.get(), key * 100);
+        }
+        assert!(tree.get(2000).unwrap().is_none());
+
+        assert_eq!(tree.insert(5, &account(6)).unwrap(), Some(account(5)));
+        assert_eq!(tree.delete(5).unwrap(), Some(account(6)));
+
+        // Values of another size stored behind the typed tree's back are caught
+        let mut tree = tree.into_inner();
+        tree.insert(7, b"abekat").unwrap();
+        let mut tree = TypedTree::<_, u64, Account>::new(tree);
+        assert!(matches!(
+            tree.get(7),
+            Err(BTreeError::UnexpectedData {
+                expected: 13,
+                actual: 6
+            })
+        ));
+    }
+}
@@ -0,0 +1,642 @@
+/*
+Validation of untrusted database files. The file is read one page at a time into a fixed
+buffer and every page is checked against the node format without ever constructing a Node,
+so a hostile file can't make us allocate more than the bounded list of issues.
+
+Databases too large to check in one go are verified page by page through a PageVerifier. It
+reads the pages of a store in order, skips the ones the store reserves for itself, and yields
+the issues of every page as soon as it is checked. Between pages it checks its budget: once
+interrupted it yields the error and stops, and next_page tells where to resume later.
+*/
+
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+
+use super::bytekeys::{BYTE_KEY_SLOT_SIZE, CELL_HEADER_SIZE};
+#[cfg(feature = "std")]
+use super::check_page_size;
+use super::checksum::{crc32c, CHECKSUM_SIZE};
+use super::freeblock::{Freeblock, FREEBLOCK_SIZE};
+use super::header::{Header, NodeType, HEADER_SIZE};
+use super::key::{Key, KEY_SIZE, MAX_INLINE_VALUE};
+use super::packed::{packed_entry, MAX_PACKED_WIDTH};
+use super::PAGE_SIZE;
+#[cfg(feature = "std")]
+use crate::cancel::Budget;
+use crate::limits::ResourceLimits;
+#[cfg(feature = "pager")]
+use crate::page::PageStore;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationLimits {
+    pub max_pages: usize,
+    pub max_issues: usize,
+    /// Size of the pages in the file
+    pub page_size: usize,
+}
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        Self {
+            max_pages: 1 << 20,
+            max_issues: 64,
+            page_size: PAGE_SIZE.into(),
+        }
+    }
+}
+
+impl From<ResourceLimits> for ValidationLimits {
+    fn from(limits: ResourceLimits) -> Self {
+        Self {
+            max_pages: limits.max_pages,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IssueKind {
+    TrailingBytes(usize),
+    TooManyPages,
+    InvalidHeader,
+    FreeSpaceOutOfRange { free_start: u16, free_end: u16 },
+    KeyCountMismatch { num_keys: u16, free_start: u16 },
+    KeysNotSorted { index: u16 },
+    ValueOutOfBounds { index: u16 },
+    FreeblockOutOfBounds { offset: u16 },
+    FreeblockChainNotSorted { offset: u16 },
+    ChecksumMismatch,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Issue {
+    pub page: usize,
+    pub kind: IssueKind,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub pages_checked: usize,
+    pub issues: Vec<Issue>,
+    /// Set when more issues were found than `max_issues` allowed us to record
+    pub truncated: bool,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty() && !self.truncated
+    }
+
+    #[cfg(feature = "std")]
+    fn push(&mut self, limits: &ValidationLimits, page: usize, kind: IssueKind) {
+        if self.issues.len() < limits.max_issues {
+            self.issues.push(Issue { page, kind });
+        } else {
+            self.truncated = true;
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn validate_file<R: Read>(reader: R) -> Result<Report, io::Error> {
+    validate_file_with_limits(reader, ValidationLimits::default())
+}
+
+#[cfg(feature = "std")]
+pub fn validate_file_with_limits<R: Read>(
+    reader: R,
+    limits: ValidationLimits,
+) -> Result<Report, io::Error> {
+    validate_file_within(reader, limits, &Budget::unlimited())
+}
+
+#[cfg(feature = "std")]
+/// Like `validate_file_with_limits`, but checks `budget` before every page
+pub fn validate_file_within<R: Read>(
+    mut reader: R,
+    limits: ValidationLimits,
+    budget: &Budget,
+) -> Result<Report, io::Error> {
+    check_page_size(limits.page_size).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported page size {}", limits.page_size),
+        )
+    })?;
+    let mut report = Report::default();
+    let mut buf = vec![0u8; limits.page_size];
+
+    loop {
+        budget.check()?;
+        let filled = fill_page(&mut reader, &mut buf)?;
+        if filled == 0 {
+            break;
+        }
+        if filled < buf.len() {
+            report.push(
+                &limits,
+                report.pages_checked,
+                IssueKind::TrailingBytes(filled),
+            );
+            break;
+        }
+        if report.pages_checked == limits.max_pages {
+            report.push(&limits, report.pages_checked, IssueKind::TooManyPages);
+            break;
+        }
+
+        check_page(&buf, |kind| {
+            report.push(&limits, report.pages_checked, kind)
+        });
+        report.pages_checked += 1;
+    }
+
+    Ok(report)
+}
+
+/// Issues found on one page by a PageVerifier, none if the page is fine
+#[cfg(feature = "pager")]
+#[derive(Debug, PartialEq)]
+pub struct PageReport {
+    pub page: usize,
+    pub issues: Vec<IssueKind>,
+}
+
+/// Checks the node pages of a store one at a time, see verify_pages
+#[cfg(feature = "pager")]
+pub struct PageVerifier<'s, S: PageStore> {
+    store: &'s mut S,
+    /// Pages the store keeps for itself, sorted
+    reserved: Vec<usize>,
+    next: usize,
+    n_pages: usize,
+    budget: Budget,
+    interrupted: bool,
+}
+
+/// Verifies the pages of `store` lazily, from the first page on
+#[cfg(feature = "pager")]
+pub fn verify_pages<S: PageStore>(store: &mut S) -> Result<PageVerifier<'_, S>, io::Error> {
+    let mut reserved = store.reserved_pages()?;
+    reserved.sort_unstable();
+    Ok(PageVerifier {
+        n_pages: store.n_pages()?,
+        store,
+        reserved,
+        next: 0,
+        budget: Budget::unlimited(),
+        interrupted: false,
+    })
+}
+
+#[cfg(feature = "pager")]
+impl<S: PageStore> PageVerifier<'_, S> {
+    /// Starts at page `page` instead, to pick up where an interrupted run stopped
+    pub fn resume_from(mut self, page: usize) -> Self {
+        self.next = page;
+        self
+    }
+
+    /// Checked before every page
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// The page checked next, where to resume after an interruption
+    pub fn next_page(&self) -> usize {
+        self.next
+    }
+
+    /// Pages passed so far, checked or skipped, out of the pages in the store
+    pub fn progress(&self) -> (usize, usize) {
+        (self.next.min(self.n_pages), self.n_pages)
+    }
+}
+
+/// Pages that can't be read are yielded as errors and passed over. An interrupted budget
+/// is yielded as an error as well, after which nothing is.
+#[cfg(feature = "pager")]
+impl<S: PageStore> Iterator for PageVerifier<'_, S> {
+    type Item = Result<PageReport, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.n_pages && !self.interrupted {
+            if let Err(interrupted) = self.budget.check() {
+                self.interrupted = true;
+                return Some(Err(interrupted.into()));
+            }
+            let page = self.next;
+            self.next += 1;
+            if self.reserved.binary_search(&page).is_ok() {
+                continue;
+            }
+
+            let mut issues = Vec::new();
+            match self.store.read_page(page) {
+                Ok(data) => check_page(data.read(), |kind| issues.push(kind)),
+                Err(err) => return Some(Err(err)),
+            }
+            return Some(Ok(PageReport { page, issues }));
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.interrupted {
+            true => (0, Some(0)),
+            // One item per page left and one for an interruption
+            false => (0, Some(self.n_pages.saturating_sub(self.next) + 1)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn fill_page<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Checks a raw page against the node format, reporting every problem found through `report`
+pub(crate) fn check_page(page: &[u8], mut report: impl FnMut(IssueKind)) {
+    let header_bytes: &[u8; HEADER_SIZE as usize] = page[..HEADER_SIZE as usize]
+        .try_into()
+        .expect("Shouldn't fail, sizes are hardcoded equal");
+    let Ok(header) = Header::intepret_from_bytes(header_bytes) else {
+        report(IssueKind::InvalidHeader);
+        return;
+    };
+
+    let page_size = page.len() as u16;
+    if header.has_checksum() {
+        let (content, rest) = page.split_at(header.content_end(page_size).into());
+        let stored = &rest[..CHECKSUM_SIZE.into()];
+        let stored = u32::from_le_bytes(stored.try_into().expect("Shouldn't fail, hardcoded"));
+        if crc32c(content) != stored {
+            report(IssueKind::ChecksumMismatch);
+        }
+    }
+
+    let num_keys = header.num_keys.get();
+    let free_start = header.free_start.get();
+    let free_end = header.free_end.get();
+    let content_end = header.content_end(page_size);
+
+    if free_start < HEADER_SIZE || free_start > free_end || free_end > content_end {
+        report(IssueKind::FreeSpaceOutOfRange {
+            free_start,
+            free_end,
+        });
+        return;
+    }
+
+    let slot_size = header.slot_size();
+    let slots_len = free_start.checked_sub(header.slots_start());
+    if slots_len.map(usize::from) != Some(num_keys as usize * slot_size as usize) {
+        report(IssueKind::KeyCountMismatch {
+            num_keys,
+            free_start,
+        });
+        return;
+    }
+
+    if header.is_packed() {
+        check_packed_page(page, header, &mut report);
+        return;
+    }
+    if header.has_key_prefix_bits() {
+        report(IssueKind::InvalidHeader);
+        return;
+    }
+    if header.has_byte_keys() {
+        check_byte_key_page(page, header, &mut report);
+        return;
+    }
+
+    let mut prev_key = None;
+    for index in 0..num_keys {
+        let pos = (HEADER_SIZE + index * KEY_SIZE) as usize;
+        let key_bytes: &[u8; KEY_SIZE as usize] = page[pos..pos + KEY_SIZE as usize]
+            .try_into()
+            .expect("Shouldn't fail, sizes are hardcoded equal");
+        let key = Key::intepret_from_bytes(key_bytes).expect("Every bit pattern is a valid key");
+
+        if prev_key.is_some_and(|prev| prev >= key.key.get()) {
+            report(IssueKind::KeysNotSorted { index });
+        }
+        prev_key = Some(key.key.get());
+
+        // Key records of internal nodes carry a child page instead of a value
+        if key.is_inline() {
+            let max_len = match header.node_type {
+                NodeType::Leaf => MAX_INLINE_VALUE,
+                NodeType::Internal => 0,
+            };
+            if key.value_len.get() > max_len {
+                report(IssueKind::ValueOutOfBounds { index });
+            }
+            continue;
+        }
+
+        let value_end = key.value_offset.get() as usize + key.value_len.get() as usize;
+        if key.value_offset.get() < free_end || value_end > content_end as usize {
+            report(IssueKind::ValueOutOfBounds { index });
+        }
+    }
+
+    // Offsets must be strictly increasing, which also bounds the walk for cyclic chains
+    let mut offset = header.first_freeblock.get();
+    let mut prev_offset = 0;
+    while offset != 0 {
+        if offset <= prev_offset {
+            report(IssueKind::FreeblockChainNotSorted { offset });
+            return;
+        }
+        if offset < free_end || offset as usize + FREEBLOCK_SIZE as usize > content_end as usize {
+            report(IssueKind::FreeblockOutOfBounds { offset });
+            return;
+        }
+
+        let fb_bytes: &[u8; FREEBLOCK_SIZE as usize] = page
+            [offset as usize..(offset + FREEBLOCK_SIZE) as usize]
+            .try_into()
+            .expect("Shouldn't fail, sizes are hardcoded equal");
+        let freeblock = Freeblock::intepret_from_bytes(fb_bytes)
+            .expect("Every bit pattern is a valid freeblock");
+
+        if freeblock.size.get() < FREEBLOCK_SIZE
+            || offset as usize + freeblock.size.get() as usize > content_end as usize
+        {
+            report(IssueKind::FreeblockOutOfBounds { offset });
+            return;
+        }
+
+        prev_offset = offset;
+        offset = freeblock.next_freeblock.get();
+    }
+}
+
+/// Packed nodes keep values inline, so they have no content area or freeblocks at all
+fn check_packed_page(page: &[u8], header: &Header, report: &mut impl FnMut(IssueKind)) {
+    if header.packed_width > MAX_PACKED_WIDTH
+        || header.free_end.get() != header.content_end(page.len() as u16)
+        || header.first_freeblock.get() != 0
+    {
+        report(IssueKind::InvalidHeader);
+        return;
+    }
+
+    let mut prev_key = None;
+    for index in 0..header.num_keys.get() {
+        let (key, _) = packed_entry(page, header, index);
+        if prev_key.is_some_and(|prev| prev >= key) {
+            report(IssueKind::KeysNotSorted { index });
+        }
+        prev_key = Some(key);
+    }
+}
+
+/// Byte keyed nodes have no freeblocks, every cell has to lie in the content area
+fn check_byte_key_page(page: &[u8], header: &Header, report: &mut impl FnMut(IssueKind)) {
+    if header.packed_width != 0
+        || header.first_freeblock.get() != 0
+        || header.node_type != NodeType::Leaf
+    {
+        report(IssueKind::InvalidHeader);
+        return;
+    }
+
+    let free_end = header.free_end.get() as usize;
+    let content_end = header.content_end(page.len() as u16);
+    let read_u16 = |pos: usize| u16::from_le_bytes([page[pos], page[pos + 1]]) as usize;
+    let mut prev_key: Option<&[u8]> = None;
+    for index in 0..header.num_keys.get() {
+        let offset = read_u16(HEADER_SIZE as usize + BYTE_KEY_SLOT_SIZE as usize * index as usize);
+        if offset < free_end || offset + CELL_HEADER_SIZE as usize > content_end as usize {
+            report(IssueKind::ValueOutOfBounds { index });
+            continue;
+        }
+        let key_start = offset + CELL_HEADER_SIZE as usize;
+        let key_end = key_start + read_u16(offset);
+        if key_end + read_u16(offset + 2) > content_end as usize {
+            report(IssueKind::ValueOutOfBounds { index });
+            continue;
+        }
+
+        // The order of a comparator isn't known here
+        let key = &page[key_start..key_end];
+        if !header.has_custom_order() && prev_key.is_some_and(|prev| prev >= key) {
+            report(IssueKind::KeysNotSorted { index });
+        }
+        prev_key = Some(key);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::packed::PACKED_KEY_SIZE;
+    use super::super::{Node, NodeConfig};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn valid_pages(n: usize) -> Vec<u8> {
+        let mut file = vec![0u8; n * PAGE_SIZE as usize];
+        for page in file.chunks_mut(PAGE_SIZE as usize) {
+            let mut node = Node::new(page).unwrap();
+            for key in 1..=10 {
+                node.insert(key, &[key as u8; 30]).unwrap();
+            }
+            node.delete(4).unwrap();
+            node.delete(7).unwrap();
+        }
+        file
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn pages_are_verified_one_at_a_time() {
+        use super::super::BTree;
+        use crate::cancel::CancelToken;
+        use crate::page::{MemoryStore, Page, Pager};
+
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into())).unwrap();
+        for key in 0..500 {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        let reports: Vec<_> = tree.verify().unwrap().map(Result::unwrap).collect();
+        let n_pages = reports.len();
+        assert!(n_pages > 10);
+        assert!(reports.iter().all(|report| report.issues.is_empty()));
+
+        let mut store = tree.into_store();
+        store.write_page(3, &Page::new(PAGE_SIZE.into())).unwrap();
+        // Stops at a cancellation and resumes where it stopped
+        let token = CancelToken::new();
+        let budget = Budget::unlimited().cancel_token(token.clone());
+        let mut verifier = verify_pages(&mut store).unwrap().budget(budget);
+        assert_eq!(verifier.next().unwrap().unwrap().page, 0);
+        token.cancel();
+        let err = verifier.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(verifier.next().is_none());
+        assert_eq!(verifier.progress(), (1, n_pages));
+
+        let resume = verifier.next_page();
+        let mut verifier = verify_pages(&mut store).unwrap().resume_from(resume);
+        let report = verifier.next().unwrap().unwrap();
+        assert_eq!(report.page, 1);
+        let bad: Vec<_> = verifier
+            .filter_map(Result::ok)
+            .filter(|r| !r.issues.is_empty())
+            .collect();
+        assert_eq!(
+            bad,
+            [PageReport {
+                page: 3,
+                issues: vec![IssueKind::FreeSpaceOutOfRange {
+                    free_start: 0,
+                    free_end: 0
+                }]
+            }]
+        );
+
+        // Pagers keep their own pages out of it
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.bin");
+        let mut tree = BTree::create(Pager::open(path.to_str().unwrap()).unwrap()).unwrap();
+        tree.insert(1, b"one").unwrap();
+        let pages: Vec<_> = tree.verify().unwrap().map(|r| r.unwrap().page).collect();
+        assert!(!pages.contains(&0));
+    }
+
+    #[test]
+    fn valid_file() {
+        let file = valid_pages(3);
+        let report = validate_file(file.as_slice()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.pages_checked, 3);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut file = valid_pages(1);
+        file.extend_from_slice(&[0; 10]);
+        let report = validate_file(file.as_slice()).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![Issue {
+                page: 1,
+                kind: IssueKind::TrailingBytes(10)
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_node_type() {
+        let mut file = valid_pages(2);
+        file[PAGE_SIZE as usize] = 0xFF;
+        let report = validate_file(file.as_slice()).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![Issue {
+                page: 1,
+                kind: IssueKind::InvalidHeader
+            }]
+        );
+    }
+
+    #[test]
+    fn value_out_of_bounds() {
+        let mut file = valid_pages(1);
+        let mut node = Node::load(&mut file).unwrap();
+        node.mut_key_at(0).unwrap().value_offset.set(PAGE_SIZE - 1);
+
+        drop(node);
+        let report = validate_file(file.as_slice()).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![Issue {
+                page: 0,
+                kind: IssueKind::ValueOutOfBounds { index: 0 }
+            }]
+        );
+    }
+
+    #[test]
+    fn cyclic_freeblock_chain() {
+        let mut file = valid_pages(1);
+        let mut node = Node::load(&mut file).unwrap();
+        let first = node.read_header().unwrap().first_freeblock.get();
+        node.mut_freeblock(first.into())
+            .unwrap()
+            .next_freeblock
+            .set(first);
+
+        drop(node);
+        let report = validate_file(file.as_slice()).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![Issue {
+                page: 0,
+                kind: IssueKind::FreeblockChainNotSorted { offset: first }
+            }]
+        );
+    }
+
+    #[test]
+    fn issue_and_page_limits() {
+        let mut file = vec![0xFFu8; 5 * PAGE_SIZE as usize];
+        let limits = ValidationLimits {
+            max_pages: 4,
+            max_issues: 2,
+            ..ValidationLimits::default()
+        };
+        let report = validate_file_with_limits(file.as_slice(), limits).unwrap();
+        assert_eq!(report.issues.len(), 2);
+        assert!(report.truncated);
+        assert_eq!(report.pages_checked, 4);
+
+        file.truncate(PAGE_SIZE as usize);
+        let report = validate_file_with_limits(file.as_slice(), limits).unwrap();
+        assert_eq!(report.pages_checked, 1);
+        assert!(!report.truncated);
+    }
+
+    #[test]
+    fn packed_pages() {
+        let mut file = vec![0u8; PAGE_SIZE as usize];
+        let config = NodeConfig {
+            adaptive_layout: true,
+            ..Default::default()
+        };
+        let mut node = Node::new_with_config(&mut file, config).unwrap();
+        for key in 1..=10 {
+            node.insert(key, &[key as u8; 4]).unwrap();
+        }
+        assert!(node.is_packed().unwrap());
+        drop(node);
+        assert!(validate_file(file.as_slice()).unwrap().is_ok());
+
+        // Swap the first two keys
+        let slot_size = (PACKED_KEY_SIZE + 4) as usize;
+        let first = HEADER_SIZE as usize;
+        file[first..first + 8].copy_from_slice(&2u64.to_le_bytes());
+        file[first + slot_size..first + slot_size + 8].copy_from_slice(&1u64.to_le_bytes());
+        assert_eq!(
+            validate_file(file.as_slice()).unwrap().issues,
+            vec![Issue {
+                page: 0,
+                kind: IssueKind::KeysNotSorted { index: 1 }
+            }]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+/*
+Read-only view of a node over a shared page, for pages that can't be borrowed mutably, like
+the pages of a memory map. The page is checked against the node format once when the view is
+created, so the accessors can index it without checks of their own. Byte keyed pages aren't
+part of trees and are rejected.
+*/
+
+use core::cmp::Ordering;
+
+use super::check_page_size;
+use super::errors::{BTreeError, CorruptionError};
+use super::header::{Header, NodeType, HEADER_SIZE};
+use super::key::{Key, KEY_SIZE};
+use super::packed::packed_entry;
+use super::verify::check_page;
+
+pub struct NodeView<'a> {
+    page: &'a [u8],
+}
+
+impl<'a> NodeView<'a> {
+    pub fn load(page: &'a [u8]) -> Result<Self, BTreeError> {
+        check_page_size(page.len())?;
+        let mut issue = None;
+        check_page(page, |kind| {
+            issue.get_or_insert(kind);
+        });
+        if let Some(issue) = issue {
+            return Err(BTreeError::Corrupt(CorruptionError::Page(issue)));
+        }
+        let view = Self { page };
+        if view.header().has_byte_keys() {
+            return Err(BTreeError::KeyTypeMismatch);
+        }
+        Ok(view)
+    }
+
+    fn header(&self) -> &'a Header {
+        let header_bytes: &[u8; HEADER_SIZE as usize] = self.page[..HEADER_SIZE as usize]
+            .try_into()
+            .expect("Shouldn't fail, sizes are hardcoded equal");
+        Header::intepret_from_bytes(header_bytes).expect("Checked on load")
+    }
+
+    fn key_record(&self, index: u16) -> &'a Key {
+        let pos = (HEADER_SIZE + KEY_SIZE * index) as usize;
+        let key_bytes: &[u8; KEY_SIZE as usize] = self.page[pos..pos + KEY_SIZE as usize]
+            .try_into()
+            .expect("Shouldn't fail, sizes are hardcoded equal");
+        Key::intepret_from_bytes(key_bytes).expect("Every bit pattern is a valid key")
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.header().node_type == NodeType::Leaf
+    }
+
+    pub fn len(&self) -> u16 {
+        self.header().num_keys.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Key and value at `index`, which has to be below `len()`. Separators of internal
+    /// nodes have empty values.
+    pub fn entry_at(&self, index: u16) -> (u64, &'a [u8]) {
+        debug_assert!(index < self.len());
+        let header = self.header();
+        if header.is_packed() {
+            return packed_entry(self.page, header, index);
+        }
+
+        let key = self.key_record(index);
+        if key.is_inline() {
+            return (key.key.get(), key.inline_value());
+        }
+        let offset = key.value_offset.get() as usize;
+        let len = key.value_len.get() as usize;
+        (key.key.get(), &self.page[offset..offset + len])
+    }
+
+    /// Index of `key` if it is present, otherwise where it would be inserted
+    fn search(&self, key: u64) -> Result<u16, u16> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.entry_at(mid).0.cmp(&key) {
+                Ordering::Equal => return Ok(mid),
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        Err(low)
+    }
+
+    pub fn get(&self, key: u64) -> Option<&'a [u8]> {
+        self.search(key).ok().map(|idx| self.entry_at(idx).1)
+    }
+
+    /// Child pointer at `idx` of an internal node, where `len()` refers to the rightmost child
+    pub fn child_at(&self, idx: u16) -> Result<u32, BTreeError> {
+        if self.is_leaf() {
+            return Err(BTreeError::NotInternal);
+        }
+        if idx == self.len() {
+            return Ok(self.header().rightmost_child_page.get());
+        }
+        Ok(self.key_record(idx).left_child_page.get())
+    }
+
+    /// Page to descend into when looking for `key`
+    pub fn find_child_page(&self, key: u64) -> Result<u32, BTreeError> {
+        let idx = self.search(key).unwrap_or_else(|idx| idx);
+        self.child_at(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{IssueKind, Node, PAGE_SIZE};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn view_matches_node() {
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap();
+        for key in (0..100).rev() {
+            node.insert(key * 2, &vec![key as u8; (key % 9) as usize])
+                .unwrap();
+        }
+        drop(node);
+
+        let view = NodeView::load(&page).unwrap();
+        assert!(view.is_leaf());
+        assert_eq!(view.len(), 100);
+        assert_eq!(view.entry_at(3), (6, [3u8; 3].as_slice()));
+        assert_eq!(view.get(196), Some([98u8; 8].as_slice()));
+        assert_eq!(view.get(17), None);
+        assert!(matches!(view.child_at(0), Err(BTreeError::NotInternal)));
+
+        page[0] = 0xFF;
+        assert!(matches!(
+            NodeView::load(&page),
+            Err(BTreeError::Corrupt(CorruptionError::Page(
+                IssueKind::InvalidHeader
+            )))
+        ));
+    }
+}
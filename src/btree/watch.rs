@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::Node;
+
+/// Keys whose versions a watcher keeps by default
+pub const WATCHED_KEYS: usize = 4096;
+
+/// Change notifications for keys. Every write to a key stamps it with a new version and
+/// wakes waiters. Only the most recently written keys keep their versions, the others
+/// report the newest version forgotten, so a waiter whose version is older than that wakes
+/// as if its key changed.
+pub struct KeyWatcher {
+    versions: Mutex<Versions>,
+    changed: Condvar,
+}
+
+struct Versions {
+    by_key: HashMap<u64, u64>,
+    /// Version of the latest write
+    latest: u64,
+    /// Newest version dropped from by_key, that of every key not in it or older
+    forgotten: u64,
+    capacity: usize,
+}
+
+impl Versions {
+    fn get(&self, key: u64) -> u64 {
+        self.by_key.get(&key).copied().unwrap_or(self.forgotten)
+    }
+}
+
+impl Default for KeyWatcher {
+    fn default() -> Self {
+        Self::with_capacity(WATCHED_KEYS)
+    }
+}
+
+impl KeyWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A watcher that keeps the versions of the last `capacity` to `2 * capacity` written
+    /// keys
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            versions: Mutex::new(Versions {
+                by_key: HashMap::new(),
+                latest: 0,
+                forgotten: 0,
+                capacity: capacity.max(1),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn version(&self, key: u64) -> u64 {
+        let versions = self.versions.lock().expect("KeyWatcher lock poisoned");
+        versions.get(key)
+    }
+
+    pub fn notify(&self, key: u64) {
+        let mut versions = self.versions.lock().expect("KeyWatcher lock poisoned");
+        versions.latest += 1;
+        let latest = versions.latest;
+        versions.by_key.insert(key, latest);
+        if versions.by_key.len() >= 2 * versions.capacity {
+            // The versions of the last `capacity` writes stay, and with them at most as many keys
+            let forgotten = latest - versions.capacity as u64;
+            versions.by_key.retain(|_, version| *version > forgotten);
+            versions.forgotten = forgotten;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Blocks until `key` is written after this call. Returns false on timeout.
+    pub fn wait_for(&self, key: u64, timeout: Duration) -> bool {
+        self.wait_for_change_since(key, self.version(key), timeout)
+    }
+
+    /// Blocks until the version of `key` moves past `version`. Returns false on timeout,
+    /// one too far out to reach waits forever.
+    pub fn wait_for_change_since(&self, key: u64, version: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut versions = self.versions.lock().expect("KeyWatcher lock poisoned");
+
+        loop {
+            if versions.get(key) > version {
+                return true;
+            }
+            let Some(deadline) = deadline else {
+                versions = self
+                    .changed
+                    .wait(versions)
+                    .expect("KeyWatcher lock poisoned");
+                continue;
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            versions = self
+                .changed
+                .wait_timeout(versions, deadline - now)
+                .expect("KeyWatcher lock poisoned")
+                .0;
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Publishes every successful insert on this node to `watcher`
+    pub fn with_watcher(mut self, watcher: &'a KeyWatcher) -> Self {
+        self.watcher = Some(watcher);
+        self
+    }
+
+    pub(crate) fn notify_watcher(&self, key: u64) {
+        if let Some(watcher) = self.watcher {
+            watcher.notify(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PAGE_SIZE;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn wait_times_out_without_writes() {
+        let watcher = KeyWatcher::new();
+        assert!(!watcher.wait_for(1, Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn insert_wakes_waiter() {
+        let watcher = KeyWatcher::new();
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let version = watcher.version(7);
+
+        thread::scope(|s| {
+            let waiter =
+                s.spawn(|| watcher.wait_for_change_since(7, version, Duration::from_secs(10)));
+
+            let mut node = Node::new(&mut page).unwrap().with_watcher(&watcher);
+            node.insert(6, b"not this one").unwrap();
+            node.insert(7, b"this one").unwrap();
+
+            assert!(waiter.join().unwrap());
+        });
+        assert_eq!(watcher.version(6), 1);
+        assert_eq!(watcher.version(7), 2);
+    }
+
+    #[test]
+    fn endless_timeout_waits_without_deadline() {
+        let watcher = KeyWatcher::new();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| watcher.wait_for_change_since(7, 0, Duration::MAX));
+            watcher.notify(7);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn only_recent_keys_keep_versions() {
+        let watcher = KeyWatcher::with_capacity(4);
+        for key in 0..100 {
+            watcher.notify(key);
+        }
+        let versions = watcher.versions.lock().unwrap();
+        assert!(versions.by_key.len() < 8);
+        drop(versions);
+
+        // Forgotten keys report a version no older than their last write
+        assert!(watcher.version(0) >= 1);
+        assert_eq!(watcher.version(99), 100);
+        let version = watcher.version(3);
+        assert!(!watcher.wait_for_change_since(3, version, Duration::from_millis(10)));
+    }
+
+    #[cfg(feature = "pager")]
+    #[test]
+    fn renames_and_swaps_in_one_leaf_notify() {
+        use super::super::BTree;
+        use crate::page::MemoryStore;
+        use std::sync::Arc;
+
+        let watcher = Arc::new(KeyWatcher::new());
+        let mut tree = BTree::create(MemoryStore::new(PAGE_SIZE.into()))
+            .unwrap()
+            .with_watcher(Arc::clone(&watcher));
+        for key in 0..4 {
+            tree.insert(key, b"value").unwrap();
+        }
+        assert_eq!(tree.depth().unwrap(), 1);
+
+        let versions: Vec<_> = (0..5).map(|key| watcher.version(key)).collect();
+        assert!(tree.rename(0, 4).unwrap());
+        assert!(tree.swap(1, 2).unwrap());
+        for key in [0, 1, 2, 4] {
+            assert!(watcher.version(key) > versions[key as usize], "key {key}");
+        }
+        assert_eq!(watcher.version(3), versions[3]);
+    }
+
+    #[test]
+    fn failed_insert_does_not_notify() {
+        let watcher = KeyWatcher::new();
+        let mut page = [0u8; PAGE_SIZE as usize];
+        let mut node = Node::new(&mut page).unwrap().with_watcher(&watcher);
+
+        let too_large = vec![0u8; PAGE_SIZE as usize];
+        assert!(node.insert(1, &too_large).is_err());
+        assert_eq!(watcher.version(1), 0);
+    }
+}
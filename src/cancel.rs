@@ -0,0 +1,120 @@
+/*
+Deadlines and cancellation for long running operations (recovery, validation, replay, GC).
+Operations check their Budget at safe points, i.e. between pages or records where stopping
+leaves everything consistent, and bail out with TimedOut or Cancelled.
+*/
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupted {
+    TimedOut,
+    Cancelled,
+}
+
+impl From<Interrupted> for io::Error {
+    fn from(interrupted: Interrupted) -> Self {
+        match interrupted {
+            Interrupted::TimedOut => io::ErrorKind::TimedOut.into(),
+            Interrupted::Cancelled => io::ErrorKind::Interrupted.into(),
+        }
+    }
+}
+
+/// Shared flag to cancel operations from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How long an operation may run and whether it should stop early. The default budget
+/// is unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    token: Option<CancelToken>,
+}
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// A budget running out after `timeout`, or never if that's too far out to reach
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Instant::now().checked_add(timeout),
+            token: None,
+        }
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Called at safe points. Cancellation wins over an expired deadline.
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if self.token.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Interrupted::Cancelled);
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(Interrupted::TimedOut);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn unlimited_budget_never_interrupts() {
+        assert_eq!(Budget::unlimited().check(), Ok(()));
+        assert_eq!(
+            Budget::with_timeout(Duration::from_secs(60)).check(),
+            Ok(())
+        );
+        assert_eq!(Budget::with_timeout(Duration::MAX).check(), Ok(()));
+    }
+
+    #[test]
+    fn deadline_and_cancellation() {
+        let expired = Budget::default().deadline(Instant::now());
+        assert_eq!(expired.check(), Err(Interrupted::TimedOut));
+
+        let token = CancelToken::new();
+        let budget = expired.cancel_token(token.clone());
+        assert_eq!(budget.check(), Err(Interrupted::TimedOut));
+        token.cancel();
+        assert_eq!(budget.check(), Err(Interrupted::Cancelled));
+
+        let err: io::Error = Interrupted::TimedOut.into();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,3 +1,14 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod btree;
+#[cfg(feature = "std")]
+pub mod cancel;
+pub mod limits;
+#[cfg(feature = "wal")]
 pub mod log;
+#[cfg(feature = "pager")]
 pub mod page;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
@@ -0,0 +1,32 @@
+/// Hard limits for opening files that can't be trusted. A hostile file can otherwise make
+/// recovery allocate whatever its headers claim or send a tree descent around in circles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResourceLimits {
+    /// Bytes that may be allocated for data read from the file, e.g. recovered log frames
+    pub max_memory: usize,
+    /// Pages the file may have
+    pub max_pages: usize,
+    /// Levels a tree descent may go through, trees cap it at btree::MAX_DEPTH
+    pub max_depth: usize,
+}
+
+impl ResourceLimits {
+    pub fn unlimited() -> Self {
+        Self {
+            max_memory: usize::MAX,
+            max_pages: usize::MAX,
+            max_depth: usize::MAX,
+        }
+    }
+}
+
+impl Default for ResourceLimits {
+    /// Generous enough for any legitimate file this crate writes
+    fn default() -> Self {
+        Self {
+            max_memory: 1 << 30,
+            max_pages: 1 << 20,
+            max_depth: 32,
+        }
+    }
+}
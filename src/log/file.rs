@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The file a log keeps its pages in. A File on disk by default, tests put simulated disks
+/// that tear writes and lose unsynced ones behind it.
+pub trait LogFile: Send {
+    /// Length in bytes, a torn append can leave it short of a whole page
+    fn size(&self) -> Result<u64, io::Error>;
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error>;
+    /// Fills `buf` from `offset` on, failing past the end of the file
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error>;
+    /// Makes every write so far durable
+    fn sync(&mut self) -> Result<(), io::Error>;
+    /// Another handle to the same file, to sync it without holding on to the log
+    fn try_clone(&self) -> Result<Box<dyn LogFile>, io::Error>;
+}
+
+impl LogFile for File {
+    fn size(&self) -> Result<u64, io::Error> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        File::set_len(self, len)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(data)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.sync_data()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn LogFile>, io::Error> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
@@ -0,0 +1,457 @@
+/*
+Checksummed frames on top of the log pages. A frame is a regular log entry with a trailer
+-----------------------------------------------------------------
+| payload | flags (1 byte) | crc32 (4 bytes) | len (2 bytes) |
+-----------------------------------------------------------------
+The checksum covers payload, flags and len. Bits 1-2 of the flags hold the id of the checksum
+function the frame was written with, so logs written with different functions stay readable. Because entries are prepended, the oldest frame of
+a page ends at the end of the page. Keeping the length in the trailer lets recovery walk a page
+from its end (oldest) towards its offset (newest) and stop at the first frame that doesn't check
+out. That frame and everything written after it is a torn tail and gets truncated.
+
+Frames larger than the compression threshold are stored lz4 compressed (with the uncompressed
+size prepended) and carry FLAG_LZ4. Payloads that don't shrink are stored as is.
+*/
+
+use std::io;
+
+use super::LogManager;
+use crate::cancel::Budget;
+use crate::limits::ResourceLimits;
+use crate::page::{Checksum, Page};
+
+pub const FRAME_TRAILER_SIZE: usize = 1 + 4 + 2;
+pub const FLAG_LZ4: u8 = 1 << 0;
+const CHECKSUM_SHIFT: u8 = 1;
+const CHECKSUM_MASK: u8 = 0b11 << CHECKSUM_SHIFT;
+
+/// Outcome of scanning the log for valid frames
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    /// Payloads of all valid frames, oldest first
+    pub frames: Vec<Vec<u8>>,
+    /// Set if an invalid frame was found and the log was truncated in front of it
+    pub truncated: bool,
+}
+
+fn checksum(function: Checksum, payload: &[u8], flags: u8, len: u16) -> u32 {
+    let mut hasher = function.hasher();
+    hasher.update(payload);
+    hasher.update(&[flags]);
+    hasher.update(&len.to_be_bytes());
+    hasher.finalize()
+}
+
+pub(crate) fn encode_frame(function: Checksum, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let len: u16 = payload
+        .len()
+        .try_into()
+        .expect("Log entries are smaller than a page");
+    let flags = flags | function.id() << CHECKSUM_SHIFT;
+
+    let mut frame = Vec::with_capacity(payload.len() + FRAME_TRAILER_SIZE);
+    frame.extend_from_slice(payload);
+    frame.push(flags);
+    frame.extend_from_slice(&checksum(function, payload, flags, len).to_be_bytes());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame
+}
+
+fn out_of_memory(limits: &ResourceLimits) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        format!(
+            "Recovered frames exceed the memory limit of {} bytes",
+            limits.max_memory
+        ),
+    )
+}
+
+/// Collects the valid frames of a page, oldest first. Returns the position the valid
+/// frames start at, which is the page offset unless an invalid frame was hit.
+/// `memory_left` is charged for every recovered payload.
+fn scan_page(
+    page: &Page,
+    frames: &mut Vec<Vec<u8>>,
+    memory_left: &mut usize,
+    limits: &ResourceLimits,
+) -> Result<usize, io::Error> {
+    let data = page.read();
+    let offset = page.get_offset() as usize;
+    if offset < size_of::<u16>() || offset > data.len() {
+        return Ok(data.len());
+    }
+
+    let mut pos = data.len();
+    while pos > offset {
+        if pos - offset < FRAME_TRAILER_SIZE {
+            return Ok(pos);
+        }
+        let trailer = &data[pos - FRAME_TRAILER_SIZE..pos];
+        let flags = trailer[0];
+        let crc = u32::from_be_bytes(trailer[1..5].try_into().expect("Slice is 4 bytes"));
+        let len = u16::from_be_bytes(trailer[5..7].try_into().expect("Slice is 2 bytes"));
+
+        let Some(start) = (pos - FRAME_TRAILER_SIZE).checked_sub(len as usize) else {
+            return Ok(pos);
+        };
+        if start < offset {
+            return Ok(pos);
+        }
+        let payload = &data[start..pos - FRAME_TRAILER_SIZE];
+        let function = Checksum::from_id((flags & CHECKSUM_MASK) >> CHECKSUM_SHIFT)
+            .expect("Every two bit id is a checksum function");
+        if checksum(function, payload, flags, len) != crc {
+            return Ok(pos);
+        }
+        let flags = flags & !CHECKSUM_MASK;
+
+        // Compressed frames claim their size up front, check it before allocating
+        let size = match flags {
+            0 => payload.len(),
+            FLAG_LZ4 if payload.len() >= 4 => {
+                u32::from_le_bytes(payload[..4].try_into().expect("Slice is 4 bytes")) as usize
+            }
+            _ => return Ok(pos),
+        };
+        *memory_left = memory_left
+            .checked_sub(size)
+            .ok_or_else(|| out_of_memory(limits))?;
+
+        let payload = match flags {
+            FLAG_LZ4 => match lz4_flex::decompress_size_prepended(payload) {
+                Ok(payload) => payload,
+                Err(_) => return Ok(pos),
+            },
+            _ => payload.to_vec(),
+        };
+        frames.push(payload);
+        pos = start;
+    }
+    Ok(pos)
+}
+
+impl LogManager {
+    /// Appends `payload` as a checksummed frame, compressed if it's above the threshold
+    pub fn append_frame(&mut self, payload: &[u8]) -> Result<(), io::Error> {
+        if self
+            .compress_above
+            .is_some_and(|threshold| payload.len() > threshold)
+        {
+            let compressed = lz4_flex::compress_prepend_size(payload);
+            if compressed.len() < payload.len() {
+                return self.append(&encode_frame(self.checksum, FLAG_LZ4, &compressed));
+            }
+        }
+        self.append(&encode_frame(self.checksum, 0, payload))
+    }
+
+    /// Checksum function for frames appended from now on
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = checksum;
+    }
+
+    /// Bounds what recovering an untrusted log may allocate and read
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    /// Compress frame payloads larger than `threshold` bytes. `None` disables compression.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compress_above = threshold;
+    }
+
+    /// Reads back every valid frame. The log is truncated in front of the first invalid
+    /// frame, so garbage from a torn write is never returned and new frames go after the
+    /// last valid one.
+    pub fn recover_frames(&mut self) -> Result<Recovery, io::Error> {
+        self.recover_frames_within(&Budget::unlimited())
+    }
+
+    /// Like `recover_frames`, but checks `budget` before every page. An interrupted
+    /// recovery hasn't modified the log and can simply be run again.
+    pub fn recover_frames_within(&mut self, budget: &Budget) -> Result<Recovery, io::Error> {
+        let mut recovery = Recovery::default();
+        if self.n_pages() > self.limits.max_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Log has {} pages, more than the limit of {}",
+                    self.n_pages(),
+                    self.limits.max_pages
+                ),
+            ));
+        }
+
+        let mut memory_left = self.limits.max_memory;
+        for index in 0..self.n_pages() {
+            budget.check()?;
+            let page = if index == self.tail_index {
+                self.tail.clone()
+            } else {
+                self.read_page(index)?
+            };
+
+            let valid_start =
+                scan_page(&page, &mut recovery.frames, &mut memory_left, &self.limits)?;
+            let valid_offset = page.get_offset() as usize;
+            if valid_start != valid_offset {
+                recovery.truncated = true;
+                self.truncate_at(index, page, valid_start)?;
+                break;
+            }
+        }
+        Ok(recovery)
+    }
+
+    /// Makes page `index` the tail, keeping only its data from `valid_start` on
+    fn truncate_at(
+        &mut self,
+        index: usize,
+        mut page: Page,
+        valid_start: usize,
+    ) -> Result<(), io::Error> {
+        page.mutate()[..valid_start].fill(0);
+        page.set_offset(valid_start);
+
+        self.tail = page;
+        self.tail_index = index;
+        self.log.set_len(((index + 1) * self.page_size) as u64)?;
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 64;
+
+    fn payloads() -> Vec<Vec<u8>> {
+        (0..20u8).map(|i| vec![i; (i as usize * 7) % 30]).collect()
+    }
+
+    fn write_log(path: &str) -> u64 {
+        let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+        for payload in payloads() {
+            lm.append_frame(&payload).unwrap();
+        }
+        lm.sync().unwrap();
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    /// Small xorshift so the corruption tests are reproducible
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn assert_prefix(recovered: &[Vec<u8>]) {
+        let expected = payloads();
+        assert!(recovered.len() <= expected.len());
+        assert_eq!(recovered, &expected[..recovered.len()]);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+        write_log(path);
+
+        let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+        let recovery = lm.recover_frames().unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                frames: payloads(),
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn randomly_truncated_logs_recover_a_prefix() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+        let full_len = write_log(path);
+        let original = std::fs::read(path).unwrap();
+
+        let mut state = 0x2545f4914f6cdd1d;
+        for _ in 0..64 {
+            let cut = next_random(&mut state) % full_len;
+            std::fs::write(path, &original[..cut as usize]).unwrap();
+
+            let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+            let recovered = lm.recover_frames().unwrap().frames;
+            assert_prefix(&recovered);
+
+            // The log stays usable after recovery
+            lm.append_frame(b"after").unwrap();
+            lm.sync().unwrap();
+            let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+            let recovery = lm.recover_frames().unwrap();
+            assert!(!recovery.truncated);
+            assert_eq!(recovery.frames.last().unwrap(), b"after");
+            assert_eq!(recovery.frames[..recovery.frames.len() - 1], recovered);
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_truncates_the_tail() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+        let full_len = write_log(path);
+        let original = std::fs::read(path).unwrap();
+
+        let mut state = 0x9e3779b97f4a7c15;
+        for _ in 0..64 {
+            let mut corrupted = original.clone();
+            let at = (next_random(&mut state) % full_len) as usize;
+            corrupted[at] ^= 1 << (next_random(&mut state) % 8);
+            std::fs::write(path, &corrupted).unwrap();
+
+            let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+            let recovery = lm.recover_frames().unwrap();
+            assert_prefix(&recovery.frames);
+            if recovery.frames.len() < payloads().len() {
+                assert!(recovery.truncated);
+            }
+
+            let len = OpenOptions::new()
+                .read(true)
+                .open(path)
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .len();
+            assert_eq!(len % PAGESIZE as u64, 0);
+            assert!(len <= full_len);
+        }
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+
+        let compressible = vec![b'a'; 200];
+        let small = vec![b'b'; 8];
+        {
+            let mut lm = LogManager::new(path, 256).unwrap();
+            lm.set_compression_threshold(Some(16));
+            lm.append_frame(&compressible).unwrap();
+            lm.append_frame(&small).unwrap();
+            lm.append_frame(&compressible).unwrap();
+            assert_eq!(lm.n_pages(), 1);
+
+            let entries = lm.read_entries(0).unwrap();
+            assert!(entries.len() < 2 * compressible.len());
+            lm.sync().unwrap();
+        }
+
+        let mut lm = LogManager::new(path, 256).unwrap();
+        assert_eq!(
+            lm.recover_frames().unwrap().frames,
+            vec![compressible.clone(), small, compressible]
+        );
+    }
+
+    #[test]
+    fn incompressible_frames_are_stored_raw() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        lm.set_compression_threshold(Some(0));
+
+        let payload: Vec<u8> = (0..20).collect();
+        lm.append_frame(&payload).unwrap();
+        let entries = lm.read_entries(0).unwrap();
+        assert_eq!(entries.len(), payload.len() + FRAME_TRAILER_SIZE);
+        assert_eq!(entries[payload.len()], 0);
+        assert_eq!(lm.recover_frames().unwrap().frames, vec![payload]);
+    }
+
+    #[test]
+    fn recovery_stops_when_out_of_budget() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+        write_log(path);
+
+        let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+        let expired = Budget::unlimited().deadline(std::time::Instant::now());
+        let err = lm.recover_frames_within(&expired).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(lm.recover_frames().unwrap().frames, payloads());
+    }
+
+    #[test]
+    fn recovery_respects_resource_limits() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+        write_log(path);
+
+        let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+        lm.set_resource_limits(ResourceLimits {
+            max_memory: 100,
+            ..ResourceLimits::default()
+        });
+        let err = lm.recover_frames().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+
+        lm.set_resource_limits(ResourceLimits {
+            max_pages: 1,
+            ..ResourceLimits::default()
+        });
+        let err = lm.recover_frames().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn forged_compressed_size_is_not_allocated() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let mut forged = u32::MAX.to_le_bytes().to_vec();
+        forged.extend_from_slice(b"garbage");
+        lm.append(&encode_frame(Checksum::default(), FLAG_LZ4, &forged))
+            .unwrap();
+        let err = lm.recover_frames().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn checksum_functions_can_be_mixed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap();
+
+        let functions = [
+            Checksum::Crc32,
+            Checksum::Crc32c,
+            Checksum::Xxh3,
+            Checksum::None,
+        ];
+        {
+            let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+            for (function, payload) in functions.iter().cycle().zip(payloads()) {
+                lm.set_checksum(*function);
+                lm.append_frame(&payload).unwrap();
+            }
+            lm.sync().unwrap();
+        }
+
+        let mut lm = LogManager::new(path, PAGESIZE).unwrap();
+        assert_eq!(lm.recover_frames().unwrap().frames, payloads());
+    }
+}
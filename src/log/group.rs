@@ -0,0 +1,530 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{LogManager, StallCause, SyncMode};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GroupCommitStats {
+    pub commits: u64,
+    /// Rounds of a leader writing out the log on behalf of the waiting commits
+    pub syncs: u64,
+    /// Rounds that fsynced the log, fewer than syncs unless in SyncMode::Full
+    pub fsyncs: u64,
+}
+
+struct SyncState {
+    durable_lsn: i32,
+    /// A leader is currently syncing on behalf of everyone waiting
+    syncing: bool,
+    /// Error of the first sync that failed. It sticks until the log is reopened: the kernel
+    /// may have dropped the pages it couldn't write, a later sync succeeding proves nothing.
+    failed: Option<io::ErrorKind>,
+    /// Tokens waiting for the next sync to finish
+    wakers: Vec<Waker>,
+    /// Highest lsn a token waits for
+    requested_lsn: i32,
+    /// The background thread syncing for tokens is running
+    syncer: bool,
+    /// How long leaders wait for more commits to join
+    window: Duration,
+    sync_mode: SyncMode,
+    stats: GroupCommitStats,
+}
+
+/// Who waits for which appends to be durable, shared by everything that hands out
+/// DurabilityTokens
+pub(crate) struct Syncs {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+impl Syncs {
+    pub(crate) fn new(sync_mode: SyncMode) -> Self {
+        Self {
+            state: Mutex::new(SyncState {
+                durable_lsn: 0,
+                syncing: false,
+                failed: None,
+                wakers: Vec::new(),
+                requested_lsn: 0,
+                syncer: false,
+                window: Duration::ZERO,
+                sync_mode,
+                stats: GroupCommitStats::default(),
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SyncState> {
+        self.state.lock().expect("GroupCommit lock poisoned")
+    }
+
+    /// Fails once a sync failed
+    pub(crate) fn check(&self) -> Result<(), io::Error> {
+        match self.state().failed {
+            Some(kind) => Err(sync_failed(kind)),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a commit a token is handed out for
+    pub(crate) fn count_commit(&self) {
+        self.state().stats.commits += 1;
+    }
+
+    /// Records how a sync outside of the rounds went and wakes everyone waiting
+    fn synced(&self, result: Result<i32, io::Error>) -> Result<(), io::Error> {
+        let mut state = self.state();
+        self.synced.notify_all();
+        state.wakers.drain(..).for_each(Waker::wake);
+        let synced_lsn = result.inspect_err(|err| state.failed = Some(err.kind()))?;
+        state.durable_lsn = state.durable_lsn.max(synced_lsn);
+        state.stats.fsyncs += 1;
+        Ok(())
+    }
+}
+
+/// Something appended to that becomes durable with syncs, like a log or a file
+pub(crate) trait Durable: Send + Sync + 'static {
+    fn syncs(&self) -> &Syncs;
+
+    /// Writes out everything appended so far and fsyncs it if `fsync`. Returns the lsn of
+    /// the latest append it covers.
+    fn sync_appended(&self, fsync: bool) -> Result<i32, io::Error>;
+}
+
+impl dyn Durable {
+    /// Blocks until every append up to `lsn` is durable. The first caller that finds its
+    /// append not durable yet becomes the leader and syncs for everyone, the others wait
+    /// for it.
+    fn wait_durable(&self, lsn: i32) -> Result<(), io::Error> {
+        let syncs = self.syncs();
+        let mut state = syncs.state();
+        loop {
+            if state.durable_lsn >= lsn {
+                return Ok(());
+            }
+            if let Some(kind) = state.failed {
+                return Err(sync_failed(kind));
+            }
+            if state.syncing {
+                state = syncs.synced.wait(state).expect("GroupCommit lock poisoned");
+                continue;
+            }
+
+            state.syncing = true;
+            let (window, fsync) = (state.window, state.sync_mode == SyncMode::Full);
+            drop(state);
+            if !window.is_zero() {
+                thread::sleep(window);
+            }
+            let result = self.sync_appended(fsync);
+
+            state = syncs.state();
+            state.syncing = false;
+            syncs.synced.notify_all();
+            state.wakers.drain(..).for_each(Waker::wake);
+            let synced_lsn = result.inspect_err(|err| state.failed = Some(err.kind()))?;
+            state.durable_lsn = state.durable_lsn.max(synced_lsn);
+            state.stats.syncs += 1;
+            state.stats.fsyncs += fsync as u64;
+        }
+    }
+
+    /// Syncs on behalf of pending tokens until none waits anymore. Tokens start it, at most
+    /// one runs at a time.
+    fn run_syncer(&self) {
+        loop {
+            let lsn = {
+                let mut state = self.syncs().state();
+                if state.requested_lsn <= state.durable_lsn || state.failed.is_some() {
+                    state.syncer = false;
+                    state.wakers.drain(..).for_each(Waker::wake);
+                    return;
+                }
+                state.requested_lsn
+            };
+            // Errors stick in the state, the tokens pick them up from there
+            let _ = self.wait_durable(lsn);
+        }
+    }
+}
+
+/// Lets concurrent committers share fsyncs. The first committer that finds its frame not
+/// yet durable becomes the leader and syncs everything appended so far, the others wait
+/// for it and usually find their frame covered once it's done.
+///
+/// A leader can wait out a window before syncing, so that commits arriving shortly after
+/// share its fsync instead of waiting for the next one. In SyncMode::Normal and Off a round
+/// only writes the log out to the OS, Normal fsyncs it on `sync` alone.
+///
+/// Once a sync fails every commit not durable before fails, so do all later ones until the
+/// log is reopened.
+///
+/// A WalStore keeps its log in one, so the commits of threads sharing a tree over it, see
+/// ConcurrentTree::commit, wait for the log together.
+pub struct GroupCommit {
+    log: Mutex<LogManager>,
+    syncs: Syncs,
+}
+
+impl GroupCommit {
+    pub fn new(log: LogManager) -> Self {
+        Self {
+            log: Mutex::new(log),
+            syncs: Syncs::new(SyncMode::default()),
+        }
+    }
+
+    /// Leaders wait up to `window` for more commits to join before syncing. Commits take
+    /// up to that much longer.
+    pub fn with_window(self, window: Duration) -> Self {
+        self.set_window(window);
+        self
+    }
+
+    /// Changes the window for the leaders that start syncing from now on
+    pub fn set_window(&self, window: Duration) {
+        self.syncs.state().window = window;
+    }
+
+    pub fn window(&self) -> Duration {
+        self.syncs.state().window
+    }
+
+    pub fn with_sync_mode(self, sync_mode: SyncMode) -> Self {
+        self.syncs.state().sync_mode = sync_mode;
+        self
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.syncs.state().sync_mode
+    }
+
+    /// Appends `payload` as a frame and blocks until it is durable
+    pub fn commit(&self, payload: &[u8]) -> Result<(), io::Error> {
+        let lsn = self.append(payload)?;
+        self.wait_durable(lsn)?;
+        self.syncs.count_commit();
+        Ok(())
+    }
+
+    /// Appends `payload` as a frame and returns right away. The token resolves once the
+    /// frame is durable, so callers can overlap other work with the fsync. Async callers
+    /// await it on any executor.
+    pub fn commit_pipelined(
+        self: &Arc<Self>,
+        payload: &[u8],
+    ) -> Result<DurabilityToken, io::Error> {
+        let lsn = self.append(payload)?;
+        Ok(DurabilityToken::new(Arc::<Self>::clone(self), lsn))
+    }
+
+    /// The log, for a WalStore appending its records itself
+    pub(super) fn log(&self) -> MutexGuard<'_, LogManager> {
+        self.log.lock().expect("GroupCommit lock poisoned")
+    }
+
+    /// What the first sync that failed failed with
+    pub(super) fn failed(&self) -> Option<io::ErrorKind> {
+        self.syncs.state().failed
+    }
+
+    /// A token for a commit whose frames were appended with `log`, which counts it
+    pub(super) fn appended_commit(self: &Arc<Self>) -> DurabilityToken {
+        let lsn = self.log().latest_lsn;
+        self.syncs.count_commit();
+        DurabilityToken::new(Arc::<Self>::clone(self), lsn)
+    }
+
+    /// Appends `payload` as a frame without waiting for it to be durable. Returns its lsn.
+    pub fn append(&self, payload: &[u8]) -> Result<i32, io::Error> {
+        self.syncs.check()?;
+        let mut log = self.log();
+        log.append_frame(payload)?;
+        Ok(log.latest_lsn)
+    }
+
+    /// Blocks until every frame up to `lsn` is durable
+    pub fn wait_durable(&self, lsn: i32) -> Result<(), io::Error> {
+        (self as &dyn Durable).wait_durable(lsn)
+    }
+
+    /// Writes out and fsyncs everything appended so far, which SyncMode::Normal leaves to
+    /// the caller. Does nothing in SyncMode::Off.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        if self.sync_mode() == SyncMode::Off {
+            return Ok(());
+        }
+        self.syncs.check()?;
+        self.syncs.synced(self.sync_appended(true))
+    }
+
+    pub fn stats(&self) -> GroupCommitStats {
+        self.syncs.state().stats
+    }
+
+    pub fn into_inner(self) -> LogManager {
+        self.log.into_inner().expect("GroupCommit lock poisoned")
+    }
+}
+
+impl Durable for GroupCommit {
+    fn syncs(&self) -> &Syncs {
+        &self.syncs
+    }
+
+    /// Writes out the tail and syncs it if `fsync`. Appends can continue while the fsync
+    /// runs, they'll be picked up by the next leader.
+    fn sync_appended(&self, fsync: bool) -> Result<i32, io::Error> {
+        let (mut file, lsn) = {
+            let mut log = self.log();
+            log.flush()?;
+            (log.log.try_clone()?, log.latest_lsn)
+        };
+        if fsync {
+            let started = Instant::now();
+            file.sync()?;
+            self.log().record_stall(StallCause::Sync, started);
+        }
+        Ok(lsn)
+    }
+}
+
+fn sync_failed(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "An earlier sync of the log failed")
+}
+
+/// Handle to a pipelined commit. Await it or call `wait_durable` to block until the
+/// commit reached the disk.
+pub struct DurabilityToken {
+    source: Arc<dyn Durable>,
+    lsn: i32,
+}
+
+impl DurabilityToken {
+    pub(crate) fn new(source: Arc<dyn Durable>, lsn: i32) -> Self {
+        Self { source, lsn }
+    }
+
+    pub fn lsn(&self) -> i32 {
+        self.lsn
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.source.syncs().state().durable_lsn >= self.lsn
+    }
+
+    pub fn wait_durable(&self) -> Result<(), io::Error> {
+        self.source.wait_durable(self.lsn)
+    }
+}
+
+impl Future for DurabilityToken {
+    type Output = Result<(), io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.source.syncs().state();
+        if state.durable_lsn >= self.lsn {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = state.failed {
+            return Poll::Ready(Err(sync_failed(kind)));
+        }
+
+        state.wakers.push(cx.waker().clone());
+        state.requested_lsn = state.requested_lsn.max(self.lsn);
+        if !state.syncer {
+            // Sync off the caller's thread, one thread serves every token
+            state.syncer = true;
+            let source = Arc::clone(&self.source);
+            thread::spawn(move || source.run_syncer());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, SimDisk};
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 128;
+
+    #[test]
+    fn one_sync_covers_earlier_appends() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let log = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let group = GroupCommit::new(log);
+
+        let first = group.append(b"one").unwrap();
+        group.append(b"two").unwrap();
+        let last = group.append(b"three").unwrap();
+
+        group.wait_durable(last).unwrap();
+        group.wait_durable(first).unwrap();
+        assert_eq!(group.stats().syncs, 1);
+    }
+
+    #[test]
+    fn concurrent_commits_are_all_durable() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap().to_owned();
+        let log = LogManager::new(&path, PAGESIZE).unwrap();
+        let group = Arc::new(GroupCommit::new(log));
+
+        let handles: Vec<_> = (0..8u8)
+            .map(|thread_id| {
+                let group = Arc::clone(&group);
+                thread::spawn(move || {
+                    for i in 0..25u8 {
+                        group.commit(&[thread_id, i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let stats = group.stats();
+        assert_eq!(stats.commits, 200);
+        assert!(stats.syncs <= stats.commits);
+        drop(Arc::into_inner(group).unwrap().into_inner());
+
+        let mut log = LogManager::new(&path, PAGESIZE).unwrap();
+        let frames = log.recover_frames().unwrap().frames;
+        assert_eq!(frames.len(), 200);
+        for thread_id in 0..8u8 {
+            let order: Vec<_> = frames
+                .iter()
+                .filter(|frame| frame[0] == thread_id)
+                .map(|frame| frame[1])
+                .collect();
+            assert_eq!(order, (0..25).collect::<Vec<_>>());
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn pipelined_commits_resolve_once_durable() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let log = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let group = Arc::new(GroupCommit::new(log));
+
+        let first = group.commit_pipelined(b"one").unwrap();
+        let second = group.commit_pipelined(b"two").unwrap();
+        assert!(!first.is_durable());
+        assert!(first.lsn() < second.lsn());
+
+        block_on(second).unwrap();
+        assert!(first.is_durable());
+        first.wait_durable().unwrap();
+        assert_eq!(group.stats().syncs, 1);
+
+        let third = group.commit_pipelined(b"three").unwrap();
+        third.wait_durable().unwrap();
+        assert!(block_on(third).is_ok());
+    }
+
+    #[test]
+    fn failed_syncs_stick() {
+        let disk = SimDisk::new(3);
+        let log = LogManager::with_file(disk.create_file(), PAGESIZE).unwrap();
+        let group = Arc::new(GroupCommit::new(log));
+        group.commit(b"durable").unwrap();
+
+        let first = group.commit_pipelined(b"one").unwrap();
+        let second = group.commit_pipelined(b"two").unwrap();
+        disk.inject(Fault::PartialSync, 0);
+        assert!(block_on(second).is_err());
+        // Every token past the failure sees it, and the disk working again changes nothing
+        assert!(block_on(first).is_err());
+        assert!(group.wait_durable(1).is_ok());
+        assert!(group.commit(b"three").is_err());
+        assert!(group.sync().is_err());
+    }
+
+    #[test]
+    fn a_window_batches_commits_into_fewer_syncs() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let log = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let group = Arc::new(GroupCommit::new(log).with_window(Duration::from_millis(20)));
+
+        let handles: Vec<_> = (0..8u8)
+            .map(|thread_id| {
+                let group = Arc::clone(&group);
+                thread::spawn(move || {
+                    for i in 0..5u8 {
+                        group.commit(&[thread_id, i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let stats = group.stats();
+        assert_eq!(stats.commits, 40);
+        assert!(stats.syncs < 40, "{stats:?}");
+        assert_eq!(stats.fsyncs, stats.syncs);
+    }
+
+    #[test]
+    fn normal_mode_fsyncs_on_sync_only() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("wal.bin");
+        let path = file_path.to_str().unwrap().to_owned();
+        for mode in [SyncMode::Normal, SyncMode::Off] {
+            let log = LogManager::new(&path, PAGESIZE).unwrap();
+            let group = GroupCommit::new(log).with_sync_mode(mode);
+            for i in 0..10u8 {
+                group.commit(&[i]).unwrap();
+            }
+            assert_eq!(group.stats().syncs, 10);
+            assert_eq!(group.stats().fsyncs, 0);
+
+            group.sync().unwrap();
+            let fsyncs = (mode == SyncMode::Normal) as u64;
+            assert_eq!(group.stats().fsyncs, fsyncs);
+            drop(group.into_inner());
+
+            // Commits reached the OS either way
+            let mut log = LogManager::new(&path, PAGESIZE).unwrap();
+            assert_eq!(log.recover_frames().unwrap().frames.len(), 10);
+            log.truncate().unwrap();
+        }
+    }
+}
@@ -7,16 +7,68 @@ The Log Manager appends binary data to a file. The file consists of multiple pag
 Data grows from left to right. The offset points to the end of the free data. This makes it easy for readers to read newests log first
 */
 
+use std::fs::OpenOptions;
 use std::io;
-
-use crate::page::{Page, PageManager};
+use std::time::Instant;
+
+use crate::limits::ResourceLimits;
+use crate::page::{Checksum, Page};
+
+pub use file::LogFile;
+pub use frame::{Recovery, FLAG_LZ4, FRAME_TRAILER_SIZE};
+pub use group::{DurabilityToken, GroupCommit, GroupCommitStats};
+#[cfg(feature = "async")]
+pub(crate) use group::{Durable, Syncs};
+pub use stall::{Stall, StallCause, StallReport, StallTotals};
+pub use twophase::TwoPhaseLog;
+pub use wal::{Checkpoint, WalRecovery, WalStore, CHECKPOINT_STEP_PAGES, MAX_UNSYNCED_PAGES};
+
+mod file;
+mod frame;
+mod group;
+mod stall;
+mod twophase;
+mod wal;
+
+/// How hard commits push the log to the disk, after SQLite's `synchronous` pragma
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    /// Every commit waits for an fsync of the log. A commit that returned survives losing
+    /// power.
+    #[default]
+    Full,
+    /// Commits are handed to the OS without an fsync and synced in batches. Losing power
+    /// can lose the latest commits, but never tears one.
+    Normal,
+    /// Never fsyncs. Survives the process crashing, not the OS or the power.
+    Off,
+}
 
 pub struct LogManager {
-    log: PageManager,
+    log: Box<dyn LogFile>,
+    page_size: usize,
     tail: Page,
     tail_index: usize,
     latest_lsn: i32,
     latest_flushed_lsn: i32,
+    pending: PendingStats,
+    stalls: StallReport,
+    compress_above: Option<usize>,
+    limits: ResourceLimits,
+    checksum: Checksum,
+}
+
+/// Writes appended to a LogManager since the last flush, or written to a tree on a WalStore
+/// since its last commit
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PendingStats {
+    /// Entries appended, keys inserted or deleted
+    pub entries: usize,
+    /// Bytes of the entries, of the values inserted as the tree stores them
+    pub bytes: usize,
+    /// The tail page for a LogManager, which flushes whenever it moves to the next one. The
+    /// pages written since the last commit for a tree.
+    pub pages_dirtied: usize,
 }
 
 impl Page {
@@ -38,26 +90,55 @@ impl Page {
 
 impl LogManager {
     pub fn new(path: &str, page_size: usize) -> Result<Self, io::Error> {
-        let mut pm = PageManager::new(path, page_size)?;
-        let logsize = pm.file.metadata()?.len();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(path)?;
+        Self::with_file(file, page_size)
+    }
 
-        // Generate new tail if log hasnt been initialized. Else, load tail from last page
-        let (tail, tail_index) = if logsize == 0 {
-            let mut page = Page::new(page_size);
-            page.set_offset(page_size);
-            (page, 0)
-        } else {
-            let tail_index = pm.n_pages()? - 1;
-            (pm.read_page(tail_index)?, tail_index)
-        };
+    /// A log kept in `file` instead of a file on disk
+    pub fn with_file(file: impl LogFile + 'static, page_size: usize) -> Result<Self, io::Error> {
+        let mut file = Box::new(file);
+        let mut logsize = file.size()?;
 
-        Ok(Self {
-            log: pm,
-            tail,
-            tail_index,
+        // A partially written last page is a torn write, drop it
+        let torn = logsize % page_size as u64;
+        if torn != 0 {
+            logsize -= torn;
+            file.set_len(logsize)?;
+        }
+
+        let mut log = Self {
+            log: file,
+            page_size,
+            tail: Page::new(page_size),
+            tail_index: 0,
             latest_lsn: 0,
             latest_flushed_lsn: 0,
-        })
+            pending: PendingStats::default(),
+            stalls: StallReport::default(),
+            compress_above: None,
+            limits: ResourceLimits::default(),
+            checksum: Checksum::default(),
+        };
+        // Generate new tail if log hasnt been initialized. Else, load tail from last page
+        if logsize == 0 {
+            log.tail.set_offset(page_size);
+        } else {
+            log.tail_index = (logsize / page_size as u64) as usize - 1;
+            log.tail = log.read_page(log.tail_index)?;
+        }
+        Ok(log)
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let mut page = Page::new(self.page_size);
+        self.log
+            .read_at((index * self.page_size) as u64, page.mutate())?;
+        Ok(page)
     }
 
     pub fn flush_since_lsn(&mut self, lsn: i32) -> Result<(), io::Error> {
@@ -68,34 +149,100 @@ impl LogManager {
     }
 
     pub fn flush(&mut self) -> Result<(), io::Error> {
-        let result = self.log.write_page(self.tail_index, &self.tail);
+        let offset = (self.tail_index * self.page_size) as u64;
+        let result = self.log.write_at(offset, self.tail.read());
         self.latest_flushed_lsn = self.latest_lsn;
+        self.pending = PendingStats::default();
+        result
+    }
+
+    /// Flushes the tail and waits until the log file reached the disk
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        let started = Instant::now();
+        let result = self.log.sync();
+        self.stalls.record(StallCause::Sync, started);
         result
     }
 
+    /// Drops every entry, leaving an empty log
+    pub fn truncate(&mut self) -> Result<(), io::Error> {
+        self.log.set_len(0)?;
+        self.tail = Page::new(self.page_size);
+        self.tail.set_offset(self.page_size);
+        self.tail_index = 0;
+        self.pending = PendingStats::default();
+        Ok(())
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.tail_index + 1
+    }
+
+    /// Returns the data area of a log page, newest entry first
+    pub fn read_entries(&mut self, index: usize) -> Result<Vec<u8>, io::Error> {
+        if index == self.tail_index {
+            let offset = self.tail.get_offset() as usize;
+            return Ok(self.tail.read()[offset..].to_vec());
+        }
+        let page = self.read_page(index)?;
+        let offset = page.get_offset() as usize;
+        Ok(page.read()[offset..].to_vec())
+    }
+
+    /// Time appends and syncs spent waiting on the log file
+    pub fn stall_report(&self) -> &StallReport {
+        &self.stalls
+    }
+
+    /// Records a stall of a writer on top of the log, like a WalStore commit
+    pub(crate) fn record_stall(&mut self, cause: StallCause, started: Instant) {
+        self.stalls.record(cause, started);
+    }
+
+    pub fn pending_stats(&self) -> PendingStats {
+        self.pending
+    }
+
+    /// Number of bytes the next flush will write to the log file
+    pub fn estimated_commit_size(&self) -> usize {
+        self.pending.pages_dirtied * self.page_size
+    }
+
+    /// Largest entry append takes, larger ones don't fit a page
+    pub fn max_entry_size(&self) -> usize {
+        self.page_size - size_of::<u16>()
+    }
+
     pub fn append(&mut self, data: &[u8]) -> Result<(), io::Error> {
         let mut offset = self.tail.get_offset() as usize;
         let freespace = offset - size_of::<u16>();
 
-        if data.len() > (self.log.page_size - size_of::<u16>()) {
+        if data.len() > self.max_entry_size() {
             panic!(
                 "Tried writing log entry of size {} with page size {}",
                 data.len(),
-                self.log.page_size
+                self.page_size
             );
         };
 
         if freespace < data.len() {
+            let started = Instant::now();
             self.flush()?;
-            self.tail = Page::new(self.log.page_size);
+            self.stalls.record(StallCause::PageRollover, started);
+            self.tail = Page::new(self.page_size);
             self.tail_index += 1;
-            self.tail.set_offset(self.log.page_size);
-            offset = self.log.page_size;
+            self.tail.set_offset(self.page_size);
+            offset = self.page_size;
         }
         let new_offset = offset - data.len();
         self.tail.mutate()[new_offset..offset].copy_from_slice(data);
         self.tail.set_offset(new_offset);
         self.latest_lsn += 1;
+
+        self.pending.entries += 1;
+        self.pending.bytes += data.len();
+        self.pending.pages_dirtied = 1;
         Ok(())
     }
 }
@@ -138,7 +285,7 @@ mod tests {
         lm.flush().unwrap();
         assert_eq!(lm.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 65]);
 
-        let data = lm.log.read_page(0).unwrap();
+        let data = lm.read_page(0).unwrap();
         assert_eq!(data.read(), &vec![0, 7, 0, 0, 0, 0, 0, 65]);
     }
 
@@ -155,7 +302,7 @@ mod tests {
         assert_eq!(lm.tail.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
         lm.flush().unwrap();
         assert_eq!(lm.tail.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
-        let data = lm.log.read_page(0).unwrap();
+        let data = lm.read_page(0).unwrap();
         assert_eq!(data.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
     }
 
@@ -172,7 +319,7 @@ mod tests {
 
         assert_eq!(lm.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 68]);
 
-        let data = lm.log.read_page(0).unwrap();
+        let data = lm.read_page(0).unwrap();
         assert_eq!(data.read(), &vec![0, 2, 67, 67, 66, 66, 65, 65]);
     }
 
@@ -214,7 +361,71 @@ mod tests {
 
         lm.append(b"BBBBBB").unwrap();
         assert_eq!(lm.tail.read(), &vec![0, 2, 66, 66, 66, 66, 66, 66]);
-        let data = lm.log.read_page(0).unwrap();
+        let data = lm.read_page(0).unwrap();
         assert_eq!(data.read(), &vec![0, 2, 65, 65, 65, 65, 65, 65]);
     }
+
+    #[test]
+    fn pending_stats() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        assert_eq!(lm.pending_stats(), PendingStats::default());
+        assert_eq!(lm.estimated_commit_size(), 0);
+
+        lm.append(b"AA").unwrap();
+        lm.append(b"B").unwrap();
+        assert_eq!(
+            lm.pending_stats(),
+            PendingStats {
+                entries: 2,
+                bytes: 3,
+                pages_dirtied: 1
+            }
+        );
+        assert_eq!(lm.estimated_commit_size(), PAGESIZE);
+
+        // Rolling over to a new page flushes the old tail
+        lm.append(b"CCCC").unwrap();
+        assert_eq!(
+            lm.pending_stats(),
+            PendingStats {
+                entries: 1,
+                bytes: 4,
+                pages_dirtied: 1
+            }
+        );
+
+        lm.flush().unwrap();
+        assert_eq!(lm.pending_stats(), PendingStats::default());
+        assert_eq!(lm.estimated_commit_size(), 0);
+    }
+
+    #[test]
+    fn stall_report() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        lm.append(b"AAAA").unwrap();
+        assert_eq!(lm.stall_report().page_rollover.count, 0);
+
+        lm.append(b"BBBB").unwrap();
+        lm.append(b"CCCC").unwrap();
+        lm.sync().unwrap();
+
+        let report = lm.stall_report();
+        assert_eq!(report.page_rollover.count, 2);
+        assert_eq!(report.sync.count, 1);
+        assert_eq!(
+            report.recent().map(|stall| stall.cause).collect::<Vec<_>>(),
+            vec![
+                StallCause::PageRollover,
+                StallCause::PageRollover,
+                StallCause::Sync
+            ]
+        );
+        assert!(report.total() >= report.sync.longest);
+    }
 }
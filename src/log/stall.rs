@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many individual stalls are kept around for inspection
+const RECENT_STALLS: usize = 32;
+
+/// Reason an append or commit had to wait for the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallCause {
+    /// The tail page was full and had to be written out before appending
+    PageRollover,
+    /// Waiting for the log file to reach the disk
+    Sync,
+    /// A commit took an automatic checkpoint step, see WalStore::set_auto_checkpoint
+    Checkpoint,
+    /// A commit wrote the pages SyncMode::Normal held back because too many piled up
+    Backpressure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stall {
+    pub cause: StallCause,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StallTotals {
+    pub count: u64,
+    pub total: Duration,
+    pub longest: Duration,
+}
+
+/// Where writes spent their time waiting. Slow syncs point at the disk, frequent
+/// rollovers at a page size that's too small for the write volume. Checkpoint and
+/// backpressure stalls of a WalStore include the syncs they wait for.
+#[derive(Debug, Clone, Default)]
+pub struct StallReport {
+    pub page_rollover: StallTotals,
+    pub sync: StallTotals,
+    pub checkpoint: StallTotals,
+    pub backpressure: StallTotals,
+    recent: VecDeque<Stall>,
+}
+
+impl StallReport {
+    pub(crate) fn record(&mut self, cause: StallCause, started: Instant) {
+        let duration = started.elapsed();
+        let totals = match cause {
+            StallCause::PageRollover => &mut self.page_rollover,
+            StallCause::Sync => &mut self.sync,
+            StallCause::Checkpoint => &mut self.checkpoint,
+            StallCause::Backpressure => &mut self.backpressure,
+        };
+        totals.count += 1;
+        totals.total += duration;
+        totals.longest = totals.longest.max(duration);
+
+        if self.recent.len() == RECENT_STALLS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Stall { cause, duration });
+    }
+
+    /// The latest stalls, oldest first
+    pub fn recent(&self) -> impl Iterator<Item = &Stall> {
+        self.recent.iter()
+    }
+
+    /// Time spent on the log file itself, checkpoint and backpressure stalls overlap it
+    pub fn total(&self) -> Duration {
+        self.page_rollover.total + self.sync.total
+    }
+}
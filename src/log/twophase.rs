@@ -0,0 +1,306 @@
+/*
+Prepared transaction state for two-phase commit. Every record is appended to its own log as a
+checksummed frame and synced before the call returns, so a prepared transaction survives a
+crash until the embedder resolves it with commit_prepared or rollback_prepared. A torn write
+only ever damages the newest frame, recovery truncates the log in front of it like the WAL's.
+Records have the following format
+-------------------------------------------
+| kind (1 byte) | txn id (8 bytes) | payload |
+-------------------------------------------
+A committed transaction keeps its payload in the log until the embedder confirms it applied it
+with applied, so a crash in between leaves it among the committed ones to apply again.
+*/
+
+use std::collections::BTreeMap;
+use std::io;
+
+use super::{LogManager, FRAME_TRAILER_SIZE};
+
+const PREPARE: u8 = 1;
+const COMMIT: u8 = 2;
+const ROLLBACK: u8 = 3;
+const APPLIED: u8 = 4;
+const RECORD_HEADER_SIZE: usize = 1 + 8;
+
+struct Record<'a> {
+    kind: u8,
+    txn_id: u64,
+    payload: &'a [u8],
+}
+
+pub struct TwoPhaseLog {
+    log: LogManager,
+    prepared: BTreeMap<u64, Vec<u8>>,
+    /// Committed transactions the embedder hasn't confirmed applying yet
+    committed: BTreeMap<u64, Vec<u8>>,
+}
+
+impl TwoPhaseLog {
+    pub fn new(path: &str, page_size: usize) -> Result<Self, io::Error> {
+        let mut log = LogManager::new(path, page_size)?;
+        let mut prepared = BTreeMap::new();
+        let mut committed = BTreeMap::new();
+
+        for frame in log.recover_frames()?.frames {
+            let record = parse_record(&frame)?;
+            match record.kind {
+                PREPARE => {
+                    prepared.insert(record.txn_id, record.payload.to_vec());
+                }
+                COMMIT => {
+                    if let Some(payload) = prepared.remove(&record.txn_id) {
+                        committed.insert(record.txn_id, payload);
+                    }
+                }
+                ROLLBACK => {
+                    prepared.remove(&record.txn_id);
+                }
+                APPLIED => {
+                    committed.remove(&record.txn_id);
+                }
+                _ => unreachable!("parse_record only returns known kinds"),
+            }
+        }
+
+        Ok(Self {
+            log,
+            prepared,
+            committed,
+        })
+    }
+
+    /// Durably records `payload` as the prepared state of `txn_id`
+    pub fn prepare(&mut self, txn_id: u64, payload: &[u8]) -> Result<(), io::Error> {
+        if self.prepared.contains_key(&txn_id) || self.committed.contains_key(&txn_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Transaction {txn_id} is already prepared"),
+            ));
+        }
+        self.write_record(PREPARE, txn_id, payload)?;
+        self.prepared.insert(txn_id, payload.to_vec());
+        Ok(())
+    }
+
+    /// Resolves a prepared transaction as committed and hands back its payload to apply. The
+    /// payload stays in the log until `applied` confirms it was applied.
+    pub fn commit_prepared(&mut self, txn_id: u64) -> Result<Vec<u8>, io::Error> {
+        self.check_prepared(txn_id)?;
+        self.write_record(COMMIT, txn_id, &[])?;
+        let payload = self
+            .prepared
+            .remove(&txn_id)
+            .expect("Presence was checked above");
+        self.committed.insert(txn_id, payload.clone());
+        Ok(payload)
+    }
+
+    pub fn rollback_prepared(&mut self, txn_id: u64) -> Result<(), io::Error> {
+        self.check_prepared(txn_id)?;
+        self.write_record(ROLLBACK, txn_id, &[])?;
+        self.prepared.remove(&txn_id);
+        Ok(())
+    }
+
+    /// Confirms the payload of a committed transaction was applied, so the log can forget it
+    pub fn applied(&mut self, txn_id: u64) -> Result<(), io::Error> {
+        if !self.committed.contains_key(&txn_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Transaction {txn_id} is not committed"),
+            ));
+        }
+        self.write_record(APPLIED, txn_id, &[])?;
+        self.committed.remove(&txn_id);
+        Ok(())
+    }
+
+    /// Transactions that are prepared but not yet committed or rolled back
+    pub fn prepared(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.prepared
+            .iter()
+            .map(|(txn_id, payload)| (*txn_id, payload.as_slice()))
+    }
+
+    /// Transactions that are committed but not confirmed applied. After a crash their
+    /// payloads have to be applied again.
+    pub fn committed(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.committed
+            .iter()
+            .map(|(txn_id, payload)| (*txn_id, payload.as_slice()))
+    }
+
+    fn check_prepared(&self, txn_id: u64) -> Result<(), io::Error> {
+        if !self.prepared.contains_key(&txn_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Transaction {txn_id} is not prepared"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, kind: u8, txn_id: u64, payload: &[u8]) -> Result<(), io::Error> {
+        let size = RECORD_HEADER_SIZE + payload.len() + FRAME_TRAILER_SIZE;
+        if size > self.log.max_entry_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Prepared payload of {} bytes doesn't fit a log page",
+                    payload.len()
+                ),
+            ));
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.push(kind);
+        record.extend_from_slice(&txn_id.to_be_bytes());
+        record.extend_from_slice(payload);
+
+        self.log.append_frame(&record)?;
+        self.log.sync()
+    }
+}
+
+fn parse_record(data: &[u8]) -> Result<Record<'_>, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    if data.len() < RECORD_HEADER_SIZE {
+        return Err(invalid("Truncated two-phase record header"));
+    }
+    let kind = data[0];
+    if !matches!(kind, PREPARE | COMMIT | ROLLBACK | APPLIED) {
+        return Err(invalid("Unknown two-phase record kind"));
+    }
+    Ok(Record {
+        kind,
+        txn_id: u64::from_be_bytes(data[1..9].try_into().expect("Slice is 8 bytes")),
+        payload: &data[RECORD_HEADER_SIZE..],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 64;
+
+    #[test]
+    fn prepared_state_survives_reopen() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("2pc.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+            log.prepare(1, b"first").unwrap();
+            log.prepare(2, b"second").unwrap();
+            log.prepare(3, b"third").unwrap();
+            assert_eq!(log.commit_prepared(1).unwrap(), b"first");
+        }
+
+        let mut log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+        assert_eq!(
+            log.prepared().collect::<Vec<_>>(),
+            vec![(2, b"second".as_slice()), (3, b"third".as_slice())]
+        );
+        // The crash came before the embedder applied the first one
+        assert_eq!(
+            log.committed().collect::<Vec<_>>(),
+            vec![(1, b"first".as_slice())]
+        );
+        log.rollback_prepared(2).unwrap();
+        log.applied(1).unwrap();
+        drop(log);
+
+        let log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+        assert_eq!(
+            log.prepared().collect::<Vec<_>>(),
+            vec![(3, b"third".as_slice())]
+        );
+        assert_eq!(log.committed().count(), 0);
+    }
+
+    #[test]
+    fn unknown_and_duplicate_transactions() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("2pc.bin");
+        let mut log = TwoPhaseLog::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        assert_eq!(
+            log.commit_prepared(9).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        log.prepare(9, b"").unwrap();
+        assert_eq!(
+            log.prepare(9, b"").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        log.rollback_prepared(9).unwrap();
+        assert_eq!(
+            log.rollback_prepared(9).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(log.applied(9).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // Payloads that don't fit a page are refused, not a panic
+        assert_eq!(
+            log.prepare(10, &[1; PAGESIZE]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(log.prepared().count(), 0);
+    }
+
+    #[test]
+    fn torn_tail_keeps_synced_records() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("2pc.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+            log.prepare(1, b"first").unwrap();
+            log.prepare(2, b"second").unwrap();
+        }
+        // Damages the newest frame, as if rewriting the tail page in place was torn
+        let mut data = std::fs::read(path).unwrap();
+        let offset = u16::from_be_bytes([data[0], data[1]]) as usize;
+        data[offset] ^= 0xff;
+        std::fs::write(path, &data).unwrap();
+
+        let mut log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+        assert_eq!(
+            log.prepared().collect::<Vec<_>>(),
+            vec![(1, b"first".as_slice())]
+        );
+        log.prepare(3, b"third").unwrap();
+        drop(log);
+        let log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+        assert_eq!(log.prepared().map(|(id, _)| id).collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    fn reprepare_after_resolution_across_pages() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("2pc.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+            for round in 0..10u8 {
+                log.prepare(5, &[round; 8]).unwrap();
+                if round < 9 {
+                    log.commit_prepared(5).unwrap();
+                    log.applied(5).unwrap();
+                }
+            }
+        }
+
+        let log = TwoPhaseLog::new(path, PAGESIZE).unwrap();
+        assert_eq!(
+            log.prepared().collect::<Vec<_>>(),
+            vec![(5, [9u8; 8].as_slice())]
+        );
+    }
+}
@@ -0,0 +1,1000 @@
+/*
+Write-ahead logging for page stores. WalStore keeps the pages written by a transaction in
+memory. commit appends an image of every dirty page to the log, followed by a commit record,
+syncs the log and only then writes the pages to the store. Every record is a checksummed frame
+----------------------------------------------------------------------
+| kind (1 byte) | lsn (8 bytes) | page (4 bytes) | page image (pages only) |
+----------------------------------------------------------------------
+Commit records carry the number of pages of their transaction in the page field. On open,
+recovery writes the pages of every committed transaction to the store again and discards the
+pages of a transaction whose commit record never made it to the log. A commit that fails
+leaves its pages dirty, and the next commit starts with an abort record that ends whatever
+records of it did make it to the log. A failed sync of the log may have dropped writes for
+good, recovery wouldn't get past the hole, so after one every commit fails until the store is
+reopened. Records after a gap in
+the lsns are ignored too, the OS wrote them out before unsynced ones that got lost. Afterwards
+the store is synced and the log truncated down to a checkpoint record that keeps the lsns
+increasing.
+
+Rollback only discards buffered writes. Pages appended to the store during a rolled back
+transaction are not handed back and stay unused.
+
+The sync mode decides when the log is synced. Full syncs it on every commit. Normal only hands
+the records to the OS and keeps the pages of the commit in memory, reads included, until the
+next sync of the store or until MAX_UNSYNCED_PAGES pile up: then the log is synced once for
+all of them and they are written to the store. The store never holds a page whose records
+could still be lost, so losing power loses the latest commits and nothing else. Off never
+syncs and writes pages to the store right away, the OS may write them out before the log.
+
+A checkpoint moves what the log holds into the store for good, so the log can start over. It
+writes the pages Normal held back, syncs the store and truncates the log down to a checkpoint
+record like recovery does. checkpoint_step copies at most a given number of held back pages
+per call and only truncates once none are left, so a long backlog is worked off in small
+pieces between commits. With an auto checkpoint threshold set, every commit that leaves the
+log at least that many pages long takes one such step.
+
+The log is kept in a GroupCommit. commit_pipelined hands the records of a commit to the OS and
+holds its pages back like SyncMode::Normal, and returns a token that syncs the log when waited
+for. Callers that wait for their tokens at the same time, on threads sharing the store through
+a ConcurrentTree, share that sync. With a commit window set the thread that syncs waits that
+long first, so that commits arriving in the meantime are covered as well.
+
+Two-phase commit splits a commit in two. prepare logs the pages of the transaction followed by
+a prepare record with the transaction's id, instead of a commit record, and syncs the log. The
+pages stay out of the store until commit_prepared or rollback_prepared logs the outcome.
+Recovery keeps a transaction whose outcome never made it to the log prepared, so the
+coordinator can resolve it after a crash. Reads see the prepared pages, and as long as a
+transaction is prepared the log isn't truncated and further writes can't be committed, they
+are built on pages that may still be rolled back.
+*/
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{
+    DurabilityToken, GroupCommit, GroupCommitStats, LogFile, LogManager, StallCause, StallReport,
+    SyncMode, FRAME_TRAILER_SIZE,
+};
+use crate::page::{CacheStats, Page, PageStore, SharedRead};
+
+const PAGE: u8 = 1;
+const COMMIT: u8 = 2;
+const CHECKPOINT: u8 = 3;
+const ABORT: u8 = 4;
+const PREPARE: u8 = 5;
+const COMMIT_PREPARED: u8 = 6;
+const ROLLBACK_PREPARED: u8 = 7;
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+/// Bytes a record takes in the log besides its page image
+const RECORD_OVERHEAD: usize = RECORD_HEADER_SIZE + FRAME_TRAILER_SIZE;
+/// Pages of commits a WalStore in SyncMode::Normal holds before it syncs the log for them
+pub const MAX_UNSYNCED_PAGES: usize = 1024;
+/// Held back pages an automatic checkpoint step copies into the store
+pub const CHECKPOINT_STEP_PAGES: usize = 256;
+
+/// What recovery found in the log when the store was opened
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WalRecovery {
+    /// Committed transactions whose pages were written to the store again
+    pub replayed: usize,
+    /// Pages of transactions that were never committed
+    pub discarded: usize,
+}
+
+/// What a checkpoint step did
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    /// Held back pages copied into the store
+    pub copied: usize,
+    /// The store was synced and the log truncated, unless a prepared transaction keeps it,
+    /// nothing is left to copy
+    pub done: bool,
+}
+
+pub struct WalStore<S> {
+    store: S,
+    /// Syncs of the log are shared with tokens waiting on other threads
+    log: Arc<GroupCommit>,
+    next_lsn: u64,
+    dirty: BTreeMap<usize, Page>,
+    /// Pages of commits the store doesn't hold yet: their records weren't synced in
+    /// SyncMode::Normal, or writing them to the store failed
+    unsynced: BTreeMap<usize, Page>,
+    /// A failed commit may have left page records without a commit record in the log
+    aborted: bool,
+    sync_mode: SyncMode,
+    /// Log length in pages from which commits take a checkpoint step
+    auto_checkpoint: Option<usize>,
+    recovery: WalRecovery,
+    /// The id and pages of the transaction prepared for two-phase commit
+    prepared: Option<(u64, BTreeMap<usize, Page>)>,
+}
+
+/// The writes since the last commit and the prepared transaction, see take_dirty
+pub(crate) type Uncommitted = (BTreeMap<usize, Page>, Option<(u64, BTreeMap<usize, Page>)>);
+
+struct Record<'a> {
+    kind: u8,
+    lsn: u64,
+    page: u32,
+    image: &'a [u8],
+}
+
+fn parse_record(data: &[u8]) -> Result<Record<'_>, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    if data.len() < RECORD_HEADER_SIZE {
+        return Err(invalid("Truncated WAL record header"));
+    }
+    let kind = data[0];
+    if !matches!(
+        kind,
+        PAGE | COMMIT | CHECKPOINT | ABORT | PREPARE | COMMIT_PREPARED | ROLLBACK_PREPARED
+    ) {
+        return Err(invalid("Unknown WAL record kind"));
+    }
+    Ok(Record {
+        kind,
+        lsn: u64::from_be_bytes(data[1..9].try_into().expect("Slice is 8 bytes")),
+        page: u32::from_be_bytes(data[9..13].try_into().expect("Slice is 4 bytes")),
+        image: &data[RECORD_HEADER_SIZE..],
+    })
+}
+
+/// The transaction id a prepare record or one resolving it carries
+fn txn_id(record: &Record) -> Option<u64> {
+    record.image.try_into().ok().map(u64::from_be_bytes)
+}
+
+fn not_prepared() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "No transaction is prepared under this id",
+    )
+}
+
+impl<S: PageStore> WalStore<S> {
+    /// Puts a log at `log_path` in front of `store`, recovering whatever a crash left in it
+    pub fn open(store: S, log_path: &str) -> Result<Self, io::Error> {
+        let log = LogManager::new(log_path, Self::log_page_size(&store))?;
+        Self::with_log(store, log)
+    }
+
+    /// Like open, with the log kept in `log_file`
+    pub fn open_with_log(store: S, log_file: impl LogFile + 'static) -> Result<Self, io::Error> {
+        let log = LogManager::with_file(log_file, Self::log_page_size(&store))?;
+        Self::with_log(store, log)
+    }
+
+    /// Log pages have to fit a whole page image plus record and frame overhead
+    fn log_page_size(store: &S) -> usize {
+        store.page_size() * 2
+    }
+
+    fn with_log(store: S, log: LogManager) -> Result<Self, io::Error> {
+        let mut wal = Self {
+            store,
+            log: Arc::new(GroupCommit::new(log).with_sync_mode(SyncMode::Full)),
+            next_lsn: 0,
+            dirty: BTreeMap::new(),
+            unsynced: BTreeMap::new(),
+            aborted: false,
+            sync_mode: SyncMode::default(),
+            auto_checkpoint: None,
+            recovery: WalRecovery::default(),
+            prepared: None,
+        };
+        wal.recover()?;
+        Ok(wal)
+    }
+
+    fn recover(&mut self) -> Result<(), io::Error> {
+        let frames = self.log.log().recover_frames()?.frames;
+        let mut pending = Vec::new();
+        let mut gap = false;
+
+        for (i, frame) in frames.iter().enumerate() {
+            let record = parse_record(frame)?;
+            // Log pages the OS wrote out of order leave a gap, nothing after it counts
+            if i > 0 && record.lsn != self.next_lsn {
+                gap = true;
+                break;
+            }
+            self.next_lsn = self.next_lsn.max(record.lsn + 1);
+            match record.kind {
+                PAGE => {
+                    if record.image.len() != self.store.page_size() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "WAL page image has the wrong size",
+                        ));
+                    }
+                    pending.push(record);
+                }
+                COMMIT if record.page as usize == pending.len() => {
+                    for page in pending.drain(..) {
+                        let image = Page::from_vec(page.image.to_vec(), self.store.page_size());
+                        self.store.write_page(page.page as usize, &image)?;
+                    }
+                    self.recovery.replayed += 1;
+                }
+                PREPARE if record.page as usize == pending.len() && txn_id(&record).is_some() => {
+                    let pages = pending.drain(..).map(|page| {
+                        let image = Page::from_vec(page.image.to_vec(), self.store.page_size());
+                        (page.page as usize, image)
+                    });
+                    self.prepared = txn_id(&record).map(|id| (id, pages.collect()));
+                }
+                COMMIT_PREPARED | ROLLBACK_PREPARED => {
+                    self.recovery.discarded += pending.len();
+                    pending.clear();
+                    match self.prepared.take() {
+                        Some((id, pages)) if Some(id) == txn_id(&record) => {
+                            if record.kind == COMMIT_PREPARED {
+                                for (index, page) in &pages {
+                                    self.store.write_page(*index, page)?;
+                                }
+                                self.recovery.replayed += 1;
+                            }
+                        }
+                        prepared => self.prepared = prepared,
+                    }
+                }
+                // A commit that doesn't match its pages, an abort or a checkpoint ends the
+                // transaction
+                _ => {
+                    self.recovery.discarded += pending.len();
+                    pending.clear();
+                }
+            }
+        }
+        self.recovery.discarded += pending.len();
+        // The log may be kept for a prepared transaction, the next commit has to end the
+        // records of the one that crashed
+        self.aborted = !pending.is_empty();
+        if gap && self.prepared.is_some() {
+            // Records appended behind the gap wouldn't count either, the prepared
+            // transaction moves to a fresh log
+            self.store.sync()?;
+            self.restart_log()?;
+            let (id, pages) = self.prepared.take().expect("Checked above");
+            let logged = self.log_records(&pages, PREPARE, &id.to_be_bytes());
+            self.prepared = Some((id, pages));
+            logged?;
+            return self.sync_log();
+        }
+        self.truncate_log()
+    }
+
+    /// Syncs the store and starts the log over. Everything the log holds has to be in the
+    /// store already, except for a prepared transaction, which keeps the log as it is until
+    /// it's resolved.
+    fn truncate_log(&mut self) -> Result<(), io::Error> {
+        self.store.sync()?;
+        if self.prepared.is_some() {
+            return Ok(());
+        }
+        self.restart_log()?;
+        self.sync_log()
+    }
+
+    /// Empties the log down to a checkpoint record
+    fn restart_log(&mut self) -> Result<(), io::Error> {
+        self.log.log().truncate()?;
+        self.append_record(CHECKPOINT, 0, &[])?;
+        self.aborted = false;
+        Ok(())
+    }
+
+    fn sync_log(&mut self) -> Result<(), io::Error> {
+        self.check_log()?;
+        self.log.sync()
+    }
+
+    /// Fails once a sync of the log failed, here or for a token
+    fn check_log(&self) -> Result<(), io::Error> {
+        match self.log.failed() {
+            Some(kind) => Err(io::Error::new(
+                kind,
+                "An earlier sync of the WAL failed, reopen the store",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn append_record(&mut self, kind: u8, page: u32, image: &[u8]) -> Result<u64, io::Error> {
+        let lsn = self.next_lsn;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + image.len());
+        record.push(kind);
+        record.extend_from_slice(&lsn.to_be_bytes());
+        record.extend_from_slice(&page.to_be_bytes());
+        record.extend_from_slice(image);
+
+        self.log.log().append_frame(&record)?;
+        self.next_lsn += 1;
+        Ok(lsn)
+    }
+
+    /// Makes the buffered writes durable as one transaction. Returns the lsn of the commit
+    /// record.
+    pub fn commit(&mut self) -> Result<u64, io::Error> {
+        self.check_unprepared()?;
+        let dirty = std::mem::take(&mut self.dirty);
+        let lsn = match self.log_commit(&dirty) {
+            Ok(lsn) => lsn,
+            Err(err) => {
+                self.dirty = dirty;
+                self.aborted = true;
+                return Err(err);
+            }
+        };
+        self.apply_commit(dirty, self.sync_mode == SyncMode::Normal)?;
+        Ok(lsn)
+    }
+
+    /// Commits without waiting for the log, whatever the sync mode: the records are only
+    /// handed to the OS and the pages held back like in SyncMode::Normal. The token
+    /// resolves once a sync of the log covers the commit. Waiting for it syncs everything
+    /// appended so far, so tokens of commits that wait at the same time share one sync.
+    pub fn commit_pipelined(&mut self) -> Result<DurabilityToken, io::Error> {
+        self.check_unprepared()?;
+        let dirty = std::mem::take(&mut self.dirty);
+        let logged = self.log_records(&dirty, COMMIT, &[]);
+        if let Err(err) = logged.and_then(|_| self.log.log().flush()) {
+            self.dirty = dirty;
+            self.aborted = true;
+            return Err(err);
+        }
+        let token = self.log.appended_commit();
+        self.apply_commit(dirty, true)?;
+        Ok(token)
+    }
+
+    /// Writes can't be committed on top of a prepared transaction, it may be rolled back
+    fn check_unprepared(&self) -> Result<(), io::Error> {
+        if self.prepared.is_some() && !self.dirty.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Writes can't be committed while a prepared transaction is unresolved",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lets a sync for tokens wait up to `window` for more commits to cover, see
+    /// GroupCommit::with_window. Each waiting commit takes up to that much longer.
+    pub fn set_commit_window(&mut self, window: Duration) {
+        self.log.set_window(window);
+    }
+
+    pub fn commit_window(&self) -> Duration {
+        self.log.window()
+    }
+
+    /// Commits and syncs shared with tokens, see GroupCommit
+    pub fn group_commit_stats(&self) -> GroupCommitStats {
+        self.log.stats()
+    }
+
+    /// Writes the pages of a commit the log holds to the store, or holds them back until
+    /// the log is synced, and takes an automatic checkpoint step
+    fn apply_commit(
+        &mut self,
+        dirty: BTreeMap<usize, Page>,
+        hold_back: bool,
+    ) -> Result<(), io::Error> {
+        if hold_back {
+            self.unsynced.extend(dirty);
+            if self.unsynced.len() >= MAX_UNSYNCED_PAGES {
+                let started = Instant::now();
+                self.sync_unsynced()?;
+                self.log
+                    .log()
+                    .record_stall(StallCause::Backpressure, started);
+            }
+        } else {
+            let pages: Vec<_> = dirty.iter().map(|(index, page)| (*index, page)).collect();
+            if let Err(err) = self.store.write_pages(&pages) {
+                // The commit is in the log, its pages wait for a checkpoint to write them
+                self.unsynced.extend(dirty);
+                return Err(err);
+            }
+            for index in dirty.keys() {
+                self.unsynced.remove(index);
+            }
+        }
+
+        if self
+            .auto_checkpoint
+            .is_some_and(|log_pages| self.log.log().n_pages() >= log_pages)
+        {
+            let started = Instant::now();
+            self.checkpoint_step(CHECKPOINT_STEP_PAGES)?;
+            self.log.log().record_stall(StallCause::Checkpoint, started);
+        }
+        Ok(())
+    }
+
+    /// Appends the records of a commit of `dirty` and syncs or flushes the log for them
+    fn log_commit(&mut self, dirty: &BTreeMap<usize, Page>) -> Result<u64, io::Error> {
+        let lsn = self.log_records(dirty, COMMIT, &[])?;
+        self.sync_for_commit()?;
+        Ok(lsn)
+    }
+
+    /// Appends a record per page of `dirty` and a `kind` record counting them
+    fn log_records(
+        &mut self,
+        dirty: &BTreeMap<usize, Page>,
+        kind: u8,
+        image: &[u8],
+    ) -> Result<u64, io::Error> {
+        self.check_log()?;
+        if self.aborted {
+            self.append_record(ABORT, 0, &[])?;
+            self.aborted = false;
+        }
+        for (index, page) in dirty {
+            let index = (*index).try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Page index too large for WAL")
+            })?;
+            self.append_record(PAGE, index, page.read())?;
+        }
+        let count = dirty.len() as u32;
+        self.append_record(kind, count, image)
+    }
+
+    /// Syncs or flushes the log for a commit, depending on the sync mode
+    fn sync_for_commit(&mut self) -> Result<(), io::Error> {
+        match self.sync_mode {
+            SyncMode::Full => self.sync_log(),
+            SyncMode::Normal | SyncMode::Off => self.log.log().flush(),
+        }
+    }
+
+    /// Makes the buffered writes durable without committing them, as the first phase of a
+    /// two-phase commit of the transaction `txn_id`. The log is synced whatever the sync
+    /// mode. Returns the lsn of the prepare record.
+    pub fn prepare(&mut self, txn_id: u64) -> Result<u64, io::Error> {
+        if self.prepared.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Another transaction is prepared already",
+            ));
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        let logged = self.log_records(&dirty, PREPARE, &txn_id.to_be_bytes());
+        match logged.and_then(|lsn| self.sync_log().map(|()| lsn)) {
+            Ok(lsn) => {
+                self.prepared = Some((txn_id, dirty));
+                Ok(lsn)
+            }
+            Err(err) => {
+                self.dirty = dirty;
+                self.aborted = true;
+                Err(err)
+            }
+        }
+    }
+
+    /// Commits the transaction prepared as `txn_id`, here or before a crash. Writes since
+    /// it was prepared stay pending. Returns the lsn of the commit record.
+    pub fn commit_prepared(&mut self, txn_id: u64) -> Result<u64, io::Error> {
+        let lsn = self.resolve_prepared(txn_id, COMMIT_PREPARED)?;
+        let (_, pages) = self.prepared.take().expect("Resolved above");
+        self.apply_commit(pages, self.sync_mode == SyncMode::Normal)?;
+        Ok(lsn)
+    }
+
+    /// Rolls back the transaction prepared as `txn_id`, with the writes since it was
+    /// prepared, which may have been built on it
+    pub fn rollback_prepared(&mut self, txn_id: u64) -> Result<(), io::Error> {
+        self.resolve_prepared(txn_id, ROLLBACK_PREPARED)?;
+        self.prepared = None;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Logs the outcome of the prepared transaction `txn_id` like a commit
+    fn resolve_prepared(&mut self, txn_id: u64, kind: u8) -> Result<u64, io::Error> {
+        if self.prepared.as_ref().map(|(id, _)| *id) != Some(txn_id) {
+            return Err(not_prepared());
+        }
+        self.check_log()?;
+        let lsn = self.append_record(kind, 0, &txn_id.to_be_bytes())?;
+        self.sync_for_commit()?;
+        Ok(lsn)
+    }
+
+    /// The id of the transaction prepared and not resolved yet, possibly found by recovery
+    pub fn prepared_transaction(&self) -> Option<u64> {
+        self.prepared.as_ref().map(|(id, _)| *id)
+    }
+
+    /// Copies every committed page into the store and truncates the log
+    pub fn checkpoint(&mut self) -> Result<Checkpoint, io::Error> {
+        self.checkpoint_step(usize::MAX)
+    }
+
+    /// Copies at most `max_pages` of the pages SyncMode::Normal held back into the store.
+    /// Once none are left, syncs the store and truncates the log.
+    pub fn checkpoint_step(&mut self, max_pages: usize) -> Result<Checkpoint, io::Error> {
+        let mut checkpoint = Checkpoint::default();
+        if !self.unsynced.is_empty() {
+            // The store may only get pages whose records can't be lost anymore
+            self.sync_log()?;
+            let indices: Vec<usize> = self.unsynced.keys().take(max_pages).copied().collect();
+            let pages: Vec<_> = indices.iter().map(|i| (*i, &self.unsynced[i])).collect();
+            self.store.write_pages(&pages)?;
+            for index in &indices {
+                self.unsynced.remove(index);
+            }
+            checkpoint.copied = indices.len();
+        }
+        if self.unsynced.is_empty() {
+            self.truncate_log()?;
+            checkpoint.done = true;
+        }
+        Ok(checkpoint)
+    }
+
+    /// Lets commits take a checkpoint step whenever they leave the log at least
+    /// `log_pages` pages long, `None` leaves checkpoints to the caller
+    pub fn set_auto_checkpoint(&mut self, log_pages: Option<usize>) {
+        self.auto_checkpoint = log_pages;
+    }
+
+    /// Length of the log in pages
+    pub fn log_pages(&self) -> usize {
+        self.log.log().n_pages()
+    }
+
+    /// Time commits spent waiting: on the log, on automatic checkpoint steps and on writing
+    /// the pages SyncMode::Normal held back
+    pub fn stall_report(&self) -> StallReport {
+        self.log.log().stall_report().clone()
+    }
+
+    /// Syncs the log for the commits SyncMode::Normal held back and writes their pages to
+    /// the store
+    fn sync_unsynced(&mut self) -> Result<(), io::Error> {
+        if self.unsynced.is_empty() {
+            return Ok(());
+        }
+        self.sync_log()?;
+        let pages: Vec<_> = self.unsynced.iter().map(|(i, page)| (*i, page)).collect();
+        self.store.write_pages(&pages)?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    /// Changes when commits sync the log. Commits held back by SyncMode::Normal are synced
+    /// first.
+    pub fn set_sync_mode(&mut self, mode: SyncMode) -> Result<(), io::Error> {
+        self.sync_unsynced()?;
+        self.sync_mode = mode;
+        Ok(())
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.sync_mode
+    }
+
+    /// Pages of commits whose log records weren't synced yet
+    pub fn unsynced_pages(&self) -> usize {
+        self.unsynced.len()
+    }
+
+    /// Discards every write since the last commit
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Pages written since the last commit
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Bytes the next commit appends to the log at most: a record per dirty page and the
+    /// commit record. Compressed page images take less.
+    pub fn estimated_commit_size(&self) -> usize {
+        let pages = self.dirty.len();
+        pages * (RECORD_OVERHEAD + self.store.page_size()) + RECORD_OVERHEAD
+    }
+
+    /// Takes the writes since the last commit out, a prepared transaction's included, so
+    /// reads see the committed pages until they are put back with restore_dirty
+    pub(crate) fn take_dirty(&mut self) -> Uncommitted {
+        (std::mem::take(&mut self.dirty), self.prepared.take())
+    }
+
+    pub(crate) fn restore_dirty(&mut self, (dirty, prepared): Uncommitted) {
+        debug_assert!(
+            self.dirty.is_empty(),
+            "Writes while the dirty pages were out"
+        );
+        self.dirty = dirty;
+        self.prepared = prepared;
+    }
+
+    /// A copy of the writes since the last commit, to go back to with reset_dirty
+    pub(crate) fn shadow_dirty(&self) -> BTreeMap<usize, Page> {
+        self.dirty.clone()
+    }
+
+    /// Replaces the writes since the last commit by a shadow copy taken earlier
+    pub(crate) fn reset_dirty(&mut self, dirty: BTreeMap<usize, Page>) {
+        self.dirty = dirty;
+    }
+
+    fn prepared_page(&self, index: usize) -> Option<&Page> {
+        self.prepared
+            .as_ref()
+            .and_then(|(_, pages)| pages.get(&index))
+    }
+
+    pub fn recovery(&self) -> WalRecovery {
+        self.recovery
+    }
+
+    /// The store below. Commits held back by SyncMode::Normal only reach it through sync,
+    /// otherwise they are left to recovery.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+/// Readers see the writes since the last commit, like read_page
+impl<S: SharedRead> SharedRead for WalStore<S> {
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        match self.dirty.get(&index).or_else(|| self.prepared_page(index)) {
+            Some(page) => Ok(page.clone()),
+            None => self.read_committed_shared(index),
+        }
+    }
+}
+
+impl<S: SharedRead> WalStore<S> {
+    /// Reads page `index` as of the last commit, passing over the writes since
+    pub(crate) fn read_committed_shared(&self, index: usize) -> Result<Page, io::Error> {
+        match self.unsynced.get(&index) {
+            Some(page) => Ok(page.clone()),
+            None => self.store.read_page_shared(index),
+        }
+    }
+}
+
+impl<S: PageStore> PageStore for WalStore<S> {
+    fn page_size(&self) -> usize {
+        self.store.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let page = self.dirty.get(&index).or_else(|| self.prepared_page(index));
+        if let Some(page) = page.or_else(|| self.unsynced.get(&index)) {
+            return Ok(page.clone());
+        }
+        self.store.read_page(index)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.dirty.insert(index, page.clone());
+        Ok(())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        // Only reserve the page, its content is written on commit like any other page
+        let index = self.store.append_page(&Page::new(self.store.page_size()))?;
+        self.dirty.insert(index, page.clone());
+        Ok(index)
+    }
+
+    // Releasing pages is never forwarded, the store could reuse a page a rollback revives
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.store.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.store.reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.store.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.store.cache_stats()
+    }
+
+    /// Commits, and syncs the commits SyncMode::Normal held back
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.commit()?;
+        self.sync_unsynced()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, PAGE_SIZE};
+    use crate::page::{MemoryStore, PageManager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn page(byte: u8) -> Page {
+        Page::from_vec(vec![byte; PAGE_SIZE as usize], PAGE_SIZE as usize)
+    }
+
+    #[test]
+    fn committed_transactions_are_replayed() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let mut wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        wal.append_page(&page(1)).unwrap();
+        wal.append_page(&page(2)).unwrap();
+        wal.commit().unwrap();
+        wal.write_page(1, &page(3)).unwrap();
+        assert_eq!(wal.read_page(1).unwrap().read(), page(3).read());
+        // Crash before the second transaction commits
+        drop(wal.log);
+
+        // The store lost everything, the log still has the first transaction
+        let mut store = MemoryStore::new(PAGE_SIZE.into());
+        store.append_page(&page(0)).unwrap();
+        store.append_page(&page(0)).unwrap();
+        let mut wal = WalStore::open(store, log_path).unwrap();
+        assert_eq!(
+            wal.recovery(),
+            WalRecovery {
+                replayed: 1,
+                discarded: 0
+            }
+        );
+        assert_eq!(wal.read_page(0).unwrap().read(), page(1).read());
+        assert_eq!(wal.read_page(1).unwrap().read(), page(2).read());
+
+        // Recovery truncated the log
+        let wal = WalStore::open(wal.into_inner(), log_path).unwrap();
+        assert_eq!(wal.recovery(), WalRecovery::default());
+    }
+
+    #[test]
+    fn partial_transactions_are_discarded() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let mut wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        wal.append_page(&page(1)).unwrap();
+        wal.commit().unwrap();
+        // Write the page records of a transaction but crash before its commit record
+        wal.append_record(PAGE, 0, page(7).read()).unwrap();
+        wal.append_record(PAGE, 0, page(8).read()).unwrap();
+        wal.log.sync().unwrap();
+        let store = wal.into_inner();
+
+        let mut wal = WalStore::open(store, log_path).unwrap();
+        assert_eq!(
+            wal.recovery(),
+            WalRecovery {
+                replayed: 1,
+                discarded: 2
+            }
+        );
+        assert_eq!(wal.read_page(0).unwrap().read(), page(1).read());
+    }
+
+    #[test]
+    fn failed_commits_keep_their_writes() {
+        use crate::testing::{Fault, SimDisk};
+
+        let disk = SimDisk::new(5);
+        let log = disk.create_file();
+        let store = disk.create_file().pages(PAGE_SIZE.into());
+        let mut wal = WalStore::open_with_log(store.clone(), log.clone()).unwrap();
+        wal.append_page(&page(1)).unwrap();
+        wal.commit().unwrap();
+
+        // Writing the log fails, committing again once the disk is back logs the page again
+        wal.write_page(0, &page(2)).unwrap();
+        disk.inject(Fault::Crash, 0);
+        assert!(wal.commit().is_err());
+        assert_eq!(wal.dirty_pages(), 1);
+        disk.restart();
+        wal.commit().unwrap();
+
+        // Syncing the log fails, the writes stay readable but can't be committed anymore
+        wal.write_page(0, &page(3)).unwrap();
+        disk.inject(Fault::PartialSync, 0);
+        assert!(wal.commit().is_err());
+        assert_eq!(wal.read_page(0).unwrap().read(), page(3).read());
+        assert!(wal.commit().is_err());
+        drop(wal);
+
+        disk.crash();
+        disk.restart();
+        let mut wal = WalStore::open_with_log(store, log).unwrap();
+        assert_eq!(wal.read_page(0).unwrap().read(), page(2).read());
+        wal.write_page(0, &page(4)).unwrap();
+        wal.commit().unwrap();
+    }
+
+    #[test]
+    fn normal_mode_holds_commits_until_sync() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let mut wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        wal.set_sync_mode(SyncMode::Normal).unwrap();
+        wal.append_page(&page(1)).unwrap();
+        wal.commit().unwrap();
+        wal.write_page(0, &page(2)).unwrap();
+        wal.commit().unwrap();
+        assert_eq!(wal.unsynced_pages(), 1);
+        assert_eq!(wal.read_page(0).unwrap().read(), page(2).read());
+        assert_eq!(wal.store.read_page(0).unwrap().read(), page(0).read());
+
+        wal.sync().unwrap();
+        assert_eq!(wal.unsynced_pages(), 0);
+        assert_eq!(wal.store.read_page(0).unwrap().read(), page(2).read());
+        assert_eq!(wal.stall_report().backpressure.count, 0);
+
+        // A commit that piles up too many held back pages writes them out
+        for _ in 0..MAX_UNSYNCED_PAGES {
+            wal.append_page(&page(5)).unwrap();
+        }
+        wal.commit().unwrap();
+        assert_eq!(wal.unsynced_pages(), 0);
+        assert_eq!(wal.stall_report().backpressure.count, 1);
+
+        // Held back commits are still in the log if the store never got them
+        wal.write_page(0, &page(3)).unwrap();
+        wal.commit().unwrap();
+        let mut wal = WalStore::open(wal.into_inner(), log_path).unwrap();
+        assert_eq!(wal.read_page(0).unwrap().read(), page(3).read());
+
+        wal.set_sync_mode(SyncMode::Off).unwrap();
+        wal.write_page(0, &page(4)).unwrap();
+        wal.commit().unwrap();
+        assert_eq!(wal.store.read_page(0).unwrap().read(), page(4).read());
+    }
+
+    #[test]
+    fn checkpoints_truncate_the_log() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let mut wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        wal.set_sync_mode(SyncMode::Normal).unwrap();
+        for byte in 0..10 {
+            wal.append_page(&page(byte)).unwrap();
+        }
+        wal.commit().unwrap();
+        let log_pages = wal.log_pages();
+        assert!(log_pages > 1);
+
+        // Held back pages are copied a few at a time, the log stays until all are
+        let step = wal.checkpoint_step(4).unwrap();
+        assert_eq!(
+            step,
+            Checkpoint {
+                copied: 4,
+                done: false
+            }
+        );
+        assert_eq!(wal.store.read_page(3).unwrap().read(), page(3).read());
+        assert_eq!(wal.store.read_page(4).unwrap().read(), page(0).read());
+        assert_eq!(wal.log_pages(), log_pages);
+        wal.checkpoint_step(4).unwrap();
+        let step = wal.checkpoint_step(4).unwrap();
+        assert_eq!(
+            step,
+            Checkpoint {
+                copied: 2,
+                done: true
+            }
+        );
+        assert_eq!(wal.log_pages(), 1);
+        assert_eq!(wal.store.read_page(9).unwrap().read(), page(9).read());
+
+        // Nothing is left to replay, and a partial transaction after the checkpoint is
+        // still discarded
+        wal.append_record(PAGE, 0, page(20).read()).unwrap();
+        wal.log.sync().unwrap();
+        let wal = WalStore::open(wal.into_inner(), log_path).unwrap();
+        assert_eq!(
+            wal.recovery(),
+            WalRecovery {
+                replayed: 0,
+                discarded: 1
+            }
+        );
+    }
+
+    #[test]
+    fn auto_checkpoints_bound_the_log() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut wal = WalStore::open(store, log_path.to_str().unwrap()).unwrap();
+        wal.set_auto_checkpoint(Some(8));
+        wal.append_page(&page(0)).unwrap();
+        for byte in 0..50 {
+            wal.write_page(0, &page(byte)).unwrap();
+            wal.commit().unwrap();
+            assert!(wal.log_pages() < 8);
+        }
+        assert_eq!(wal.store.read_page(0).unwrap().read(), page(49).read());
+        let report = wal.stall_report();
+        assert!(report.checkpoint.count > 0);
+        assert!(report
+            .recent()
+            .any(|stall| stall.cause == StallCause::Checkpoint));
+    }
+
+    #[test]
+    fn pipelined_commits_share_a_sync() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("wal.bin");
+        let log_path = log_path.to_str().unwrap();
+
+        let mut wal = WalStore::open(MemoryStore::new(PAGE_SIZE.into()), log_path).unwrap();
+        wal.append_page(&page(1)).unwrap();
+        let first = wal.commit_pipelined().unwrap();
+        wal.write_page(0, &page(2)).unwrap();
+        let second = wal.commit_pipelined().unwrap();
+        assert!(!first.is_durable());
+        assert_eq!(wal.unsynced_pages(), 1);
+
+        second.wait_durable().unwrap();
+        assert!(first.is_durable());
+        let stats = wal.group_commit_stats();
+        assert_eq!((stats.commits, stats.syncs), (2, 1));
+        // Held back until the store is synced, recovery has them before
+        assert_eq!(wal.store.read_page(0).unwrap().read(), page(0).read());
+        let mut wal = WalStore::open(wal.into_inner(), log_path).unwrap();
+        assert_eq!(wal.read_page(0).unwrap().read(), page(2).read());
+    }
+
+    #[test]
+    fn tree_commit_and_rollback() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("db.bin");
+        let log_path = dir.path().join("wal.bin");
+        let open = || {
+            let pages = PageManager::new(db_path.to_str().unwrap(), PAGE_SIZE.into()).unwrap();
+            WalStore::open(pages, log_path.to_str().unwrap()).unwrap()
+        };
+
+        let mut tree = BTree::create(open()).unwrap();
+        for key in 0..300 {
+            tree.insert(key, &[key as u8; 50]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        for key in 0..300 {
+            tree.delete(key).unwrap();
+        }
+        tree.insert(1000, b"uncommitted").unwrap();
+        tree.rollback();
+        assert_eq!(tree.get(1000).unwrap(), None);
+        assert_eq!(tree.get(5).unwrap(), Some(vec![5; 50]));
+
+        // Uncommitted writes never reach the file
+        tree.insert(2000, b"lost").unwrap();
+        drop(tree);
+        let mut tree = BTree::open(open(), 0).unwrap();
+        assert_eq!(tree.get(2000).unwrap(), None);
+        for key in 0..300 {
+            assert_eq!(tree.get(key).unwrap(), Some(vec![key as u8; 50]));
+        }
+    }
+}
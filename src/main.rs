@@ -1,4 +1,321 @@
-use e_bin::btree::Node;
-const PS: usize = 4096;
+/*
+Inspector for database files. Every command opens a file, looks at it and exits with 0 if all
+is well, 1 if it found something worth a look, like corrupt pages or husks, and 2 if it
+couldn't do its job. Commands that walk a tree start at the root page recorded in the file
+header, page 1 in files that never recorded one, unless they are given a root page.
+*/
 
-fn main() {}
+use std::env;
+use std::process::ExitCode;
+
+use e_bin::btree::{verify_pages, BTree, Node};
+use e_bin::page::Pager;
+
+const USAGE: &str = "usage: e-bin info <file>
+       e-bin dump <file> <page>
+       e-bin keys <file> [root page]
+       e-bin free <file> [root page]
+       e-bin verify <file>
+       e-bin check <file> [root page]
+       e-bin husks <file> [root page]
+       e-bin import-sqlite <sqlite file> <table> <file>";
+
+/// Leaves listed by free, most fragmented first
+const FRAGMENTED_LEAVES: usize = 10;
+
+fn open(path: &str) -> Result<Pager, String> {
+    Pager::open(path).map_err(|err| format!("Can't open {path}: {err}"))
+}
+
+fn open_tree(path: &str, root: Option<u32>) -> Result<BTree<Pager>, String> {
+    let pager = open(path)?;
+    let root = root.unwrap_or(match pager.root() {
+        0 => 1,
+        root => root,
+    });
+    BTree::open(pager, root).map_err(|err| format!("Can't open tree at page {root}: {err}"))
+}
+
+fn info(path: &str) -> Result<bool, String> {
+    let pager = open(path)?;
+    let header = pager.header();
+    println!("format version  {}", header.format_version);
+    println!("page size       {}", header.page_size);
+    println!("pages           {}", header.page_count);
+    println!("free pages      {}", header.free_pages);
+    println!("checksum        {:?}", header.checksum);
+    println!("root page       {}", header.root_page);
+    println!("catalog page    {}", header.catalog_page);
+    Ok(true)
+}
+
+fn dump(path: &str, page_no: u32) -> Result<bool, String> {
+    let mut pager = open(path)?;
+    let mut page = pager
+        .read(page_no)
+        .map_err(|err| format!("Can't read page {page_no}: {err}"))?;
+    let node =
+        Node::load(page.mutate()).map_err(|err| format!("Page {page_no} isn't a node: {err}"))?;
+    print!("{}", node.debug_dump());
+    Ok(true)
+}
+
+fn keys(path: &str, root: Option<u32>) -> Result<bool, String> {
+    let mut tree = open_tree(path, root)?;
+    for entry in tree.iter() {
+        let (key, value) = entry.map_err(|err| format!("Can't read tree: {err}"))?;
+        println!("{key}\t{} bytes", value.len());
+    }
+    Ok(true)
+}
+
+fn free(path: &str, root: Option<u32>) -> Result<bool, String> {
+    let mut tree = open_tree(path, root)?;
+    let stats = tree
+        .stats()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    let leaves = tree
+        .fragmentation()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    let header = tree.store().header();
+    println!("pages             {}", header.page_count);
+    println!("free pages        {}", header.free_pages);
+    println!("leaves            {}", leaves.len());
+    println!("height            {}", stats.height);
+    println!("entries           {}", stats.entries);
+    println!("fragmented bytes  {}", stats.fragmented_bytes);
+
+    let fragmented = tree
+        .most_fragmented(FRAGMENTED_LEAVES)
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    if !fragmented.is_empty() {
+        println!("most fragmented leaves");
+    }
+    for leaf in fragmented {
+        println!(
+            "  page {:<8} {} fragmented bytes, {} in freeblocks",
+            leaf.page_id, leaf.fragmented_bytes, leaf.freeblock_bytes
+        );
+    }
+    Ok(true)
+}
+
+fn verify(path: &str) -> Result<bool, String> {
+    let mut pager = open(path)?;
+    let (mut pages, mut clean) = (0, true);
+    let verifier = verify_pages(&mut pager).map_err(|err| format!("Can't verify: {err}"))?;
+    for report in verifier {
+        pages += 1;
+        match report {
+            Ok(report) => {
+                for issue in &report.issues {
+                    println!("page {}: {issue:?}", report.page);
+                    clean = false;
+                }
+            }
+            Err(err) => {
+                println!("unreadable page: {err}");
+                clean = false;
+            }
+        }
+    }
+    println!("{pages} pages checked");
+    Ok(clean)
+}
+
+fn check(path: &str, root: Option<u32>) -> Result<bool, String> {
+    let mut tree = open_tree(path, root)?;
+    let mut clean = true;
+
+    // Iterators skip keys outside the separators above their leaf, so a leaf holding keys
+    // it shouldn't has more entries than the iteration yields
+    let mut entries = 0u64;
+    for entry in tree.iter() {
+        entry.map_err(|err| format!("Can't read tree: {err}"))?;
+        entries += 1;
+    }
+    let stats = tree
+        .stats()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    if stats.entries != entries {
+        println!(
+            "{} entries in leaves, {entries} of them in key order",
+            stats.entries
+        );
+        clean = false;
+    }
+
+    let husks = tree
+        .husks()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    for page in &husks.empty_leaves {
+        println!("empty leaf {page}");
+    }
+    for page in &husks.orphaned {
+        println!("orphaned page {page}");
+    }
+    clean &= husks.is_empty();
+
+    // Every leaf keeps the invariants of the node format
+    let leaves = tree
+        .fragmentation()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+    let mut pager = tree.into_store();
+    for leaf in &leaves {
+        let mut page = pager
+            .read(leaf.page_id)
+            .map_err(|err| format!("Can't read page {}: {err}", leaf.page_id))?;
+        let valid = Node::load(page.mutate()).and_then(|node| {
+            node.validate()?;
+            node.check_overlaps()
+        });
+        if let Err(err) = valid {
+            println!("leaf {}: {err}", leaf.page_id);
+            clean = false;
+        }
+    }
+
+    println!("{entries} entries in {} leaves", leaves.len());
+    Ok(clean)
+}
+
+fn husks(path: &str, root: Option<u32>) -> Result<bool, String> {
+    let mut tree = open_tree(path, root)?;
+    let report = tree
+        .husks()
+        .map_err(|err| format!("Can't walk tree: {err}"))?;
+
+    for page in &report.empty_leaves {
+        println!("empty leaf {page}");
+    }
+    for page in &report.orphaned {
+        println!("orphaned   {page}");
+    }
+    println!(
+        "{} empty leaves, {} orphaned pages",
+        report.empty_leaves.len(),
+        report.orphaned.len()
+    );
+    Ok(report.is_empty())
+}
+
+#[cfg(feature = "sqlite")]
+fn import_sqlite(sqlite_path: &str, table: &str, path: &str) -> Result<bool, String> {
+    let conn = rusqlite::Connection::open(sqlite_path)
+        .map_err(|err| format!("Can't open {sqlite_path}: {err}"))?;
+    let pager = Pager::open(path).map_err(|err| format!("Can't open {path}: {err}"))?;
+    let (tree, rows) = BTree::import_sqlite(pager, &conn, table)
+        .map_err(|err| format!("Can't import {table}: {err}"))?;
+
+    let root = tree.root();
+    let mut pager = tree.into_store();
+    pager
+        .set_root(root)
+        .map_err(|err| format!("Can't record root page: {err}"))?;
+    println!("imported {rows} rows into the tree at page {root}");
+    Ok(true)
+}
+
+fn page_no(arg: &str) -> Result<u32, String> {
+    arg.parse().map_err(|_| format!("Invalid page {arg}"))
+}
+
+/// Runs the command in `args`, the arguments without the program name
+fn run(args: &[String]) -> Result<bool, String> {
+    let (command, path, root) = match args {
+        [command, path] => (command.as_str(), path, None),
+        [command, path, page] if command != "dump" => (command.as_str(), path, Some(page)),
+        [_, path, page] => return dump(path, page_no(page)?),
+        #[cfg(feature = "sqlite")]
+        [command, sqlite_path, table, path] if command == "import-sqlite" => {
+            return import_sqlite(sqlite_path, table, path)
+        }
+        _ => return Err(USAGE.to_owned()),
+    };
+    let root = root.map(|root| page_no(root)).transpose()?;
+    match (command, root) {
+        ("info", None) => info(path),
+        ("verify", None) => verify(path),
+        ("keys", _) => keys(path, root),
+        ("free", _) => free(path, root),
+        ("check", _) => check(path, root),
+        ("husks", _) => husks(path, root),
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        // Findings aren't errors, but scripts should notice them
+        Ok(false) => ExitCode::from(1),
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use e_bin::page::PageStore;
+    use tempfile::tempdir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// A file holding a tree of `n` keys, with its root recorded
+    fn database(path: &str, n: u64) {
+        let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+        for key in 0..n {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+        pager.sync().unwrap();
+    }
+
+    #[test]
+    fn commands_inspect_a_database() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        let path = path.to_str().unwrap();
+        database(path, 500);
+
+        for command in ["info", "keys", "free", "verify", "check", "husks"] {
+            assert_eq!(run(&args(&[command, path])), Ok(true), "{command}");
+        }
+        let root = Pager::open(path).unwrap().root().to_string();
+        assert_eq!(run(&args(&["dump", path, &root])), Ok(true));
+        assert_eq!(run(&args(&["keys", path, &root])), Ok(true));
+        assert!(run(&args(&["dump", path])).is_err());
+        assert!(run(&args(&["info", path, "1"])).is_err());
+        assert!(run(&args(&["keys", path, "root"])).is_err());
+        assert!(run(&args(&["frobnicate", path])).is_err());
+    }
+
+    #[test]
+    fn damaged_pages_are_findings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        let path = path.to_str().unwrap();
+        database(path, 500);
+
+        // Points the first slot of a leaf's value far past the end of the page
+        let mut tree = open_tree(path, None).unwrap();
+        let leaf = tree.fragmentation().unwrap()[0].page_id;
+        let mut pager = tree.into_store();
+        let mut page = pager.read(leaf).unwrap();
+        page.mutate()[28..30].copy_from_slice(&u16::MAX.to_le_bytes());
+        pager.write(leaf, &page).unwrap();
+        pager.sync().unwrap();
+        drop(pager);
+
+        assert_eq!(run(&args(&["verify", path])), Ok(false));
+        assert_eq!(run(&args(&["dump", path, &leaf.to_string()])), Ok(true));
+    }
+}
@@ -0,0 +1,311 @@
+/*
+Async access to a database file for trees used inside async services. Page I/O goes through
+tokio::fs and is awaited, the node code stays synchronous and works on the fetched buffers:
+load a page, look at or change it with NodeView or Node, flush it back.
+
+The file format is the one Pager writes, and the file is locked the same way, so an async
+and a sync pager never have the same file open. Files are created by Pager. AsyncPager only
+appends pages and doesn't reuse free ones, the freelist is left to Pager.
+
+commit hands the writes so far to the OS and returns a DurabilityToken instead of waiting for
+the disk, so callers overlap their work with the fsync. Waiting for a token fsyncs the file
+off the async threads, tokens waited for at the same time share one fsync.
+*/
+
+use std::fs::OpenOptions;
+use std::io::{self, SeekFrom};
+#[cfg(feature = "wal")]
+use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(feature = "wal")]
+use std::sync::Arc;
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::header::{FileHeader, FILE_HEADER_SIZE};
+use super::{platform, Page};
+use crate::btree::{BTreeError, LimitError, NodeView};
+use crate::limits::ResourceLimits;
+#[cfg(feature = "wal")]
+use crate::log::{DurabilityToken, Durable, SyncMode, Syncs};
+
+const META_PAGE: u32 = 0;
+
+pub struct AsyncPager {
+    file: File,
+    header: FileHeader,
+    page_size: usize,
+    n_pages: u32,
+    #[cfg(feature = "wal")]
+    syncs: Arc<FileSyncs>,
+}
+
+/// Syncs of the file for the tokens commit hands out
+#[cfg(feature = "wal")]
+struct FileSyncs {
+    /// Another handle to the file, synced without holding on to the pager
+    file: std::fs::File,
+    /// Commits whose writes were handed to the OS
+    commits: AtomicI32,
+    syncs: Syncs,
+}
+
+#[cfg(feature = "wal")]
+impl Durable for FileSyncs {
+    fn syncs(&self) -> &Syncs {
+        &self.syncs
+    }
+
+    fn sync_appended(&self, fsync: bool) -> Result<i32, io::Error> {
+        // Every commit counted so far flushed its writes first
+        let lsn = self.commits.load(Ordering::Acquire);
+        if fsync {
+            self.file.sync_data()?;
+        }
+        Ok(lsn)
+    }
+}
+
+impl AsyncPager {
+    /// Opens the existing database file at `path`. The file stays locked while the pager
+    /// lives, like it does for a Pager.
+    pub async fn open(path: &str) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        platform::lock(&file)?;
+        #[cfg(feature = "wal")]
+        let syncs = Arc::new(FileSyncs {
+            file: file.try_clone()?,
+            commits: AtomicI32::new(0),
+            syncs: Syncs::new(SyncMode::Full),
+        });
+        let mut file = File::from_std(file);
+
+        let mut data = [0; FILE_HEADER_SIZE];
+        file.read_exact(&mut data)
+            .await
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    io::Error::new(io::ErrorKind::InvalidData, "Not a database file")
+                }
+                _ => err,
+            })?;
+        let header = FileHeader::read(&data)?;
+        let page_size = header.page_size as usize;
+        let n_pages = file.metadata().await?.len() / page_size as u64;
+        header.validate(n_pages as usize)?;
+        Ok(Self {
+            file,
+            header,
+            page_size,
+            n_pages: n_pages.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Database file is too large")
+            })?,
+            #[cfg(feature = "wal")]
+            syncs,
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Root of the tree recorded in the file header
+    pub fn root(&self) -> u32 {
+        self.header.root_page
+    }
+
+    pub fn n_pages(&self) -> u32 {
+        self.n_pages
+    }
+
+    fn check_page_no(&self, page_no: u32) -> Result<(), io::Error> {
+        if page_no == META_PAGE || page_no >= self.n_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Page {page_no} isn't a data page"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn offset(&self, page_no: u32) -> u64 {
+        page_no as u64 * self.page_size as u64
+    }
+
+    pub async fn load_page(&mut self, page_no: u32) -> Result<Page, io::Error> {
+        self.check_page_no(page_no)?;
+        let mut data = vec![0; self.page_size];
+        self.file
+            .seek(SeekFrom::Start(self.offset(page_no)))
+            .await?;
+        self.file.read_exact(&mut data).await?;
+        Ok(Page::from_vec(data, self.page_size))
+    }
+
+    /// Writes `page` back to `page_no`. Like every write it's only durable after sync.
+    pub async fn flush_page(&mut self, page_no: u32, page: &Page) -> Result<(), io::Error> {
+        self.check_page_no(page_no)?;
+        self.file
+            .seek(SeekFrom::Start(self.offset(page_no)))
+            .await?;
+        self.file.write_all(page.read()).await
+    }
+
+    /// Adds `page` at the end of the file and returns its number
+    pub async fn append_page(&mut self, page: &Page) -> Result<u32, io::Error> {
+        let page_no = self.n_pages;
+        self.file
+            .seek(SeekFrom::Start(self.offset(page_no)))
+            .await?;
+        self.file.write_all(page.read()).await?;
+        self.n_pages += 1;
+        Ok(page_no)
+    }
+
+    /// Records `root` as the root of the file's tree
+    pub async fn set_root(&mut self, root: u32) -> Result<(), io::Error> {
+        self.check_page_no(root)?;
+        self.header.root_page = root;
+        let mut meta = Page::new(self.page_size);
+        self.header.write(meta.mutate());
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.file.write_all(meta.read()).await
+    }
+
+    /// Hands every write so far to the OS and returns without waiting for the disk. The
+    /// token resolves once an fsync covers them, see the module docs.
+    #[cfg(feature = "wal")]
+    pub async fn commit(&mut self) -> Result<DurabilityToken, io::Error> {
+        self.syncs.syncs.check()?;
+        self.file.flush().await?;
+        let lsn = self.syncs.commits.fetch_add(1, Ordering::AcqRel) + 1;
+        self.syncs.syncs.count_commit();
+        Ok(DurabilityToken::new(
+            Arc::<FileSyncs>::clone(&self.syncs),
+            lsn,
+        ))
+    }
+
+    pub async fn sync(&mut self) -> Result<(), io::Error> {
+        self.file.flush().await?;
+        self.file.sync_data().await
+    }
+
+    /// Looks `key` up in the tree rooted at `root`, loading one page per level. Returns the
+    /// stored bytes, values of trees that compress them are still compressed.
+    pub async fn get(&mut self, root: u32, key: u64) -> Result<Option<Vec<u8>>, BTreeError> {
+        let max_depth = ResourceLimits::default().max_depth;
+        let mut page_no = root;
+        for _ in 0..max_depth {
+            let page = self.load_page(page_no).await?;
+            let node = NodeView::load(page.read())
+                .map_err(|err| err.in_page(page_no, self.offset(page_no)))?;
+            if node.is_leaf() {
+                return Ok(node.get(key).map(<[u8]>::to_vec));
+            }
+            page_no = node.find_child_page(key)?;
+        }
+        Err(BTreeError::LimitExceeded(LimitError::MaxDepth {
+            limit: max_depth,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, Node};
+    use crate::page::{PageStore, Pager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn trees_are_read_and_written_through_fetched_pages() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+        for key in 0..500 {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+        pager.sync().unwrap();
+        drop(pager);
+
+        let mut pager = AsyncPager::open(path).await.unwrap();
+        assert_eq!(
+            Pager::open(path).err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(pager.root(), root);
+        assert_eq!(pager.get(root, 77).await.unwrap(), Some(vec![77; 100]));
+        assert_eq!(pager.get(root, 500).await.unwrap(), None);
+        assert!(pager.load_page(0).await.is_err());
+
+        // Change a leaf with the node code and flush it
+        let mut page_no = root;
+        let mut page = pager.load_page(page_no).await.unwrap();
+        while !NodeView::load(page.read()).unwrap().is_leaf() {
+            page_no = NodeView::load(page.read())
+                .unwrap()
+                .find_child_page(77)
+                .unwrap();
+            page = pager.load_page(page_no).await.unwrap();
+        }
+        Node::load(page.mutate())
+            .unwrap()
+            .insert(77, b"seventy-seven")
+            .unwrap();
+        pager.flush_page(page_no, &page).await.unwrap();
+        pager.sync().await.unwrap();
+        assert_eq!(
+            pager.get(root, 77).await.unwrap(),
+            Some(b"seventy-seven".to_vec())
+        );
+        drop(pager);
+
+        let mut tree = BTree::open(Pager::open(path).unwrap(), root).unwrap();
+        assert_eq!(tree.get(77).unwrap(), Some(b"seventy-seven".to_vec()));
+        assert_eq!(tree.get(78).unwrap(), Some(vec![78; 100]));
+    }
+
+    #[cfg(feature = "wal")]
+    #[tokio::test]
+    async fn commits_return_before_the_fsync() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+        tree.into_store().sync().unwrap();
+
+        let mut pager = AsyncPager::open(path).await.unwrap();
+        let first_page = pager
+            .append_page(&Page::new(pager.page_size()))
+            .await
+            .unwrap();
+        let first = pager.commit().await.unwrap();
+        let mut page = Page::new(pager.page_size());
+        page.mutate()[..4].copy_from_slice(b"late");
+        pager.flush_page(first_page, &page).await.unwrap();
+        let second = pager.commit().await.unwrap();
+        assert!(first.lsn() < second.lsn());
+        assert!(!first.is_durable());
+
+        // One fsync covers both
+        second.await.unwrap();
+        assert!(first.is_durable());
+        first.await.unwrap();
+        drop(pager);
+
+        let mut pager = Pager::open(path).unwrap();
+        let page = PageStore::read_page(&mut pager, first_page as usize).unwrap();
+        assert_eq!(&page.read()[..4], b"late");
+    }
+}
@@ -0,0 +1,224 @@
+/*
+The catalog names the trees in a database file and keeps a little metadata for each of them,
+so tools can list the trees and read their properties without opening any of them. It lives
+in a single page the meta page points at. Numbers are big endian like in the meta page.
+Catalog page
+---------------------------------------------
+| number of trees (2 bytes) | tree | tree | ... |
+---------------------------------------------
+Tree
+------------------------------------------------------------------------------------------------------
+| name len (2 bytes) | name | root (4 bytes) | schema version (4 bytes) | created at (8 bytes) | codec fingerprint (8 bytes) | properties |
+------------------------------------------------------------------------------------------------------------------------------------
+Properties are a count (2 bytes) followed by that many
+| key len (2 bytes) | key | value len (2 bytes) | value |
+*/
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What the catalog knows about one tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeMeta {
+    pub root: u32,
+    /// Version of whatever the user stores in the tree, 0 unless set
+    pub schema_version: u32,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// `schema_fingerprint` of the key and value types, 0 if the tree isn't typed
+    pub codec_fingerprint: u64,
+    pub properties: BTreeMap<String, Vec<u8>>,
+}
+
+impl TreeMeta {
+    /// Metadata of a tree rooted at `root` that is created right now
+    pub fn new(root: u32) -> Self {
+        Self {
+            root,
+            schema_version: 0,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            codec_fingerprint: 0,
+            properties: BTreeMap::new(),
+        }
+    }
+}
+
+/// Named trees of a database file, see `Pager::catalog` and `Pager::write_catalog`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    trees: BTreeMap<String, TreeMeta>,
+}
+
+impl Catalog {
+    pub fn get(&self, name: &str) -> Option<&TreeMeta> {
+        self.trees.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut TreeMeta> {
+        self.trees.get_mut(name)
+    }
+
+    /// Adds or replaces the tree called `name`, returning what was stored before
+    pub fn insert(&mut self, name: &str, meta: TreeMeta) -> Option<TreeMeta> {
+        self.trees.insert(name.to_string(), meta)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<TreeMeta> {
+        self.trees.remove(name)
+    }
+
+    /// Trees sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TreeMeta)> {
+        self.trees.iter().map(|(name, meta)| (name.as_str(), meta))
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// Serializes the catalog, failing if it doesn't fit into `page_size` bytes
+    pub(crate) fn encode(&self, page_size: usize) -> Result<Vec<u8>, io::Error> {
+        let mut buf = Vec::with_capacity(page_size);
+        put_len(&mut buf, self.trees.len())?;
+        for (name, meta) in &self.trees {
+            put_bytes(&mut buf, name.as_bytes())?;
+            buf.extend_from_slice(&meta.root.to_be_bytes());
+            buf.extend_from_slice(&meta.schema_version.to_be_bytes());
+            buf.extend_from_slice(&meta.created_at.to_be_bytes());
+            buf.extend_from_slice(&meta.codec_fingerprint.to_be_bytes());
+            put_len(&mut buf, meta.properties.len())?;
+            for (key, value) in &meta.properties {
+                put_bytes(&mut buf, key.as_bytes())?;
+                put_bytes(&mut buf, value)?;
+            }
+        }
+        if buf.len() > page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Catalog needs {} bytes but a page holds {page_size}",
+                    buf.len()
+                ),
+            ));
+        }
+        buf.resize(page_size, 0);
+        Ok(buf)
+    }
+
+    /// Reads a catalog page, every length is checked since the page comes from disk
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, io::Error> {
+        let mut reader = Reader { data, pos: 0 };
+        let mut trees = BTreeMap::new();
+        for _ in 0..reader.u16()? {
+            let name = reader.string()?;
+            let mut meta = TreeMeta {
+                root: u32::from_be_bytes(reader.array()?),
+                schema_version: u32::from_be_bytes(reader.array()?),
+                created_at: u64::from_be_bytes(reader.array()?),
+                codec_fingerprint: u64::from_be_bytes(reader.array()?),
+                properties: BTreeMap::new(),
+            };
+            for _ in 0..reader.u16()? {
+                let key = reader.string()?;
+                let value = reader.bytes()?.to_vec();
+                meta.properties.insert(key, value);
+            }
+            trees.insert(name, meta);
+        }
+        Ok(Self { trees })
+    }
+}
+
+fn put_len(buf: &mut Vec<u8>, len: usize) -> Result<(), io::Error> {
+    let len: u16 = len.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Catalog entry is longer than 65535",
+        )
+    })?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), io::Error> {
+    put_len(buf, bytes.len())?;
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes_of(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        let data = self.data;
+        let bytes = data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Catalog is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], io::Error> {
+        Ok(self
+            .bytes_of(N)?
+            .try_into()
+            .expect("Shouldn't fail, hardcoded"))
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], io::Error> {
+        let len = self.u16()?;
+        self.bytes_of(len.into())
+    }
+
+    fn string(&mut self) -> Result<String, io::Error> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Catalog name isn't utf-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn roundtrip() {
+        let mut catalog = Catalog::default();
+        let mut users = TreeMeta::new(3);
+        users.schema_version = 2;
+        users.codec_fingerprint = 7;
+        users
+            .properties
+            .insert("owner".to_string(), b"abekat".to_vec());
+        catalog.insert("users", users);
+        catalog.insert("events", TreeMeta::new(9));
+
+        let page = catalog.encode(4096).unwrap();
+        assert_eq!(page.len(), 4096);
+        let decoded = Catalog::decode(&page).unwrap();
+        assert_eq!(decoded, catalog);
+        assert_eq!(
+            decoded.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["events", "users"]
+        );
+
+        // A page of zeroes is an empty catalog, garbage is rejected
+        assert!(Catalog::decode(&[0; 64]).unwrap().is_empty());
+        assert!(Catalog::decode(&[0xff; 64]).is_err());
+        assert!(catalog.encode(16).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use xxhash_rust::xxh3::Xxh3;
+
+/// Checksum function a database is created with. The id is stored in the file header and
+/// in log frames, so readers always know which function to verify with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// crc32 (IEEE), what the log used before the checksum became selectable
+    #[default]
+    Crc32,
+    /// crc32c (Castagnoli), hardware accelerated with SSE4.2 where available
+    Crc32c,
+    /// Lower 32 bits of xxh3-64, fastest in software
+    Xxh3,
+    /// No checksum at all, corruption goes unnoticed
+    None,
+}
+
+impl Checksum {
+    pub fn id(self) -> u8 {
+        match self {
+            Checksum::Crc32 => 0,
+            Checksum::Crc32c => 1,
+            Checksum::Xxh3 => 2,
+            Checksum::None => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Checksum::Crc32),
+            1 => Some(Checksum::Crc32c),
+            2 => Some(Checksum::Xxh3),
+            3 => Some(Checksum::None),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(self) -> ChecksumHasher {
+        ChecksumHasher(match self {
+            Checksum::Crc32 => State::Crc32(crc32fast::Hasher::new()),
+            Checksum::Crc32c => State::Crc32c(0),
+            Checksum::Xxh3 => State::Xxh3(Box::new(Xxh3::new())),
+            Checksum::None => State::None,
+        })
+    }
+
+    pub fn compute(self, data: &[u8]) -> u32 {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// Incremental checksum, for data that isn't contiguous
+pub struct ChecksumHasher(State);
+
+enum State {
+    Crc32(crc32fast::Hasher),
+    Crc32c(u32),
+    Xxh3(Box<Xxh3>),
+    None,
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            State::Crc32(hasher) => hasher.update(data),
+            State::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            State::Xxh3(hasher) => hasher.update(data),
+            State::None => {}
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        match self.0 {
+            State::Crc32(hasher) => hasher.finalize(),
+            State::Crc32c(crc) => crc,
+            State::Xxh3(hasher) => hasher.digest() as u32,
+            State::None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const ALL: [Checksum; 4] = [
+        Checksum::Crc32,
+        Checksum::Crc32c,
+        Checksum::Xxh3,
+        Checksum::None,
+    ];
+
+    #[test]
+    fn ids_round_trip() {
+        for checksum in ALL {
+            assert_eq!(Checksum::from_id(checksum.id()), Some(checksum));
+        }
+        assert_eq!(Checksum::from_id(4), None);
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data = b"the trash bin where data goes to rot";
+        for checksum in ALL {
+            let mut hasher = checksum.hasher();
+            hasher.update(&data[..10]);
+            hasher.update(&data[10..]);
+            assert_eq!(hasher.finalize(), checksum.compute(data));
+        }
+        // Known check values for "123456789"
+        assert_eq!(Checksum::Crc32.compute(b"123456789"), 0xcbf43926);
+        assert_eq!(Checksum::Crc32c.compute(b"123456789"), 0xe3069283);
+        assert_eq!(Checksum::None.compute(data), 0);
+    }
+}
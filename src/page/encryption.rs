@@ -0,0 +1,305 @@
+/*
+Encryption of pages at rest. Encrypted sits in front of another store and seals every page
+written through it with an AEAD cipher, AES-256-GCM or XChaCha20-Poly1305, under the key it
+was set up with. The end of the page holds what it takes to open it again
+----------------------------------------------------------------------
+| encrypted page body | write counter (8 bytes) | tag (16 bytes) |
+----------------------------------------------------------------------
+Those RESERVED_TAIL bytes have to be unused by whatever writes the page, trees over a store
+that reserves_tail flag their nodes and end the content area before them.
+
+The nonce is the page number followed by the write counter, both little endian. The page
+number ties a page to its place, a page copied over another one fails to open. The counter
+keeps the nonces of rewrites of the same page apart, it starts at a random value whenever a
+store is set up and grows with every write, so sessions with the same key don't reuse nonces
+either.
+
+Only pages going through the store are encrypted. A Pager below it keeps its file header,
+catalog and freelist in the clear, and a WalStore below it logs sealed pages while one above
+it logs them in the clear. Anything reading the file directly, mmap snapshots and
+validate_file, sees ciphertext.
+*/
+
+use std::io;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+
+use super::{CacheStats, Page, PageStore};
+use crate::btree::RESERVED_TAIL;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+const COUNTER_SIZE: usize = 8;
+
+const _: () = assert!(COUNTER_SIZE + TAG_SIZE == RESERVED_TAIL as usize);
+
+/// An AEAD cipher pages are sealed with
+pub trait PageCipher {
+    /// Encrypts `data` in place, returning its tag
+    fn seal(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) -> [u8; TAG_SIZE];
+
+    /// Decrypts `data` in place, false if `tag` doesn't match it
+    fn open(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8], tag: &[u8; TAG_SIZE]) -> bool;
+}
+
+/// AES-256 in Galois/Counter Mode, fastest where the CPU has AES instructions
+pub struct Aes256Gcm(aes_gcm::Aes256Gcm);
+
+impl Aes256Gcm {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self(aes_gcm::Aes256Gcm::new(key.into()))
+    }
+}
+
+impl PageCipher for Aes256Gcm {
+    fn seal(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) -> [u8; TAG_SIZE] {
+        self.0
+            .encrypt_in_place_detached(nonce.into(), &[], data)
+            .expect("Pages are far below the GCM length limit")
+            .into()
+    }
+
+    fn open(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8], tag: &[u8; TAG_SIZE]) -> bool {
+        self.0
+            .decrypt_in_place_detached(nonce.into(), &[], data, tag.into())
+            .is_ok()
+    }
+}
+
+/// XChaCha20-Poly1305, constant time in software. The nonce is padded with zeros to its 24
+/// bytes.
+pub struct XChaCha20Poly1305(chacha20poly1305::XChaCha20Poly1305);
+
+impl XChaCha20Poly1305 {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self(chacha20poly1305::XChaCha20Poly1305::new(key.into()))
+    }
+
+    fn extend(nonce: &[u8; NONCE_SIZE]) -> chacha20poly1305::XNonce {
+        let mut extended = chacha20poly1305::XNonce::default();
+        extended[..NONCE_SIZE].copy_from_slice(nonce);
+        extended
+    }
+}
+
+impl PageCipher for XChaCha20Poly1305 {
+    fn seal(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) -> [u8; TAG_SIZE] {
+        self.0
+            .encrypt_in_place_detached(&Self::extend(nonce), &[], data)
+            .expect("Pages are far below the ChaCha length limit")
+            .into()
+    }
+
+    fn open(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8], tag: &[u8; TAG_SIZE]) -> bool {
+        self.0
+            .decrypt_in_place_detached(&Self::extend(nonce), &[], data, tag.into())
+            .is_ok()
+    }
+}
+
+/// Encrypts pages on their way into the inner store and decrypts them on their way out
+pub struct Encrypted<S, C> {
+    inner: S,
+    cipher: C,
+    /// Counter of the next write
+    counter: u64,
+}
+
+fn nonce(index: usize, counter: u64) -> Result<[u8; NONCE_SIZE], io::Error> {
+    let page = u32::try_from(index).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Page index too large for encryption",
+        )
+    })?;
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..4].copy_from_slice(&page.to_le_bytes());
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Ok(nonce)
+}
+
+impl<S: PageStore, C: PageCipher> Encrypted<S, C> {
+    /// Encrypts the pages of `inner` with `cipher`. Fails if the OS has no randomness to
+    /// start the write counter from.
+    pub fn new(inner: S, cipher: C) -> Result<Self, io::Error> {
+        let mut counter = [0; COUNTER_SIZE];
+        getrandom::fill(&mut counter)?;
+        Ok(Self {
+            inner,
+            cipher,
+            counter: u64::from_le_bytes(counter),
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn seal(&mut self, index: usize, page: &Page) -> Result<Page, io::Error> {
+        let mut sealed = page.clone();
+        let data = sealed.mutate();
+        let (body, tail) = data.split_at_mut(data.len() - RESERVED_TAIL as usize);
+        if tail.iter().any(|&byte| byte != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Page uses the tail reserved for encryption",
+            ));
+        }
+        let counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+        let tag = self.cipher.seal(&nonce(index, counter)?, body);
+        tail[..COUNTER_SIZE].copy_from_slice(&counter.to_le_bytes());
+        tail[COUNTER_SIZE..].copy_from_slice(&tag);
+        Ok(sealed)
+    }
+}
+
+impl<S: PageStore, C: PageCipher> PageStore for Encrypted<S, C> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    /// Fails with InvalidData if the page doesn't open, because the key is wrong or the page
+    /// was changed or moved
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let mut page = self.inner.read_page(index)?;
+        let data = page.mutate();
+        let (body, tail) = data.split_at_mut(data.len() - RESERVED_TAIL as usize);
+        let (counter, tag) = tail.split_at(COUNTER_SIZE);
+        let counter = u64::from_le_bytes(counter.try_into().expect("Split at its size"));
+        let tag = tag.try_into().expect("The rest of the tail is the tag");
+        if !self.cipher.open(&nonce(index, counter)?, body, tag) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Page {index} can't be decrypted, wrong key or tampered page"),
+            ));
+        }
+        tail.fill(0);
+        Ok(page)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        let sealed = self.seal(index, page)?;
+        self.inner.write_page(index, &sealed)
+    }
+
+    /// The page number is part of the nonce, so the page is appended blank and sealed once
+    /// its number is known
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let index = self.inner.append_page(&Page::new(self.page_size()))?;
+        self.write_page(index, page)?;
+        Ok(index)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        self.inner.release_page(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.inner.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.inner.reserved_pages()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.inner.sync()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        true
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, PAGE_SIZE};
+    use crate::page::MemoryStore;
+    use pretty_assertions::assert_eq;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+    fn page(fill: u8) -> Page {
+        let mut page = Page::new(PAGE_SIZE.into());
+        let body = PAGE_SIZE as usize - RESERVED_TAIL as usize;
+        page.mutate()[..body].fill(fill);
+        page
+    }
+
+    #[test]
+    fn pages_round_trip() {
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut encrypted = Encrypted::new(store, Aes256Gcm::new(&KEY)).unwrap();
+        let first = encrypted.append_page(&page(1)).unwrap();
+        let second = encrypted.append_page(&page(2)).unwrap();
+        assert_eq!(encrypted.read_page(first).unwrap().read(), page(1).read());
+        assert_eq!(encrypted.read_page(second).unwrap().read(), page(2).read());
+
+        // Stored sealed, a rewrite of the same content gets a fresh nonce
+        let mut store = encrypted.into_inner();
+        let sealed = store.read_page(first).unwrap();
+        assert!(!sealed.read().windows(16).any(|bytes| bytes == [1; 16]));
+        let mut encrypted = Encrypted::new(store, Aes256Gcm::new(&KEY)).unwrap();
+        encrypted.write_page(first, &page(1)).unwrap();
+        assert_ne!(
+            encrypted.inner.read_page(first).unwrap().read(),
+            sealed.read()
+        );
+
+        // Pages that use the tail are rejected
+        let mut full = page(1);
+        full.mutate().fill(1);
+        assert_eq!(
+            encrypted.write_page(first, &full).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn pages_only_open_with_their_key_and_place() {
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let mut encrypted = Encrypted::new(store, XChaCha20Poly1305::new(&KEY)).unwrap();
+        encrypted.append_page(&page(1)).unwrap();
+        encrypted.append_page(&page(2)).unwrap();
+        let mut store = encrypted.into_inner();
+
+        let mut wrong_key = Encrypted::new(store, XChaCha20Poly1305::new(&[8; KEY_SIZE])).unwrap();
+        let err = wrong_key.read_page(0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        store = wrong_key.into_inner();
+
+        // A page moved to another place
+        let moved = store.read_page(0).unwrap();
+        store.write_page(1, &moved).unwrap();
+        let mut encrypted = Encrypted::new(store, XChaCha20Poly1305::new(&KEY)).unwrap();
+        assert!(encrypted.read_page(0).is_ok());
+        assert!(encrypted.read_page(1).is_err());
+    }
+
+    #[test]
+    fn trees_leave_the_tail_to_the_store() {
+        let store = MemoryStore::new(PAGE_SIZE.into());
+        let encrypted = Encrypted::new(store, Aes256Gcm::new(&KEY)).unwrap();
+        let mut tree = BTree::create(encrypted).unwrap();
+        for key in 0..2000u64 {
+            tree.insert(key, &key.to_le_bytes().repeat(8)).unwrap();
+        }
+        let root = tree.root();
+
+        let store = tree.into_store().into_inner();
+        let encrypted = Encrypted::new(store, Aes256Gcm::new(&KEY)).unwrap();
+        let mut tree = BTree::open(encrypted, root).unwrap();
+        for key in (0..2000u64).step_by(7) {
+            assert_eq!(tree.get(key).unwrap(), Some(key.to_le_bytes().repeat(8)));
+        }
+        tree.delete(7).unwrap();
+        assert_eq!(tree.get(7).unwrap(), None);
+    }
+}
@@ -0,0 +1,159 @@
+/*
+The file header at the start of page 0 identifies a database file and says how to read it.
+Numbers are big endian.
+---------------------------------------------------------------------------------------------------------
+| magic (8 bytes) | page size (4 bytes) | freelist head (4 bytes) | free pages (4 bytes) | checksum (1 byte) |
+---------------------------------------------------------------------------------------------------------
+| catalog page (4 bytes) | format version (4 bytes) | page count (4 bytes) | root page (4 bytes) |
+-------------------------------------------------------------------------------------------------
+The checksum byte is the id of the checksum function chosen when the file was created. A
+catalog or root page of 0 means none was set. Files written before the format version was
+added have 0 there and the same layout as version 1, with the fields after it zeroed as well.
+*/
+
+use std::io;
+
+use super::Checksum;
+use crate::btree::check_page_size;
+
+pub const MAGIC: &[u8; 8] = b"e-binpgr";
+/// Version of the on-disk format written by this build
+pub const FORMAT_VERSION: u32 = 1;
+/// Bytes of page 0 the header takes up
+pub const FILE_HEADER_SIZE: usize = 37;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub format_version: u32,
+    pub page_size: u32,
+    /// Pages in the file, including page 0
+    pub page_count: u32,
+    pub freelist_head: u32,
+    pub free_pages: u32,
+    pub checksum: Checksum,
+    pub catalog_page: u32,
+    pub root_page: u32,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(
+        data[offset..offset + 4]
+            .try_into()
+            .expect("Shouldn't fail, hardcoded"),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl FileHeader {
+    /// Header of an empty file, which only has page 0
+    pub fn new(page_size: u32, checksum: Checksum) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            page_size,
+            page_count: 1,
+            freelist_head: 0,
+            free_pages: 0,
+            checksum,
+            catalog_page: 0,
+            root_page: 0,
+        }
+    }
+
+    /// Parses the header at the start of `data`, rejecting foreign files, newer format
+    /// versions and unsupported page sizes or checksum functions
+    pub fn read(data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < FILE_HEADER_SIZE || &data[..8] != MAGIC {
+            return Err(invalid_data("Not a database file"));
+        }
+        let format_version = read_u32(data, 25);
+        if format_version > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported format version {format_version}"),
+            ));
+        }
+        let page_size = read_u32(data, 8);
+        check_page_size(page_size as usize)
+            .map_err(|_| invalid_data("Database file has an unsupported page size"))?;
+        let checksum = Checksum::from_id(data[20]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unknown checksum function {}", data[20]),
+            )
+        })?;
+        Ok(Self {
+            format_version: FORMAT_VERSION,
+            page_size,
+            page_count: read_u32(data, 29),
+            freelist_head: read_u32(data, 12),
+            free_pages: read_u32(data, 16),
+            checksum,
+            catalog_page: read_u32(data, 21),
+            root_page: read_u32(data, 33),
+        })
+    }
+
+    /// Writes the header to the start of `data`, which is at least FILE_HEADER_SIZE long
+    pub fn write(&self, data: &mut [u8]) {
+        data[..8].copy_from_slice(MAGIC);
+        data[8..12].copy_from_slice(&self.page_size.to_be_bytes());
+        data[12..16].copy_from_slice(&self.freelist_head.to_be_bytes());
+        data[16..20].copy_from_slice(&self.free_pages.to_be_bytes());
+        data[20] = self.checksum.id();
+        data[21..25].copy_from_slice(&self.catalog_page.to_be_bytes());
+        data[25..29].copy_from_slice(&self.format_version.to_be_bytes());
+        data[29..33].copy_from_slice(&self.page_count.to_be_bytes());
+        data[33..37].copy_from_slice(&self.root_page.to_be_bytes());
+    }
+
+    /// Checks that every page the header points at exists in a file of `n_pages` pages. A file
+    /// shorter than the recorded page count was truncated, a longer one may have been cut off
+    /// between appending a page and recording it, which is harmless.
+    pub fn validate(&self, n_pages: usize) -> Result<(), io::Error> {
+        if (self.page_count as usize) > n_pages {
+            return Err(invalid_data("Database file is shorter than its page count"));
+        }
+        if self.freelist_head as usize >= n_pages || self.free_pages as usize >= n_pages {
+            return Err(invalid_data("Freelist points outside the database file"));
+        }
+        if self.catalog_page as usize >= n_pages {
+            return Err(invalid_data("Catalog points outside the database file"));
+        }
+        if self.root_page as usize >= n_pages {
+            return Err(invalid_data("Root points outside the database file"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn roundtrip_and_versions() {
+        let mut header = FileHeader::new(8192, Checksum::Xxh3);
+        header.root_page = 3;
+        header.page_count = 5;
+        let mut data = [0u8; 64];
+        header.write(&mut data);
+        assert_eq!(FileHeader::read(&data).unwrap(), header);
+        header.validate(5).unwrap();
+        assert!(header.validate(4).is_err());
+
+        // Files from before the format version read as version 1
+        data[25..29].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(FileHeader::read(&data).unwrap(), header);
+
+        data[25..29].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            FileHeader::read(&data).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert!(FileHeader::read(&data[..20]).is_err());
+    }
+}
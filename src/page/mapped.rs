@@ -0,0 +1,431 @@
+/*
+A page table between a tree and its store. Trees address logical pages, the table maps each to
+the physical page of the inner store that holds it. The first write to a page after a commit
+goes to a fresh physical page instead of overwriting the committed one, later writes before
+the next commit go to that fresh page again. Committing writes the table pages with changed
+entries, again to fresh pages, and then points a header at them. Parents keep addressing their
+children by logical page, so updating a leaf rewrites the leaf, one table page and the header
+instead of every node on its path.
+
+A crash before the header is durable leaves the last commit intact, pages written since then
+are unreferenced and free on the next open. The header has two slots on consecutive physical
+pages, a commit writes the one the last commit didn't, so a torn header write falls back to the
+other slot. Numbers are big endian.
+------------------------------------------------------------------------------------------
+| magic (8 bytes) | epoch (8 bytes) | logical pages (4 bytes) | table pages (4 bytes) |
+------------------------------------------------------------------------------------------
+| checksum (4 bytes) | physical page of each table page (4 bytes each) |
+--------------------------------------------------------------------
+The checksum covers every other byte of the header. Table pages hold the physical page of each
+logical one, 0 for a logical page that isn't mapped. Physical pages from the header slots on
+belong to the table, the inner store is meant to hold nothing else there.
+*/
+
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+
+use super::{CacheStats, Checksum, Page, PageStore, SharedRead};
+
+const MAGIC: &[u8; 8] = b"e-binmap";
+/// Bytes of a header slot before the physical pages of the table
+const HEADER_SIZE: usize = 28;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(
+        data[offset..offset + 4]
+            .try_into()
+            .expect("Shouldn't fail, hardcoded"),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A store that maps logical pages to physical pages of `S`, see the module docs
+pub struct Mapped<S> {
+    inner: S,
+    /// Physical page of the first header slot, the second follows it
+    header: usize,
+    epoch: u64,
+    /// Physical page of each logical one, 0 if unmapped
+    table: Vec<u32>,
+    /// Physical pages the table was written to by the last commit
+    table_pages: Vec<u32>,
+    /// Table pages with entries changed since the last commit
+    dirty: BTreeSet<usize>,
+    /// Physical pages written since the last commit, safe to overwrite until the next one
+    fresh: HashSet<u32>,
+    /// Physical pages the last commit doesn't reference
+    free: Vec<u32>,
+    /// Physical pages the last commit references but the next one won't, free after it
+    retired: Vec<u32>,
+    /// Logical pages without a physical page, reused before the table grows
+    unmapped: BTreeSet<usize>,
+}
+
+impl<S: PageStore> Mapped<S> {
+    /// Sets up an empty table at the end of `inner`
+    pub fn create(mut inner: S) -> Result<Self, io::Error> {
+        let empty = Page::new(inner.page_size());
+        let header = inner.append_page(&empty)?;
+        if inner.append_page(&empty)? != header + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Header slots of a page table have to be consecutive",
+            ));
+        }
+        let mut mapped = Self {
+            inner,
+            header,
+            epoch: 0,
+            table: Vec::new(),
+            table_pages: Vec::new(),
+            dirty: BTreeSet::new(),
+            fresh: HashSet::new(),
+            free: Vec::new(),
+            retired: Vec::new(),
+            unmapped: BTreeSet::new(),
+        };
+        mapped.write_header()?;
+        mapped.inner.sync()?;
+        Ok(mapped)
+    }
+
+    /// Opens the table whose first header slot is physical page `header`, as of its last
+    /// commit
+    pub fn open(mut inner: S, header: usize) -> Result<Self, io::Error> {
+        let slots = [
+            Self::read_header(&mut inner, header)?,
+            Self::read_header(&mut inner, header + 1)?,
+        ];
+        let (epoch, logical, table_pages) = slots
+            .into_iter()
+            .flatten()
+            .max_by_key(|(epoch, _, _)| *epoch)
+            .ok_or_else(|| invalid_data("Neither header slot of the page table is valid"))?;
+
+        let per_page = inner.page_size() / 4;
+        let mut table = Vec::with_capacity(logical);
+        for &page in &table_pages {
+            let page = inner.read_page(page as usize)?;
+            let entries = page.read().chunks_exact(4).map(|entry| read_u32(entry, 0));
+            table.extend(entries.take(per_page.min(logical - table.len())));
+        }
+        if table.len() != logical {
+            return Err(invalid_data("Page table is shorter than its logical pages"));
+        }
+
+        let n_pages = inner.n_pages()?;
+        let used: HashSet<u32> = table.iter().chain(&table_pages).copied().collect();
+        if used.iter().any(|&page| page as usize >= n_pages) {
+            return Err(invalid_data("Page table points outside the store"));
+        }
+        let free = (header + 2..n_pages)
+            .map(|page| page as u32)
+            .filter(|page| !used.contains(page))
+            .collect();
+        let unmapped = (0..table.len()).filter(|&i| table[i] == 0).collect();
+        Ok(Self {
+            inner,
+            header,
+            epoch,
+            table,
+            table_pages,
+            dirty: BTreeSet::new(),
+            fresh: HashSet::new(),
+            free,
+            retired: Vec::new(),
+            unmapped,
+        })
+    }
+
+    /// Physical page of the first header slot, what open needs
+    pub fn header_page(&self) -> usize {
+        self.header
+    }
+
+    /// Number of commits so far
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Epoch, logical pages and table pages in a header slot, `None` if it's torn or was never
+    /// written
+    fn read_header(
+        inner: &mut S,
+        slot: usize,
+    ) -> Result<Option<(u64, usize, Vec<u32>)>, io::Error> {
+        let page = inner.read_page(slot)?;
+        let data = page.read();
+        if &data[..8] != MAGIC {
+            return Ok(None);
+        }
+        let n_table = read_u32(data, 20) as usize;
+        let end = HEADER_SIZE + 4 * n_table;
+        if end > data.len() {
+            return Ok(None);
+        }
+        let mut hasher = Checksum::Crc32.hasher();
+        hasher.update(&data[..24]);
+        hasher.update(&data[HEADER_SIZE..end]);
+        if hasher.finalize() != read_u32(data, 24) {
+            return Ok(None);
+        }
+        let epoch = u64::from_be_bytes(data[8..16].try_into().expect("Hardcoded"));
+        let table_pages = (0..n_table)
+            .map(|i| read_u32(data, HEADER_SIZE + 4 * i))
+            .collect();
+        Ok(Some((epoch, read_u32(data, 16) as usize, table_pages)))
+    }
+
+    fn write_header(&mut self) -> Result<(), io::Error> {
+        let page_size = self.inner.page_size();
+        if HEADER_SIZE + 4 * self.table_pages.len() > page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "Page table outgrew its header",
+            ));
+        }
+        let mut page = Page::new(page_size);
+        let data = page.mutate();
+        data[..8].copy_from_slice(MAGIC);
+        data[8..16].copy_from_slice(&self.epoch.to_be_bytes());
+        data[16..20].copy_from_slice(&(self.table.len() as u32).to_be_bytes());
+        data[20..24].copy_from_slice(&(self.table_pages.len() as u32).to_be_bytes());
+        for (i, table_page) in self.table_pages.iter().enumerate() {
+            let offset = HEADER_SIZE + 4 * i;
+            data[offset..offset + 4].copy_from_slice(&table_page.to_be_bytes());
+        }
+        let end = HEADER_SIZE + 4 * self.table_pages.len();
+        let mut hasher = Checksum::Crc32.hasher();
+        hasher.update(&data[..24]);
+        hasher.update(&data[HEADER_SIZE..end]);
+        data[24..28].copy_from_slice(&hasher.finalize().to_be_bytes());
+        self.inner
+            .write_page(self.header + (self.epoch % 2) as usize, &page)
+    }
+
+    /// Writes `page` to a physical page the last commit doesn't reference
+    fn write_fresh(&mut self, page: &Page) -> Result<u32, io::Error> {
+        let physical = match self.free.pop() {
+            Some(physical) => {
+                self.inner.write_page(physical as usize, page)?;
+                physical
+            }
+            None => self.inner.append_page(page)? as u32,
+        };
+        self.fresh.insert(physical);
+        Ok(physical)
+    }
+
+    /// Gives up a physical page, right away if no commit references it
+    fn drop_physical(&mut self, physical: u32) {
+        match self.fresh.remove(&physical) {
+            true => self.free.push(physical),
+            false => self.retired.push(physical),
+        }
+    }
+
+    fn map(&mut self, logical: usize, physical: u32) {
+        self.table[logical] = physical;
+        self.dirty.insert(logical / (self.inner.page_size() / 4));
+    }
+
+    fn physical(&self, logical: usize) -> Result<u32, io::Error> {
+        match self.table.get(logical) {
+            Some(&physical) if physical != 0 => Ok(physical),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Page {logical} isn't mapped"),
+            )),
+        }
+    }
+}
+
+impl<S: PageStore> PageStore for Mapped<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let physical = self.physical(index)?;
+        self.inner.read_page(physical as usize)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        let physical = self.physical(index)?;
+        if self.fresh.contains(&physical) {
+            return self.inner.write_page(physical as usize, page);
+        }
+        let fresh = self.write_fresh(page)?;
+        self.retired.push(physical);
+        self.map(index, fresh);
+        Ok(())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let physical = self.write_fresh(page)?;
+        let logical = self.unmapped.pop_first().unwrap_or_else(|| {
+            self.table.push(0);
+            self.table.len() - 1
+        });
+        self.map(logical, physical);
+        Ok(logical)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        let physical = self.physical(index)?;
+        self.drop_physical(physical);
+        self.map(index, 0);
+        self.unmapped.insert(index);
+        Ok(true)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        Ok(self.table.len())
+    }
+
+    /// Logical pages without a physical page
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        Ok(self.unmapped.iter().copied().collect())
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.inner.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+
+    /// Commits: writes the changed table pages, makes them durable along with the pages
+    /// written since the last commit, then writes and syncs the header
+    fn sync(&mut self) -> Result<(), io::Error> {
+        if self.dirty.is_empty() {
+            return self.inner.sync();
+        }
+        let page_size = self.inner.page_size();
+        let per_page = page_size / 4;
+        for table_page in std::mem::take(&mut self.dirty) {
+            let mut page = Page::new(page_size);
+            let entries = self.table.iter().skip(table_page * per_page).take(per_page);
+            for (chunk, entry) in page.mutate().chunks_exact_mut(4).zip(entries) {
+                chunk.copy_from_slice(&entry.to_be_bytes());
+            }
+            let physical = self.write_fresh(&page)?;
+            match self.table_pages.get_mut(table_page) {
+                Some(old) => self.retired.push(std::mem::replace(old, physical)),
+                None => self.table_pages.push(physical),
+            }
+        }
+        self.inner.sync()?;
+
+        self.epoch += 1;
+        self.write_header()?;
+        self.inner.sync()?;
+        self.fresh.clear();
+        self.free.append(&mut self.retired);
+        Ok(())
+    }
+}
+
+impl<S: SharedRead> SharedRead for Mapped<S> {
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        self.inner.read_page_shared(self.physical(index)? as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BTree;
+    use crate::page::{MemoryStore, PageStoreExt, Pager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn updates_rewrite_the_leaf_not_its_path() {
+        let store = MemoryStore::new(4096).with_metrics();
+        let mut tree = BTree::create(Mapped::create(store).unwrap()).unwrap();
+        for key in 0..3000 {
+            tree.insert(key, &[key as u8; 500]).unwrap();
+        }
+        assert!(tree.depth().unwrap() > 2);
+        let root = tree.root();
+        let mut mapped = tree.into_store();
+        mapped.sync().unwrap();
+        mapped.inner.reset();
+
+        let mut tree = BTree::open(mapped, root).unwrap();
+        tree.insert(1234, &[7; 500]).unwrap();
+        tree.insert(1234, &[8; 500]).unwrap();
+        let mut mapped = tree.into_store();
+        mapped.sync().unwrap();
+        // Both updates go to the same fresh copy of the leaf, the commit adds one table page
+        // and the header, however deep the tree
+        let stats = mapped.inner.stats();
+        assert_eq!(stats.writes + stats.appends, 4);
+        assert_eq!(stats.syncs, 2);
+        let mut tree = BTree::open(mapped, root).unwrap();
+        assert_eq!(tree.get(1234).unwrap(), Some(vec![8; 500]));
+    }
+
+    #[test]
+    fn crashes_fall_back_to_the_last_commit() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let mut tree = BTree::create(Mapped::create(Pager::open(path).unwrap()).unwrap()).unwrap();
+        let (root, header) = (tree.root(), tree.store().header_page());
+        for key in 0..2000 {
+            tree.insert(key, &[1; 64]).unwrap();
+        }
+        let mut mapped = tree.into_store();
+        mapped.sync().unwrap();
+        let mut tree = BTree::open(mapped, root).unwrap();
+        for key in 0..3000 {
+            tree.insert(key, &[2; 64]).unwrap();
+        }
+        for key in 0..100 {
+            tree.delete(key).unwrap();
+        }
+        // Dies without committing, though everything written so far reached the file
+        let mut pager = tree.into_store().into_inner();
+        pager.sync().unwrap();
+        drop(pager);
+
+        let mapped = Mapped::open(Pager::open(path).unwrap(), header).unwrap();
+        assert_eq!(mapped.epoch(), 1);
+        let (physical, free) = (mapped.inner.n_pages().unwrap(), mapped.free.len());
+        assert!(free > 0);
+        let mut tree = BTree::open(mapped, root).unwrap();
+        assert_eq!(tree.get(0).unwrap(), Some(vec![1; 64]));
+        assert_eq!(tree.get(1999).unwrap(), Some(vec![1; 64]));
+        assert_eq!(tree.get(2000).unwrap(), None);
+
+        // Pages of the lost writes are reused rather than leaked
+        for key in 0..3000 {
+            tree.insert(key, &[3; 64]).unwrap();
+        }
+        let mut mapped = tree.into_store();
+        mapped.sync().unwrap();
+        assert!(mapped.inner.n_pages().unwrap() < physical + free);
+
+        // A torn header leaves the slot of the commit before
+        let torn = mapped.header + (mapped.epoch % 2) as usize;
+        let page_size = mapped.page_size();
+        mapped
+            .inner
+            .write_page(torn, &Page::from_vec(vec![0xab; page_size], page_size))
+            .unwrap();
+        let mapped = Mapped::open(mapped.into_inner(), header).unwrap();
+        assert_eq!(mapped.epoch(), 1);
+        let mut tree = BTree::open(mapped, root).unwrap();
+        assert_eq!(tree.get(1999).unwrap(), Some(vec![1; 64]));
+    }
+}
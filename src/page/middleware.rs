@@ -0,0 +1,563 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::{Page, PageStore};
+
+/// Decorators available on every store
+pub trait PageStoreExt: PageStore + Sized {
+    fn read_only(self) -> ReadOnly<Self> {
+        ReadOnly { inner: self }
+    }
+
+    fn with_metrics(self) -> Metrics<Self> {
+        Metrics {
+            inner: self,
+            stats: StoreStats::default(),
+        }
+    }
+
+    fn with_latency(self, latency: Latency) -> Delayed<Self> {
+        Delayed {
+            inner: self,
+            latency,
+        }
+    }
+
+    /// Keeps up to `capacity` pages in memory. Writes go through to the inner store.
+    fn with_cache(self, capacity: usize) -> Cached<Self> {
+        Cached {
+            inner: self,
+            capacity,
+            pages: HashMap::new(),
+            order: VecDeque::new(),
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+impl<S: PageStore> PageStoreExt for S {}
+
+/// Rejects every write with `PermissionDenied`
+pub struct ReadOnly<S> {
+    inner: S,
+}
+
+impl<S> ReadOnly<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Page store is read-only")
+}
+
+impl<S: PageStore> PageStore for ReadOnly<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        self.inner.read_page(index)
+    }
+
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        self.inner.read_pages(indices)
+    }
+
+    fn write_page(&mut self, _index: usize, _page: &Page) -> Result<(), io::Error> {
+        Err(read_only_error())
+    }
+
+    fn write_pages(&mut self, _pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        Err(read_only_error())
+    }
+
+    fn append_page(&mut self, _page: &Page) -> Result<usize, io::Error> {
+        Err(read_only_error())
+    }
+
+    fn release_page(&mut self, _index: usize) -> Result<bool, io::Error> {
+        Err(read_only_error())
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.inner.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.inner.reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.inner.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Reads served from a page cache and reads that missed it, see PageStore::cache_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StoreStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub appends: u64,
+    pub syncs: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Counts the operations that reach the inner store
+pub struct Metrics<S> {
+    inner: S,
+    stats: StoreStats,
+}
+
+impl<S> Metrics<S> {
+    pub fn stats(&self) -> StoreStats {
+        self.stats
+    }
+
+    pub fn reset(&mut self) {
+        self.stats = StoreStats::default();
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: PageStore> PageStore for Metrics<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let page = self.inner.read_page(index)?;
+        self.stats.reads += 1;
+        self.stats.bytes_read += page.read().len() as u64;
+        Ok(page)
+    }
+
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        let pages = self.inner.read_pages(indices)?;
+        for page in &pages {
+            self.stats.reads += 1;
+            self.stats.bytes_read += page.read().len() as u64;
+        }
+        Ok(pages)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.inner.write_page(index, page)?;
+        self.stats.writes += 1;
+        self.stats.bytes_written += page.read().len() as u64;
+        Ok(())
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        self.inner.write_pages(pages)?;
+        for (_, page) in pages {
+            self.stats.writes += 1;
+            self.stats.bytes_written += page.read().len() as u64;
+        }
+        Ok(())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let index = self.inner.append_page(page)?;
+        self.stats.appends += 1;
+        self.stats.bytes_written += page.read().len() as u64;
+        Ok(index)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        self.inner.release_page(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.inner.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.inner.reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.inner.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.inner.sync()?;
+        self.stats.syncs += 1;
+        Ok(())
+    }
+}
+
+/// Delays injected before operations reach the inner store
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Latency {
+    pub read: Duration,
+    pub write: Duration,
+    pub sync: Duration,
+}
+
+pub struct Delayed<S> {
+    inner: S,
+    latency: Latency,
+}
+
+impl<S> Delayed<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: PageStore> PageStore for Delayed<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        thread::sleep(self.latency.read);
+        self.inner.read_page(index)
+    }
+
+    /// A batch is delayed once, like a single read
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        thread::sleep(self.latency.read);
+        self.inner.read_pages(indices)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        thread::sleep(self.latency.write);
+        self.inner.write_page(index, page)
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        thread::sleep(self.latency.write);
+        self.inner.write_pages(pages)
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        thread::sleep(self.latency.write);
+        self.inner.append_page(page)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        thread::sleep(self.latency.write);
+        self.inner.release_page(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.inner.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.inner.reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.inner.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        thread::sleep(self.latency.sync);
+        self.inner.sync()
+    }
+}
+
+/// Write-through page cache evicting the page that was cached first
+pub struct Cached<S> {
+    inner: S,
+    capacity: usize,
+    pages: HashMap<usize, Page>,
+    order: VecDeque<usize>,
+    stats: CacheStats,
+}
+
+impl<S> Cached<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn cache(&mut self, index: usize, page: &Page) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.pages.insert(index, page.clone()).is_some() {
+            return;
+        }
+        self.order.push_back(index);
+        if self.order.len() > self.capacity {
+            let evicted = self.order.pop_front().expect("Order isn't empty");
+            self.pages.remove(&evicted);
+        }
+    }
+}
+
+impl<S: PageStore> PageStore for Cached<S> {
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        if let Some(page) = self.pages.get(&index) {
+            self.stats.hits += 1;
+            return Ok(page.clone());
+        }
+        self.stats.misses += 1;
+        let page = self.inner.read_page(index)?;
+        self.cache(index, &page);
+        Ok(page)
+    }
+
+    /// Pages missing from the cache are read from the inner store in one batch
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        let mut pages: Vec<Option<Page>> = indices
+            .iter()
+            .map(|index| self.pages.get(index).cloned())
+            .collect();
+        let missing: Vec<usize> = indices
+            .iter()
+            .zip(&pages)
+            .filter(|(_, page)| page.is_none())
+            .map(|(index, _)| *index)
+            .collect();
+        self.stats.hits += (indices.len() - missing.len()) as u64;
+        self.stats.misses += missing.len() as u64;
+        let mut read = Vec::new();
+        if !missing.is_empty() {
+            read = self.inner.read_pages(&missing)?;
+        }
+        for (index, page) in missing.iter().zip(&read) {
+            self.cache(*index, page);
+        }
+        let mut read = read.into_iter();
+        for page in &mut pages {
+            if page.is_none() {
+                *page = read.next();
+            }
+        }
+        Ok(pages
+            .into_iter()
+            .map(|page| page.expect("Every page was read"))
+            .collect())
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.inner.write_page(index, page)?;
+        self.cache(index, page);
+        Ok(())
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        self.inner.write_pages(pages)?;
+        for (index, page) in pages {
+            self.cache(*index, page);
+        }
+        Ok(())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let index = self.inner.append_page(page)?;
+        self.cache(index, page);
+        Ok(index)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        // The store may reuse the page's content, e.g. for its freelist
+        if self.pages.remove(&index).is_some() {
+            self.order.retain(|&cached| cached != index);
+        }
+        self.inner.release_page(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.inner.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        self.inner.reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        self.inner.reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MemoryStore;
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Instant;
+    const PAGESIZE: usize = 32;
+
+    fn page(byte: u8) -> Page {
+        Page::from_vec(vec![byte; PAGESIZE], PAGESIZE)
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let mut store = MemoryStore::new(PAGESIZE);
+        store.append_page(&page(1)).unwrap();
+
+        let mut store = store.read_only();
+        assert_eq!(
+            store.write_page(0, &page(2)).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(store.append_page(&page(2)).is_err());
+        assert_eq!(store.read_page(0).unwrap().read(), page(1).read());
+    }
+
+    #[test]
+    fn cache_serves_reads_and_stacks_with_metrics() {
+        let mut store = MemoryStore::new(PAGESIZE).with_metrics().with_cache(2);
+        for byte in 0..3 {
+            store.append_page(&page(byte)).unwrap();
+        }
+
+        // Page 0 was evicted, 1 and 2 are cached
+        for index in [1, 2, 1, 0] {
+            assert_eq!(
+                store.read_page(index).unwrap().read(),
+                page(index as u8).read()
+            );
+        }
+        store.write_page(2, &page(7)).unwrap();
+        assert_eq!(store.read_page(2).unwrap().read(), page(7).read());
+
+        let stats = store.into_inner().stats();
+        assert_eq!(
+            stats,
+            StoreStats {
+                reads: 1,
+                writes: 1,
+                appends: 3,
+                syncs: 0,
+                bytes_read: PAGESIZE as u64,
+                bytes_written: 4 * PAGESIZE as u64,
+            }
+        );
+    }
+
+    /// Counts the batches that reach it
+    struct Batches {
+        inner: MemoryStore,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl PageStore for Batches {
+        fn page_size(&self) -> usize {
+            PAGESIZE
+        }
+
+        fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+            self.inner.read_page(index)
+        }
+
+        fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+            self.reads += 1;
+            self.inner.read_pages(indices)
+        }
+
+        fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+            self.inner.write_page(index, page)
+        }
+
+        fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+            self.writes += 1;
+            self.inner.write_pages(pages)
+        }
+
+        fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+            self.inner.append_page(page)
+        }
+
+        fn n_pages(&self) -> Result<usize, io::Error> {
+            self.inner.n_pages()
+        }
+
+        fn sync(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn batches_reach_the_inner_store() {
+        let batches = Batches {
+            inner: MemoryStore::new(PAGESIZE),
+            reads: 0,
+            writes: 0,
+        };
+        let mut store = batches
+            .with_latency(Latency::default())
+            .with_metrics()
+            .with_cache(2);
+        for byte in 0..4 {
+            store.append_page(&page(byte)).unwrap();
+        }
+        store.write_pages(&[(0, &page(5)), (1, &page(6))]).unwrap();
+
+        // 0 and 1 are cached, 2 and 3 are read in one batch
+        let pages = store.read_pages(&[2, 0, 3, 1]).unwrap();
+        let bytes: Vec<u8> = pages.iter().map(|page| page.read()[0]).collect();
+        assert_eq!(bytes, vec![2, 5, 3, 6]);
+        assert_eq!(store.cache_stats(), CacheStats { hits: 2, misses: 2 });
+
+        let mut store = store.into_inner().read_only();
+        assert!(store.write_pages(&[(0, &page(7))]).is_err());
+        let store = store.into_inner();
+        assert_eq!(store.stats().reads, 2);
+        assert_eq!(store.stats().writes, 2);
+        let batches = store.into_inner().into_inner();
+        assert_eq!((batches.reads, batches.writes), (1, 1));
+    }
+
+    #[test]
+    fn latency_is_injected() {
+        let latency = Latency {
+            read: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut store = MemoryStore::new(PAGESIZE).with_latency(latency);
+        store.append_page(&page(1)).unwrap();
+
+        let started = Instant::now();
+        store.read_page(0).unwrap();
+        assert!(started.elapsed() >= latency.read);
+    }
+}
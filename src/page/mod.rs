@@ -3,6 +3,47 @@ use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::{self, Read, Seek, SeekFrom};
 
+#[cfg(feature = "mmap")]
+use memmap2::MmapMut;
+#[cfg(feature = "mmap")]
+use shared::ReaderTable;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use uring::Ring;
+
+#[cfg(feature = "async")]
+pub use asyncpager::AsyncPager;
+pub use catalog::{Catalog, TreeMeta};
+pub use checksum::{Checksum, ChecksumHasher};
+#[cfg(feature = "encryption")]
+pub use encryption::{Aes256Gcm, Encrypted, PageCipher, XChaCha20Poly1305};
+pub use header::{FileHeader, FORMAT_VERSION};
+pub use mapped::Mapped;
+pub use middleware::{
+    CacheStats, Cached, Delayed, Latency, Metrics, PageStoreExt, ReadOnly, StoreStats,
+};
+pub use pager::{HoleStats, Pager};
+#[cfg(feature = "mmap")]
+pub use shared::{ActiveReader, SharedReader, READER_SLOTS};
+pub use store::{MemoryStore, PageStore, SharedRead};
+
+#[cfg(feature = "async")]
+mod asyncpager;
+mod catalog;
+mod checksum;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod header;
+mod mapped;
+mod middleware;
+mod pager;
+mod platform;
+#[cfg(feature = "mmap")]
+mod shared;
+mod store;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
+#[derive(Clone)]
 pub struct Page {
     data: Vec<u8>,
 }
@@ -34,9 +75,34 @@ impl Page {
     }
 }
 
+/// How a PageManager moves pages between memory and its file
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// A system call per page
+    #[default]
+    File,
+    /// Copies from and to a writable memory map of the file
+    #[cfg(feature = "mmap")]
+    Mmap,
+    /// Batches of pages submitted to an io_uring, bypassing the page cache if `direct`
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring { direct: bool },
+}
+
+/// Pages of a file, read and written with file I/O or, once `map` was called, copied from and
+/// to a writable memory map of the file. The map covers the whole file and is redone when the
+/// file grows, shrinking or changing the file behind the manager's back isn't allowed while
+/// it's mapped.
 pub struct PageManager {
     pub file: File,
     pub page_size: usize,
+    #[cfg(feature = "mmap")]
+    map: Option<MmapMut>,
+    /// Reader table of the file once it's shared with SharedReaders
+    #[cfg(feature = "mmap")]
+    readers: Option<ReaderTable>,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    ring: Option<Ring>,
 }
 
 impl PageManager {
@@ -47,12 +113,132 @@ impl PageManager {
             .truncate(false)
             .create(true)
             .open(path)?;
-        Ok(Self { file, page_size })
+        Ok(Self {
+            file,
+            page_size,
+            #[cfg(feature = "mmap")]
+            map: None,
+            #[cfg(feature = "mmap")]
+            readers: None,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            ring: None,
+        })
+    }
+
+    /// Moves pages of the file at `path`, the one the manager was opened on, through
+    /// `backend` from now on
+    #[cfg_attr(
+        not(all(feature = "io-uring", target_os = "linux")),
+        allow(unused_variables)
+    )]
+    pub fn set_backend(&mut self, path: &str, backend: Backend) -> Result<(), io::Error> {
+        match backend {
+            Backend::File => {}
+            #[cfg(feature = "mmap")]
+            Backend::Mmap => self.map()?,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Backend::IoUring { direct } => self.ring = Some(Ring::open(path, direct)?),
+        }
+        Ok(())
+    }
+
+    /// Lets SharedReaders read the file at `path`, which this manager has to hold the lock
+    /// of. Writes keep them out from now on until the next sync.
+    #[cfg(feature = "mmap")]
+    pub fn share(&mut self, path: &str) -> Result<(), io::Error> {
+        let readers = ReaderTable::open(path, true)?;
+        readers.reset();
+        self.readers = Some(readers);
+        Ok(())
+    }
+
+    /// Readers of the file, empty unless it's shared
+    #[cfg(feature = "mmap")]
+    pub fn readers(&self) -> Vec<ActiveReader> {
+        self.readers
+            .as_ref()
+            .map_or_else(Vec::new, ReaderTable::readers)
+    }
+
+    /// Tells readers of a shared file that it's changing
+    fn begin_write(&self) {
+        #[cfg(feature = "mmap")]
+        if let Some(readers) = &self.readers {
+            readers.begin_write();
+        }
+    }
+
+    /// Serves pages from a writable memory map of the file from now on, which turns reads
+    /// and writes into copies without system calls
+    #[cfg(feature = "mmap")]
+    pub fn map(&mut self) -> Result<(), io::Error> {
+        // Safety: the mapping is only unsound if the file changes size or content behind
+        // it. Pagers lock their file against each other, and within this process the
+        // file is only written through this manager, which remaps after growing it.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn is_mapped(&self) -> bool {
+        self.map.is_some()
+    }
+
+    /// Page `index` as a slice of the map, writes to it go to the file without a copy.
+    /// `None` if the file isn't mapped or has no such page.
+    #[cfg(feature = "mmap")]
+    pub fn page_mut(&mut self, index: usize) -> Option<&mut [u8]> {
+        let start = index.checked_mul(self.page_size)?;
+        self.map.as_mut()?.get_mut(start..start + self.page_size)
+    }
+
+    /// Grows the mapped file to at least `n_pages` pages and maps it again
+    #[cfg(feature = "mmap")]
+    fn grow_map(&mut self, n_pages: usize) -> Result<(), io::Error> {
+        if self.n_pages()? >= n_pages {
+            return Ok(());
+        }
+        // Windows refuses to resize a file that is mapped
+        self.map = None;
+        self.file.set_len((n_pages * self.page_size) as u64)?;
+        self.map()
+    }
+
+    /// Makes every write so far durable, those made through the map included
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("sync").entered();
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            map.flush()?;
+        }
+        self.file.sync_data()?;
+        #[cfg(feature = "mmap")]
+        if let Some(readers) = &self.readers {
+            readers.end_write();
+        }
+        Ok(())
     }
 }
 
 impl PageManager {
     pub fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            let mut pages = ring.read(self.page_size, &[index])?;
+            return Ok(pages.pop().expect("One page was read"));
+        }
+        #[cfg(feature = "mmap")]
+        if self.map.is_some() {
+            let page_size = self.page_size;
+            return match self.page_mut(index) {
+                Some(data) => Ok(Page::from_vec(data.to_vec(), page_size)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Page is beyond the end of the file",
+                )),
+            };
+        }
         let mut buf = vec![0; self.page_size];
         let offset = (index * self.page_size)
             .try_into()
@@ -64,6 +250,26 @@ impl PageManager {
         Ok(Page::from_vec(buf, self.page_size))
     }
 
+    /// Like read_page, through a shared reference so that threads can read at once. Reads
+    /// from the map if there is one and from the file otherwise, also on an io_uring.
+    pub fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            let start = index.checked_mul(self.page_size);
+            let data = start.and_then(|start| map.get(start..start + self.page_size));
+            return match data {
+                Some(data) => Ok(Page::from_vec(data.to_vec(), self.page_size)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Page is beyond the end of the file",
+                )),
+            };
+        }
+        let mut buf = vec![0; self.page_size];
+        platform::read_exact_at(&self.file, &mut buf, (index * self.page_size) as u64)?;
+        Ok(Page::from_vec(buf, self.page_size))
+    }
+
     pub fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
         if page.read().len() != self.page_size {
             panic!(
@@ -72,6 +278,18 @@ impl PageManager {
                 self.page_size
             );
         }
+        self.begin_write();
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.write(self.page_size, &[(index, page)]);
+        }
+        #[cfg(feature = "mmap")]
+        if self.map.is_some() {
+            self.grow_map(index + 1)?;
+            let data = self.page_mut(index).expect("The map was grown to cover it");
+            data.copy_from_slice(page.read());
+            return Ok(());
+        }
         let offset = (index * self.page_size)
             .try_into()
             .expect("usize couldn't be converted into u64");
@@ -89,19 +307,117 @@ impl PageManager {
         }
         let filesize = self.file.metadata()?.len() as usize;
         let new_page_index = filesize / self.page_size;
+        self.begin_write();
 
+        #[cfg(feature = "mmap")]
+        if self.map.is_some() {
+            self.write_page(new_page_index, page)?;
+            return Ok(new_page_index);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.ring.is_some() {
+            self.write_page(new_page_index, page)?;
+            return Ok(new_page_index);
+        }
         self.file.seek(SeekFrom::End(0))?;
         self.file.write_all(page.read())?;
 
         Ok(new_page_index)
     }
 
+    /// Reads the pages at `indices`, in one batch on an io_uring
+    pub fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.read(self.page_size, indices);
+        }
+        indices.iter().map(|&index| self.read_page(index)).collect()
+    }
+
+    /// Writes every page to its index, in one batch on an io_uring
+    pub fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        if let Some((_, page)) = pages
+            .iter()
+            .find(|(_, page)| page.read().len() != self.page_size)
+        {
+            panic!(
+                "Tried write page with size {} when page size is set to {}",
+                page.read().len(),
+                self.page_size
+            );
+        }
+        self.begin_write();
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.write(self.page_size, pages);
+        }
+        for (index, page) in pages {
+            self.write_page(*index, page)?;
+        }
+        Ok(())
+    }
+
     pub fn n_pages(&self) -> Result<usize, io::Error> {
+        // The map covers the whole file, its length saves asking the file system
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            return Ok(map.len() / self.page_size);
+        }
         let filesize = self.file.metadata()?.len();
 
-        assert!(filesize as usize % self.page_size == 0);
+        assert!((filesize as usize).is_multiple_of(self.page_size));
         Ok(filesize as usize / self.page_size)
     }
+
+    /// Locks the file against other page managers until this one is dropped. Fails with
+    /// WouldBlock if another one holds the lock already.
+    pub fn lock(&self) -> Result<(), io::Error> {
+        platform::lock(&self.file)
+    }
+
+    /// Reserves disk space for the file to grow to `n_pages` pages, so appending them can't
+    /// run out of space halfway through. The file itself doesn't grow.
+    pub fn preallocate(&self, n_pages: usize) -> Result<(), io::Error> {
+        platform::preallocate(&self.file, (n_pages * self.page_size) as u64)
+    }
+
+    /// Cuts the file down to its first `n_pages` pages and makes the new length durable.
+    /// Shared files can't shrink, readers in other processes may map the pages cut off.
+    pub fn truncate(&mut self, n_pages: usize) -> Result<(), io::Error> {
+        #[cfg(feature = "mmap")]
+        if self.readers.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Shared files can't shrink",
+            ));
+        }
+        // Windows refuses to resize a file that is mapped
+        #[cfg(feature = "mmap")]
+        let mapped = self.map.take().is_some();
+        self.file.set_len((n_pages * self.page_size) as u64)?;
+        self.file.sync_all()?;
+        #[cfg(feature = "mmap")]
+        if mapped {
+            self.map()?;
+        }
+        Ok(())
+    }
+
+    /// Zeroes `count` pages from `index` on, returning their disk space to the file system
+    /// where it supports sparse files. Returns whether the space was released.
+    pub fn punch_hole(&mut self, index: usize, count: usize) -> Result<bool, io::Error> {
+        let offset = (index * self.page_size) as u64;
+        let len = (count * self.page_size) as u64;
+        self.begin_write();
+        if platform::punch_hole(&self.file, offset, len)? {
+            return Ok(true);
+        }
+        let zeroed = Page::new(self.page_size);
+        for index in index..index + count {
+            self.write_page(index, &zeroed)?;
+        }
+        Ok(false)
+    }
 }
 
 #[cfg(test)]
@@ -203,4 +519,72 @@ mod test {
 
         assert!(manager.read_page(3).is_err());
     }
+
+    #[test]
+    fn page_manager_lock() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let path = file_path.to_str().unwrap();
+        let manager = PageManager::new(path, PAGESIZE).unwrap();
+        manager.lock().unwrap();
+
+        let other = PageManager::new(path, PAGESIZE).unwrap();
+        assert_eq!(other.lock().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        drop(manager);
+        other.lock().unwrap();
+    }
+
+    #[test]
+    fn page_manager_punch_hole() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.preallocate(8).unwrap();
+        assert_eq!(manager.n_pages().unwrap(), 0);
+
+        for i in 0..4 {
+            let page = Page::from_vec(vec![i as u8 + 1; PAGESIZE], PAGESIZE);
+            manager.append_page(&page).unwrap();
+        }
+        manager.punch_hole(1, 2).unwrap();
+        assert_eq!(manager.n_pages().unwrap(), 4);
+        let contents: Vec<u8> = (0..4)
+            .map(|i| manager.read_page(i).unwrap().read()[0])
+            .collect();
+        assert_eq!(contents, vec![1, 0, 0, 4]);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn page_manager_mapped() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let path = file_path.to_str().unwrap();
+        let mut manager = PageManager::new(path, PAGESIZE).unwrap();
+        manager
+            .append_page(&Page::from_vec(vec![1; PAGESIZE], PAGESIZE))
+            .unwrap();
+        manager.map().unwrap();
+
+        // Appends and writes past the end grow the file and the map
+        manager
+            .append_page(&Page::from_vec(vec![2; PAGESIZE], PAGESIZE))
+            .unwrap();
+        manager
+            .write_page(4, &Page::from_vec(vec![5; PAGESIZE], PAGESIZE))
+            .unwrap();
+        assert_eq!(manager.n_pages().unwrap(), 5);
+        manager.page_mut(3).unwrap().fill(4);
+        assert!(manager.page_mut(5).is_none());
+        assert!(manager.read_page(5).is_err());
+        manager.sync().unwrap();
+
+        let contents: Vec<u8> = (0..5)
+            .map(|i| manager.read_page(i).unwrap().read()[0])
+            .collect();
+        assert_eq!(contents, vec![1, 2, 0, 4, 5]);
+        drop(manager);
+        let mut manager = PageManager::new(path, PAGESIZE).unwrap();
+        assert_eq!(manager.read_page(3).unwrap().read(), [4; PAGESIZE]);
+    }
 }
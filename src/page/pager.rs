@@ -0,0 +1,933 @@
+/*
+A database file made of pages of a size chosen at creation, PAGE_SIZE unless stated
+otherwise. Page 0 holds the file header, every other page is handed out by number. Released
+pages form a linked freelist and are reused before the file grows.
+Free page
+-----------------------------------------------------------------
+| next free page (4 bytes) | run length (4 bytes) | unused |
+-----------------------------------------------------------------
+A freelist head or next pointer of 0 ends the list, page 0 is never free. The run length
+counts the pages right after this one that are free as well, they are part of the entry and
+their contents are ignored. With hole punching on, runs of enough pages have the space of
+those pages returned to the file system, so the file stays the same size but takes up less
+disk. Files without runs have 0 there.
+*/
+
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::header::{FileHeader, FILE_HEADER_SIZE};
+#[cfg(feature = "mmap")]
+use super::ActiveReader;
+use super::{Backend, Catalog, Checksum, Page, PageManager, PageStore, SharedRead};
+#[cfg(feature = "mmap")]
+use crate::btree::MmapSnapshot;
+use crate::btree::{check_page_size, PAGE_SIZE};
+use crate::limits::ResourceLimits;
+
+const META_PAGE: u32 = 0;
+
+pub struct Pager {
+    pages: PageManager,
+    header: FileHeader,
+    /// Shortest run of free pages punched out of the file as pages are freed, if any
+    hole_punching: Option<u32>,
+    holes: HoleStats,
+}
+
+/// Free space returned to the file system since the pager was opened
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HoleStats {
+    /// Ranges of free pages punched out of the file
+    pub holes: u64,
+    /// Pages in those ranges
+    pub pages: u64,
+    /// Pages of those that were only zeroed, the file system has no sparse files
+    pub zeroed: u64,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(
+        data[offset..offset + 4]
+            .try_into()
+            .expect("Shouldn't fail, hardcoded"),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the file header of an existing file. Has to read the file directly, the page size
+/// isn't known before the header is parsed.
+fn read_header(pages: &mut PageManager) -> Result<FileHeader, io::Error> {
+    let mut data = [0; FILE_HEADER_SIZE];
+    pages.file.seek(SeekFrom::Start(0))?;
+    pages
+        .file
+        .read_exact(&mut data)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_data("Not a database file"),
+            _ => err,
+        })?;
+    FileHeader::read(&data)
+}
+
+impl Pager {
+    /// Opens the database file at `path`, creating it if it doesn't exist. The file stays
+    /// locked while the pager lives, a second pager on it fails with WouldBlock.
+    pub fn open(path: &str) -> Result<Self, io::Error> {
+        Self::open_with_limits(path, ResourceLimits::default())
+    }
+
+    /// Like `open`, moving pages through `backend` instead of a system call per page
+    pub fn open_with_backend(path: &str, backend: Backend) -> Result<Self, io::Error> {
+        let mut pager = Self::open(path)?;
+        pager.pages.set_backend(path, backend)?;
+        Ok(pager)
+    }
+
+    /// Like `open`, and lets SharedReaders in this and other processes read the file while
+    /// the pager writes it. They see the file as of the last sync.
+    #[cfg(feature = "mmap")]
+    pub fn open_shared(path: &str) -> Result<Self, io::Error> {
+        let mut pager = Self::open(path)?;
+        pager.pages.share(path)?;
+        Ok(pager)
+    }
+
+    /// Creates a new database file that uses `checksum`. Fails if the file exists already.
+    pub fn create(path: &str, checksum: Checksum) -> Result<Self, io::Error> {
+        Self::create_with_page_size(path, checksum, PAGE_SIZE.into())
+    }
+
+    /// Like `create`, but with pages of `page_size` bytes. The size is recorded in the file
+    /// header, so opening the file later picks it up.
+    pub fn create_with_page_size(
+        path: &str,
+        checksum: Checksum,
+        page_size: usize,
+    ) -> Result<Self, io::Error> {
+        check_page_size(page_size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported page size {page_size}"),
+            )
+        })?;
+        let pages = PageManager::new(path, page_size)?;
+        pages.lock()?;
+        if pages.n_pages()? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Database file exists already",
+            ));
+        }
+        Self::init(pages, checksum)
+    }
+
+    fn init(pages: PageManager, checksum: Checksum) -> Result<Self, io::Error> {
+        let header = FileHeader::new(pages.page_size as u32, checksum);
+        let mut pager = Self {
+            pages,
+            header,
+            hole_punching: None,
+            holes: HoleStats::default(),
+        };
+        pager.write_meta()?;
+        Ok(pager)
+    }
+
+    /// Opens a file that can't be trusted, rejecting it if it's larger than `limits` allow
+    pub fn open_with_limits(path: &str, limits: ResourceLimits) -> Result<Self, io::Error> {
+        let mut pages = PageManager::new(path, PAGE_SIZE.into())?;
+        pages.lock()?;
+        if pages.file.metadata()?.len() == 0 {
+            return Self::init(pages, Checksum::default());
+        }
+        let header = read_header(&mut pages)?;
+        pages.page_size = header.page_size as usize;
+        let n_pages = pages.n_pages()?;
+        if n_pages > limits.max_pages {
+            return Err(invalid_data("Database file has more pages than allowed"));
+        }
+        header.validate(n_pages)?;
+        Ok(Self {
+            pages,
+            header,
+            hole_punching: None,
+            holes: HoleStats::default(),
+        })
+    }
+
+    /// Reserves disk space for `pages` more pages past the end of the file, so a burst of
+    /// allocations can't fail halfway through for lack of space
+    pub fn reserve(&mut self, pages: usize) -> Result<(), io::Error> {
+        self.pages.preallocate(self.pages.n_pages()? + pages)
+    }
+
+    /// Page number of a zeroed page, taken from the freelist if possible
+    pub fn allocate(&mut self) -> Result<u32, io::Error> {
+        self.allocate_with(&Page::new(self.pages.page_size))
+    }
+
+    /// Stores `page` in a newly allocated page and returns its number
+    fn allocate_with(&mut self, page: &Page) -> Result<u32, io::Error> {
+        if self.header.freelist_head == 0 {
+            let page_no: u32 = self
+                .pages
+                .append_page(page)?
+                .try_into()
+                .map_err(|_| invalid_data("Database file is full"))?;
+            self.header.page_count = page_no + 1;
+            self.write_meta()?;
+            return Ok(page_no);
+        }
+
+        let page_no = self.header.freelist_head;
+        let (next, run) = self.free_entry(page_no)?;
+        let n_pages = self.pages.n_pages()? as u64;
+        // A corrupt freelist could hand out live pages over and over
+        if self.header.free_pages == 0
+            || next as u64 >= n_pages
+            || page_no as u64 + run as u64 >= n_pages
+        {
+            return Err(invalid_data("Freelist is corrupt"));
+        }
+        self.pages.write_page(page_no as usize, page)?;
+        if run == 0 {
+            self.header.freelist_head = next;
+        } else {
+            // The rest of the run becomes an entry of its own
+            self.write_free_entry(page_no + 1, next, run - 1)?;
+            self.header.freelist_head = page_no + 1;
+        }
+        self.header.free_pages -= 1;
+        self.write_meta()?;
+        Ok(page_no)
+    }
+
+    /// Puts `page_no` on the freelist. It must not be used afterwards.
+    pub fn free(&mut self, page_no: u32) -> Result<(), io::Error> {
+        self.check_page_no(page_no)?;
+
+        let extended = match self.hole_punching {
+            Some(min_pages) => self.extend_head_run(page_no, min_pages)?,
+            None => false,
+        };
+        if !extended {
+            self.write_free_entry(page_no, self.header.freelist_head, 0)?;
+            self.header.freelist_head = page_no;
+        }
+        self.header.free_pages += 1;
+        self.write_meta()
+    }
+
+    /// Adds `page_no` to the run at the head of the freelist if it's next to it, punching the
+    /// run out of the file once it's `min_pages` long
+    fn extend_head_run(&mut self, page_no: u32, min_pages: u32) -> Result<bool, io::Error> {
+        let head = self.header.freelist_head;
+        if head == 0 {
+            return Ok(false);
+        }
+        let (next, run) = self.free_entry(head)?;
+        let (first, tail_page) = if page_no as u64 == head as u64 + run as u64 + 1 {
+            (head, page_no)
+        } else if page_no + 1 == head {
+            (page_no, head)
+        } else {
+            return Ok(false);
+        };
+        self.write_free_entry(first, next, run + 1)?;
+        self.header.freelist_head = first;
+
+        let len = run + 2;
+        if len == min_pages.max(2) {
+            self.punch(first + 1, len - 1)?;
+        } else if len > min_pages {
+            self.punch(tail_page, 1)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the space of runs of at least `min_pages` free pages to the file system. The
+    /// freelist is sorted and regrouped into runs first, so pages freed in any order count.
+    /// Returns the number of pages punched out.
+    pub fn punch_holes(&mut self, min_pages: u32) -> Result<u64, io::Error> {
+        let min_pages = min_pages.max(2);
+        let runs = self.free_runs()?;
+        // Pages punched out by an earlier pass or as they were freed
+        let punched: HashSet<u32> = runs
+            .iter()
+            .filter(|&&(_, run)| run + 1 >= min_pages)
+            .flat_map(|&(first, run)| first + 1..=first + run)
+            .collect();
+
+        let free = runs
+            .iter()
+            .flat_map(|&(first, run)| first..=first + run)
+            .collect();
+        let groups = self.write_freelist(free)?;
+
+        let before = self.holes.pages;
+        for (first, run) in groups {
+            if run + 1 < min_pages {
+                continue;
+            }
+            let mut start = None;
+            for page_no in first + 1..=first + run + 1 {
+                let pending = page_no <= first + run && !punched.contains(&page_no);
+                match (start, pending) {
+                    (None, true) => start = Some(page_no),
+                    (Some(from), false) => {
+                        self.punch(from, page_no - from)?;
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(self.holes.pages - before)
+    }
+
+    /// Sorts the freelist, so that the lowest free pages are handed out first
+    pub fn sort_freelist(&mut self) -> Result<(), io::Error> {
+        let free = self.freelist()?;
+        self.write_freelist(free).map(drop)
+    }
+
+    /// Cuts the free pages at the end of the file off. The file is synced first, then the
+    /// header with the shorter page count and freelist, and only then truncated. A crash in
+    /// between leaves a file longer than its header says, which opens fine. Returns the
+    /// number of pages cut off.
+    pub fn truncate_free_tail(&mut self) -> Result<u32, io::Error> {
+        let mut free = self.freelist()?;
+        free.sort_unstable();
+        let n_pages = self.pages.n_pages()? as u32;
+        let mut end = n_pages;
+        while free.last() == Some(&(end - 1)) {
+            free.pop();
+            end -= 1;
+        }
+        if end == n_pages {
+            return Ok(0);
+        }
+        self.pages.sync()?;
+        self.write_freelist(free)?;
+        self.header.page_count = end;
+        self.write_meta()?;
+        self.pages.sync()?;
+        self.pages.truncate(end as usize)?;
+        Ok(n_pages - end)
+    }
+
+    /// Rewrites the freelist to hold the pages in `free`, sorted and grouped into runs.
+    /// Returns the runs.
+    fn write_freelist(&mut self, mut free: Vec<u32>) -> Result<Vec<(u32, u32)>, io::Error> {
+        free.sort_unstable();
+        if free.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(invalid_data("Freelist is corrupt"));
+        }
+        let free_pages = free.len() as u32;
+        let mut groups: Vec<(u32, u32)> = Vec::new();
+        for page_no in free {
+            match groups.last_mut() {
+                Some((first, run)) if *first + *run + 1 == page_no => *run += 1,
+                _ => groups.push((page_no, 0)),
+            }
+        }
+
+        // Lowest pages first, so they are reused before the ones further out
+        let mut next = 0;
+        for &(first, run) in groups.iter().rev() {
+            self.write_free_entry(first, next, run)?;
+            next = first;
+        }
+        self.header.freelist_head = next;
+        self.header.free_pages = free_pages;
+        self.write_meta()?;
+        Ok(groups)
+    }
+
+    /// Punches runs of at least `min_pages` free pages out of the file as pages are freed
+    /// next to each other, or stops with `None`. Off by default.
+    pub fn set_hole_punching(&mut self, min_pages: Option<u32>) {
+        self.hole_punching = min_pages;
+    }
+
+    /// Free space returned to the file system since the pager was opened
+    pub fn hole_stats(&self) -> HoleStats {
+        self.holes
+    }
+
+    fn punch(&mut self, first: u32, count: u32) -> Result<(), io::Error> {
+        let released = self.pages.punch_hole(first as usize, count as usize)?;
+        self.holes.holes += 1;
+        self.holes.pages += count as u64;
+        if !released {
+            self.holes.zeroed += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Next pointer and run length of the freelist entry at `page_no`
+    fn free_entry(&mut self, page_no: u32) -> Result<(u32, u32), io::Error> {
+        let page = self.pages.read_page(page_no as usize)?;
+        Ok((read_u32(page.read(), 0), read_u32(page.read(), 4)))
+    }
+
+    fn write_free_entry(&mut self, page_no: u32, next: u32, run: u32) -> Result<(), io::Error> {
+        let mut page = Page::new(self.pages.page_size);
+        page.mutate()[..4].copy_from_slice(&next.to_be_bytes());
+        page.mutate()[4..8].copy_from_slice(&run.to_be_bytes());
+        self.pages.write_page(page_no as usize, &page)
+    }
+
+    /// First page and run length of every freelist entry, in list order
+    fn free_runs(&mut self) -> Result<Vec<(u32, u32)>, io::Error> {
+        let free_pages = self.header.free_pages as u64;
+        let n_pages = self.pages.n_pages()? as u64;
+        let mut runs = Vec::new();
+        let mut listed = 0;
+        let mut page_no = self.header.freelist_head;
+        while page_no != 0 {
+            let (next, run) = self.free_entry(page_no)?;
+            listed += run as u64 + 1;
+            // The count bounds the walk, a corrupt list could be cyclic
+            if listed > free_pages || page_no as u64 + run as u64 >= n_pages {
+                return Err(invalid_data("Freelist is corrupt"));
+            }
+            runs.push((page_no, run));
+            page_no = next;
+        }
+        Ok(runs)
+    }
+
+    pub fn read(&mut self, page_no: u32) -> Result<Page, io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("pager_read", page = page_no).entered();
+        self.check_page_no(page_no)?;
+        self.pages.read_page(page_no as usize)
+    }
+
+    pub fn write(&mut self, page_no: u32, page: &Page) -> Result<(), io::Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("pager_write", page = page_no).entered();
+        self.check_page_no(page_no)?;
+        self.pages.write_page(page_no as usize, page)
+    }
+
+    /// Page numbers on the freelist, most recently freed first unless it was sorted
+    pub fn freelist(&mut self) -> Result<Vec<u32>, io::Error> {
+        Ok(self
+            .free_runs()?
+            .into_iter()
+            .flat_map(|(first, run)| first..=first + run)
+            .collect())
+    }
+
+    /// Names and metadata of the trees in the file, empty if no catalog was written yet
+    pub fn catalog(&mut self) -> Result<Catalog, io::Error> {
+        if self.header.catalog_page == 0 {
+            return Ok(Catalog::default());
+        }
+        Catalog::decode(
+            self.pages
+                .read_page(self.header.catalog_page as usize)?
+                .read(),
+        )
+    }
+
+    /// Replaces the catalog, allocating its page the first time
+    pub fn write_catalog(&mut self, catalog: &Catalog) -> Result<(), io::Error> {
+        let page_size = self.pages.page_size;
+        let page = Page::from_vec(catalog.encode(page_size)?, page_size);
+        if self.header.catalog_page == 0 {
+            self.header.catalog_page = self.allocate_with(&page)?;
+            return self.write_meta();
+        }
+        self.pages
+            .write_page(self.header.catalog_page as usize, &page)
+    }
+
+    /// The file header as last written
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Page the file's main tree is rooted at, 0 if none was set
+    pub fn root(&self) -> u32 {
+        self.header.root_page
+    }
+
+    /// Records the page the file's main tree is rooted at in the file header
+    pub fn set_root(&mut self, root: u32) -> Result<(), io::Error> {
+        self.check_page_no(root)?;
+        self.header.root_page = root;
+        self.write_meta()
+    }
+
+    /// Maps the file read-only to read the tree recorded in the file header without copying
+    /// any values. The pager can't write while the snapshot is alive.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_snapshot(&self) -> Result<MmapSnapshot<'_>, io::Error> {
+        MmapSnapshot::new(
+            self,
+            &self.pages.file,
+            self.pages.page_size,
+            self.header.root_page,
+        )
+    }
+
+    /// Reads and writes pages through a writable memory map of the file from now on,
+    /// instead of a system call per page
+    #[cfg(feature = "mmap")]
+    pub fn map_pages(&mut self) -> Result<(), io::Error> {
+        self.pages.map()
+    }
+
+    /// Data page `page_no` as a slice of the map, so a node can be loaded and changed in
+    /// place. Fails with Unsupported unless map_pages was called.
+    #[cfg(feature = "mmap")]
+    pub fn page_mut(&mut self, page_no: u32) -> Result<&mut [u8], io::Error> {
+        self.check_page_no(page_no)?;
+        self.pages
+            .page_mut(page_no as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "Pager isn't memory mapped"))
+    }
+
+    /// Readers of the file, empty unless it was opened with open_shared
+    #[cfg(feature = "mmap")]
+    pub fn readers(&self) -> Vec<ActiveReader> {
+        self.pages.readers()
+    }
+
+    /// Checksum function the file was created with
+    pub fn checksum(&self) -> Checksum {
+        self.header.checksum
+    }
+
+    /// Number of pages on the freelist
+    pub fn free_pages(&self) -> u32 {
+        self.header.free_pages
+    }
+
+    fn check_page_no(&self, page_no: u32) -> Result<(), io::Error> {
+        if page_no == META_PAGE || page_no as usize >= self.pages.n_pages()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Page {page_no} isn't a data page"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_meta(&mut self) -> Result<(), io::Error> {
+        let mut meta = Page::new(self.pages.page_size);
+        self.header.write(meta.mutate());
+        self.pages.write_page(META_PAGE as usize, &meta)
+    }
+}
+
+impl SharedRead for Pager {
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        self.check_page_no(index as u32)?;
+        self.pages.read_page_shared(index)
+    }
+}
+
+impl PageStore for Pager {
+    fn page_size(&self) -> usize {
+        self.pages.page_size
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        self.read(index as u32)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.write(index as u32, page)
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        Ok(self.allocate_with(page)? as usize)
+    }
+
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        for &index in indices {
+            self.check_page_no(index as u32)?;
+        }
+        self.pages.read_pages(indices)
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        for &(index, _) in pages {
+            self.check_page_no(index as u32)?;
+        }
+        self.pages.write_pages(pages)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        self.free(index as u32)?;
+        Ok(true)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        self.pages.n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        let mut reserved = vec![META_PAGE as usize];
+        if self.header.catalog_page != 0 {
+            reserved.push(self.header.catalog_page as usize);
+        }
+        reserved.extend(self.freelist()?.into_iter().map(|page_no| page_no as usize));
+        Ok(reserved)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.pages.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, Node};
+    use crate::page::{TreeMeta, FORMAT_VERSION};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn nodes_on_disk_pages() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let page_no = {
+            let mut pager = Pager::open(path).unwrap();
+            let page_no = pager.allocate().unwrap();
+            let mut page = pager.read(page_no).unwrap();
+            let mut node = Node::new(page.mutate()).unwrap();
+            node.insert(1, b"one").unwrap();
+            drop(node);
+            pager.write(page_no, &page).unwrap();
+            page_no
+        };
+
+        let mut pager = Pager::open(path).unwrap();
+        let mut page = pager.read(page_no).unwrap();
+        let node = Node::load(page.mutate()).unwrap();
+        assert_eq!(node.get(1).unwrap(), Some(b"one".as_slice()));
+        assert!(pager.read(0).is_err());
+        assert!(pager.read(page_no + 1).is_err());
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut pager = Pager::open(path).unwrap();
+            let allocated: Vec<_> = (0..4).map(|_| pager.allocate().unwrap()).collect();
+            assert_eq!(allocated, vec![1, 2, 3, 4]);
+            pager.free(2).unwrap();
+            pager.free(4).unwrap();
+            assert!(pager.free(0).is_err());
+        }
+
+        // The freelist survives reopening
+        let mut pager = Pager::open(path).unwrap();
+        assert_eq!(pager.free_pages(), 2);
+        assert_eq!(pager.freelist().unwrap(), vec![4, 2]);
+        assert_eq!(pager.allocate().unwrap(), 4);
+        assert_eq!(pager.allocate().unwrap(), 2);
+        assert!(pager.read(2).unwrap().read().iter().all(|&byte| byte == 0));
+        assert_eq!(pager.allocate().unwrap(), 5);
+        assert_eq!(pager.free_pages(), 0);
+    }
+
+    #[test]
+    fn freed_runs_are_punched() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut pager = Pager::open(path).unwrap();
+            pager.set_hole_punching(Some(4));
+            for _ in 0..10 {
+                let page_no = pager.allocate().unwrap();
+                let page = Page::from_vec(vec![page_no as u8; PAGE_SIZE.into()], PAGE_SIZE.into());
+                pager.write(page_no, &page).unwrap();
+            }
+            for page_no in 3..=8 {
+                pager.free(page_no).unwrap();
+            }
+            let stats = pager.hole_stats();
+            assert_eq!((stats.holes, stats.pages), (3, 5));
+            assert_eq!(pager.n_pages().unwrap(), 11);
+        }
+
+        let mut pager = Pager::open(path).unwrap();
+        assert_eq!(pager.free_pages(), 6);
+        assert_eq!(pager.freelist().unwrap(), vec![3, 4, 5, 6, 7, 8]);
+        assert!(pager.read(5).unwrap().read().iter().all(|&byte| byte == 0));
+        assert_eq!(pager.read(9).unwrap().read()[0], 9);
+        let allocated: Vec<_> = (0..7).map(|_| pager.allocate().unwrap()).collect();
+        assert_eq!(allocated, vec![3, 4, 5, 6, 7, 8, 11]);
+    }
+
+    #[test]
+    fn punch_holes_regroups_the_freelist() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let mut pager = Pager::open(file_path.to_str().unwrap()).unwrap();
+        for _ in 0..10 {
+            pager.allocate().unwrap();
+        }
+        for page_no in [5, 2, 7, 3, 9, 6, 4] {
+            pager.free(page_no).unwrap();
+        }
+
+        assert_eq!(pager.punch_holes(3).unwrap(), 5);
+        assert_eq!(pager.freelist().unwrap(), vec![2, 3, 4, 5, 6, 7, 9]);
+        assert_eq!(pager.hole_stats().holes, 1);
+        // Punched pages aren't punched again
+        assert_eq!(pager.punch_holes(3).unwrap(), 0);
+        assert_eq!(pager.allocate().unwrap(), 2);
+        assert_eq!(pager.freelist().unwrap(), vec![3, 4, 5, 6, 7, 9]);
+    }
+
+    #[test]
+    fn btree_releases_pages_to_the_freelist() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        for key in 0..1000 {
+            tree.delete(key).unwrap();
+        }
+        let root = tree.root();
+        let pager = tree.into_store();
+        assert_eq!(pager.free_pages() as usize, pager.n_pages().unwrap() - 2);
+        drop(pager);
+
+        let mut tree = BTree::open(Pager::open(path).unwrap(), root).unwrap();
+        // The meta page and the freelist aren't mistaken for husks
+        assert!(tree.husks().unwrap().is_empty());
+        tree.insert(7, b"seven").unwrap();
+        assert_eq!(tree.get(7).unwrap(), Some(b"seven".to_vec()));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_pages_reach_the_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut pager = Pager::open(path).unwrap();
+        assert_eq!(
+            pager.page_mut(1).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        pager.map_pages().unwrap();
+        let mut tree = BTree::create(pager).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        for key in (0..1000).step_by(3) {
+            tree.delete(key).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+
+        // A node changed in place, straight in the map
+        let free = pager.freelist().unwrap();
+        let leaf = (1..pager.n_pages().unwrap() as u32)
+            .filter(|page_no| !free.contains(page_no))
+            .find(|&page_no| {
+                let node = Node::load(pager.page_mut(page_no).unwrap()).unwrap();
+                node.is_leaf().unwrap() && node.read_header().unwrap().num_keys.get() > 0
+            })
+            .unwrap();
+        let mut node = Node::load(pager.page_mut(leaf).unwrap()).unwrap();
+        let key = node.key_at(0).unwrap();
+        node.insert(key, &[0xee; 100]).unwrap();
+        drop(node);
+        pager.sync().unwrap();
+        drop(pager);
+
+        let mut tree = BTree::open(Pager::open(path).unwrap(), root).unwrap();
+        assert_eq!(tree.get(key).unwrap(), Some(vec![0xee; 100]));
+        for key in (1..1000).filter(|key| key % 3 != 0) {
+            assert!(tree.get(key).unwrap().is_some());
+        }
+        assert_eq!(tree.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn catalog_survives_reopening() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+            tree.insert(1, b"one").unwrap();
+            let mut meta = TreeMeta::new(tree.root());
+            meta.schema_version = 3;
+            meta.properties
+                .insert("encoding".to_string(), b"utf-8".to_vec());
+
+            let root = tree.root();
+            let mut pager = tree.into_store();
+            assert!(pager.catalog().unwrap().is_empty());
+            let mut catalog = Catalog::default();
+            catalog.insert("users", meta);
+            pager.write_catalog(&catalog).unwrap();
+
+            catalog.get_mut("users").unwrap().root = root;
+            catalog.insert("empty", TreeMeta::new(root));
+            pager.write_catalog(&catalog).unwrap();
+        }
+
+        let mut pager = Pager::open(path).unwrap();
+        let catalog = pager.catalog().unwrap();
+        assert_eq!(catalog.len(), 2);
+        let users = catalog.get("users").unwrap();
+        assert_eq!(users.schema_version, 3);
+        assert_eq!(users.properties["encoding"], b"utf-8");
+
+        // The catalog page belongs to the pager, not to any tree
+        let mut tree = BTree::open(pager, users.root).unwrap();
+        assert_eq!(tree.get(1).unwrap(), Some(b"one".to_vec()));
+        assert!(tree.husks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn page_size_is_chosen_at_creation() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        assert!(Pager::create_with_page_size(path, Checksum::Crc32, 1000).is_err());
+        let pager = Pager::create_with_page_size(path, Checksum::Crc32, 16384).unwrap();
+        let mut tree = BTree::create(pager).unwrap();
+        for key in 0..500 {
+            tree.insert(key, &[key as u8; 100]).unwrap();
+        }
+        let root = tree.root();
+        drop(tree);
+
+        let pager = Pager::open(path).unwrap();
+        assert_eq!(pager.page_size(), 16384);
+        let n_pages = pager.n_pages().unwrap();
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            n_pages as u64 * 16384
+        );
+        let mut tree = BTree::open(pager, root).unwrap();
+        assert_eq!(tree.get(499).unwrap(), Some(vec![243; 100]));
+    }
+
+    #[test]
+    fn file_header_tracks_pages_and_root() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        {
+            let mut tree = BTree::create(Pager::open(path).unwrap()).unwrap();
+            for key in 0..300 {
+                tree.insert(key, &[key as u8; 100]).unwrap();
+            }
+            let root = tree.root();
+            let mut pager = tree.into_store();
+            pager.set_root(root).unwrap();
+            assert!(pager.set_root(1000).is_err());
+        }
+
+        let pager = Pager::open(path).unwrap();
+        let header = *pager.header();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.page_count as usize, pager.n_pages().unwrap());
+        let mut tree = BTree::open(pager, header.root_page).unwrap();
+        assert_eq!(tree.get(299).unwrap(), Some(vec![43; 100]));
+        drop(tree);
+
+        // A truncated file is caught before any page is read
+        let data = std::fs::read(path).unwrap();
+        std::fs::write(path, &data[..data.len() - PAGE_SIZE as usize]).unwrap();
+        assert_eq!(
+            Pager::open(path).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        std::fs::write(&file_path, vec![1; PAGE_SIZE as usize]).unwrap();
+        let err = Pager::open(file_path.to_str().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_freelists_are_rejected() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        {
+            let mut pager = Pager::open(path).unwrap();
+            pager.allocate().unwrap();
+            pager.free(1).unwrap();
+        }
+
+        // Point the only free page back at itself
+        let mut data = std::fs::read(path).unwrap();
+        data[PAGE_SIZE as usize..PAGE_SIZE as usize + 4].copy_from_slice(&1u32.to_be_bytes());
+        std::fs::write(path, &data).unwrap();
+        let mut pager = Pager::open(path).unwrap();
+        assert_eq!(pager.allocate().unwrap(), 1);
+        assert_eq!(
+            pager.allocate().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let limits = ResourceLimits {
+            max_pages: 1,
+            ..ResourceLimits::default()
+        };
+        assert!(Pager::open_with_limits(path, limits).is_err());
+    }
+
+    #[test]
+    fn checksum_is_chosen_at_creation() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        Pager::create(path, Checksum::Xxh3).unwrap();
+        assert_eq!(
+            Pager::create(path, Checksum::Crc32c).err().unwrap().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(Pager::open(path).unwrap().checksum(), Checksum::Xxh3);
+
+        let mut data = std::fs::read(path).unwrap();
+        data[20] = 42;
+        std::fs::write(path, &data).unwrap();
+        assert_eq!(
+            Pager::open(path).err().unwrap().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+}
@@ -0,0 +1,273 @@
+/*
+File operations the standard library doesn't cover the same way on every platform: locking a
+database file against other processes, reserving disk space ahead of writes, releasing the
+space of a byte range while keeping the file size and reading at an offset through a shared
+handle.
+
+Linux uses flock and fallocate, Windows LockFileEx, the allocation size of the file and sparse
+ranges. Other unix systems get flock only. Where a platform can't release space, punch_hole
+reports it and the caller zeroes the range instead, so freed pages read as zeros everywhere.
+
+Windows locks are mandatory, a lock on the whole file would block every read through another
+handle, mmap snapshots included. The lock covers a single byte far beyond any real file, the
+way SQLite's lock bytes do, which excludes other pagers and nothing else.
+*/
+
+use std::fs::File;
+use std::io;
+
+fn locked() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "Database file is locked by another pager",
+    )
+}
+
+/// Takes an exclusive lock on `file` that lasts until it's closed. Fails with WouldBlock
+/// instead of waiting if another handle holds it.
+pub(crate) fn lock(file: &File) -> io::Result<()> {
+    imp::lock(file)
+}
+
+/// Reserves disk space for the file to grow to `len` bytes without changing its size. Only a
+/// hint, does nothing where the platform or file system can't reserve space.
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    imp::preallocate(file, len)
+}
+
+/// Releases the disk space of `len` bytes at `offset`, which read as zeros afterwards. Returns
+/// false, changing nothing, if the platform or file system can't.
+pub(crate) fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    imp::punch_hole(file, offset, len)
+}
+
+/// Fills `buf` from `offset` on. Takes the file by shared reference, so threads can read
+/// through one handle at once. The file position is left alone on unix and moved on Windows,
+/// callers that read through it seek first anyway.
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::read_exact_at(file, buf, offset);
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.seek_read(&mut buf[filled..], offset + filled as u64) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (file, buf, offset);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(unix)]
+fn flock(file: &File) -> io::Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(std::fs::TryLockError::WouldBlock) => Err(locked()),
+        Err(std::fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    pub(super) use super::flock as lock;
+
+    fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<bool> {
+        let (Ok(offset), Ok(len)) = (offset.try_into(), len.try_into()) else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+        // Safety: fallocate only reads its arguments, the descriptor is open for as long as
+        // `file` lives
+        if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    pub(super) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+        let size = file.metadata()?.len();
+        if len > size {
+            fallocate(file, libc::FALLOC_FL_KEEP_SIZE, size, len - size)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        fallocate(file, mode, offset, len)
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod imp {
+    use std::fs::File;
+    use std::io;
+
+    pub(super) use super::flock as lock;
+
+    pub(super) fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::{mem, ptr};
+
+    use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_LOCK_VIOLATION, HANDLE};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAllocationInfo, LockFileEx, SetFileInformationByHandle, FILE_ALLOCATION_INFO,
+        LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+    };
+    use windows_sys::Win32::System::Ioctl::{
+        FILE_ZERO_DATA_INFORMATION, FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA,
+    };
+    use windows_sys::Win32::System::IO::{
+        DeviceIoControl, OVERLAPPED, OVERLAPPED_0, OVERLAPPED_0_0,
+    };
+
+    /// Offset of the lock byte, beyond the largest file with u32 page numbers
+    const LOCK_BYTE: u64 = 1 << 62;
+
+    fn handle(file: &File) -> HANDLE {
+        file.as_raw_handle()
+    }
+
+    pub(super) fn lock(file: &File) -> io::Result<()> {
+        let mut overlapped = OVERLAPPED {
+            Anonymous: OVERLAPPED_0 {
+                Anonymous: OVERLAPPED_0_0 {
+                    Offset: LOCK_BYTE as u32,
+                    OffsetHigh: (LOCK_BYTE >> 32) as u32,
+                },
+            },
+            ..Default::default()
+        };
+        let flags = LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY;
+        // Safety: the handle is open for as long as `file` lives, the lock is synchronous so
+        // `overlapped` only has to outlive the call
+        if unsafe { LockFileEx(handle(file), flags, 0, 1, 0, &mut overlapped) } != 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(code) if code as u32 == ERROR_LOCK_VIOLATION => Err(super::locked()),
+            _ => Err(err),
+        }
+    }
+
+    pub(super) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+        if len <= file.metadata()?.len() {
+            return Ok(());
+        }
+        let info = FILE_ALLOCATION_INFO {
+            AllocationSize: len as i64,
+        };
+        // Safety: `info` is a FILE_ALLOCATION_INFO of the size passed along
+        let done = unsafe {
+            SetFileInformationByHandle(
+                handle(file),
+                FileAllocationInfo,
+                ptr::from_ref(&info).cast(),
+                mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+            )
+        };
+        if done == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn control(
+        file: &File,
+        code: u32,
+        input: Option<&FILE_ZERO_DATA_INFORMATION>,
+    ) -> io::Result<bool> {
+        let (input, input_len) = match input {
+            Some(input) => (
+                ptr::from_ref(input).cast(),
+                mem::size_of::<FILE_ZERO_DATA_INFORMATION>() as u32,
+            ),
+            None => (ptr::null(), 0),
+        };
+        let mut returned = 0;
+        // Safety: the input buffer is valid for its length, there is no output buffer and
+        // the call is synchronous
+        let done = unsafe {
+            DeviceIoControl(
+                handle(file),
+                code,
+                input,
+                input_len,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        if done != 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // File systems without sparse files, FAT for one
+            Some(code) if code as u32 == ERROR_INVALID_FUNCTION => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    pub(super) fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+        if !control(file, FSCTL_SET_SPARSE, None)? {
+            return Ok(false);
+        }
+        let range = FILE_ZERO_DATA_INFORMATION {
+            FileOffset: offset as i64,
+            BeyondFinalZero: (offset + len) as i64,
+        };
+        control(file, FSCTL_SET_ZERO_DATA, Some(&range))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::fs::File;
+    use std::io;
+
+    pub(super) fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
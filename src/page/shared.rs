@@ -0,0 +1,402 @@
+/*
+Readers in other processes. A pager holds the lock of its file, so no second pager opens it,
+but any number of SharedReaders can, in this process or others. They map the file read-only
+and coordinate with the writer through a lock file next to it, `<path>-lock`, mapped shared
+by everyone
+--------------------------------------------------------------------------
+| magic (8 bytes) | generation (8 bytes) | reader slots (64 x 16 bytes) |
+--------------------------------------------------------------------------
+Reader slot
+-------------------------------------------------------
+| pid (4 bytes) | unused (4 bytes) | generation (8 bytes) |
+-------------------------------------------------------
+Integers are native endian, they are atomics shared through the map and the lock file never
+leaves the machine.
+
+The generation is a sequence lock. A writer opened with Pager::open_shared makes it odd with
+its first write after a sync and even again once the next sync is done, so it is odd exactly
+while the file holds writes that don't belong together yet. Readers read while it's even and
+check it didn't move afterwards, retrying if it did. Nodes are updated in place, there is no
+older version of a page to read instead, so a reader that finds a write in progress fails
+with WouldBlock instead of waiting for a writer that may be idle. A writer that crashed with
+the generation odd leaves it odd until the next writer opens the file.
+
+A reader takes a slot by swapping its pid into a free one, and records the generation it
+reads at there, so the writer can list who reads what. Slots of processes that no longer
+exist count as free. Where that can't be checked, off unix, slots are only freed by their
+readers.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use memmap2::{Mmap, MmapMut};
+
+use super::header::{FileHeader, FILE_HEADER_SIZE};
+use crate::btree::{get_in, BTreeError, MmapIter};
+use crate::limits::ResourceLimits;
+
+const MAGIC: &[u8; 8] = b"e-binlck";
+const GENERATION_OFFSET: usize = 8;
+const SLOTS_OFFSET: usize = 16;
+const SLOT_SIZE: usize = 16;
+pub const READER_SLOTS: usize = 64;
+const TABLE_SIZE: usize = SLOTS_OFFSET + READER_SLOTS * SLOT_SIZE;
+/// Generation of a slot whose reader isn't reading
+const IDLE: u64 = u64::MAX;
+
+/// A reader in the table of a shared file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActiveReader {
+    pub pid: u32,
+    /// Generation of the file the reader is reading, `None` between reads
+    pub generation: Option<u64>,
+}
+
+fn lock_path(path: &str) -> String {
+    format!("{path}-lock")
+}
+
+fn write_in_progress() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "The writer is changing the file")
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Safety: signal 0 only checks whether the process exists and may be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+/// The lock file of a database file, see the top of the file
+pub(crate) struct ReaderTable {
+    map: MmapMut,
+    /// Whether the table belongs to the writer, which leaves the generation even when dropped
+    writer: bool,
+}
+
+impl ReaderTable {
+    /// Opens the lock file of the database file at `path`, creating it if needed
+    pub(crate) fn open(path: &str, writer: bool) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(lock_path(path))?;
+        // Only ever grows and zero fills, so processes racing to set it up agree
+        if file.metadata()?.len() < TABLE_SIZE as u64 {
+            file.set_len(TABLE_SIZE as u64)?;
+        }
+        // Safety: the lock file is only changed through atomics on shared maps like this
+        // one and never shrinks
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        if map[..MAGIC.len()] == [0; MAGIC.len()] {
+            map[..MAGIC.len()].copy_from_slice(MAGIC);
+        } else if &map[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a lock file of a database file",
+            ));
+        }
+        Ok(Self { map, writer })
+    }
+
+    fn atomic_u64(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset + 8 <= TABLE_SIZE && offset.is_multiple_of(8));
+        // Safety: the map is page aligned and outlives the reference, the offset is aligned
+        // and in bounds, and every process only accesses the word atomically
+        unsafe { AtomicU64::from_ptr(self.map.as_ptr().add(offset).cast_mut().cast()) }
+    }
+
+    fn atomic_u32(&self, offset: usize) -> &AtomicU32 {
+        assert!(offset + 4 <= TABLE_SIZE && offset.is_multiple_of(4));
+        // Safety: like atomic_u64
+        unsafe { AtomicU32::from_ptr(self.map.as_ptr().add(offset).cast_mut().cast()) }
+    }
+
+    fn generation(&self) -> &AtomicU64 {
+        self.atomic_u64(GENERATION_OFFSET)
+    }
+
+    fn slot(&self, slot: usize) -> (&AtomicU32, &AtomicU64) {
+        let offset = SLOTS_OFFSET + slot * SLOT_SIZE;
+        (self.atomic_u32(offset), self.atomic_u64(offset + 8))
+    }
+
+    /// Called by a new writer, ends the writes a crashed one never finished
+    pub(crate) fn reset(&self) {
+        self.end_write();
+    }
+
+    /// Makes the generation odd, if it isn't already, before the writer changes the file
+    pub(crate) fn begin_write(&self) {
+        let generation = self.generation();
+        let current = generation.load(Ordering::Relaxed);
+        if current.is_multiple_of(2) {
+            generation.store(current + 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+        }
+    }
+
+    /// Makes the generation even again once the writer's changes are complete
+    pub(crate) fn end_write(&self) {
+        let generation = self.generation();
+        let current = generation.load(Ordering::Relaxed);
+        if !current.is_multiple_of(2) {
+            generation.store(current + 1, Ordering::Release);
+        }
+    }
+
+    /// Takes a free slot for this process
+    fn claim(&self) -> Result<usize, io::Error> {
+        let pid = std::process::id();
+        for slot in 0..READER_SLOTS {
+            let (owner, generation) = self.slot(slot);
+            let current = owner.load(Ordering::Relaxed);
+            if current != 0 && is_alive(current) {
+                continue;
+            }
+            if owner
+                .compare_exchange(current, pid, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                generation.store(IDLE, Ordering::Relaxed);
+                return Ok(slot);
+            }
+        }
+        Err(io::Error::other("Every reader slot is taken"))
+    }
+
+    fn release(&self, slot: usize) {
+        let (owner, generation) = self.slot(slot);
+        generation.store(IDLE, Ordering::Relaxed);
+        owner.store(0, Ordering::Release);
+    }
+
+    /// Starts a read in `slot`, returning the generation read at. Fails with WouldBlock
+    /// while a write is in progress.
+    fn begin_read(&self, slot: usize) -> Result<u64, io::Error> {
+        let generation = self.generation().load(Ordering::Acquire);
+        if !generation.is_multiple_of(2) {
+            return Err(write_in_progress());
+        }
+        self.slot(slot).1.store(generation, Ordering::Relaxed);
+        Ok(generation)
+    }
+
+    /// Ends the read started at `generation`, false if the file changed in the meantime
+    fn end_read(&self, slot: usize, generation: u64) -> bool {
+        fence(Ordering::Acquire);
+        self.slot(slot).1.store(IDLE, Ordering::Relaxed);
+        self.generation().load(Ordering::Relaxed) == generation
+    }
+
+    /// The readers holding a slot
+    pub(crate) fn readers(&self) -> Vec<ActiveReader> {
+        (0..READER_SLOTS)
+            .filter_map(|slot| {
+                let (owner, generation) = self.slot(slot);
+                let pid = owner.load(Ordering::Acquire);
+                let generation = generation.load(Ordering::Relaxed);
+                (pid != 0 && is_alive(pid)).then_some(ActiveReader {
+                    pid,
+                    generation: (generation != IDLE).then_some(generation),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for ReaderTable {
+    fn drop(&mut self) {
+        if self.writer {
+            self.end_write();
+        }
+    }
+}
+
+/// A read-only view of a database file that another process, or a pager in this one, may be
+/// writing. Values are copied out of the map, the writer may change it right after a read.
+pub struct SharedReader {
+    file: File,
+    map: Mmap,
+    table: ReaderTable,
+    slot: usize,
+    page_size: usize,
+    max_depth: usize,
+}
+
+impl SharedReader {
+    /// Opens the database file at `path` for reading and takes a slot in its reader table.
+    /// Fails if all READER_SLOTS are taken by live readers.
+    pub fn open(path: &str) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        // Safety: the map is only read, through copies that are thrown away unless the
+        // generation shows the writer left the file alone while they were made
+        let map = unsafe { Mmap::map(&file)? };
+        let header =
+            FileHeader::read(map.get(..FILE_HEADER_SIZE).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Not a database file")
+            })?)?;
+        let table = ReaderTable::open(path, false)?;
+        let slot = table.claim()?;
+        Ok(Self {
+            file,
+            map,
+            table,
+            slot,
+            page_size: header.page_size as usize,
+            max_depth: ResourceLimits::default().max_depth,
+        })
+    }
+
+    /// Runs `read` on the header and the map of a file no writer is changing, remapping
+    /// first if the file grew
+    fn read<T>(
+        &mut self,
+        read: impl Fn(&FileHeader, &[u8]) -> Result<T, BTreeError>,
+    ) -> Result<T, BTreeError> {
+        loop {
+            let generation = self.table.begin_read(self.slot)?;
+            let result = self.read_mapped(&read);
+            if self.table.end_read(self.slot, generation) {
+                return result;
+            }
+        }
+    }
+
+    fn read_mapped<T>(
+        &mut self,
+        read: &impl Fn(&FileHeader, &[u8]) -> Result<T, BTreeError>,
+    ) -> Result<T, BTreeError> {
+        let header = FileHeader::read(&self.map[..FILE_HEADER_SIZE])?;
+        if header.page_count as usize * self.page_size > self.map.len() {
+            // Safety: like the map made in open
+            self.map = unsafe { Mmap::map(&self.file)? };
+        }
+        read(&header, &self.map)
+    }
+
+    /// Root of the tree recorded in the file header
+    pub fn root(&mut self) -> Result<u32, BTreeError> {
+        self.read(|header, _| Ok(header.root_page))
+    }
+
+    /// Looks `key` up in the tree rooted at `root`
+    pub fn get(&mut self, root: u32, key: u64) -> Result<Option<Vec<u8>>, BTreeError> {
+        let (page_size, max_depth) = (self.page_size, self.max_depth);
+        self.read(|_, map| Ok(get_in(map, page_size, root, max_depth, key)?.map(<[u8]>::to_vec)))
+    }
+
+    /// Every entry of the tree rooted at `root`, in key order, read at a single generation
+    pub fn entries(&mut self, root: u32) -> Result<Vec<(u64, Vec<u8>)>, BTreeError> {
+        let (page_size, max_depth) = (self.page_size, self.max_depth);
+        self.read(|_, map| {
+            MmapIter::new(map, page_size, root, max_depth)
+                .map(|entry| entry.map(|(key, value)| (key, value.to_vec())))
+                .collect()
+        })
+    }
+}
+
+impl Drop for SharedReader {
+    fn drop(&mut self) {
+        self.table.release(self.slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BTree;
+    use crate::page::{PageStore, Pager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn readers_see_synced_writes_only() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+
+        let mut tree = BTree::create(Pager::open_shared(path).unwrap()).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, &[key as u8; 40]).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+        pager.sync().unwrap();
+
+        let mut reader = SharedReader::open(path).unwrap();
+        let mut other = SharedReader::open(path).unwrap();
+        assert_eq!(reader.root().unwrap(), root);
+        assert_eq!(reader.get(root, 10).unwrap(), Some(vec![10; 40]));
+        assert_eq!(other.get(root, 1000).unwrap(), None);
+        let pid = std::process::id();
+        let idle = ActiveReader {
+            pid,
+            generation: None,
+        };
+        assert_eq!(pager.readers(), [idle, idle]);
+        drop(other);
+        assert_eq!(pager.readers(), [idle]);
+
+        // Unsynced writes keep readers out, the file grows past their map meanwhile
+        let mut tree = BTree::open(pager, root).unwrap();
+        for key in 1000..3000 {
+            tree.insert(key, &[key as u8; 40]).unwrap();
+        }
+        let err = reader.get(root, 10).unwrap_err();
+        assert!(matches!(err, BTreeError::Io(err) if err.kind() == io::ErrorKind::WouldBlock));
+        let mut pager = tree.into_store();
+        pager.sync().unwrap();
+        let entries = reader.entries(root).unwrap();
+        assert_eq!(entries.len(), 3000);
+        assert_eq!(entries[2999], (2999, vec![2999u16 as u8; 40]));
+
+        // A second writer is still locked out
+        assert_eq!(
+            Pager::open_shared(path).err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slots_of_dead_readers_are_taken_over() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let mut pager = Pager::open_shared(path).unwrap();
+        pager.sync().unwrap();
+
+        // Above any pid the kernel hands out
+        let dead = i32::MAX as u32;
+        let table = ReaderTable::open(path, false).unwrap();
+        for slot in 0..READER_SLOTS {
+            table.slot(slot).0.store(dead, Ordering::Relaxed);
+        }
+        assert_eq!(pager.readers(), []);
+        let reader = SharedReader::open(path).unwrap();
+        assert_eq!(reader.slot, 0);
+
+        let pid = std::process::id();
+        for slot in 0..READER_SLOTS {
+            table.slot(slot).0.store(pid, Ordering::Relaxed);
+        }
+        assert!(SharedReader::open(path).is_err());
+    }
+}
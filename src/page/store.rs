@@ -0,0 +1,248 @@
+use std::io;
+
+use super::{CacheStats, Page, PageManager};
+
+/// Storage the database keeps its pages in. Decorators from the `middleware` module wrap
+/// any store, so custom setups can be layered without touching the store itself.
+pub trait PageStore {
+    fn page_size(&self) -> usize;
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error>;
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error>;
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error>;
+    /// Reads the pages at `indices`. Stores that can batch reads, like a Pager on an
+    /// io_uring, do them at once.
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        indices.iter().map(|&index| self.read_page(index)).collect()
+    }
+    /// Writes every page to its index, at once where the store can batch writes
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        for (index, page) in pages {
+            self.write_page(*index, page)?;
+        }
+        Ok(())
+    }
+    /// Hands back a page that is no longer used. Returns false if the store doesn't track
+    /// free pages, in which case the caller has to remember the page for reuse itself.
+    fn release_page(&mut self, _index: usize) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    fn n_pages(&self) -> Result<usize, io::Error>;
+    /// Pages the store keeps for itself, like its metadata or the pages on its freelist
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        Ok(Vec::new())
+    }
+    /// Makes every write so far durable
+    fn sync(&mut self) -> Result<(), io::Error>;
+    /// Whether the store keeps the last RESERVED_TAIL bytes of every page for itself, like
+    /// Encrypted does for nonces and tags. Trees over it leave them unused.
+    fn reserves_tail(&self) -> bool {
+        false
+    }
+    /// Reads the store's page cache served and missed, none for stores without a cache
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+}
+
+/// Stores that can read pages through a shared reference, so that readers on many threads
+/// don't have to take turns
+pub trait SharedRead: PageStore {
+    /// Reads page `index` like read_page
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error>;
+}
+
+impl SharedRead for PageManager {
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        PageManager::read_page_shared(self, index)
+    }
+}
+
+impl PageStore for PageManager {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        PageManager::read_page(self, index)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        PageManager::write_page(self, index, page)
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        PageManager::append_page(self, page)
+    }
+
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        PageManager::read_pages(self, indices)
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        PageManager::write_pages(self, pages)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        PageManager::n_pages(self)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        PageManager::sync(self)
+    }
+}
+
+impl<S: PageStore + ?Sized> PageStore for Box<S> {
+    fn page_size(&self) -> usize {
+        (**self).page_size()
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        (**self).read_page(index)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        (**self).write_page(index, page)
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        (**self).append_page(page)
+    }
+
+    fn read_pages(&mut self, indices: &[usize]) -> Result<Vec<Page>, io::Error> {
+        (**self).read_pages(indices)
+    }
+
+    fn write_pages(&mut self, pages: &[(usize, &Page)]) -> Result<(), io::Error> {
+        (**self).write_pages(pages)
+    }
+
+    fn release_page(&mut self, index: usize) -> Result<bool, io::Error> {
+        (**self).release_page(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        (**self).n_pages()
+    }
+
+    fn reserved_pages(&mut self) -> Result<Vec<usize>, io::Error> {
+        (**self).reserved_pages()
+    }
+
+    fn reserves_tail(&self) -> bool {
+        (**self).reserves_tail()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        (**self).cache_stats()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        (**self).sync()
+    }
+}
+
+/// Pages kept in memory only, mostly useful for tests
+pub struct MemoryStore {
+    pages: Vec<Page>,
+    page_size: usize,
+}
+
+impl MemoryStore {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            pages: Vec::new(),
+            page_size,
+        }
+    }
+
+    fn check_size(&self, page: &Page) {
+        if page.read().len() != self.page_size {
+            panic!(
+                "Tried storing page with size {} when page size is set to {}",
+                page.read().len(),
+                self.page_size
+            );
+        }
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        self.read_page_shared(index)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.check_size(page);
+        // Like a file, writing past the end fills the gap with zeroed pages
+        while self.pages.len() <= index {
+            self.pages.push(Page::new(self.page_size));
+        }
+        self.pages[index] = page.clone();
+        Ok(())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        self.check_size(page);
+        self.pages.push(page.clone());
+        Ok(self.pages.len() - 1)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        Ok(self.pages.len())
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl SharedRead for MemoryStore {
+    fn read_page_shared(&self, index: usize) -> Result<Page, io::Error> {
+        self.pages.get(index).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Page {index} doesn't exist"),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 32;
+
+    fn round_trip(store: &mut dyn PageStore) {
+        for i in 0..3 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE);
+            assert_eq!(store.append_page(&page).unwrap(), i);
+        }
+        store
+            .write_page(1, &Page::from_vec(vec![9; PAGESIZE], PAGESIZE))
+            .unwrap();
+        store.sync().unwrap();
+
+        assert_eq!(store.n_pages().unwrap(), 3);
+        assert!(store.read_page(1).unwrap().read().iter().all(|&b| b == 9));
+        assert!(store.read_page(2).unwrap().read().iter().all(|&b| b == 2));
+        assert!(store.read_page(3).is_err());
+    }
+
+    #[test]
+    fn stores_behave_alike() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        round_trip(&mut manager);
+
+        round_trip(&mut MemoryStore::new(PAGESIZE));
+
+        let mut boxed: Box<dyn PageStore> = Box::new(MemoryStore::new(PAGESIZE));
+        round_trip(&mut boxed);
+    }
+}
@@ -0,0 +1,251 @@
+/*
+Page I/O through io_uring on Linux. Reads and writes of many pages are queued together and
+the ring is entered once per QUEUE_DEPTH pages instead of once per page, single pages take
+the same path. The ring has a handle of its own on the file, opened with O_DIRECT if asked,
+so pages bypass the page cache. Everything else, the lock, the file size, syncing and
+punching holes, stays with the manager's handle.
+
+O_DIRECT wants buffers, offsets and lengths aligned to the logical block size of the device.
+Offsets and lengths are multiples of the page size, which is at least MIN_PAGE_SIZE, and
+pages are copied through buffers aligned to ALIGNMENT. Devices with blocks larger than the
+page size, or file systems without O_DIRECT, fail with InvalidInput.
+*/
+
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::ptr::NonNull;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use super::Page;
+
+/// Entries of the submission queue, the most pages in flight at once
+const QUEUE_DEPTH: u32 = 64;
+/// Alignment of the buffers of direct I/O, the largest logical block size in common use
+const ALIGNMENT: usize = 4096;
+
+/// A zeroed heap buffer aligned for direct I/O
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, ALIGNMENT).expect("Page sizes are small");
+        // Safety: page sizes are never zero
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // Safety: the allocation is initialized and `layout.size()` bytes long
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: like as_slice, and the buffer is borrowed mutably
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safety: allocated in new with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+pub(crate) struct Ring {
+    ring: IoUring,
+    file: File,
+    direct: bool,
+}
+
+fn offset(index: usize, page_size: usize) -> u64 {
+    (index as u64) * (page_size as u64)
+}
+
+impl Ring {
+    /// Sets up a ring on the file at `path`, bypassing the page cache if `direct`
+    pub(crate) fn open(path: &str, direct: bool) -> Result<Self, io::Error> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        Ok(Self {
+            ring: IoUring::new(QUEUE_DEPTH)?,
+            file: options.open(path)?,
+            direct,
+        })
+    }
+
+    pub(crate) fn read(&mut self, page_size: usize, indices: &[usize]) -> io::Result<Vec<Page>> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        if self.direct {
+            let mut buffers: Vec<_> = indices
+                .iter()
+                .map(|_| AlignedBuffer::new(page_size))
+                .collect();
+            let entries = buffers.iter_mut().zip(indices).map(|(buffer, &index)| {
+                let data = buffer.as_mut_slice();
+                opcode::Read::new(fd, data.as_mut_ptr(), data.len() as u32)
+                    .offset(offset(index, page_size))
+                    .build()
+            });
+            // Safety: the buffers live until the function returns, after every read completed
+            unsafe { self.run(entries.collect(), page_size, io::ErrorKind::UnexpectedEof)? };
+            return Ok(buffers
+                .iter()
+                .map(|buffer| Page::from_vec(buffer.as_slice().to_vec(), page_size))
+                .collect());
+        }
+
+        let mut pages: Vec<_> = indices.iter().map(|_| Page::new(page_size)).collect();
+        let entries = pages.iter_mut().zip(indices).map(|(page, &index)| {
+            let data = page.mutate();
+            opcode::Read::new(fd, data.as_mut_ptr(), data.len() as u32)
+                .offset(offset(index, page_size))
+                .build()
+        });
+        // Safety: like above
+        unsafe { self.run(entries.collect(), page_size, io::ErrorKind::UnexpectedEof)? };
+        Ok(pages)
+    }
+
+    pub(crate) fn write(&mut self, page_size: usize, pages: &[(usize, &Page)]) -> io::Result<()> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let buffers: Vec<_> = match self.direct {
+            true => pages
+                .iter()
+                .map(|(_, page)| {
+                    let mut buffer = AlignedBuffer::new(page_size);
+                    buffer.as_mut_slice().copy_from_slice(page.read());
+                    Some(buffer)
+                })
+                .collect(),
+            false => pages.iter().map(|_| None).collect(),
+        };
+        let entries = pages.iter().zip(&buffers).map(|((index, page), buffer)| {
+            let data = buffer.as_ref().map_or(page.read(), AlignedBuffer::as_slice);
+            opcode::Write::new(fd, data.as_ptr(), data.len() as u32)
+                .offset(offset(*index, page_size))
+                .build()
+        });
+        // Safety: the pages and buffers live until the function returns, after every write
+        // completed
+        unsafe { self.run(entries.collect(), page_size, io::ErrorKind::WriteZero) }
+    }
+
+    /// Submits `entries` QUEUE_DEPTH at a time and waits for all of them. Operations that
+    /// move fewer than `len` bytes fail with `short`.
+    ///
+    /// # Safety
+    /// The buffers of the entries have to stay valid until this returns.
+    unsafe fn run(
+        &mut self,
+        entries: Vec<squeue::Entry>,
+        len: usize,
+        short: io::ErrorKind,
+    ) -> io::Result<()> {
+        for chunk in entries.chunks(QUEUE_DEPTH as usize) {
+            let mut result = Ok(());
+            for entry in chunk {
+                // Safety: up to the caller, and the queue has room for a whole chunk
+                unsafe { self.ring.submission().push(entry) }
+                    .map_err(|_| io::Error::other("Submission queue is full"))?;
+            }
+            let mut completed = 0;
+            while completed < chunk.len() {
+                // Submitted entries stay in flight, a wait that was interrupted is retried
+                match self.ring.submit_and_wait(chunk.len() - completed) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {}
+                    Err(err) => return Err(err),
+                }
+                for completion in self.ring.completion() {
+                    completed += 1;
+                    let moved = completion.result();
+                    if moved < 0 {
+                        result = result.and(Err(io::Error::from_raw_os_error(-moved)));
+                    } else if moved as usize != len {
+                        result = result.and(Err(io::Error::new(short, "Short page I/O")));
+                    }
+                }
+            }
+            result?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BTree;
+    use crate::page::{Backend, PageStore, Pager};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const PAGE: usize = 4096;
+
+    // Kernels and sandboxes without io_uring, or file systems without O_DIRECT, can't run
+    // these, so they only run with `cargo test -- --ignored`
+
+    #[test]
+    #[ignore = "needs io_uring and O_DIRECT"]
+    fn pages_round_trip_in_batches() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("pages.bin");
+        let path = file_path.to_str().unwrap();
+        File::create(path).unwrap();
+
+        for direct in [false, true] {
+            let mut ring = Ring::open(path, direct).unwrap();
+            // More pages than fit in the queue at once
+            let pages: Vec<_> = (0..150)
+                .map(|i| Page::from_vec(vec![i as u8 + direct as u8; PAGE], PAGE))
+                .collect();
+            let writes: Vec<_> = pages.iter().enumerate().collect();
+            ring.write(PAGE, &writes).unwrap();
+
+            let indices: Vec<usize> = (0..150).rev().collect();
+            let read = ring.read(PAGE, &indices).unwrap();
+            for (index, page) in indices.iter().zip(&read) {
+                assert_eq!(page.read(), pages[*index].read());
+            }
+            let err = ring.read(PAGE, &[150]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs io_uring and O_DIRECT"]
+    fn pagers_run_on_the_ring() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("db.bin");
+        let path = file_path.to_str().unwrap();
+        let pager = Pager::open_with_backend(path, Backend::IoUring { direct: true }).unwrap();
+        let mut tree = BTree::create(pager).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, &[key as u8; 64]).unwrap();
+        }
+        let root = tree.root();
+        let mut pager = tree.into_store();
+        pager.set_root(root).unwrap();
+        pager.sync().unwrap();
+        drop(pager);
+
+        let mut tree = BTree::open(Pager::open(path).unwrap(), root).unwrap();
+        assert_eq!(tree.get(1234).unwrap(), Some(vec![1234u16 as u8; 64]));
+        let mut pager = tree.into_store();
+        let pages = pager.read_pages(&[1, 2, 3]).unwrap();
+        assert_eq!(pages[1].read(), pager.read_page(2).unwrap().read());
+    }
+}
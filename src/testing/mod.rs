@@ -0,0 +1,23 @@
+/*
+Support for testing code built on the crate and the crate itself under random workloads, behind
+the `test-utils` feature. Models run operations against a node or a tree and against a
+BTreeMap doing the same thing, and report the first step where the two disagree or the node
+breaks an invariant. The fuzz targets in fuzz/ drive them with the operations a fuzzer comes up
+with, deriving those needs the `arbitrary` feature. The `proptest` feature adds strategies
+generating operations instead. With the `wal` feature, simulated disks lose power under the
+write-ahead log and check what it recovers.
+*/
+
+mod model;
+#[cfg(feature = "wal")]
+mod sim;
+#[cfg(any(test, feature = "proptest"))]
+mod strategies;
+
+#[cfg(feature = "pager")]
+pub use model::TreeModel;
+pub use model::{check_node_ops, Mismatch, NodeModel, NodeOp};
+#[cfg(feature = "wal")]
+pub use sim::{Fault, Outcome, SimDisk, SimFile, SimStore, Simulation, Violation, SECTOR_SIZE};
+#[cfg(any(test, feature = "proptest"))]
+pub use strategies::{node_op, node_ops, KEYS};
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+#[cfg(feature = "pager")]
+use crate::btree::BTree;
+use crate::btree::{BTreeError, Node, NodeConfig, PAGE_SIZE};
+#[cfg(feature = "pager")]
+use crate::page::MemoryStore;
+
+/// An operation on a node or tree and the model
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum NodeOp {
+    /// Keys are small so operations run into each other's keys
+    Insert {
+        key: u16,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: u16,
+    },
+    Get {
+        key: u16,
+    },
+    Defrag,
+}
+
+/// Where a node and its model disagreed
+#[derive(Debug, Error)]
+#[error("step {step}, {op:?}: {reason}")]
+pub struct Mismatch {
+    /// Operations applied before, counting from 0
+    pub step: usize,
+    /// `None` when the node broke an invariant before any operation
+    pub op: Option<NodeOp>,
+    pub reason: String,
+}
+
+/// A node in a page of its own next to a BTreeMap holding what the node should
+pub struct NodeModel {
+    page: Vec<u8>,
+    config: NodeConfig,
+    model: BTreeMap<u64, Vec<u8>>,
+    steps: usize,
+}
+
+impl NodeModel {
+    pub fn new(page_size: u16) -> Result<Self, BTreeError> {
+        Self::with_config(page_size, NodeConfig::default())
+    }
+
+    pub fn with_config(page_size: u16, config: NodeConfig) -> Result<Self, BTreeError> {
+        let mut page = vec![0; page_size.into()];
+        Node::new_with_config(&mut page, config)?;
+        Ok(NodeModel {
+            page,
+            config,
+            model: BTreeMap::new(),
+            steps: 0,
+        })
+    }
+
+    pub fn page(&self) -> &[u8] {
+        &self.page
+    }
+
+    /// What the node should hold
+    pub fn model(&self) -> &BTreeMap<u64, Vec<u8>> {
+        &self.model
+    }
+
+    /// Applies `op` to both and checks the node afterwards. Inserts the node has no room for
+    /// or its limits reject leave both unchanged.
+    pub fn apply(&mut self, op: &NodeOp) -> Result<(), Mismatch> {
+        let step = self.steps;
+        self.steps += 1;
+        let mismatch = |reason: String| Mismatch {
+            step,
+            op: Some(op.clone()),
+            reason,
+        };
+
+        let mut node = Node::load_with_config(&mut self.page, self.config)
+            .map_err(|err| mismatch(format!("load failed: {err}")))?;
+        match *op {
+            NodeOp::Insert { key, ref value } => match node.insert(key.into(), value) {
+                Ok(old) => {
+                    let expected = self.model.insert(key.into(), value.clone());
+                    let old = old.map(|old| old.value);
+                    if old != expected {
+                        return Err(mismatch(format!("replaced {old:?}, expected {expected:?}")));
+                    }
+                }
+                Err(BTreeError::NotEnoughSpace { .. } | BTreeError::LimitExceeded(_)) => {}
+                Err(err) => return Err(mismatch(format!("insert failed: {err}"))),
+            },
+            NodeOp::Delete { key } => {
+                let deleted = node
+                    .delete(key.into())
+                    .map_err(|err| mismatch(format!("delete failed: {err}")))?
+                    .map(|deleted| deleted.value);
+                let expected = self.model.remove(&key.into());
+                if deleted != expected {
+                    return Err(mismatch(format!(
+                        "deleted {deleted:?}, expected {expected:?}"
+                    )));
+                }
+            }
+            NodeOp::Get { key } => {
+                let value = node
+                    .get(key.into())
+                    .map_err(|err| mismatch(format!("get failed: {err}")))?;
+                let expected = self.model.get(&key.into()).map(Vec::as_slice);
+                if value != expected {
+                    return Err(mismatch(format!("got {value:?}, expected {expected:?}")));
+                }
+            }
+            // Padding of aligned values can leave too little room to lay them out again
+            NodeOp::Defrag => match node.defrag() {
+                Ok(()) | Err(BTreeError::NotEnoughSpace { .. }) => {}
+                Err(err) => return Err(mismatch(format!("defrag failed: {err}"))),
+            },
+        }
+        drop(node);
+
+        self.check().map_err(|err| Mismatch {
+            op: Some(op.clone()),
+            ..err
+        })
+    }
+
+    /// Checks the node's invariants and that it holds exactly what the model does
+    pub fn check(&mut self) -> Result<(), Mismatch> {
+        let mismatch = |reason: String| Mismatch {
+            step: self.steps,
+            op: None,
+            reason,
+        };
+        let node = Node::load_with_config(&mut self.page, self.config)
+            .map_err(|err| mismatch(format!("load failed: {err}")))?;
+        node.validate()
+            .and_then(|()| node.check_overlaps())
+            .map_err(|err| mismatch(format!("invalid node: {err}")))?;
+
+        let entries = node
+            .iter()
+            .map_err(|err| mismatch(format!("iteration failed: {err}")))?;
+        compare(entries, &self.model).map_err(mismatch)
+    }
+}
+
+/// A tree over a MemoryStore next to a BTreeMap holding what the tree should
+#[cfg(feature = "pager")]
+pub struct TreeModel {
+    tree: BTree<MemoryStore>,
+    model: BTreeMap<u64, Vec<u8>>,
+    steps: usize,
+}
+
+#[cfg(feature = "pager")]
+impl TreeModel {
+    /// An empty tree, small pages make for deep trees
+    pub fn new(page_size: u16) -> Result<Self, BTreeError> {
+        Ok(TreeModel {
+            tree: BTree::create(MemoryStore::new(page_size.into()))?,
+            model: BTreeMap::new(),
+            steps: 0,
+        })
+    }
+
+    pub fn tree(&mut self) -> &mut BTree<MemoryStore> {
+        &mut self.tree
+    }
+
+    /// What the tree should hold
+    pub fn model(&self) -> &BTreeMap<u64, Vec<u8>> {
+        &self.model
+    }
+
+    /// Applies `op` to both and checks the tree afterwards, Defrag defragments every
+    /// fragmented leaf. Values the tree's limits reject leave both unchanged.
+    pub fn apply(&mut self, op: &NodeOp) -> Result<(), Mismatch> {
+        let step = self.steps;
+        self.steps += 1;
+        let mismatch = |reason: String| Mismatch {
+            step,
+            op: Some(op.clone()),
+            reason,
+        };
+
+        match *op {
+            NodeOp::Insert { key, ref value } => match self.tree.insert(key.into(), value) {
+                Ok(old) => {
+                    let expected = self.model.insert(key.into(), value.clone());
+                    let old = old.map(|old| old.value);
+                    if old != expected {
+                        return Err(mismatch(format!("replaced {old:?}, expected {expected:?}")));
+                    }
+                }
+                Err(BTreeError::LimitExceeded(_)) => {}
+                Err(err) => return Err(mismatch(format!("insert failed: {err}"))),
+            },
+            NodeOp::Delete { key } => {
+                let deleted = self
+                    .tree
+                    .delete(key.into())
+                    .map_err(|err| mismatch(format!("delete failed: {err}")))?
+                    .map(|deleted| deleted.value);
+                let expected = self.model.remove(&key.into());
+                if deleted != expected {
+                    return Err(mismatch(format!(
+                        "deleted {deleted:?}, expected {expected:?}"
+                    )));
+                }
+            }
+            NodeOp::Get { key } => {
+                let value = self
+                    .tree
+                    .get(key.into())
+                    .map_err(|err| mismatch(format!("get failed: {err}")))?;
+                let expected = self.model.get(&key.into());
+                if value.as_ref() != expected {
+                    return Err(mismatch(format!("got {value:?}, expected {expected:?}")));
+                }
+            }
+            NodeOp::Defrag => {
+                let defrag = |tree: &mut BTree<MemoryStore>| {
+                    for leaf in tree.most_fragmented(usize::MAX)? {
+                        tree.defrag_leaf(leaf.page_id)?;
+                    }
+                    Ok::<_, BTreeError>(())
+                };
+                defrag(&mut self.tree).map_err(|err| mismatch(format!("defrag failed: {err}")))?;
+            }
+        }
+
+        self.check().map_err(|err| Mismatch {
+            op: Some(op.clone()),
+            ..err
+        })
+    }
+
+    /// Checks the tree's invariants and that it holds exactly what the model does
+    pub fn check(&mut self) -> Result<(), Mismatch> {
+        let mismatch = |reason: String| Mismatch {
+            step: self.steps,
+            op: None,
+            reason,
+        };
+        self.tree
+            .validate()
+            .map_err(|err| mismatch(format!("invalid tree: {err}")))?;
+        let entries = self
+            .tree
+            .iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| mismatch(format!("iteration failed: {err}")))?;
+        let entries = entries.iter().map(|(key, value)| (*key, &value[..]));
+        compare(entries, &self.model).map_err(mismatch)
+    }
+}
+
+/// Fails unless `entries` are exactly those of `model`, in the same order
+fn compare<'e>(
+    entries: impl Iterator<Item = (u64, &'e [u8])>,
+    model: &BTreeMap<u64, Vec<u8>>,
+) -> Result<(), String> {
+    let mut expected = model.iter();
+    for (key, value) in entries {
+        match expected.next() {
+            Some((&expected_key, expected_value))
+                if key == expected_key && value == &expected_value[..] => {}
+            Some((expected_key, _)) => {
+                return Err(format!(
+                    "found key {key} with {value:?}, expected key {expected_key}"
+                ))
+            }
+            None => return Err(format!("found key {key}, expected none")),
+        }
+    }
+    match expected.next() {
+        Some((key, _)) => Err(format!("key {key} is missing")),
+        None => Ok(()),
+    }
+}
+
+/// Applies `ops` to an empty node of the default page size and its model, stopping at the
+/// first mismatch
+pub fn check_node_ops(ops: &[NodeOp]) -> Result<(), Mismatch> {
+    let mut model = NodeModel::new(PAGE_SIZE).expect("PAGE_SIZE is a valid page size");
+    for op in ops {
+        model.apply(op)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Mostly inserts of values from a few bytes to a few hundred, so the node fills up and
+    /// deletes leave freeblocks of every size behind
+    fn random_ops(seed: u64, len: usize) -> Vec<NodeOp> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                let key = (next_random(&mut state) % 300) as u16;
+                match next_random(&mut state) % 40 {
+                    0..=19 => {
+                        let len = match next_random(&mut state) % 3 {
+                            0 => next_random(&mut state) % 8,
+                            1 => next_random(&mut state) % 64,
+                            _ => next_random(&mut state) % 400,
+                        };
+                        NodeOp::Insert {
+                            key,
+                            value: vec![key as u8; len as usize],
+                        }
+                    }
+                    20..=31 => NodeOp::Delete { key },
+                    32..=38 => NodeOp::Get { key },
+                    _ => NodeOp::Defrag,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_operations_match_the_model() {
+        for seed in 1..=10 {
+            let ops = random_ops(seed, 200);
+            if let Err(mismatch) = check_node_ops(&ops) {
+                panic!("seed {seed}: {mismatch}");
+            }
+        }
+
+        let configs = [
+            NodeConfig {
+                inline_values: true,
+                ..Default::default()
+            },
+            NodeConfig {
+                adaptive_layout: true,
+                prefix_compression: true,
+                ..Default::default()
+            },
+            NodeConfig {
+                align_values: true,
+                checksums: true,
+                verify_checksums: true,
+                ..Default::default()
+            },
+        ];
+        for config in configs {
+            for seed in 1..=4 {
+                let mut model = NodeModel::with_config(1024, config).unwrap();
+                for op in random_ops(seed, 200) {
+                    if let Err(mismatch) = model.apply(&op) {
+                        panic!("seed {seed}, {config:?}: {mismatch}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn damage_is_a_mismatch() {
+        let mut model = NodeModel::new(PAGE_SIZE).unwrap();
+        for key in 0..10 {
+            let value = vec![1; 20];
+            model.apply(&NodeOp::Insert { key, value }).unwrap();
+        }
+        model.apply(&NodeOp::Get { key: 3 }).unwrap();
+        assert_eq!(model.model().len(), 10);
+
+        // Swaps two values behind the model's back
+        let mut node = Node::load(&mut model.page).unwrap();
+        node.insert(3, &[2; 20]).unwrap();
+        drop(node);
+        let mismatch = model.apply(&NodeOp::Get { key: 3 }).unwrap_err();
+        assert_eq!(mismatch.step, 11);
+        assert_eq!(mismatch.op, Some(NodeOp::Get { key: 3 }));
+        assert!(mismatch.reason.starts_with("got Some([2,"), "{mismatch}");
+    }
+}
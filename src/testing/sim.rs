@@ -0,0 +1,649 @@
+/*
+Deterministic simulation of the storage below a WalStore, behind the `wal` feature. A SimDisk
+keeps its files in memory and tells what was written apart from what reached the disk: reads
+see a write right away, but it's only durable once its file was synced. When the power goes
+out every write since the last sync is kept, lost or torn at sector granularity, in any order,
+decided by a generator seeded by the caller, so a seed that fails fails the same way again.
+
+Faults hit the n-th I/O operation after they were injected. Crash takes the power out before
+the operation. PartialSync makes the next sync write out only some of its file's writes and
+fail, the others stay visible to reads but never reach the disk, like Linux forgets dirty
+pages after a failed fsync.
+
+Simulation runs transactions of page writes through a WalStore on a SimDisk with a fault
+injected, takes the power out wherever the workload stopped, crashes the first recovery too
+and recovers again. The store then has to hold every page as of one commit, never a mix of
+two, and no commit older than the last one the sync mode promised to keep. SyncMode::Off
+promises nothing once the power goes out, only recovering at all is checked for it.
+*/
+
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use thiserror::Error;
+
+use crate::log::{LogFile, SyncMode, WalRecovery, WalStore};
+use crate::page::{Page, PageStore};
+
+/// Writes are torn at this granularity
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The power goes out before the operation
+    Crash,
+    /// The first sync from the operation on fails after writing out only some writes
+    PartialSync,
+}
+
+/// Files in memory that lose unsynced writes like a disk losing power, see the module docs
+#[derive(Clone)]
+pub struct SimDisk {
+    state: Arc<Mutex<DiskState>>,
+}
+
+struct DiskState {
+    random: u64,
+    /// I/O operations so far
+    ops: u64,
+    /// The fault and the operation it hits
+    fault: Option<(Fault, u64)>,
+    crashed: bool,
+    files: Vec<FileState>,
+}
+
+#[derive(Default)]
+struct FileState {
+    durable: Vec<u8>,
+    /// What reads see, the durable bytes with the unsynced writes on top
+    current: Vec<u8>,
+    unsynced: Vec<Write>,
+}
+
+enum Write {
+    At { offset: usize, data: Vec<u8> },
+    SetLen(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Read,
+    Write,
+    Sync,
+}
+
+/// SplitMix64, any state works as a seed
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn write_at(bytes: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if bytes.len() < offset + data.len() {
+        bytes.resize(offset + data.len(), 0);
+    }
+    bytes[offset..offset + data.len()].copy_from_slice(data);
+}
+
+impl FileState {
+    fn apply(&mut self, write: Write) {
+        match write {
+            Write::At { offset, data } => write_at(&mut self.durable, offset, &data),
+            Write::SetLen(len) => self.durable.resize(len, 0),
+        }
+    }
+
+    /// Writes the unsynced writes out the way losing power would: each one whole, not at all
+    /// or some of its sectors. File systems order length changes, a lost one takes every
+    /// write after it along.
+    fn write_out(&mut self, random: &mut u64) {
+        for write in std::mem::take(&mut self.unsynced) {
+            match write {
+                Write::SetLen(len) if next_random(random).is_multiple_of(2) => {
+                    self.durable.resize(len, 0);
+                }
+                Write::SetLen(_) => break,
+                Write::At { offset, data } => match next_random(random) % 3 {
+                    0 => write_at(&mut self.durable, offset, &data),
+                    1 => {}
+                    _ => {
+                        let mut start = offset;
+                        while start < offset + data.len() {
+                            let end =
+                                ((start / SECTOR_SIZE + 1) * SECTOR_SIZE).min(offset + data.len());
+                            if next_random(random).is_multiple_of(2) {
+                                write_at(
+                                    &mut self.durable,
+                                    start,
+                                    &data[start - offset..end - offset],
+                                );
+                            }
+                            start = end;
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl DiskState {
+    /// Counts an operation and fails it if the disk is out or a fault hits it
+    fn begin(&mut self, file: usize, op: Op) -> Result<(), io::Error> {
+        self.ops += 1;
+        if self.crashed {
+            return Err(io::Error::other("Simulated disk lost power"));
+        }
+        match self.fault {
+            Some((Fault::Crash, at)) if self.ops > at => {
+                self.fault = None;
+                self.crash();
+                Err(io::Error::other("Simulated disk lost power"))
+            }
+            Some((Fault::PartialSync, at)) if self.ops > at && op == Op::Sync => {
+                self.fault = None;
+                let file = &mut self.files[file];
+                file.write_out(&mut self.random);
+                Err(io::Error::other("Simulated sync failed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn crash(&mut self) {
+        self.crashed = true;
+        for file in &mut self.files {
+            file.write_out(&mut self.random);
+            file.current = file.durable.clone();
+        }
+    }
+}
+
+impl SimDisk {
+    pub fn new(seed: u64) -> Self {
+        SimDisk {
+            state: Arc::new(Mutex::new(DiskState {
+                random: seed,
+                ops: 0,
+                fault: None,
+                crashed: false,
+                files: Vec::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, DiskState> {
+        self.state.lock().expect("SimDisk lock poisoned")
+    }
+
+    /// A new empty file
+    pub fn create_file(&self) -> SimFile {
+        let mut state = self.state();
+        state.files.push(FileState::default());
+        SimFile {
+            disk: self.clone(),
+            id: state.files.len() - 1,
+        }
+    }
+
+    /// Lets `fault` hit the I/O operation `after` operations from now, replacing the fault
+    /// injected before
+    pub fn inject(&self, fault: Fault, after: u64) {
+        let mut state = self.state();
+        state.fault = Some((fault, state.ops.saturating_add(after)));
+    }
+
+    /// Takes back the injected fault if it didn't hit yet
+    pub fn disarm(&self) {
+        self.state().fault = None;
+    }
+
+    /// I/O operations so far
+    pub fn ops(&self) -> u64 {
+        self.state().ops
+    }
+
+    /// Takes the power out, every operation fails until restart
+    pub fn crash(&self) {
+        let mut state = self.state();
+        if !state.crashed {
+            state.crash();
+        }
+    }
+
+    /// Brings the power back, files hold what reached the disk
+    pub fn restart(&self) {
+        self.state().crashed = false;
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.state().crashed
+    }
+}
+
+/// A file on a SimDisk, clones are handles to the same file
+#[derive(Clone)]
+pub struct SimFile {
+    disk: SimDisk,
+    id: usize,
+}
+
+impl SimFile {
+    /// The file as a store of `page_size` pages
+    pub fn pages(self, page_size: usize) -> SimStore {
+        SimStore {
+            file: self,
+            page_size,
+        }
+    }
+
+    /// The bytes of the file that are durable
+    pub fn durable(&self) -> Vec<u8> {
+        self.disk.state().files[self.id].durable.clone()
+    }
+}
+
+impl LogFile for SimFile {
+    fn size(&self) -> Result<u64, io::Error> {
+        Ok(self.disk.state().files[self.id].current.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        let mut state = self.disk.state();
+        state.begin(self.id, Op::Write)?;
+        let file = &mut state.files[self.id];
+        file.current.resize(len as usize, 0);
+        file.unsynced.push(Write::SetLen(len as usize));
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        let mut state = self.disk.state();
+        state.begin(self.id, Op::Read)?;
+        let offset = offset as usize;
+        let data = state.files[self.id]
+            .current
+            .get(offset..offset + buf.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the end"))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.disk.state();
+        state.begin(self.id, Op::Write)?;
+        let file = &mut state.files[self.id];
+        write_at(&mut file.current, offset as usize, data);
+        file.unsynced.push(Write::At {
+            offset: offset as usize,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        let mut state = self.disk.state();
+        state.begin(self.id, Op::Sync)?;
+        let file = &mut state.files[self.id];
+        // Not a copy of what reads see, a failed sync may have dropped writes for good
+        for write in std::mem::take(&mut file.unsynced) {
+            file.apply(write);
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn LogFile>, io::Error> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// Pages in a SimFile
+#[derive(Clone)]
+pub struct SimStore {
+    file: SimFile,
+    page_size: usize,
+}
+
+impl PageStore for SimStore {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, index: usize) -> Result<Page, io::Error> {
+        let mut page = Page::new(self.page_size);
+        self.file
+            .read_at((index * self.page_size) as u64, page.mutate())?;
+        Ok(page)
+    }
+
+    fn write_page(&mut self, index: usize, page: &Page) -> Result<(), io::Error> {
+        self.file
+            .write_at((index * self.page_size) as u64, page.read())
+    }
+
+    fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let index = self.n_pages()?;
+        self.write_page(index, page)?;
+        Ok(index)
+    }
+
+    fn n_pages(&self) -> Result<usize, io::Error> {
+        Ok(self.file.size()? as usize / self.page_size)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.file.sync()
+    }
+}
+
+/// A workload for WalStores on SimDisks, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Simulation {
+    pub page_size: usize,
+    /// Pages of the store, transactions write to them at random
+    pub pages: usize,
+    pub transactions: usize,
+    /// Most pages a transaction writes
+    pub max_writes: usize,
+    pub sync_mode: SyncMode,
+    /// Commits between checkpoints, `None` for no checkpoints
+    pub checkpoint_every: Option<usize>,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation {
+            page_size: 1024,
+            pages: 8,
+            transactions: 12,
+            max_writes: 3,
+            sync_mode: SyncMode::Full,
+            checkpoint_every: Some(5),
+        }
+    }
+}
+
+/// What a simulation run got to and what survived it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Outcome {
+    /// Commits started, the last one may not have finished
+    pub attempted: usize,
+    /// Commits that returned
+    pub acknowledged: usize,
+    /// Commits the sync mode promised to keep
+    pub durable: usize,
+    /// The commit the store holds the pages of, `None` for a mix only SyncMode::Off may
+    /// leave behind
+    pub recovered: Option<usize>,
+    /// What the recovery that succeeded found in the log
+    pub recovery: WalRecovery,
+}
+
+/// A broken promise, with what it takes to run into it again
+#[derive(Debug, Error)]
+#[error("seed {seed}, {fault:?} after {after} operations: {reason}")]
+pub struct Violation {
+    pub seed: u64,
+    pub fault: Fault,
+    pub after: u64,
+    pub reason: String,
+}
+
+/// The content of page `index` as written by transaction `txn`, zeros before the first
+fn content(txn: usize, index: usize, page_size: usize) -> Page {
+    let mut page = Page::new(page_size);
+    if txn > 0 {
+        let data = page.mutate();
+        data.fill((txn * 31 + index) as u8);
+        data[..8].copy_from_slice(&(txn as u64).to_le_bytes());
+    }
+    page
+}
+
+/// The transaction that wrote `page`, `None` for a page that's torn
+fn writer(page: &Page, index: usize) -> Option<usize> {
+    let txn = u64::from_le_bytes(page.read()[..8].try_into().expect("Slice is 8 bytes")) as usize;
+    (page.read() == content(txn, index, page.read().len()).read()).then_some(txn)
+}
+
+/// Recovery crashes within this many operations, if it takes that many
+const RECOVERY_CRASH_WINDOW: u64 = 64;
+
+impl Simulation {
+    /// I/O operations the workload does when nothing fails, the points a fault can hit
+    pub fn operations(&self, seed: u64) -> Result<u64, Violation> {
+        let disk = SimDisk::new(seed);
+        let violation = |reason| Violation {
+            seed,
+            fault: Fault::Crash,
+            after: u64::MAX,
+            reason,
+        };
+        let (mut wal, _) = self
+            .setup(&disk)
+            .map_err(|err| violation(format!("{err}")))?;
+        let start = disk.ops();
+        self.workload(&mut wal, seed, &mut Vec::new(), &mut Outcome::default())
+            .map_err(|err| violation(format!("workload failed: {err}")))?;
+        Ok(disk.ops() - start)
+    }
+
+    /// Runs the workload on a disk seeded with `seed`, with `fault` hitting it `after`
+    /// operations in, then recovers and checks what survived
+    pub fn run(&self, seed: u64, fault: Fault, after: u64) -> Result<Outcome, Violation> {
+        let violation = |reason| Violation {
+            seed,
+            fault,
+            after,
+            reason,
+        };
+        let disk = SimDisk::new(seed);
+        let (mut wal, (store, log)) = self
+            .setup(&disk)
+            .map_err(|err| violation(format!("setup failed: {err}")))?;
+
+        disk.inject(fault, after);
+        let mut history = vec![vec![0; self.pages]];
+        let mut outcome = Outcome::default();
+        // The workload stops at the first error, whatever it left is up to recovery
+        let _ = self.workload(&mut wal, seed, &mut history, &mut outcome);
+        drop(wal);
+        disk.disarm();
+        disk.crash();
+        disk.restart();
+
+        // Recovery that crashes has to leave a log the next one still recovers from
+        let after = next_random(&mut seed.wrapping_add(1)) % RECOVERY_CRASH_WINDOW;
+        disk.inject(Fault::Crash, after);
+        let recovered = WalStore::open_with_log(store.clone(), log.clone());
+        disk.disarm();
+        let mut wal = match recovered {
+            Ok(wal) => wal,
+            Err(_) => {
+                disk.crash();
+                disk.restart();
+                WalStore::open_with_log(store, log)
+                    .map_err(|err| violation(format!("recovery failed: {err}")))?
+            }
+        };
+        outcome.recovery = wal.recovery();
+
+        let mut writers = Vec::with_capacity(self.pages);
+        for index in 0..self.pages {
+            let page = wal
+                .read_page(index)
+                .map_err(|err| violation(format!("reading page {index} failed: {err}")))?;
+            writers.push(writer(&page, index));
+        }
+        outcome.recovered = (0..history.len()).rev().find(|&txn| {
+            writers
+                .iter()
+                .zip(&history[txn])
+                .all(|(writer, expected)| *writer == Some(*expected))
+        });
+
+        if self.sync_mode == SyncMode::Off {
+            return Ok(outcome);
+        }
+        match outcome.recovered {
+            None => Err(violation(format!(
+                "pages {writers:?} don't match any commit"
+            ))),
+            Some(recovered) if recovered < outcome.durable => Err(violation(format!(
+                "recovered commit {recovered}, commit {} was durable",
+                outcome.durable
+            ))),
+            Some(_) => Ok(outcome),
+        }
+    }
+
+    /// Runs with `fault` hitting every operation of the workload in turn
+    pub fn run_everywhere(&self, seed: u64, fault: Fault) -> Result<Vec<Outcome>, Violation> {
+        (0..self.operations(seed)?)
+            .map(|after| self.run(seed, fault, after))
+            .collect()
+    }
+
+    /// An empty store of zeroed pages behind a WalStore, with the handles to reopen it
+    fn setup(
+        &self,
+        disk: &SimDisk,
+    ) -> Result<(WalStore<SimStore>, (SimStore, SimFile)), io::Error> {
+        let mut store = disk.create_file().pages(self.page_size);
+        let log = disk.create_file();
+        for _ in 0..self.pages {
+            store.append_page(&Page::new(self.page_size))?;
+        }
+        store.sync()?;
+        let mut wal = WalStore::open_with_log(store.clone(), log.clone())?;
+        wal.set_sync_mode(self.sync_mode)?;
+        Ok((wal, (store, log)))
+    }
+
+    /// Commits the transactions, recording the writer of every page as of each commit in
+    /// `history` and how far it got in `outcome`
+    fn workload(
+        &self,
+        wal: &mut WalStore<SimStore>,
+        seed: u64,
+        history: &mut Vec<Vec<usize>>,
+        outcome: &mut Outcome,
+    ) -> Result<(), io::Error> {
+        // Faults and writes come from different generators, so the fault doesn't change
+        // the workload
+        let mut random = !seed;
+        for txn in 1..=self.transactions {
+            let mut writers = history.last().cloned().unwrap_or(vec![0; self.pages]);
+            for _ in 0..=next_random(&mut random) as usize % self.max_writes {
+                let index = next_random(&mut random) as usize % self.pages;
+                wal.write_page(index, &content(txn, index, self.page_size))?;
+                writers[index] = txn;
+            }
+            history.push(writers);
+            outcome.attempted = txn;
+
+            wal.commit()?;
+            outcome.acknowledged = txn;
+            if self.sync_mode == SyncMode::Full {
+                outcome.durable = txn;
+            }
+            if self
+                .checkpoint_every
+                .is_some_and(|every| txn.is_multiple_of(every))
+            {
+                wal.checkpoint()?;
+                outcome.durable = txn;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn power_loss_keeps_synced_writes() {
+        let mut torn = 0;
+        for seed in 0..32 {
+            let disk = SimDisk::new(seed);
+            let mut file = disk.create_file();
+            file.write_at(0, &[1; 2 * SECTOR_SIZE]).unwrap();
+            file.sync().unwrap();
+            file.write_at(0, &[2; 2 * SECTOR_SIZE]).unwrap();
+            file.write_at(2 * SECTOR_SIZE as u64, &[3; SECTOR_SIZE])
+                .unwrap();
+
+            disk.crash();
+            assert!(file.read_at(0, &mut [0; 1]).is_err());
+            disk.restart();
+            let durable = file.durable();
+            assert!(durable.len() >= 2 * SECTOR_SIZE);
+            for sector in durable.chunks(SECTOR_SIZE) {
+                assert!(sector.iter().all(|&byte| byte == sector[0]));
+            }
+            let sectors: Vec<u8> = durable.chunks(SECTOR_SIZE).map(|s| s[0]).collect();
+            torn += (sectors[..2] == [1, 2] || sectors[..2] == [2, 1]) as usize;
+
+            let mut buf = vec![0; durable.len()];
+            file.read_at(0, &mut buf).unwrap();
+            assert_eq!(buf, durable);
+        }
+        assert!(torn > 0);
+    }
+
+    #[test]
+    fn failed_syncs_forget_writes() {
+        let disk = SimDisk::new(7);
+        let mut file = disk.create_file();
+        let writes = 32;
+        for i in 0..writes {
+            file.write_at(i * SECTOR_SIZE as u64, &[i as u8 + 1; SECTOR_SIZE])
+                .unwrap();
+        }
+        disk.inject(Fault::PartialSync, 0);
+        assert!(file.sync().is_err());
+
+        // Reads see every write, the disk only some of them even after a sync
+        let mut buf = vec![0; writes as usize * SECTOR_SIZE];
+        file.read_at(0, &mut buf).unwrap();
+        file.sync().unwrap();
+        let durable = file.durable();
+        assert!(durable.len() <= buf.len());
+        assert_ne!(durable[..], buf[..durable.len()]);
+        assert_eq!(file.size().unwrap(), buf.len() as u64);
+    }
+
+    #[test]
+    fn wal_keeps_its_promises_under_faults() {
+        let full = Simulation::default();
+        let normal = Simulation {
+            sync_mode: SyncMode::Normal,
+            ..full
+        };
+        for simulation in [full, normal] {
+            for fault in [Fault::Crash, Fault::PartialSync] {
+                for seed in 0..8 {
+                    let outcomes = simulation.run_everywhere(seed, fault).unwrap();
+                    assert!(outcomes.iter().any(|outcome| outcome.recovery.replayed > 0));
+                }
+            }
+        }
+
+        // Without a fault the power only goes out after the last commit
+        let outcome = normal.run(0, Fault::Crash, u64::MAX).unwrap();
+        assert_eq!(outcome.acknowledged, normal.transactions);
+        assert!(outcome.recovered >= Some(10));
+
+        let off = Simulation {
+            sync_mode: SyncMode::Off,
+            ..full
+        };
+        off.run_everywhere(0, Fault::Crash).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+/*
+proptest strategies for model operations, behind the `proptest` feature. Keys come from a small
+range, so operations keep running into each other's keys, values have anywhere from none to
+`max_value` bytes. proptest shrinks a failing sequence to the shortest one it can find that
+still fails, usually a handful of operations.
+*/
+
+use proptest::collection::{vec, SizeRange};
+use proptest::prelude::*;
+
+use super::NodeOp;
+
+/// Keys of generated operations are below this
+pub const KEYS: u16 = 300;
+
+/// Mostly inserts and deletes, so nodes fill up and leave freeblocks behind
+pub fn node_op(max_value: usize) -> impl Strategy<Value = NodeOp> {
+    prop_oneof![
+        5 => (0..KEYS, vec(any::<u8>(), 0..=max_value))
+            .prop_map(|(key, value)| NodeOp::Insert { key, value }),
+        3 => (0..KEYS).prop_map(|key| NodeOp::Delete { key }),
+        1 => (0..KEYS).prop_map(|key| NodeOp::Get { key }),
+        1 => Just(NodeOp::Defrag),
+    ]
+}
+
+pub fn node_ops(max_value: usize, len: impl Into<SizeRange>) -> impl Strategy<Value = Vec<NodeOp>> {
+    vec(node_op(max_value), len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::check_node_ops;
+    #[cfg(feature = "pager")]
+    use super::super::TreeModel;
+    use super::*;
+    use proptest::test_runner::TestCaseError;
+
+    proptest! {
+        // Every case runs its operations in a debug build, a few cases per run go a long way
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn nodes_keep_their_invariants(ops in node_ops(400, 0..150)) {
+            check_node_ops(&ops).map_err(|mismatch| TestCaseError::fail(mismatch.to_string()))?;
+        }
+
+        #[cfg(feature = "pager")]
+        #[test]
+        fn trees_keep_their_invariants(ops in node_ops(100, 0..80)) {
+            let mut model = TreeModel::new(512).unwrap();
+            for op in &ops {
+                model
+                    .apply(op)
+                    .map_err(|mismatch| TestCaseError::fail(mismatch.to_string()))?;
+            }
+        }
+    }
+}